@@ -1,13 +1,56 @@
+#[cfg(feature = "sdk")]
 use std::env;
-use std::path::PathBuf;
+#[cfg(feature = "sdk")]
+use std::path::{Path, PathBuf};
 
 fn main() {
-    // Tell cargo to look for libraries in the specified directory.
-    println!("cargo:rustc-link-search=asi_sdk/ASI_linux_mac_SDK_V1.29/lib/armv8");
+    // Without the `sdk` feature the crate does not call into the ASI Camera2
+    // SDK, so there is nothing to generate bindings for or link against.
+    #[cfg(feature = "sdk")]
+    build_sdk();
+}
+
+// Returns the subdirectory of the SDK's lib directory holding the library for
+// the platform we're building for.
+#[cfg(feature = "sdk")]
+fn sdk_lib_dir() -> &'static str {
+    let target_arch = env::var("CARGO_CFG_TARGET_ARCH").unwrap();
+    match target_arch.as_str() {
+        "x86_64" => "x64",
+        "x86" => "x86",
+        "aarch64" => "armv8",
+        // Both armv6 and armv7 report "arm"; tell them apart by target triple,
+        // e.g. armv7-unknown-linux-gnueabihf vs. arm-unknown-linux-gnueabihf.
+        "arm" => if env::var("TARGET").unwrap().starts_with("armv7") {
+            "armv7"
+        } else {
+            "armv6"
+        },
+        _ => panic!("ASI Camera2 SDK is not available for {}", target_arch),
+    }
+}
+
+#[cfg(feature = "sdk")]
+fn build_sdk() {
+    // With the `dynamic` feature the library is loaded at runtime instead.
+    if cfg!(not(feature = "dynamic")) {
+        // Only some architectures' libraries are vendored; fail here rather
+        // than with an obscure link error.
+        let lib_dir = format!("asi_sdk/ASI_linux_mac_SDK_V1.29/lib/{}", sdk_lib_dir());
+        if !Path::new(&lib_dir).is_dir() {
+            panic!("{} not found. Copy the library for this architecture from the \
+                    ASI Camera2 SDK (Linux & Mac, V1.29), or build with the `dynamic` \
+                    feature.", lib_dir);
+        }
+        println!("cargo:rerun-if-changed={}", lib_dir);
+
+        // Tell cargo to look for libraries in the specified directory.
+        println!("cargo:rustc-link-search={}", lib_dir);
 
-    // Tell cargo to tell rustc to link the library statically.
-    println!("cargo:rustc-link-lib=static=ASICamera2");
-    println!("cargo:rustc-link-lib=dylib=stdc++");
+        // Tell cargo to tell rustc to link the library statically.
+        println!("cargo:rustc-link-lib=static=ASICamera2");
+        println!("cargo:rustc-link-lib=dylib=stdc++");
+    }
 
     // Tell cargo to invalidate the built crate whenever the wrapper changes.
     println!("cargo:rerun-if-changed=wrapper.h");
@@ -15,13 +58,18 @@ fn main() {
     // The bindgen::Builder is the main entry point
     // to bindgen, and lets you build up options for
     // the resulting bindings.
-    let bindings = bindgen::Builder::default()
+    let mut builder = bindgen::Builder::default()
         // The input header we would like to generate bindings for.
         .header("wrapper.h")
         .clang_arg("-Iasi_sdk/ASI_linux_mac_SDK_V1.29/include")
         // Tell cargo to invalidate the built crate whenever any of the
         // included header files changed.
-        .parse_callbacks(Box::new(bindgen::CargoCallbacks))
+        .parse_callbacks(Box::new(bindgen::CargoCallbacks));
+    if cfg!(feature = "dynamic") {
+        // The functions are provided by the dynamic_sdk module instead.
+        builder = builder.ignore_functions();
+    }
+    let bindings = builder
         // Finish the builder and generate the bindings.
         .generate()
         // Unwrap the Result and panic on failure.
@@ -0,0 +1,363 @@
+// Copyright (c) 2023 Steven Rosenthal smr@dt3.org
+// See LICENSE file in root directory for license terms.
+
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::mpsc::{self, Receiver, Sender, TryRecvError};
+use std::task::{Context, Poll};
+use std::thread;
+use std::time::Duration;
+
+use futures_core::Stream;
+use log::{info, warn};
+use tokio::sync::mpsc::error::TrySendError;
+use tokio::sync::{mpsc as frame_channel, oneshot};
+
+use crate::asi_camera2_sdk::{ASIError, ASIErrorKind,
+                             ASI_ERROR_CODE_ASI_ERROR_GENERAL_ERROR,
+                             ASI_ERROR_CODE_ASI_ERROR_VIDEO_MODE_ACTIVE};
+use crate::camera_backend::CameraBackend;
+use crate::exposure::{CancelToken, ExposureOptions};
+use crate::frame::Frame;
+use crate::lock_ignoring_poison;
+
+// How long the worker waits for each video frame before checking for requests
+// and for the stream having been dropped.
+const VIDEO_POLL_INTERVAL_MS: i32 = 100;
+
+type Job<B> = Box<dyn FnOnce(&mut Worker<B>) + Send>;
+
+/// Async access to a camera. The camera is moved to a dedicated worker thread
+/// that makes all of its SDK calls, so that their blocking never ties up the
+/// async executor. Requests are run by the worker in the order they are made.
+///
+/// Only tokio's synchronization primitives are used, so the futures returned
+/// can be awaited on any executor.
+///
+/// Dropping an AsyncCamera ends the worker once it has finished its current
+/// request, stopping video capture; the camera is then dropped on the worker
+/// thread.
+pub struct AsyncCamera<B: CameraBackend + Send + 'static> {
+    camera_id: i32,
+    jobs: Sender<Job<B>>,
+}
+
+impl<B: CameraBackend + Send + 'static> AsyncCamera<B> {
+    /// Moves `camera`, which should already be opened and initialized, to a
+    /// new worker thread.
+    pub fn new(camera: B) -> Self {
+        let camera_id = camera.camera_id();
+        let (jobs, job_receiver) = mpsc::channel();
+        thread::spawn(move || Worker{camera, video: None}.run(job_receiver));
+        AsyncCamera{camera_id, jobs}
+    }
+
+    pub fn camera_id(&self) -> i32 { self.camera_id }
+
+    /// Runs `f` with the camera on the worker thread and returns its result.
+    /// This gives async access to all of the CameraBackend methods, e.g.
+    /// `camera.run(|c| c.set_control_value(ControlType::Gain, 100, false))`.
+    pub async fn run<F, T>(&self, f: F) -> Result<T, ASIError>
+    where F: FnOnce(&mut B) -> Result<T, ASIError> + Send + 'static,
+          T: Send + 'static
+    {
+        self.run_worker(move |worker| f(&mut worker.camera)).await
+    }
+
+    /// Async version of CameraBackend::capture_exposure(). Dropping the
+    /// returned future before it completes cancels the exposure.
+    pub async fn capture_exposure(&self, duration: Duration, is_dark: bool,
+                                  timeout: Option<Duration>) -> Result<Frame, ASIError> {
+        self.capture_exposure_with(duration, is_dark, timeout,
+                                   &ExposureOptions::default()).await
+    }
+
+    /// Async version of CameraBackend::capture_exposure_with(). If `options`
+    /// has no cancel token, dropping the returned future before it completes
+    /// cancels the exposure.
+    pub async fn capture_exposure_with(&self, duration: Duration, is_dark: bool,
+                                       timeout: Option<Duration>,
+                                       options: &ExposureOptions)
+                                       -> Result<Frame, ASIError> {
+        let mut options = options.clone();
+        let _cancel_on_drop = if options.cancel.is_none() {
+            let cancel = CancelToken::new();
+            options.cancel = Some(cancel.clone());
+            Some(CancelOnDrop(cancel))
+        } else {
+            None
+        };
+        self.run(move |camera| {
+            camera.capture_exposure_with(duration, is_dark, timeout, &options)
+        }).await
+    }
+
+    /// Starts video capture and returns a stream of its frames. Up to
+    /// `queue_capacity` frames are buffered for the consumer; frames arriving
+    /// when the buffer is full are discarded (see
+    /// AsyncVideoStream::dropped_frames()). Other requests are run between
+    /// frames.
+    ///
+    /// The stream ends after yielding an error (waiting for a frame timing out
+    /// is not one), or when the AsyncCamera is dropped. Dropping the stream
+    /// stops video capture; requests made after that find it stopped.
+    pub async fn start_video(&self, queue_capacity: usize)
+                             -> Result<AsyncVideoStream, ASIError> {
+        let (frame_sender, frames) = frame_channel::channel(queue_capacity.max(1));
+        let dropped = Arc::new(AtomicU64::new(0));
+        let error = Arc::new(Mutex::new(None));
+        let video = Video{frames: frame_sender, dropped: dropped.clone(),
+                          error: error.clone()};
+        self.run_worker(move |worker| worker.start_video(video)).await?;
+        Ok(AsyncVideoStream{frames, dropped, error})
+    }
+
+    async fn run_worker<F, T>(&self, f: F) -> Result<T, ASIError>
+    where F: FnOnce(&mut Worker<B>) -> Result<T, ASIError> + Send + 'static,
+          T: Send + 'static
+    {
+        let (result_sender, result) = oneshot::channel();
+        let job: Job<B> = Box::new(move |worker| {
+            // Nothing to do if the caller is no longer waiting.
+            let _ = result_sender.send(f(worker));
+        });
+        if self.jobs.send(job).is_err() {
+            return Err(worker_exited());
+        }
+        result.await.unwrap_or_else(|_| Err(worker_exited()))
+    }
+}
+
+/// Video frames from AsyncCamera::start_video(). Dropping the stream stops
+/// video capture.
+pub struct AsyncVideoStream {
+    frames: frame_channel::Receiver<Result<Frame, ASIError>>,
+    dropped: Arc<AtomicU64>,
+    // The error that ended the stream, yielded once the queued frames have
+    // been.
+    error: Arc<Mutex<Option<ASIError>>>,
+}
+
+impl AsyncVideoStream {
+    /// Returns the next frame, or None once the stream has ended.
+    pub async fn next_frame(&mut self) -> Option<Result<Frame, ASIError>> {
+        match self.frames.recv().await {
+            Some(frame) => Some(frame),
+            None => self.take_error(),
+        }
+    }
+
+    /// Frames discarded because the consumer fell behind. Frames dropped by the
+    /// SDK are counted separately, in FrameMetadata::dropped_frames.
+    pub fn dropped_frames(&self) -> u64 {
+        self.dropped.load(Ordering::Relaxed)
+    }
+
+    fn take_error(&self) -> Option<Result<Frame, ASIError>> {
+        lock_ignoring_poison(&self.error).take().map(Err)
+    }
+}
+
+impl Stream for AsyncVideoStream {
+    type Item = Result<Frame, ASIError>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>)
+                 -> Poll<Option<Self::Item>> {
+        match self.frames.poll_recv(cx) {
+            Poll::Ready(None) => Poll::Ready(self.take_error()),
+            poll => poll,
+        }
+    }
+}
+
+struct CancelOnDrop(CancelToken);
+
+impl Drop for CancelOnDrop {
+    fn drop(&mut self) { self.0.cancel(); }
+}
+
+struct Video {
+    frames: frame_channel::Sender<Result<Frame, ASIError>>,
+    dropped: Arc<AtomicU64>,
+    error: Arc<Mutex<Option<ASIError>>>,
+}
+
+// State of the worker thread.
+struct Worker<B> {
+    camera: B,
+    video: Option<Video>,
+}
+
+impl<B: CameraBackend> Worker<B> {
+    // Body of the worker thread.
+    fn run(mut self, jobs: Receiver<Job<B>>) {
+        loop {
+            if self.video.as_ref().is_some_and(|video| video.frames.is_closed()) {
+                self.stop_video();
+            }
+            let job = if self.video.is_some() {
+                match jobs.try_recv() {
+                    Ok(job) => Some(job),
+                    Err(TryRecvError::Empty) => None,
+                    Err(TryRecvError::Disconnected) => break,
+                }
+            } else {
+                match jobs.recv() {
+                    Ok(job) => Some(job),
+                    Err(_) => break,
+                }
+            };
+            match job {
+                Some(job) => job(&mut self),
+                None => self.capture_video_frame(),
+            }
+        }
+        self.stop_video();
+        info!("Async worker for camera id {} exiting", self.camera.camera_id());
+    }
+
+    fn start_video(&mut self, video: Video) -> Result<(), ASIError> {
+        if self.video.is_some() {
+            return Err(ASIError::new(ASI_ERROR_CODE_ASI_ERROR_VIDEO_MODE_ACTIVE,
+                                     "start_video"));
+        }
+        self.camera.start_video_capture()?;
+        self.video = Some(video);
+        Ok(())
+    }
+
+    fn stop_video(&mut self) {
+        if self.video.take().is_some() {
+            if let Err(e) = self.camera.stop_video_capture() {
+                warn!("Error stopping video capture on camera id {}: {}",
+                      self.camera.camera_id(), e);
+            }
+        }
+    }
+
+    fn capture_video_frame(&mut self) {
+        let Some(video) = &self.video else {
+            return;
+        };
+        match self.camera.get_video_frame(VIDEO_POLL_INTERVAL_MS) {
+            Ok(frame) => match video.frames.try_send(Ok(frame)) {
+                Ok(()) => (),
+                Err(TrySendError::Full(_)) => {
+                    video.dropped.fetch_add(1, Ordering::Relaxed);
+                },
+                Err(TrySendError::Closed(_)) => self.stop_video(),
+            },
+            Err(e) if e.kind() == ASIErrorKind::Timeout => (),
+            Err(e) => {
+                warn!("Video stream stopped: {}", e);
+                // Rather than waiting for room in the queue, which the
+                // consumer might never make, leave the error for the stream to
+                // yield after the queued frames. Stopping video closes the
+                // queue.
+                *lock_ignoring_poison(&video.error) = Some(e);
+                self.stop_video();
+            },
+        }
+    }
+}
+
+// The worker thread exits only when its AsyncCamera is dropped, so this
+// indicates that it panicked.
+fn worker_exited() -> ASIError {
+    ASIError::new(ASI_ERROR_CODE_ASI_ERROR_GENERAL_ERROR, "AsyncCamera::run")
+}
+
+#[cfg(test)]
+mod tests {
+    use std::future::Future;
+    use std::pin::pin;
+    use std::task::{Wake, Waker};
+    use std::thread::sleep;
+    use std::time::Instant;
+
+    use super::*;
+    use crate::simulated_camera::test_support::simulated_camera;
+    use crate::types::{ExposureStatus, ImgType};
+
+    struct ThreadWaker(thread::Thread);
+
+    impl Wake for ThreadWaker {
+        fn wake(self: Arc<Self>) { self.0.unpark(); }
+    }
+
+    fn waker() -> Waker {
+        Waker::from(Arc::new(ThreadWaker(thread::current())))
+    }
+
+    // Runs `future` to completion on this thread; no particular executor is
+    // needed.
+    fn block_on<F: Future>(future: F) -> F::Output {
+        let waker = waker();
+        let mut cx = Context::from_waker(&waker);
+        let mut future = pin!(future);
+        loop {
+            if let Poll::Ready(output) = future.as_mut().poll(&mut cx) {
+                return output;
+            }
+            thread::park();
+        }
+    }
+
+    #[test]
+    fn capture_exposure() {
+        let camera = AsyncCamera::new(simulated_camera(ImgType::Raw8));
+        let frame = block_on(camera.capture_exposure(
+            Duration::from_millis(1), false, None)).unwrap();
+        assert_eq!((frame.width, frame.height), (64, 32));
+    }
+
+    #[test]
+    fn dropped_capture_cancels_exposure() {
+        let camera = AsyncCamera::new(simulated_camera(ImgType::Raw8));
+        {
+            let waker = waker();
+            let mut future = pin!(camera.capture_exposure(
+                Duration::from_secs(10), false, None));
+            assert!(future.as_mut().poll(&mut Context::from_waker(&waker)).is_pending());
+            // Let the worker start the exposure.
+            sleep(Duration::from_millis(50));
+        }
+        let start = Instant::now();
+        let status = block_on(camera.run(|camera| camera.get_exp_status())).unwrap();
+        assert_eq!(status, ExposureStatus::Failed);
+        assert!(start.elapsed() < Duration::from_secs(1));
+    }
+
+    #[test]
+    fn video_stream_ends_with_error() {
+        let camera = AsyncCamera::new(simulated_camera(ImgType::Raw8));
+        let mut stream = block_on(camera.start_video(4)).unwrap();
+        for _ in 0..3 {
+            block_on(stream.next_frame()).unwrap().unwrap();
+        }
+        block_on(camera.run(|camera| camera.close())).unwrap();
+        // Any queued frames, then the error.
+        let error = loop {
+            match block_on(stream.next_frame()).unwrap() {
+                Ok(_frame) => (),
+                Err(e) => break e,
+            }
+        };
+        assert_eq!(error.kind(), ASIErrorKind::CameraClosed);
+        assert!(block_on(stream.next_frame()).is_none());
+    }
+
+    #[test]
+    fn dropped_stream_stops_video() {
+        let camera = AsyncCamera::new(simulated_camera(ImgType::Raw8));
+        let mut stream = block_on(camera.start_video(4)).unwrap();
+        block_on(stream.next_frame()).unwrap().unwrap();
+        drop(stream);
+        let result = block_on(camera.run(|camera| camera.get_video_frame(0)));
+        assert_eq!(result.unwrap_err().kind(), ASIErrorKind::InvalidSequence);
+        // Video can be started again.
+        let mut stream = block_on(camera.start_video(4)).unwrap();
+        block_on(stream.next_frame()).unwrap().unwrap();
+    }
+}
@@ -1,12 +1,14 @@
-use std::ffi::CStr;
-
-use asi_camera2::asi_camera2_sdk;
 use asi_camera2::asi_camera2_sdk::ASICamera;
+use asi_camera2::types::{CameraInfo, ControlCaps};
 
 // Simple tool to enumerate the attached ASI camera(s) and print information
 // about each.
 
 fn main() {
+    match ASICamera::get_sdk_version() {
+        Ok(version) => println!("sdk_version: {}", version),
+        Err(e) => println!("sdk_version: {}", e),
+    }
     let num_cameras = ASICamera::num_connected_asi_cameras();
     println!("num_cameras: {}", num_cameras);
     for cam_index in 0..num_cameras {
@@ -14,7 +16,7 @@ fn main() {
         let camera_info = ASICamera::get_property(cam_index).unwrap();
         print_camera_info(&camera_info);
 
-        let mut camera = ASICamera::new(camera_info.CameraID);
+        let mut camera = ASICamera::new(camera_info.camera_id);
         camera.open().unwrap();
         camera.init().unwrap();
 
@@ -28,57 +30,41 @@ fn main() {
     }
 }
 
-fn print_camera_info(camera_info: &asi_camera2_sdk::ASI_CAMERA_INFO) {
-    println!("  Name: {:?}", CStr::from_bytes_until_nul(&camera_info.Name).unwrap());
-    println!("  CameraID: {}", camera_info.CameraID);
+fn print_camera_info(camera_info: &CameraInfo) {
+    println!("  Name: {:?}", camera_info.name);
+    println!("  CameraID: {}", camera_info.camera_id);
     println!("  MaxHeight,MaxWidth: {},{}",
-             camera_info.MaxHeight, camera_info.MaxWidth);
-    println!("  IsColorCam: {}", camera_info.IsColorCam != 0);
-    println!("  BayerPattern: {}", camera_info.BayerPattern);
-    print!(  "  SupportedBins: ");
-    for j in 0..16 {
-        let bin = camera_info.SupportedBins[j];
-        if bin == 0 {
-            break;
-        }
-        if j > 0 {
-            print!(",");
-        }
-        print!("{}", bin);
+             camera_info.max_height, camera_info.max_width);
+    println!("  IsColorCam: {}", camera_info.is_color_cam);
+    if let Some(bayer_pattern) = camera_info.bayer_pattern {
+        println!("  BayerPattern: {}", bayer_pattern);
     }
-    println!();
-    print!(  "  SupportedVideoFormat: ");
-    for j in 0..8 {
-        let vf = camera_info.SupportedVideoFormat[j];
-        if vf == -1 {
-            break;
-        }
-        if j > 0 {
-            print!(",");
-        }
-        print!("{}", vf);
-    }
-    println!();
-    println!("  PixelSize: {}", camera_info.PixelSize);
-    println!("  MechanicalShutter: {}", camera_info.MechanicalShutter != 0);
-    println!("  ST4Port: {}", camera_info.ST4Port != 0);
-    println!("  IsCoolerCam: {}", camera_info.IsCoolerCam != 0);
-    println!("  IsUSB3Host: {}", camera_info.IsUSB3Host != 0);
-    println!("  IsUSB3Camera: {}", camera_info.IsUSB3Camera != 0);
-    println!("  ElecPerADU: {}", camera_info.ElecPerADU);
-    println!("  BitDepth: {}", camera_info.BitDepth);
-    println!("  IsTriggerCam: {}", camera_info.IsTriggerCam != 0);
+    println!("  SupportedBins: {}", join(&camera_info.supported_bins));
+    println!("  SupportedVideoFormat: {}",
+             join(&camera_info.supported_video_formats));
+    println!("  PixelSize: {}", camera_info.pixel_size);
+    println!("  MechanicalShutter: {}", camera_info.mechanical_shutter);
+    println!("  ST4Port: {}", camera_info.st4_port);
+    println!("  IsCoolerCam: {}", camera_info.is_cooler_cam);
+    println!("  IsUSB3Host: {}", camera_info.is_usb3_host);
+    println!("  IsUSB3Camera: {}", camera_info.is_usb3_camera);
+    println!("  ElecPerADU: {}", camera_info.elec_per_adu);
+    println!("  BitDepth: {}", camera_info.bit_depth);
+    println!("  IsTriggerCam: {}", camera_info.is_trigger_cam);
 }
 
-fn print_control_caps(control_caps: &asi_camera2_sdk::ASI_CONTROL_CAPS) {
-    println!("  Name: {:?}", CStr::from_bytes_until_nul(&control_caps.Name).unwrap());
-    println!("  Description: {:?}",
-             CStr::from_bytes_until_nul(&control_caps.Description).unwrap());
+fn print_control_caps(control_caps: &ControlCaps) {
+    println!("  Name: {:?}", control_caps.name);
+    println!("  Description: {:?}", control_caps.description);
     println!("  MaxValue/MinValue/DefaultValue: {}/{}/{}",
-             control_caps.MaxValue,
-             control_caps.MinValue,
-             control_caps.DefaultValue);
-    println!("  IsAutoSupported: {}", control_caps.IsAutoSupported != 0);
-    println!("  IsWritable: {}", control_caps.IsWritable != 0);
-    println!("  ControlType: {}", control_caps.ControlType);
+             control_caps.max_value,
+             control_caps.min_value,
+             control_caps.default_value);
+    println!("  IsAutoSupported: {}", control_caps.is_auto_supported);
+    println!("  IsWritable: {}", control_caps.is_writable);
+    println!("  ControlType: {}", control_caps.control_type);
+}
+
+fn join<T: ToString>(values: &[T]) -> String {
+    values.iter().map(|v| v.to_string()).collect::<Vec<_>>().join(",")
 }
@@ -1,5 +1,3 @@
-use rusb;
-
 fn main() {
     for device in rusb::devices().unwrap().iter() {
         let device_desc = device.device_descriptor().unwrap();
@@ -9,5 +7,11 @@ fn main() {
             device.address(),
             device_desc.vendor_id(),
             device_desc.product_id());
+        #[cfg(feature = "sdk")]
+        if asi_camera2::asi_camera2_sdk::ASICamera::is_supported_usb_device(
+            device_desc.vendor_id(), device_desc.product_id())
+        {
+            println!("    ASI camera supported by the SDK");
+        }
     }
 }
@@ -1,11 +1,11 @@
-use std::vec;
+use std::path::Path;
 use std::time::{Duration, Instant};
-use std::thread::sleep;
 
-use image::GrayImage;
-
-use asi_camera2::asi_camera2_sdk;
 use asi_camera2::asi_camera2_sdk::ASICamera;
+use asi_camera2::camera_backend::CameraBackend;
+use asi_camera2::export::save_frame;
+use asi_camera2::fits::save_fits;
+use asi_camera2::types::{FlipStatus, ImgType};
 
 // Simple tool to use single-exposure mode to capture a greyscale image from the
 // attached ASI camera.
@@ -20,56 +20,34 @@ fn main() {
     }
 
     let camera_info = ASICamera::get_property(0).unwrap();
-    let width = camera_info.MaxWidth;
-    let height = camera_info.MaxHeight;
+    let width = camera_info.max_width;
+    let height = camera_info.max_height;
 
-    let mut camera = ASICamera::new(camera_info.CameraID);
+    let mut camera = ASICamera::new(camera_info.camera_id);
     camera.open().unwrap();
     camera.init().unwrap();
 
-    // Allocate buffer to receive camera data.
     println!("width/height: {}/{}", width, height);
-    let mut pixels = vec![0u8; (width*height).try_into().unwrap()];
 
     // Set ROI: whole sensor, no binning, greyscale.
     camera.set_roi_format(
-        width as i32, height as i32,
-        /*bin=*/1, asi_camera2_sdk::ASI_IMG_TYPE_ASI_IMG_RAW8).unwrap();
+        width, height,
+        /*bin=*/1, ImgType::Raw8).unwrap();
 
     let exposure_time_millisec = 10;
-    camera.set_control_value(asi_camera2_sdk::ASI_CONTROL_TYPE_ASI_EXPOSURE,
-                             exposure_time_millisec * 1000,
-                             /*auto=*/false).unwrap();
     // Invert.
-    camera.set_control_value(asi_camera2_sdk::ASI_CONTROL_TYPE_ASI_FLIP,
-                             asi_camera2_sdk::ASI_FLIP_STATUS_ASI_FLIP_BOTH as i64,
-                             /*auto=*/false).unwrap();
+    camera.set_flip(FlipStatus::Both).unwrap();
 
     let exp_start = Instant::now();
-    camera.start_exposure(/*is_dark=*/false).unwrap();
-    sleep(Duration::from_millis(exposure_time_millisec as u64));
-    // Note: in single-exposure mode, the ASI camera seems to take ~300ms to reach
-    // ASI_EXP_SUCCESS state, even if the camera exposure time is much shorter.
-    loop {
-        let exp_status = camera.get_exp_status().unwrap();
-        if exp_status == asi_camera2_sdk::ASI_EXPOSURE_STATUS_ASI_EXP_WORKING {
-            sleep(Duration::from_millis(10));
-            continue;
-        }
-        if exp_status != asi_camera2_sdk::ASI_EXPOSURE_STATUS_ASI_EXP_SUCCESS {
-            panic!("Exposure failed with status: {}", exp_status);
-        }
-        break;
-    }
+    let frame = camera.capture_exposure(
+        Duration::from_millis(exposure_time_millisec),
+        /*is_dark=*/false,
+        /*timeout=*/Some(Duration::from_secs(5))).unwrap();
     println!("Elapsed from exposure start: {:?}", exp_start.elapsed());
 
-    let readout_start = Instant::now();
-    unsafe {
-        camera.get_data_after_exp(pixels.as_mut_ptr(), width*height).unwrap();
-    }
-    println!("Elapsed from readout start: {:?}", readout_start.elapsed());
+    // Save the frame with its acquisition details, for processing tools.
+    save_fits(&camera, &frame, Path::new("image.fits")).unwrap();
 
-    // Move 'pixels' into a GrayImage.
-    let image = GrayImage::from_raw(width as u32, height as u32, pixels).unwrap();
-    image.save("image.jpg").unwrap();
+    // Also save it as a PNG, for viewing.
+    save_frame(&camera, &frame, Path::new("image.png"), /*debayer=*/false).unwrap();
 }
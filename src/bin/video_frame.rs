@@ -1,10 +1,10 @@
-use std::vec;
+use std::path::Path;
 use std::time::Instant;
 
-use image::GrayImage;
-
-use asi_camera2::asi_camera2_sdk;
 use asi_camera2::asi_camera2_sdk::ASICamera;
+use asi_camera2::camera_backend::CameraBackend;
+use asi_camera2::export::save_frame;
+use asi_camera2::types::{ControlType, ImgType};
 
 // Simple tool to use video mode to capture a single greyscale image from the
 // attached ASI camera.
@@ -19,29 +19,27 @@ fn main() {
     }
 
     let camera_info = ASICamera::get_property(0).unwrap();
-    let width = camera_info.MaxWidth;
-    let height = camera_info.MaxHeight;
+    let width = camera_info.max_width;
+    let height = camera_info.max_height;
 
-    let mut camera = ASICamera::new(camera_info.CameraID);
+    let mut camera = ASICamera::new(camera_info.camera_id);
     camera.open().unwrap();
     camera.init().unwrap();
 
-    // Allocate buffer to receive camera data.
     println!("width/height: {}/{}", width, height);
-    let mut pixels = vec![0u8; (width*height).try_into().unwrap()];
 
     // Set ROI: whole sensor, no binning, greyscale.
     camera.set_roi_format(
-        width as i32, height as i32,
-        /*bin=*/1, asi_camera2_sdk::ASI_IMG_TYPE_ASI_IMG_RAW8).unwrap();
+        width, height,
+        /*bin=*/1, ImgType::Raw8).unwrap();
 
     let exposure_time_millisec = 50;
-    camera.set_control_value(asi_camera2_sdk::ASI_CONTROL_TYPE_ASI_EXPOSURE,
+    camera.set_control_value(ControlType::Exposure,
                              exposure_time_millisec * 1000,
                              /*auto=*/false).unwrap();
-    camera.set_control_value(asi_camera2_sdk::ASI_CONTROL_TYPE_ASI_GAIN,
+    camera.set_control_value(ControlType::Gain,
                              50, /*auto=*/false).unwrap();
-    camera.set_control_value(asi_camera2_sdk::ASI_CONTROL_TYPE_ASI_OFFSET,
+    camera.set_control_value(ControlType::Offset,
                              10, /*auto=*/false).unwrap();
 
     let video_capture_start = Instant::now();
@@ -51,6 +49,7 @@ fn main() {
     // Capture and discard several frames to get a sense for timings. The first
     // frame takes ~300ms to capture; subsequent frames capture at the exposure
     // interval, down to 30ms or so.
+    let mut frame = None;
     for i in 0..5 {
         // Play with changing parameters while video is running. Findings:
         // * Changing ROI incurs a ~250ms penalty.
@@ -58,7 +57,7 @@ fn main() {
         // * Changing exposure duration incurs no time penalty.
         if i == 3 {
         //     let update_start = Instant::now();
-        //     camera.set_control_value(asi_camera2_sdk::ASI_CONTROL_TYPE_ASI_EXPOSURE,
+        //     camera.set_control_value(ControlType::Exposure,
         //                              10 * 1000,
         //                              /*auto=*/false).unwrap();
         //     println!("update took: {:?}", update_start.elapsed());
@@ -66,11 +65,8 @@ fn main() {
         // Get the video data. This will block until the currently exposing frame
         // is complete.
         let get_data_start = Instant::now();
-        unsafe {
-            camera.get_video_data(
-                pixels.as_mut_ptr(), width*height, /*wait_ms=*/-1).unwrap();
-        }
-        println!("get_video_data took: {:?}", get_data_start.elapsed());
+        frame = Some(camera.get_video_frame(/*wait_ms=*/-1).unwrap());
+        println!("get_video_frame took: {:?}", get_data_start.elapsed());
     }
     let video_capture_stop = Instant::now();
     camera.stop_video_capture().unwrap();
@@ -78,9 +74,9 @@ fn main() {
 
     println!("dropped frames: {}", camera.get_dropped_frames().unwrap());
 
-    // Move 'pixels' into a GrayImage.
-    let image = GrayImage::from_raw(width as u32, height as u32, pixels).unwrap();
+    // Save the last frame, losslessly and with its capture metadata.
     let save_start = Instant::now();
-    image.save("vid_image.jpg").unwrap();
+    save_frame(&camera, &frame.unwrap(), Path::new("vid_image.png"),
+               /*debayer=*/false).unwrap();
     println!("save took: {:?}", save_start.elapsed());
 }
@@ -0,0 +1,392 @@
+// Copyright (c) 2023 Steven Rosenthal smr@dt3.org
+// See LICENSE file in root directory for license terms.
+
+use std::any::TypeId;
+use std::collections::HashMap;
+use std::fmt;
+use std::mem::size_of_val;
+use std::path::Path;
+use std::sync::Mutex;
+use std::time::{Duration, Instant, SystemTime};
+
+use log::warn;
+
+use crate::asi_camera2_sdk::{ASIError,
+                             ASIErrorKind,
+                             ASI_ERROR_CODE_ASI_ERROR_BUFFER_TOO_SMALL,
+                             ASI_ERROR_CODE_ASI_ERROR_INVALID_CONTROL_TYPE,
+                             ASI_ERROR_CODE_ASI_ERROR_INVALID_IMGTYPE};
+use crate::exposure::{self, ExposureOptions};
+use crate::frame::{self, Frame, FrameMetadata};
+use crate::types::{CameraInfo, CameraMode, ControlCaps, ControlType, ExposureStatus,
+                   FlipStatus, GainOffsets, GainPreset, GuideDirection, ImgType,
+                   LmhGainOffsets, TriggerOutputConfig, TriggerOutputPin};
+use crate::lock_ignoring_poison;
+
+/// The operations provided by an ASI camera. `ASICamera` implements this by
+/// calling into the ASI Camera2 SDK; `SimulatedCamera` implements it in pure
+/// Rust so that code built on this crate can be exercised without a camera
+/// attached.
+///
+/// The required method signatures and semantics mirror those of `ASICamera`;
+/// see the ASI Camera2 SDK documentation (ASICamera2.h) for details. The
+/// provided methods add safe frame capture on top: they size the transfer from
+/// the current ROI format and validate the caller's buffer against it.
+pub trait CameraBackend {
+    fn camera_id(&self) -> i32;
+    fn camera_info(&self) -> Result<CameraInfo, ASIError>;
+
+    fn open(&mut self) -> Result<(), ASIError>;
+    fn init(&self) -> Result<(), ASIError>;
+    fn close(&mut self) -> Result<(), ASIError>;
+
+    fn get_num_controls(&self) -> Result<i32, ASIError>;
+    fn get_control_caps(&self, control_index: i32)
+                        -> Result<ControlCaps, ASIError>;
+
+    /// The return value is the control's value and whether it is automatic.
+    fn get_control_value(&self, control_type: ControlType)
+                         -> Result<(i64, bool), ASIError>;
+    fn set_control_value(&mut self, control_type: ControlType,
+                         value: i64, auto: bool) -> Result<(), ASIError>;
+
+    /// The return value is (width, height, bin, img_type).
+    fn get_roi_format(&self) -> Result<(i32, i32, i32, ImgType), ASIError>;
+    fn set_roi_format(&mut self, width: i32, height: i32,
+                      bin: i32, img_type: ImgType) -> Result<(), ASIError>;
+
+    /// The return value is (x, y).
+    fn get_start_pos(&self) -> Result<(i32, i32), ASIError>;
+    fn set_start_pos(&mut self, start_x: i32, start_y: i32)
+                     -> Result<(), ASIError>;
+
+    fn get_dropped_frames(&self) -> Result<i32, ASIError>;
+
+    /// Returns the FrameMetadata::sequence_number for the next frame read from
+    /// the camera: 0 on the first call on this instance, then counting up.
+    fn next_sequence_number(&self) -> u64;
+
+    /// `bmp_path` is an 8-bit BMP file of the full sensor size, as written by
+    /// dark_frame::create_dark_file().
+    fn enable_dark_subtract(&mut self, bmp_path: &Path) -> Result<(), ASIError>;
+    fn disable_dark_subtract(&mut self) -> Result<(), ASIError>;
+
+    fn start_video_capture(&mut self) -> Result<(), ASIError>;
+    fn stop_video_capture(&mut self) -> Result<(), ASIError>;
+
+    /// # Safety
+    /// `buffer` must be valid for writes of `buff_size` bytes, and `buff_size`
+    /// must be large enough to hold a frame of the current ROI format.
+    unsafe fn get_video_data(&self, buffer: *mut u8, buff_size: i64, wait_ms: i32)
+                             -> Result<(), ASIError>;
+
+    /// Only for cameras with an ST4 port.
+    fn pulse_guide_on(&self, direction: GuideDirection) -> Result<(), ASIError>;
+    fn pulse_guide_off(&self, direction: GuideDirection) -> Result<(), ASIError>;
+    /// Turns the guide output for `direction` on for `duration`, without
+    /// blocking. Implementations should use a PulseGuider, so that pulses are
+    /// stopped when the camera is closed or dropped.
+    fn pulse_guide(&self, direction: GuideDirection, duration: Duration)
+                   -> Result<(), ASIError>;
+
+    /// `is_dark` is relevant only if the camera has a mechanical shutter.
+    fn start_exposure(&mut self, is_dark: bool) -> Result<(), ASIError>;
+    fn stop_exposure(&mut self) -> Result<(), ASIError>;
+    fn get_exp_status(&self) -> Result<ExposureStatus, ASIError>;
+
+    /// # Safety
+    /// `buffer` must be valid for writes of `buff_size` bytes, and `buff_size`
+    /// must be large enough to hold a frame of the current ROI format.
+    unsafe fn get_data_after_exp(&self, buffer: *mut u8, buff_size: i64)
+                                 -> Result<(), ASIError>;
+
+    fn get_gain_offset(&self) -> Result<GainOffsets, ASIError>;
+    fn get_lmh_gain_offset(&self) -> Result<LmhGainOffsets, ASIError>;
+
+    /// The alias stored in the camera's flash; see ASICamera::set_id().
+    fn get_id(&self) -> Result<String, ASIError>;
+    fn set_id(&mut self, alias: &str) -> Result<(), ASIError>;
+    /// Hex digits; see ASICamera::get_serial_number().
+    fn get_serial_number(&self) -> Result<String, ASIError>;
+
+    /// The trigger methods are only for cameras whose CameraInfo::is_trigger_cam
+    /// is set; see trigger::TriggeredCapture for capturing in a trigger mode.
+    fn get_camera_support_mode(&self) -> Result<Vec<CameraMode>, ASIError>;
+    fn get_camera_mode(&self) -> Result<CameraMode, ASIError>;
+    /// Video capture must be stopped.
+    fn set_camera_mode(&mut self, mode: CameraMode) -> Result<(), ASIError>;
+    /// In TrigSoftEdge mode `start` must be true. In TrigSoftLevel mode the
+    /// exposure runs from a `start` of true until one of false.
+    fn send_soft_trigger(&self, start: bool) -> Result<(), ASIError>;
+    fn get_trigger_output_config(&self, pin: TriggerOutputPin)
+                                 -> Result<TriggerOutputConfig, ASIError>;
+    fn set_trigger_output_config(&mut self, pin: TriggerOutputPin,
+                                 config: &TriggerOutputConfig) -> Result<(), ASIError>;
+
+    /// Returns the caps of all of the camera's controls. Controls of a type
+    /// unknown to this crate (e.g. added by a newer SDK) are omitted.
+    fn controls(&self) -> Result<HashMap<ControlType, ControlCaps>, ASIError> {
+        let mut controls = HashMap::new();
+        for control_index in 0..self.get_num_controls()? {
+            match self.get_control_caps(control_index) {
+                Ok(caps) => {
+                    controls.insert(caps.control_type, caps);
+                },
+                Err(e) if e.kind() == ASIErrorKind::InvalidControlType => {
+                    warn!("Skipping control {}: {}", control_index, e);
+                },
+                Err(e) => return Err(e),
+            }
+        }
+        Ok(controls)
+    }
+
+    /// Returns the value of the Flip control.
+    fn get_flip(&self) -> Result<FlipStatus, ASIError> {
+        let (value, _auto) = self.get_control_value(ControlType::Flip)?;
+        FlipStatus::try_from(value as u32)
+    }
+
+    fn set_flip(&mut self, flip: FlipStatus) -> Result<(), ASIError> {
+        self.set_control_value(ControlType::Flip,
+                               u32::from(flip) as i64, /*auto=*/false)
+    }
+
+    /// Returns the (gain, offset) that `preset` stands for on this camera,
+    /// from the manufacturer's recommendations (get_gain_offset() and
+    /// get_lmh_gain_offset()). The SDK does not give offsets for the Low and
+    /// Medium presets; those use the offsets for highest dynamic range and
+    /// unity gain respectively.
+    ///
+    /// Unity gain is computed from CameraInfo::elec_per_adu, taking the Gain
+    /// control to be in units of 0.1dB as it is on ZWO cameras.
+    fn gain_preset(&self, preset: GainPreset) -> Result<(i64, i64), ASIError> {
+        let controls = self.controls()?;
+        let Some(gain_caps) = controls.get(&ControlType::Gain) else {
+            return Err(ASIError::new(ASI_ERROR_CODE_ASI_ERROR_INVALID_CONTROL_TYPE,
+                                     "gain_preset"));
+        };
+        let gain_offsets = self.get_gain_offset()?;
+        let (gain, offset) = match preset {
+            GainPreset::HighestDR =>
+                (gain_caps.min_value, gain_offsets.offset_highest_dr),
+            GainPreset::Unity => {
+                let elec_per_adu = self.camera_info()?.elec_per_adu as f64;
+                let unity_gain = (200.0 * elec_per_adu.log10()).round() as i64;
+                (unity_gain.clamp(gain_caps.min_value, gain_caps.max_value),
+                 gain_offsets.offset_unity_gain)
+            },
+            GainPreset::LowestReadNoise =>
+                (gain_offsets.gain_lowest_read_noise as i64,
+                 gain_offsets.offset_lowest_read_noise),
+            GainPreset::Low | GainPreset::Medium | GainPreset::High => {
+                let lmh = self.get_lmh_gain_offset()?;
+                match preset {
+                    GainPreset::Low =>
+                        (lmh.low_gain as i64, gain_offsets.offset_highest_dr),
+                    GainPreset::Medium =>
+                        (lmh.medium_gain as i64, gain_offsets.offset_unity_gain),
+                    _ => (lmh.high_gain as i64, lmh.high_offset),
+                }
+            },
+        };
+        Ok((gain, offset as i64))
+    }
+
+    /// Sets the Gain (turning off auto gain) and Offset controls to `preset`'s
+    /// values; see gain_preset(). Returns the (gain, offset) that were set.
+    fn apply_gain_preset(&mut self, preset: GainPreset)
+                         -> Result<(i64, i64), ASIError> {
+        let (gain, offset) = self.gain_preset(preset)?;
+        self.set_control_value(ControlType::Gain, gain, /*auto=*/false)?;
+        self.set_control_value(ControlType::Offset, offset, /*auto=*/false)?;
+        Ok((gain, offset))
+    }
+
+    /// Returns the number of bytes needed to hold a frame of the current ROI
+    /// format.
+    fn frame_size(&self) -> Result<usize, ASIError> {
+        let (width, height, _bin, img_type) = self.get_roi_format()?;
+        Ok(frame::frame_size(width, height, img_type))
+    }
+
+    /// Safe version of get_video_data(). `buffer` must hold at least
+    /// frame_size() bytes.
+    fn read_video_data(&self, buffer: &mut [u8], wait_ms: i32)
+                       -> Result<(), ASIError> {
+        let frame_size = check_buffer(self, size_of_val(buffer), "read_video_data")?;
+        unsafe { self.get_video_data(buffer.as_mut_ptr(), frame_size, wait_ms) }
+    }
+
+    /// Like read_video_data(), for RAW16 frames. `buffer` must hold at least
+    /// width*height pixels.
+    fn read_video_data_u16(&self, buffer: &mut [u16], wait_ms: i32)
+                           -> Result<(), ASIError> {
+        check_raw16(self, "read_video_data_u16")?;
+        let frame_size =
+            check_buffer(self, size_of_val(buffer), "read_video_data_u16")?;
+        unsafe {
+            self.get_video_data(buffer.as_mut_ptr() as *mut u8, frame_size, wait_ms)
+        }
+    }
+
+    /// Returns the next video frame, waiting up to `wait_ms` (-1 means forever).
+    fn get_video_frame(&self, wait_ms: i32) -> Result<Frame, ASIError> {
+        let (width, height, bin, img_type) = self.get_roi_format()?;
+        let mut frame = Frame::new(width, height, bin, img_type);
+        self.read_video_data(&mut frame.data, wait_ms)?;
+        frame.metadata = self.frame_metadata()?;
+        Ok(frame)
+    }
+
+    /// Safe version of get_data_after_exp(). `buffer` must hold at least
+    /// frame_size() bytes.
+    fn read_data_after_exp(&self, buffer: &mut [u8]) -> Result<(), ASIError> {
+        let frame_size =
+            check_buffer(self, size_of_val(buffer), "read_data_after_exp")?;
+        unsafe { self.get_data_after_exp(buffer.as_mut_ptr(), frame_size) }
+    }
+
+    /// Like read_data_after_exp(), for RAW16 frames. `buffer` must hold at
+    /// least width*height pixels.
+    fn read_data_after_exp_u16(&self, buffer: &mut [u16]) -> Result<(), ASIError> {
+        check_raw16(self, "read_data_after_exp_u16")?;
+        let frame_size =
+            check_buffer(self, size_of_val(buffer), "read_data_after_exp_u16")?;
+        unsafe { self.get_data_after_exp(buffer.as_mut_ptr() as *mut u8, frame_size) }
+    }
+
+    /// Returns the frame of a successfully completed exposure.
+    fn get_frame_after_exp(&self) -> Result<Frame, ASIError> {
+        let (width, height, bin, img_type) = self.get_roi_format()?;
+        let mut frame = Frame::new(width, height, bin, img_type);
+        self.read_data_after_exp(&mut frame.data)?;
+        frame.metadata = self.frame_metadata()?;
+        Ok(frame)
+    }
+
+    /// Sets the Exposure control to `duration`, takes a single exposure and
+    /// returns its frame. Sleeps through the exposure, then polls its status
+    /// until the SDK reports it done, which typically takes a few hundred ms
+    /// longer. `timeout` bounds that extra wait (None waits indefinitely).
+    /// Failed exposures are retried; see ExposureOptions::default().
+    fn capture_exposure(&mut self, duration: Duration, is_dark: bool,
+                        timeout: Option<Duration>) -> Result<Frame, ASIError> {
+        exposure::capture_exposure(self, duration, is_dark, timeout,
+                                   &ExposureOptions::default())
+    }
+
+    /// Like capture_exposure(), with the retry count and a cancellation token
+    /// given by `options`.
+    fn capture_exposure_with(&mut self, duration: Duration, is_dark: bool,
+                             timeout: Option<Duration>, options: &ExposureOptions)
+                             -> Result<Frame, ASIError> {
+        exposure::capture_exposure(self, duration, is_dark, timeout, options)
+    }
+
+    /// Returns the metadata of a frame that has just been read with
+    /// read_video_data() or read_data_after_exp(), taking the next sequence
+    /// number. get_video_frame() and get_frame_after_exp() call this; callers
+    /// reading into their own buffers can call it to get the same metadata.
+    ///
+    /// The frame has already been read, so settings that cannot be read back
+    /// are logged and reported as 0 rather than failing the call.
+    fn frame_metadata(&self) -> Result<FrameMetadata, ASIError> {
+        let timestamp = Instant::now();
+        let system_time = SystemTime::now();
+        let sequence_number = self.next_sequence_number();
+        let (start_x, start_y) =
+            setting_or_default(self.get_start_pos(), "start position", sequence_number);
+        let control = |control_type| {
+            let (value, _auto) = setting_or_default(
+                self.get_control_value(control_type), control_type, sequence_number);
+            value
+        };
+        let exposure_us = control(ControlType::Exposure);
+        let gain = control(ControlType::Gain);
+        let offset = control(ControlType::Offset);
+        let temperature = self.get_control_value(ControlType::Temperature)
+            .ok().map(|(value, _auto)| value as f64 / 10.0);
+        let dropped_frames = setting_or_default(
+            self.get_dropped_frames(), "dropped frames", sequence_number);
+        Ok(FrameMetadata{timestamp, system_time, sequence_number,
+                         start_x, start_y,
+                         exposure: Duration::from_micros(exposure_us.max(0) as u64),
+                         gain, offset, temperature, dropped_frames})
+    }
+}
+
+// Returns the setting read by `result`, or logs the error and returns the
+// default value.
+fn setting_or_default<T: Default>(result: Result<T, ASIError>, setting: impl fmt::Display,
+                                  sequence_number: u64) -> T {
+    result.unwrap_or_else(|e| {
+        warn!("Error reading {} for frame {}: {}", setting, sequence_number, e);
+        T::default()
+    })
+}
+
+// Returns the frame size, in bytes, if a buffer of `buffer_size` bytes can hold
+// it.
+fn check_buffer<B: CameraBackend + ?Sized>(camera: &B, buffer_size: usize,
+                                           source: &str) -> Result<i64, ASIError> {
+    let frame_size = camera.frame_size()?;
+    if buffer_size < frame_size {
+        return Err(ASIError::new(ASI_ERROR_CODE_ASI_ERROR_BUFFER_TOO_SMALL, source));
+    }
+    Ok(frame_size as i64)
+}
+
+fn check_raw16<B: CameraBackend + ?Sized>(camera: &B, source: &str)
+                                          -> Result<(), ASIError> {
+    let (_width, _height, _bin, img_type) = camera.get_roi_format()?;
+    if img_type != ImgType::Raw16 {
+        return Err(ASIError::new(ASI_ERROR_CODE_ASI_ERROR_INVALID_IMGTYPE, source));
+    }
+    Ok(())
+}
+
+// The ids of the cameras this process has open, by backend type. Opening a
+// camera again through another instance, e.g. to read its serial number, and
+// closing that instance would close the camera under its owner.
+static OPEN_CAMERAS: Mutex<Vec<(TypeId, i32)>> = Mutex::new(Vec::new());
+
+// Records that a camera of backend `B` was opened or closed. Backends call
+// this only when the camera's state actually changes.
+pub(crate) fn set_camera_open<B: 'static>(camera_id: i32, open: bool) {
+    let key = (TypeId::of::<B>(), camera_id);
+    let mut open_cameras = lock_ignoring_poison(&OPEN_CAMERAS);
+    if open {
+        open_cameras.push(key);
+    } else if let Some(index) = open_cameras.iter().position(|k| *k == key) {
+        open_cameras.swap_remove(index);
+    }
+}
+
+/// Returns the first of `cameras` for which `matches` is true, e.g. to look a
+/// camera up by serial number in a supervisor::Reconnector. Each camera is
+/// opened to be queried and then closed again, so cameras this process already
+/// has open are skipped, as are cameras that cannot be opened (e.g. because
+/// another process is using them). The returned camera is closed.
+pub fn find_camera<B, I, F>(cameras: I, matches: F) -> Option<B>
+where B: CameraBackend + 'static,
+      I: IntoIterator<Item = B>,
+      F: Fn(&B) -> Result<bool, ASIError>
+{
+    for mut camera in cameras {
+        let camera_id = camera.camera_id();
+        if lock_ignoring_poison(&OPEN_CAMERAS).contains(&(TypeId::of::<B>(), camera_id)) {
+            continue;
+        }
+        let result = camera.open().and_then(|_| matches(&camera));
+        if let Err(e) = camera.close() {
+            warn!("Error closing camera id {}: {}", camera_id, e);
+        }
+        match result {
+            Ok(true) => return Some(camera),
+            Ok(false) => (),
+            Err(e) => warn!("Skipping camera id {}: {}", camera_id, e),
+        }
+    }
+    None
+}
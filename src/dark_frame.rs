@@ -0,0 +1,257 @@
+// Copyright (c) 2023 Steven Rosenthal smr@dt3.org
+// See LICENSE file in root directory for license terms.
+
+use std::fs;
+use std::path::Path;
+use std::time::Duration;
+
+use log::{info, warn};
+
+use crate::asi_camera2_sdk::{ASIError,
+                             ASI_ERROR_CODE_ASI_ERROR_GENERAL_ERROR,
+                             ASI_ERROR_CODE_ASI_ERROR_INVALID_FILEFORMAT,
+                             ASI_ERROR_CODE_ASI_ERROR_INVALID_IMGTYPE,
+                             ASI_ERROR_CODE_ASI_ERROR_INVALID_PATH};
+use crate::camera_backend::CameraBackend;
+use crate::exposure::ExposureOptions;
+use crate::frame::Frame;
+use crate::types::{ControlType, ImgType};
+
+// Sizes of the BMP file header, the BITMAPINFOHEADER and the 256 entry
+// grayscale palette.
+const FILE_HEADER_SIZE: usize = 14;
+const INFO_HEADER_SIZE: usize = 40;
+const PALETTE_SIZE: usize = 256 * 4;
+
+// capture_dark_frame() gives up after this many consecutive failed exposures.
+const MAX_FAILED_EXPOSURES: u32 = 3;
+
+/// Captures `num_frames` dark frames with the camera's current gain, offset and
+/// exposure time, and returns their per-pixel median.
+///
+/// The SDK's dark subtraction needs a full sensor (max width x height), 8-bit
+/// dark frame regardless of the ROI in use, so if the current ROI format is not
+/// full sensor RAW8 at bin 1 it is changed for the duration of the capture and
+/// then restored. Video capture must not be running.
+pub fn capture_dark_frame<B>(camera: &mut B, num_frames: usize) -> Result<Frame, ASIError>
+where B: CameraBackend + ?Sized
+{
+    if num_frames == 0 {
+        return Err(ASIError::new(ASI_ERROR_CODE_ASI_ERROR_GENERAL_ERROR,
+                                 "capture_dark_frame"));
+    }
+    let camera_info = camera.camera_info()?;
+    let roi_format = camera.get_roi_format()?;
+    let start_pos = camera.get_start_pos()?;
+    let full_format = (camera_info.max_width, camera_info.max_height, 1, ImgType::Raw8);
+    if roi_format != full_format {
+        let (width, height, bin, img_type) = full_format;
+        camera.set_roi_format(width, height, bin, img_type)?;
+    }
+
+    let result = capture_frames(camera, num_frames);
+
+    if roi_format != full_format {
+        let (width, height, bin, img_type) = roi_format;
+        // Attempt both, even if the first fails.
+        let roi_result = camera.set_roi_format(width, height, bin, img_type);
+        let pos_result = camera.set_start_pos(start_pos.0, start_pos.1);
+        if let Err(e) = roi_result.and(pos_result) {
+            if result.is_ok() {
+                return Err(e);
+            }
+            // The capture error is returned instead.
+            warn!("Error restoring ROI on camera id {}: {}", camera.camera_id(), e);
+        }
+    }
+    Ok(median_combine(&result?))
+}
+
+/// Writes `frame`, which must be RAW8 or Y8, as an 8-bit grayscale BMP file.
+/// This is the format ASICamera::enable_dark_subtract() expects.
+pub fn write_bmp(frame: &Frame, path: &Path) -> Result<(), ASIError> {
+    if frame.img_type != ImgType::Raw8 && frame.img_type != ImgType::Y8 {
+        return Err(ASIError::new(ASI_ERROR_CODE_ASI_ERROR_INVALID_IMGTYPE, "write_bmp"));
+    }
+    let width = frame.width as usize;
+    let height = frame.height as usize;
+    let row_size = padded_row_size(width);
+    let data_offset = FILE_HEADER_SIZE + INFO_HEADER_SIZE + PALETTE_SIZE;
+    let file_size = data_offset + row_size * height;
+
+    let mut bmp = Vec::with_capacity(file_size);
+    bmp.extend_from_slice(b"BM");
+    bmp.extend_from_slice(&(file_size as u32).to_le_bytes());
+    bmp.extend_from_slice(&[0; 4]);  // Reserved.
+    bmp.extend_from_slice(&(data_offset as u32).to_le_bytes());
+
+    bmp.extend_from_slice(&(INFO_HEADER_SIZE as u32).to_le_bytes());
+    bmp.extend_from_slice(&frame.width.to_le_bytes());
+    bmp.extend_from_slice(&frame.height.to_le_bytes());  // Positive: bottom-up.
+    bmp.extend_from_slice(&1u16.to_le_bytes());  // Planes.
+    bmp.extend_from_slice(&8u16.to_le_bytes());  // Bits per pixel.
+    bmp.extend_from_slice(&0u32.to_le_bytes());  // No compression.
+    bmp.extend_from_slice(&((row_size * height) as u32).to_le_bytes());
+    bmp.extend_from_slice(&2835i32.to_le_bytes());  // 72 DPI, horizontal.
+    bmp.extend_from_slice(&2835i32.to_le_bytes());  // 72 DPI, vertical.
+    bmp.extend_from_slice(&256u32.to_le_bytes());  // Palette entries.
+    bmp.extend_from_slice(&0u32.to_le_bytes());  // All colors are important.
+
+    for i in 0..=255u8 {
+        bmp.extend_from_slice(&[i, i, i, 0]);
+    }
+    for row in frame.data.chunks_exact(width).rev() {
+        bmp.extend_from_slice(row);
+        bmp.resize(bmp.len() + row_size - width, 0);
+    }
+
+    fs::write(path, bmp).map_err(|e| {
+        warn!("Error writing {:?}: {}", path, e);
+        ASIError::new(ASI_ERROR_CODE_ASI_ERROR_INVALID_PATH, "write_bmp")
+    })
+}
+
+/// Captures a dark frame as with capture_dark_frame() and writes it to `path`
+/// with write_bmp(), ready for ASICamera::enable_dark_subtract().
+pub fn create_dark_file<B>(camera: &mut B, num_frames: usize, path: &Path)
+                           -> Result<(), ASIError>
+where B: CameraBackend + ?Sized
+{
+    let dark_frame = capture_dark_frame(camera, num_frames)?;
+    write_bmp(&dark_frame, path)?;
+    info!("Wrote {}x{} dark frame to {:?}", dark_frame.width, dark_frame.height, path);
+    Ok(())
+}
+
+// Reads an 8-bit BMP file as written by write_bmp(). The return value is
+// (width, height, pixels), with pixels in top-down order.
+pub(crate) fn read_bmp(path: &Path) -> Result<(i32, i32, Vec<u8>), ASIError> {
+    let bmp = fs::read(path).map_err(|e| {
+        warn!("Error reading {:?}: {}", path, e);
+        ASIError::new(ASI_ERROR_CODE_ASI_ERROR_INVALID_PATH, "read_bmp")
+    })?;
+    let invalid = || ASIError::new(ASI_ERROR_CODE_ASI_ERROR_INVALID_FILEFORMAT,
+                                   "read_bmp");
+    let u16_at = |offset: usize| -> Result<u16, ASIError> {
+        let bytes = bmp.get(offset..offset + 2).ok_or_else(invalid)?;
+        Ok(u16::from_le_bytes([bytes[0], bytes[1]]))
+    };
+    let i32_at = |offset: usize| -> Result<i32, ASIError> {
+        let bytes = bmp.get(offset..offset + 4).ok_or_else(invalid)?;
+        Ok(i32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]))
+    };
+    if !bmp.starts_with(b"BM") || u16_at(28)? != 8 || i32_at(30)? != 0 {
+        return Err(invalid());
+    }
+    let data_offset = usize::try_from(i32_at(10)?).map_err(|_| invalid())?;
+    let width = i32_at(18)?;
+    let height = i32_at(22)?;
+    if width <= 0 || height == 0 || height == i32::MIN {
+        return Err(invalid());
+    }
+    let width = width as usize;
+    let rows = height.unsigned_abs() as usize;
+    // Check that the file holds all of the rows before allocating for them.
+    let row_size = padded_row_size(width);
+    let data_end = row_size.checked_mul(rows)
+        .and_then(|data_size| data_size.checked_add(data_offset));
+    if data_end.is_none_or(|data_end| data_end > bmp.len()) {
+        return Err(invalid());
+    }
+    let mut pixels = Vec::with_capacity(width * rows);
+    for y in 0..rows {
+        // Rows are bottom-up unless the height is negative.
+        let row = if height > 0 { rows - 1 - y } else { y };
+        let start = data_offset + row * row_size;
+        pixels.extend_from_slice(&bmp[start..start + width]);
+    }
+    Ok((width as i32, rows as i32, pixels))
+}
+
+// BMP rows are padded to a multiple of 4 bytes.
+fn padded_row_size(width: usize) -> usize {
+    width.div_ceil(4) * 4
+}
+
+fn capture_frames<B>(camera: &mut B, num_frames: usize) -> Result<Vec<Frame>, ASIError>
+where B: CameraBackend + ?Sized
+{
+    let (exposure_us, _auto) = camera.get_control_value(ControlType::Exposure)?;
+    let exposure = Duration::from_micros(exposure_us.max(0) as u64);
+    let options = ExposureOptions{max_retries: MAX_FAILED_EXPOSURES - 1,
+                                  ..Default::default()};
+    let mut frames = Vec::with_capacity(num_frames);
+    while frames.len() < num_frames {
+        frames.push(camera.capture_exposure_with(
+            exposure, /*is_dark=*/true, /*timeout=*/None, &options)?);
+    }
+    Ok(frames)
+}
+
+// Returns the per-pixel median of `frames`, which must be non-empty and all of
+// the same format.
+fn median_combine(frames: &[Frame]) -> Frame {
+    let first = &frames[0];
+    let mut result = Frame::new(first.width, first.height, first.bin, first.img_type);
+    result.metadata = first.metadata.clone();
+    let mut values = vec![0u8; frames.len()];
+    for (i, pixel) in result.data.iter_mut().enumerate() {
+        for (value, frame) in values.iter_mut().zip(frames) {
+            *value = frame.data[i];
+        }
+        values.sort_unstable();
+        *pixel = values[values.len() / 2];
+    }
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::asi_camera2_sdk::ASIErrorKind;
+    use crate::simulated_camera::{SimulatedCamera, SimulatorConfig};
+    use crate::simulated_camera::test_support::temp_path;
+
+    #[test]
+    fn roi_restored() {
+        for failed_exposures in [0, MAX_FAILED_EXPOSURES] {
+            let mut camera = SimulatedCamera::new(
+                SimulatorConfig{failed_exposures, ..Default::default()});
+            camera.open().unwrap();
+            camera.set_control_value(ControlType::Exposure, 1000, false).unwrap();
+            camera.set_roi_format(320, 240, 2, ImgType::Raw16).unwrap();
+            camera.set_start_pos(8, 4).unwrap();
+            let result = capture_dark_frame(&mut camera, 2);
+            if failed_exposures == 0 {
+                let dark = result.unwrap();
+                assert_eq!((dark.width, dark.height, dark.img_type), (1280, 960, ImgType::Raw8));
+            } else {
+                assert_eq!(result.unwrap_err().kind(), ASIErrorKind::ExposureFailed);
+            }
+            assert_eq!(camera.get_roi_format().unwrap(), (320, 240, 2, ImgType::Raw16));
+            assert_eq!(camera.get_start_pos().unwrap(), (8, 4));
+        }
+    }
+
+    #[test]
+    fn bmp_round_trip() {
+        let path = temp_path("bmp_round_trip.bmp");
+        let mut frame = Frame::new(6, 3, 1, ImgType::Raw8);
+        frame.data = (0..18).collect();
+        write_bmp(&frame, &path).unwrap();
+        assert_eq!(read_bmp(&path).unwrap(), (6, 3, frame.data));
+
+        // Dimensions larger than the file, and too large to address.
+        let mut bmp = fs::read(&path).unwrap();
+        for (width, height) in [(6i32, 4), (1 << 30, i32::MAX), (6, i32::MIN)] {
+            bmp[18..22].copy_from_slice(&width.to_le_bytes());
+            bmp[22..26].copy_from_slice(&height.to_le_bytes());
+            fs::write(&path, &bmp).unwrap();
+            assert_eq!(read_bmp(&path).unwrap_err().kind(), ASIErrorKind::InvalidFileFormat);
+        }
+        bmp[10..14].copy_from_slice(&(-1i32).to_le_bytes());
+        fs::write(&path, &bmp).unwrap();
+        assert_eq!(read_bmp(&path).unwrap_err().kind(), ASIErrorKind::InvalidFileFormat);
+        fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,212 @@
+// Copyright (c) 2023 Steven Rosenthal smr@dt3.org
+// See LICENSE file in root directory for license terms.
+
+use std::env;
+use std::ffi::{OsStr, OsString};
+use std::os::raw::{c_char, c_int, c_long, c_uchar};
+use std::path::Path;
+use std::ptr;
+use std::sync::{Once, OnceLock};
+
+use libloading::Library;
+use log::{info, warn};
+
+use crate::asi_camera2_sdk::{ASIError,
+                             ASI_CAMERA_INFO,
+                             ASI_CAMERA_MODE,
+                             ASI_CONTROL_CAPS,
+                             ASI_ERROR_LIBRARY_UNAVAILABLE,
+                             ASI_EXPOSURE_STATUS,
+                             ASI_ID,
+                             ASI_SN,
+                             ASI_SUPPORTED_MODE,
+                             ASI_TRIG_OUTPUT_PIN};
+
+/// Environment variable naming the libASICamera2 to load, when load_library()
+/// is not given a path.
+pub const ASI_CAMERA2_LIB_ENV: &str = "ASI_CAMERA2_LIB";
+
+#[cfg(target_os = "macos")]
+const DEFAULT_LIBRARY_NAME: &str = "libASICamera2.dylib";
+#[cfg(not(target_os = "macos"))]
+const DEFAULT_LIBRARY_NAME: &str = "libASICamera2.so";
+
+static SDK_LIBRARY: OnceLock<SdkLibrary> = OnceLock::new();
+// The first SDK call tries the default library, if none is loaded yet. A
+// missing library is not tried again (and logged) on every call.
+static DEFAULT_LOAD: Once = Once::new();
+
+/// Loads libASICamera2 from `path`. If `path` is None, the library named by
+/// the ASI_CAMERA2_LIB environment variable is loaded, falling back to
+/// libASICamera2.so on the system library search path.
+///
+/// Calling this is optional: the first SDK call loads the library from the
+/// default location if needed, trying only once. Calling it explicitly lets the caller choose the
+/// library and get a clean error if it is absent. Once a library has been
+/// loaded, subsequent calls have no effect.
+pub fn load_library(path: Option<&Path>) -> Result<(), ASIError> {
+    if SDK_LIBRARY.get().is_some() {
+        return Ok(());
+    }
+    let path = match path {
+        Some(path) => path.as_os_str().to_owned(),
+        None => env::var_os(ASI_CAMERA2_LIB_ENV)
+            .unwrap_or_else(|| OsString::from(DEFAULT_LIBRARY_NAME)),
+    };
+    match SdkLibrary::load(&path) {
+        Ok(library) => {
+            info!("Loaded ASI Camera2 SDK from {:?}", path);
+            // If another thread won a race to load the library, ours is simply
+            // dropped.
+            let _ = SDK_LIBRARY.set(library);
+            Ok(())
+        },
+        Err(e) => {
+            warn!("Error loading ASI Camera2 SDK from {:?}: {}", path, e);
+            Err(ASIError{error_code: ASI_ERROR_LIBRARY_UNAVAILABLE,
+                         source: format!("load_library({:?})", path)})
+        },
+    }
+}
+
+fn sdk_library() -> Option<&'static SdkLibrary> {
+    DEFAULT_LOAD.call_once(|| {
+        if SDK_LIBRARY.get().is_none() {
+            let _ = load_library(None);
+        }
+    });
+    SDK_LIBRARY.get()
+}
+
+// Declares the SDK's functions. For each one this generates an entry point in
+// SdkLibrary, resolved when the library is loaded, and a free function with the
+// same name and signature as the bindgen-generated declaration it replaces.
+// The free function returns `unavailable` if the library cannot be loaded or
+// lacks the function (e.g. one added in a later SDK version).
+macro_rules! sdk_functions {
+    ($($name:ident($($arg:ident: $arg_type:ty),*) -> $ret:ty,
+       unavailable = $unavailable:expr;)*) => {
+        struct SdkLibrary {
+            // Keeps the entry points valid. Never dropped once in SDK_LIBRARY.
+            _library: Library,
+            $($name: Option<unsafe extern "C" fn($($arg_type),*) -> $ret>,)*
+        }
+
+        impl SdkLibrary {
+            fn load(path: &OsStr) -> Result<Self, libloading::Error> {
+                unsafe {
+                    let library = Library::new(path)?;
+                    $(let $name = library.get::<unsafe extern "C" fn($($arg_type),*) -> $ret>(
+                        concat!(stringify!($name), "\0").as_bytes()).ok().map(|f| *f);
+                      if $name.is_none() {
+                          warn!("ASI Camera2 SDK lacks {}", stringify!($name));
+                      })*
+                    Ok(SdkLibrary{_library: library, $($name,)*})
+                }
+            }
+        }
+
+        $(
+        /// # Safety
+        /// As for the corresponding function in ASICamera2.h.
+        pub unsafe fn $name($($arg: $arg_type),*) -> $ret {
+            match sdk_library().and_then(|library| library.$name) {
+                Some(function) => function($($arg),*),
+                None => $unavailable,
+            }
+        }
+        )*
+    };
+}
+
+const UNAVAILABLE: c_int = ASI_ERROR_LIBRARY_UNAVAILABLE;
+
+sdk_functions! {
+    ASIGetNumOfConnectedCameras() -> c_int, unavailable = 0;
+    ASIGetProductIDs(pPIDs: *mut c_int) -> c_int, unavailable = 0;
+    ASICameraCheck(iVID: c_int, iPID: c_int) -> c_int, unavailable = 0;
+    ASIGetCameraProperty(pASICameraInfo: *mut ASI_CAMERA_INFO,
+                         iCameraIndex: c_int) -> c_int,
+        unavailable = UNAVAILABLE;
+    ASIGetCameraPropertyByID(iCameraID: c_int,
+                             pASICameraInfo: *mut ASI_CAMERA_INFO) -> c_int,
+        unavailable = UNAVAILABLE;
+    ASIOpenCamera(iCameraID: c_int) -> c_int, unavailable = UNAVAILABLE;
+    ASIInitCamera(iCameraID: c_int) -> c_int, unavailable = UNAVAILABLE;
+    ASICloseCamera(iCameraID: c_int) -> c_int, unavailable = UNAVAILABLE;
+    ASIGetNumOfControls(iCameraID: c_int, piNumberOfControls: *mut c_int) -> c_int,
+        unavailable = UNAVAILABLE;
+    ASIGetControlCaps(iCameraID: c_int, iControlIndex: c_int,
+                      pControlCaps: *mut ASI_CONTROL_CAPS) -> c_int,
+        unavailable = UNAVAILABLE;
+    ASIGetControlValue(iCameraID: c_int, ControlType: c_int,
+                       plValue: *mut c_long, pbAuto: *mut c_int) -> c_int,
+        unavailable = UNAVAILABLE;
+    ASISetControlValue(iCameraID: c_int, ControlType: c_int,
+                       lValue: c_long, bAuto: c_int) -> c_int,
+        unavailable = UNAVAILABLE;
+    ASISetROIFormat(iCameraID: c_int, iWidth: c_int, iHeight: c_int,
+                    iBin: c_int, Img_type: c_int) -> c_int,
+        unavailable = UNAVAILABLE;
+    ASIGetROIFormat(iCameraID: c_int, piWidth: *mut c_int, piHeight: *mut c_int,
+                    piBin: *mut c_int, pImg_type: *mut c_int) -> c_int,
+        unavailable = UNAVAILABLE;
+    ASISetStartPos(iCameraID: c_int, iStartX: c_int, iStartY: c_int) -> c_int,
+        unavailable = UNAVAILABLE;
+    ASIGetStartPos(iCameraID: c_int, piStartX: *mut c_int,
+                   piStartY: *mut c_int) -> c_int,
+        unavailable = UNAVAILABLE;
+    ASIGetDroppedFrames(iCameraID: c_int, piDropFrames: *mut c_int) -> c_int,
+        unavailable = UNAVAILABLE;
+    ASIEnableDarkSubtract(iCameraID: c_int, pcBMPPath: *mut c_char) -> c_int,
+        unavailable = UNAVAILABLE;
+    ASIDisableDarkSubtract(iCameraID: c_int) -> c_int, unavailable = UNAVAILABLE;
+    ASIStartVideoCapture(iCameraID: c_int) -> c_int, unavailable = UNAVAILABLE;
+    ASIStopVideoCapture(iCameraID: c_int) -> c_int, unavailable = UNAVAILABLE;
+    ASIGetVideoData(iCameraID: c_int, pBuffer: *mut c_uchar, lBuffSize: c_long,
+                    iWaitms: c_int) -> c_int,
+        unavailable = UNAVAILABLE;
+    ASIPulseGuideOn(iCameraID: c_int, direction: c_int) -> c_int,
+        unavailable = UNAVAILABLE;
+    ASIPulseGuideOff(iCameraID: c_int, direction: c_int) -> c_int,
+        unavailable = UNAVAILABLE;
+    ASIStartExposure(iCameraID: c_int, bIsDark: c_int) -> c_int,
+        unavailable = UNAVAILABLE;
+    ASIStopExposure(iCameraID: c_int) -> c_int, unavailable = UNAVAILABLE;
+    ASIGetExpStatus(iCameraID: c_int,
+                    pExpStatus: *mut ASI_EXPOSURE_STATUS) -> c_int,
+        unavailable = UNAVAILABLE;
+    ASIGetDataAfterExp(iCameraID: c_int, pBuffer: *mut c_uchar,
+                       lBuffSize: c_long) -> c_int,
+        unavailable = UNAVAILABLE;
+    ASIGetID(iCameraID: c_int, pID: *mut ASI_ID) -> c_int,
+        unavailable = UNAVAILABLE;
+    ASISetID(iCameraID: c_int, ID: ASI_ID) -> c_int, unavailable = UNAVAILABLE;
+    ASIGetGainOffset(iCameraID: c_int, pOffset_HighestDR: *mut c_int,
+                     pOffset_UnityGain: *mut c_int, pGain_LowestRN: *mut c_int,
+                     pOffset_LowestRN: *mut c_int) -> c_int,
+        unavailable = UNAVAILABLE;
+    ASIGetLMHGainOffset(iCameraID: c_int, pLGain: *mut c_int, pMGain: *mut c_int,
+                        pHGain: *mut c_int, pHOffset: *mut c_int) -> c_int,
+        unavailable = UNAVAILABLE;
+    ASIGetSDKVersion() -> *mut c_char, unavailable = ptr::null_mut();
+    ASIGetCameraSupportMode(iCameraID: c_int,
+                            pSupportedMode: *mut ASI_SUPPORTED_MODE) -> c_int,
+        unavailable = UNAVAILABLE;
+    ASIGetCameraMode(iCameraID: c_int, mode: *mut ASI_CAMERA_MODE) -> c_int,
+        unavailable = UNAVAILABLE;
+    ASISetCameraMode(iCameraID: c_int, mode: ASI_CAMERA_MODE) -> c_int,
+        unavailable = UNAVAILABLE;
+    ASISendSoftTrigger(iCameraID: c_int, bStart: c_int) -> c_int,
+        unavailable = UNAVAILABLE;
+    ASIGetSerialNumber(iCameraID: c_int, pSN: *mut ASI_SN) -> c_int,
+        unavailable = UNAVAILABLE;
+    ASISetTriggerOutputIOConf(iCameraID: c_int, pin: ASI_TRIG_OUTPUT_PIN,
+                              bPinHigh: c_int, lDelay: c_long,
+                              lDuration: c_long) -> c_int,
+        unavailable = UNAVAILABLE;
+    ASIGetTriggerOutputIOConf(iCameraID: c_int, pin: ASI_TRIG_OUTPUT_PIN,
+                              bPinHigh: *mut c_int, lDelay: *mut c_long,
+                              lDuration: *mut c_long) -> c_int,
+        unavailable = UNAVAILABLE;
+}
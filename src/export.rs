@@ -0,0 +1,367 @@
+// Copyright (c) 2023 Steven Rosenthal smr@dt3.org
+// See LICENSE file in root directory for license terms.
+
+use std::fs::File;
+use std::io::BufWriter;
+use std::path::Path;
+
+use image::{DynamicImage, ImageBuffer, Luma, Rgb};
+use log::warn;
+use tiff::encoder::{colortype, TiffEncoder};
+use tiff::tags::Tag;
+
+use crate::asi_camera2_sdk::{ASIError,
+                             ASI_ERROR_CODE_ASI_ERROR_INVALID_FILEFORMAT,
+                             ASI_ERROR_CODE_ASI_ERROR_INVALID_IMGTYPE,
+                             ASI_ERROR_CODE_ASI_ERROR_INVALID_PATH};
+use crate::camera_backend::CameraBackend;
+use crate::fits::format_date;
+use crate::frame::Frame;
+use crate::types::{BayerPattern, CameraInfo, ImgType};
+
+/// Returns `frame` as an image without loss: RAW8 and Y8 frames as 8-bit
+/// grayscale, RAW16 frames as 16-bit grayscale (an `ImageBuffer<Luma<u16>>`),
+/// and RGB24 frames, which the SDK delivers in B, G, R order, as 8-bit RGB.
+///
+/// If `debayer` is given, RAW8 and RAW16 frames are instead demosaiced with
+/// that Bayer pattern, by bilinear interpolation, to 8- or 16-bit RGB.
+pub fn frame_to_image(frame: &Frame, debayer: Option<BayerPattern>)
+                      -> Result<DynamicImage, ASIError> {
+    let width = frame.width as u32;
+    let height = frame.height as u32;
+    let invalid = || ASIError::new(ASI_ERROR_CODE_ASI_ERROR_INVALID_IMGTYPE,
+                                   "frame_to_image");
+    let image = match (frame.img_type, debayer) {
+        (ImgType::Raw8, Some(pattern)) => {
+            let values: Vec<u16> = frame.data.iter().map(|v| *v as u16).collect();
+            let rgb = debayer_bilinear(&values, frame.width as usize,
+                                       frame.height as usize, pattern);
+            let rgb = rgb.into_iter().map(|v| v as u8).collect();
+            DynamicImage::ImageRgb8(
+                ImageBuffer::<Rgb<u8>, _>::from_raw(width, height, rgb).ok_or_else(invalid)?)
+        },
+        (ImgType::Raw16, Some(pattern)) => {
+            let values = frame.pixels_u16().ok_or_else(invalid)?;
+            let rgb = debayer_bilinear(&values, frame.width as usize,
+                                       frame.height as usize, pattern);
+            DynamicImage::ImageRgb16(
+                ImageBuffer::<Rgb<u16>, _>::from_raw(width, height, rgb).ok_or_else(invalid)?)
+        },
+        (ImgType::Raw8 | ImgType::Y8, _) => DynamicImage::ImageLuma8(
+            ImageBuffer::<Luma<u8>, _>::from_raw(width, height, frame.data.clone())
+                .ok_or_else(invalid)?),
+        (ImgType::Raw16, None) => DynamicImage::ImageLuma16(
+            ImageBuffer::<Luma<u16>, _>::from_raw(width, height,
+                                                  frame.pixels_u16().ok_or_else(invalid)?)
+                .ok_or_else(invalid)?),
+        (ImgType::Rgb24, _) => {
+            let rgb = frame.data.chunks_exact(3)
+                .flat_map(|bgr| [bgr[2], bgr[1], bgr[0]])
+                .collect();
+            DynamicImage::ImageRgb8(
+                ImageBuffer::<Rgb<u8>, _>::from_raw(width, height, rgb).ok_or_else(invalid)?)
+        },
+    };
+    Ok(image)
+}
+
+/// Returns the capture metadata of `frame`, as (key, value) pairs of text to
+/// embed in an image file. `camera_info` adds the camera's name and pixel size.
+pub fn frame_text_metadata(frame: &Frame, camera_info: Option<&CameraInfo>)
+                           -> Vec<(String, String)> {
+    let metadata = &frame.metadata;
+    let exposure_start = metadata.system_time.checked_sub(metadata.exposure)
+        .unwrap_or(metadata.system_time);
+    let mut text = Vec::new();
+    if let Some(camera_info) = camera_info {
+        text.push(("Camera".to_string(), camera_info.name.clone()));
+        text.push(("PixelSize".to_string(),
+                   format!("{}", camera_info.pixel_size * frame.bin as f64)));
+    }
+    text.push(("DateObs".to_string(), format_date(exposure_start)));
+    text.push(("Exposure".to_string(),
+               format!("{}", metadata.exposure.as_secs_f64())));
+    text.push(("Gain".to_string(), metadata.gain.to_string()));
+    text.push(("Offset".to_string(), metadata.offset.to_string()));
+    if let Some(temperature) = metadata.temperature {
+        text.push(("Temperature".to_string(), format!("{}", temperature)));
+    }
+    text.push(("Binning".to_string(), frame.bin.to_string()));
+    text.push(("StartX".to_string(), metadata.start_x.to_string()));
+    text.push(("StartY".to_string(), metadata.start_y.to_string()));
+    text.push(("ImageType".to_string(), frame.img_type.to_string()));
+    text.push(("SequenceNumber".to_string(), metadata.sequence_number.to_string()));
+    text
+}
+
+/// Writes `frame` (see frame_to_image()) to the PNG file `path`, with each
+/// entry of `text` (e.g. from frame_text_metadata()) as a tEXt chunk. 16-bit
+/// frames are written at 16 bits.
+pub fn write_png(frame: &Frame, debayer: Option<BayerPattern>,
+                 text: &[(String, String)], path: &Path) -> Result<(), ASIError> {
+    let image = frame_to_image(frame, debayer)?;
+    let (color, depth, data) = match &image {
+        DynamicImage::ImageLuma8(image) =>
+            (png::ColorType::Grayscale, png::BitDepth::Eight, image.as_raw().clone()),
+        DynamicImage::ImageRgb8(image) =>
+            (png::ColorType::Rgb, png::BitDepth::Eight, image.as_raw().clone()),
+        DynamicImage::ImageLuma16(image) =>
+            (png::ColorType::Grayscale, png::BitDepth::Sixteen, to_be_bytes(image.as_raw())),
+        DynamicImage::ImageRgb16(image) =>
+            (png::ColorType::Rgb, png::BitDepth::Sixteen, to_be_bytes(image.as_raw())),
+        _ => unreachable!("frame_to_image() returned {:?}", image.color()),
+    };
+    let file = File::create(path).map_err(|e| write_error(path, &e, "write_png"))?;
+    let mut encoder = png::Encoder::new(BufWriter::new(file), image.width(), image.height());
+    encoder.set_color(color);
+    encoder.set_depth(depth);
+    for (key, value) in text {
+        encoder.add_text_chunk(key.clone(), value.clone())
+            .map_err(|e| write_error(path, &e, "write_png"))?;
+    }
+    encoder.write_header()
+        .and_then(|mut writer| {
+            writer.write_image_data(&data)?;
+            writer.finish()
+        })
+        .map_err(|e| write_error(path, &e, "write_png"))
+}
+
+/// Writes `frame` (see frame_to_image()) to the uncompressed TIFF file `path`,
+/// at 16 bits for 16-bit frames. The entries of `text` are written as
+/// "key=value" lines in the ImageDescription tag; a "Camera" entry also sets the
+/// Model tag, and a "DateObs" entry the DateTime tag.
+pub fn write_tiff(frame: &Frame, debayer: Option<BayerPattern>,
+                  text: &[(String, String)], path: &Path) -> Result<(), ASIError> {
+    let image = frame_to_image(frame, debayer)?;
+    let description: Vec<String> =
+        text.iter().map(|(key, value)| format!("{}={}", key, value)).collect();
+    let description = description.join("\n");
+    let lookup = |key: &str| {
+        text.iter().find(|(k, _)| k == key).map(|(_, value)| value.as_str())
+    };
+    // TIFF dates are "yyyy:mm:dd hh:mm:ss".
+    let date_time = lookup("DateObs").filter(|date| date.len() >= 19).map(|date| {
+        format!("{} {}", date[..10].replace('-', ":"), &date[11..19])
+    });
+
+    let file = File::create(path).map_err(|e| write_error(path, &e, "write_tiff"))?;
+    let mut encoder = TiffEncoder::new(BufWriter::new(file))
+        .map_err(|e| write_error(path, &e, "write_tiff"))?;
+    let (width, height) = (image.width(), image.height());
+    macro_rules! write_image {
+        ($color_type:ty, $data:expr) => {{
+            let mut tiff_image = encoder.new_image::<$color_type>(width, height)?;
+            let directory = tiff_image.encoder();
+            directory.write_tag(Tag::ImageDescription, description.as_str())?;
+            directory.write_tag(Tag::Software, "asi_camera2")?;
+            if let Some(camera) = lookup("Camera") {
+                directory.write_tag(Tag::Make, "ZWO")?;
+                directory.write_tag(Tag::Model, camera)?;
+            }
+            if let Some(date_time) = &date_time {
+                directory.write_tag(Tag::DateTime, date_time.as_str())?;
+            }
+            tiff_image.write_data($data)
+        }};
+    }
+    let result = (|| match &image {
+        DynamicImage::ImageLuma8(image) => write_image!(colortype::Gray8, image.as_raw()),
+        DynamicImage::ImageRgb8(image) => write_image!(colortype::RGB8, image.as_raw()),
+        DynamicImage::ImageLuma16(image) => write_image!(colortype::Gray16, image.as_raw()),
+        DynamicImage::ImageRgb16(image) => write_image!(colortype::RGB16, image.as_raw()),
+        _ => unreachable!("frame_to_image() returned {:?}", image.color()),
+    })();
+    result.map_err(|e| write_error(path, &e, "write_tiff"))
+}
+
+/// Writes `frame`, just captured by `camera`, to `path` as PNG or TIFF according
+/// to its extension (.png, .tif or .tiff), with frame_text_metadata() embedded.
+/// If `debayer` is set, raw frames from color cameras are demosaiced with the
+/// camera's Bayer pattern.
+pub fn save_frame<B>(camera: &B, frame: &Frame, path: &Path, debayer: bool)
+                     -> Result<(), ASIError>
+where B: CameraBackend + ?Sized
+{
+    let camera_info = camera.camera_info()?;
+    let text = frame_text_metadata(frame, Some(&camera_info));
+    let pattern = if debayer { camera_info.bayer_pattern } else { None };
+    let extension = path.extension().and_then(|extension| extension.to_str())
+        .map(|extension| extension.to_ascii_lowercase());
+    match extension.as_deref() {
+        Some("png") => write_png(frame, pattern, &text, path),
+        Some("tif" | "tiff") => write_tiff(frame, pattern, &text, path),
+        _ => Err(ASIError::new(ASI_ERROR_CODE_ASI_ERROR_INVALID_FILEFORMAT,
+                               "save_frame")),
+    }
+}
+
+// Demosaics `values`, a `width` x `height` Bayer mosaic, returning interleaved
+// R, G, B values. Each missing color at a pixel is the mean of the neighboring
+// pixels (within the surrounding 3x3) of that color.
+fn debayer_bilinear(values: &[u16], width: usize, height: usize, pattern: BayerPattern)
+                    -> Vec<u16> {
+    // Colors (0=R, 1=G, 2=B) of the top left 2x2 cell, row by row.
+    let cell = match pattern {
+        BayerPattern::Rg => [0, 1, 1, 2],
+        BayerPattern::Bg => [2, 1, 1, 0],
+        BayerPattern::Gr => [1, 0, 2, 1],
+        BayerPattern::Gb => [1, 2, 0, 1],
+    };
+    let color_at = |x: usize, y: usize| cell[(y % 2) * 2 + x % 2];
+    let mut rgb = vec![0u16; width * height * 3];
+    for y in 0..height {
+        for x in 0..width {
+            let mut sums = [0u32; 3];
+            let mut counts = [0u32; 3];
+            for ny in y.saturating_sub(1)..(y + 2).min(height) {
+                for nx in x.saturating_sub(1)..(x + 2).min(width) {
+                    let color = color_at(nx, ny);
+                    sums[color] += values[ny * width + nx] as u32;
+                    counts[color] += 1;
+                }
+            }
+            let own_color = color_at(x, y);
+            let pixel = &mut rgb[(y * width + x) * 3..][..3];
+            for color in 0..3 {
+                pixel[color] = if color == own_color {
+                    values[y * width + x]
+                } else {
+                    sums[color].checked_div(counts[color]).unwrap_or(0) as u16
+                };
+            }
+        }
+    }
+    rgb
+}
+
+fn to_be_bytes(values: &[u16]) -> Vec<u8> {
+    values.iter().flat_map(|value| value.to_be_bytes()).collect()
+}
+
+fn write_error(path: &Path, e: &dyn std::error::Error, source: &str) -> ASIError {
+    warn!("Error writing {:?}: {}", path, e);
+    ASIError::new(ASI_ERROR_CODE_ASI_ERROR_INVALID_PATH, source)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+    use std::io::BufReader;
+
+    use tiff::decoder::{Decoder, DecodingResult};
+
+    use super::*;
+    use crate::asi_camera2_sdk::ASIErrorKind;
+    use crate::simulated_camera::test_support::{simulated_camera, simulated_frame,
+                                                temp_path};
+
+    #[test]
+    fn rgb24_reordered() {
+        let mut frame = Frame::new(2, 1, 1, ImgType::Rgb24);
+        frame.data = vec![1, 2, 3, 4, 5, 6];
+        let image = frame_to_image(&frame, None).unwrap();
+        assert_eq!(image.as_rgb8().unwrap().as_raw(), &[3, 2, 1, 6, 5, 4]);
+    }
+
+    #[test]
+    fn raw16_png_big_endian() {
+        let mut frame = Frame::new(2, 1, 1, ImgType::Raw16);
+        frame.data = [0x0102u16, 0xa0b0].iter().flat_map(|v| v.to_le_bytes()).collect();
+        let image = frame_to_image(&frame, None).unwrap();
+        assert_eq!(image.as_luma16().unwrap().as_raw(), &[0x0102, 0xa0b0]);
+
+        let path = temp_path("raw16.png");
+        write_png(&frame, None, &[], &path).unwrap();
+        let mut reader = png::Decoder::new(BufReader::new(File::open(&path).unwrap()))
+            .read_info().unwrap();
+        assert_eq!(reader.info().bit_depth, png::BitDepth::Sixteen);
+        let mut data = vec![0; reader.output_buffer_size().unwrap()];
+        reader.next_frame(&mut data).unwrap();
+        assert_eq!(data, [0x01, 0x02, 0xa0, 0xb0]);
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn debayer_patterns() {
+        // A mosaic with R = 100, G = 200 and B = 300 demosaics to that color at
+        // every pixel, if the pattern is followed.
+        for (pattern, layout) in [(BayerPattern::Rg, "RGGB"), (BayerPattern::Bg, "BGGR"),
+                                  (BayerPattern::Gr, "GRBG"), (BayerPattern::Gb, "GBRG")] {
+            let layout = layout.as_bytes();
+            let values: Vec<u16> = (0..16).map(|i| {
+                match layout[(i / 4 % 2) * 2 + i % 2] {
+                    b'R' => 100,
+                    b'G' => 200,
+                    _ => 300,
+                }
+            }).collect();
+            let rgb = debayer_bilinear(&values, 4, 4, pattern);
+            assert!(rgb.chunks_exact(3).all(|pixel| pixel == [100, 200, 300]),
+                    "{:?}: {:?}", pattern, rgb);
+        }
+
+        // Missing colors are interpolated from the neighbors.
+        let rgb = debayer_bilinear(&[10, 20, 30, 40], 2, 2, BayerPattern::Rg);
+        assert_eq!(rgb, [10, 25, 40,  10, 20, 40,  10, 30, 40,  10, 25, 40]);
+    }
+
+    #[test]
+    fn png_round_trip() {
+        let camera = simulated_camera(ImgType::Raw16);
+        let (frame, camera_info) = simulated_frame(ImgType::Raw16);
+        let path = temp_path("round_trip.png");
+        save_frame(&camera, &frame, &path, false).unwrap();
+
+        let mut reader = png::Decoder::new(BufReader::new(File::open(&path).unwrap()))
+            .read_info().unwrap();
+        let text: Vec<(String, String)> = reader.info().uncompressed_latin1_text.iter()
+            .map(|chunk| (chunk.keyword.clone(), chunk.text.clone()))
+            .collect();
+        assert_eq!(text, frame_text_metadata(&frame, Some(&camera_info)));
+        let mut data = vec![0; reader.output_buffer_size().unwrap()];
+        reader.next_frame(&mut data).unwrap();
+        let pixels: Vec<u16> = data.chunks_exact(2)
+            .map(|pixel| u16::from_be_bytes([pixel[0], pixel[1]]))
+            .collect();
+        assert_eq!(pixels, frame.pixels_u16().unwrap());
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn tiff_round_trip() {
+        let camera = simulated_camera(ImgType::Raw16);
+        let (frame, camera_info) = simulated_frame(ImgType::Raw16);
+        let path = temp_path("round_trip.tiff");
+        save_frame(&camera, &frame, &path, false).unwrap();
+
+        let text = frame_text_metadata(&frame, Some(&camera_info));
+        let mut decoder = Decoder::new(File::open(&path).unwrap()).unwrap();
+        assert_eq!(decoder.dimensions().unwrap(), (64, 32));
+        let description = decoder.get_tag_ascii_string(Tag::ImageDescription).unwrap();
+        let expected: Vec<String> =
+            text.iter().map(|(key, value)| format!("{}={}", key, value)).collect();
+        assert_eq!(description, expected.join("\n"));
+        assert_eq!(decoder.get_tag_ascii_string(Tag::Model).unwrap(), camera_info.name);
+        // "2024-02-29T12:34:56.789" becomes "2024:02:29 12:34:56".
+        let date_obs = &text.iter().find(|(key, _)| key == "DateObs").unwrap().1;
+        let date_time = decoder.get_tag_ascii_string(Tag::DateTime).unwrap();
+        assert_eq!(date_time, format!("{} {}", date_obs[..10].replace('-', ":"),
+                                      &date_obs[11..19]));
+        match decoder.read_image().unwrap() {
+            DecodingResult::U16(pixels) => assert_eq!(pixels, frame.pixels_u16().unwrap()),
+            _ => panic!("expected 16-bit pixels"),
+        }
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn unknown_extension() {
+        let camera = simulated_camera(ImgType::Raw8);
+        let (frame, _camera_info) = simulated_frame(ImgType::Raw8);
+        let path = temp_path("unknown_extension.jpg");
+        assert_eq!(save_frame(&camera, &frame, &path, false).unwrap_err().kind(),
+                   ASIErrorKind::InvalidFileFormat);
+        assert!(!path.exists());
+    }
+}
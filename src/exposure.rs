@@ -0,0 +1,224 @@
+// Copyright (c) 2023 Steven Rosenthal smr@dt3.org
+// See LICENSE file in root directory for license terms.
+
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread::sleep;
+use std::time::{Duration, Instant};
+
+use log::{info, warn};
+
+use crate::asi_camera2_sdk::{ASIError,
+                             ASI_ERROR_CANCELLED,
+                             ASI_ERROR_EXPOSURE_FAILED,
+                             ASI_ERROR_CODE_ASI_ERROR_TIMEOUT};
+use crate::camera_backend::CameraBackend;
+use crate::frame::Frame;
+use crate::types::{ControlType, ExposureStatus};
+
+// Polling of the exposure status starts at the first interval and doubles up
+// to the second. The SDK typically takes a few hundred ms beyond the exposure
+// duration to report ExposureStatus::Success.
+const MIN_POLL_INTERVAL: Duration = Duration::from_millis(5);
+const MAX_POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+/// Lets another thread cancel capture_exposure(). Clones share the same
+/// state.
+#[derive(Clone, Debug, Default)]
+pub struct CancelToken {
+    cancelled: Arc<AtomicBool>,
+}
+
+impl CancelToken {
+    pub fn new() -> Self { Self::default() }
+
+    /// Makes a capture_exposure() using this token, or the next one started,
+    /// stop its exposure and return an ASIErrorKind::Cancelled error.
+    pub fn cancel(&self) {
+        self.cancelled.store(true, Ordering::Relaxed);
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.cancelled.load(Ordering::Relaxed)
+    }
+
+    /// Clears the cancellation, so the token can be used again.
+    pub fn reset(&self) {
+        self.cancelled.store(false, Ordering::Relaxed);
+    }
+}
+
+/// Options for capture_exposure().
+#[derive(Clone, Debug)]
+pub struct ExposureOptions {
+    /// How many times an exposure ending in ExposureStatus::Failed is retried
+    /// before giving up.
+    pub max_retries: u32,
+    pub cancel: Option<CancelToken>,
+}
+
+impl Default for ExposureOptions {
+    fn default() -> Self {
+        ExposureOptions{max_retries: 2, cancel: None}
+    }
+}
+
+/// Sets the Exposure control to `duration`, takes a single exposure and
+/// returns its frame. See CameraBackend::capture_exposure().
+///
+/// `timeout` bounds how long each exposure may take beyond `duration`; when it
+/// passes, the exposure is stopped and an ASIErrorKind::Timeout error is
+/// returned. None waits indefinitely. If the exposure fails more than
+/// `options.max_retries` times, an ASIErrorKind::ExposureFailed error is
+/// returned. Video capture must not be running.
+pub fn capture_exposure<B>(camera: &mut B, duration: Duration, is_dark: bool,
+                           timeout: Option<Duration>, options: &ExposureOptions)
+                           -> Result<Frame, ASIError>
+where B: CameraBackend + ?Sized
+{
+    camera.set_control_value(ControlType::Exposure,
+                             duration.as_micros() as i64, /*auto=*/false)?;
+    let mut failures = 0;
+    loop {
+        check_cancelled(camera, options)?;
+        camera.start_exposure(is_dark)?;
+        match wait_for_exposure(camera, duration, timeout, options)? {
+            ExposureStatus::Success => return camera.get_frame_after_exp(),
+            status => {
+                failures += 1;
+                warn!("Exposure on camera id {} ended with status {}",
+                      camera.camera_id(), status);
+                if failures > options.max_retries {
+                    return Err(ASIError{error_code: ASI_ERROR_EXPOSURE_FAILED,
+                                        source: "capture_exposure".to_string()});
+                }
+            },
+        }
+    }
+}
+
+// Waits for the exposure started on `camera` to end, and returns its final
+// status. Stops the exposure if it is cancelled or times out.
+fn wait_for_exposure<B>(camera: &mut B, duration: Duration, timeout: Option<Duration>,
+                        options: &ExposureOptions) -> Result<ExposureStatus, ASIError>
+where B: CameraBackend + ?Sized
+{
+    let start = Instant::now();
+    let deadline = timeout.map(|timeout| start + duration + timeout);
+    let exposure_end = start + duration;
+    let mut poll_interval = MIN_POLL_INTERVAL;
+    loop {
+        let now = Instant::now();
+        if now >= exposure_end {
+            let status = camera.get_exp_status()?;
+            if status != ExposureStatus::Working {
+                return Ok(status);
+            }
+        }
+        if let Err(e) = check_cancelled(camera, options) {
+            stop_exposure(camera);
+            return Err(e);
+        }
+        if deadline.is_some_and(|deadline| now >= deadline) {
+            stop_exposure(camera);
+            return Err(ASIError::new(ASI_ERROR_CODE_ASI_ERROR_TIMEOUT,
+                                     "capture_exposure"));
+        }
+        // Sleep through the exposure, waking up periodically to check for
+        // cancellation, then poll with backoff.
+        let interval = if now < exposure_end {
+            (exposure_end - now).min(MAX_POLL_INTERVAL)
+        } else {
+            let interval = poll_interval;
+            poll_interval = (poll_interval * 2).min(MAX_POLL_INTERVAL);
+            interval
+        };
+        match deadline {
+            Some(deadline) => sleep(interval.min(deadline.saturating_duration_since(now))),
+            None => sleep(interval),
+        }
+    }
+}
+
+fn check_cancelled<B>(camera: &B, options: &ExposureOptions) -> Result<(), ASIError>
+where B: CameraBackend + ?Sized
+{
+    if options.cancel.as_ref().is_some_and(|cancel| cancel.is_cancelled()) {
+        info!("Exposure on camera id {} cancelled", camera.camera_id());
+        return Err(ASIError{error_code: ASI_ERROR_CANCELLED,
+                            source: "capture_exposure".to_string()});
+    }
+    Ok(())
+}
+
+fn stop_exposure<B: CameraBackend + ?Sized>(camera: &mut B) {
+    if let Err(e) = camera.stop_exposure() {
+        warn!("Error stopping exposure on camera id {}: {}", camera.camera_id(), e);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::thread;
+
+    use super::*;
+    use crate::asi_camera2_sdk::ASIErrorKind;
+    use crate::simulated_camera::{SimulatedCamera, SimulatorConfig};
+    use crate::types::ImgType;
+
+    fn opened_camera(config: SimulatorConfig) -> SimulatedCamera {
+        let mut camera = SimulatedCamera::new(config);
+        camera.open().unwrap();
+        camera.set_roi_format(64, 32, 1, ImgType::Raw8).unwrap();
+        camera
+    }
+
+    #[test]
+    fn timeout() {
+        let config = SimulatorConfig{readout_time: Duration::from_secs(10),
+                                     ..Default::default()};
+        let mut camera = opened_camera(config);
+        let error = capture_exposure(&mut camera, Duration::from_millis(1), false,
+                                     Some(Duration::from_millis(50)),
+                                     &ExposureOptions::default()).unwrap_err();
+        assert_eq!(error.kind(), ASIErrorKind::Timeout);
+        // The exposure was stopped.
+        assert_eq!(camera.get_exp_status().unwrap(), ExposureStatus::Failed);
+    }
+
+    #[test]
+    fn retries() {
+        let options = ExposureOptions{max_retries: 2, cancel: None};
+        let mut camera = opened_camera(SimulatorConfig{failed_exposures: 2, ..Default::default()});
+        let frame = capture_exposure(&mut camera, Duration::from_millis(1), false,
+                                     None, &options).unwrap();
+        assert_eq!((frame.width, frame.height), (64, 32));
+
+        let mut camera = opened_camera(SimulatorConfig{failed_exposures: 3, ..Default::default()});
+        let error = capture_exposure(&mut camera, Duration::from_millis(1), false,
+                                     None, &options).unwrap_err();
+        assert_eq!(error.kind(), ASIErrorKind::ExposureFailed);
+        assert_eq!(error.error_code, ASI_ERROR_EXPOSURE_FAILED);
+    }
+
+    #[test]
+    fn cancel() {
+        let mut camera = opened_camera(SimulatorConfig::default());
+        let cancel = CancelToken::new();
+        let options = ExposureOptions{max_retries: 0, cancel: Some(cancel.clone())};
+        let canceller = thread::spawn(move || {
+            sleep(Duration::from_millis(50));
+            cancel.cancel();
+        });
+        let start = Instant::now();
+        let error = capture_exposure(&mut camera, Duration::from_secs(10), false,
+                                     None, &options).unwrap_err();
+        // Within one poll interval of the cancellation.
+        assert!(start.elapsed() < Duration::from_millis(50) + 2 * MAX_POLL_INTERVAL,
+                "{:?}", start.elapsed());
+        assert_eq!(error.kind(), ASIErrorKind::Cancelled);
+        assert_eq!(error.error_code, ASI_ERROR_CANCELLED);
+        assert_eq!(camera.get_exp_status().unwrap(), ExposureStatus::Failed);
+        canceller.join().unwrap();
+    }
+}
@@ -0,0 +1,459 @@
+// Copyright (c) 2023 Steven Rosenthal smr@dt3.org
+// See LICENSE file in root directory for license terms.
+
+use std::fs;
+use std::path::Path;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use log::warn;
+
+use crate::asi_camera2_sdk::{ASIError,
+                             ASI_ERROR_CODE_ASI_ERROR_GENERAL_ERROR,
+                             ASI_ERROR_CODE_ASI_ERROR_INVALID_PATH};
+use crate::camera_backend::CameraBackend;
+use crate::frame::Frame;
+use crate::types::{BayerPattern, CameraInfo, ImgType};
+
+// FITS files are made of 2880 byte blocks, and headers of 80 character cards.
+const BLOCK_SIZE: usize = 2880;
+const CARD_SIZE: usize = 80;
+// Longest string value, without its quotes, that fits in a card after the
+// keyword and "= ".
+const MAX_STRING_SIZE: usize = CARD_SIZE - 12;
+
+// Keywords written by write_fits() from the frame's format, which set() does not
+// accept.
+const STRUCTURAL_KEYWORDS: &[&str] =
+    &["SIMPLE", "BITPIX", "NAXIS", "NAXIS1", "NAXIS2", "NAXIS3", "BZERO", "BSCALE",
+      "EXTEND", "END"];
+
+/// The value of a FITS header card.
+#[derive(Clone, Debug, PartialEq)]
+pub enum FitsValue {
+    Logical(bool),
+    Integer(i64),
+    Float(f64),
+    String(String),
+}
+
+impl From<bool> for FitsValue {
+    fn from(value: bool) -> Self { FitsValue::Logical(value) }
+}
+
+impl From<i64> for FitsValue {
+    fn from(value: i64) -> Self { FitsValue::Integer(value) }
+}
+
+impl From<i32> for FitsValue {
+    fn from(value: i32) -> Self { FitsValue::Integer(value as i64) }
+}
+
+impl From<f64> for FitsValue {
+    fn from(value: f64) -> Self { FitsValue::Float(value) }
+}
+
+impl From<&str> for FitsValue {
+    fn from(value: &str) -> Self { FitsValue::String(value.to_string()) }
+}
+
+impl From<String> for FitsValue {
+    fn from(value: String) -> Self { FitsValue::String(value) }
+}
+
+#[derive(Clone, Debug)]
+struct Card {
+    keyword: String,
+    value: FitsValue,
+    comment: String,
+}
+
+/// The descriptive keywords of a FITS header. The structural keywords (BITPIX,
+/// NAXISn, BZERO etc.) are added by write_fits() from the frame being written.
+#[derive(Clone, Debug, Default)]
+pub struct FitsHeader {
+    cards: Vec<Card>,
+}
+
+impl FitsHeader {
+    pub fn new() -> Self { Self::default() }
+
+    /// Returns the standard acquisition keywords for `frame`, captured by the
+    /// camera described by `camera_info`:
+    /// * DATE-OBS: UTC start of the exposure, taken to have ended when the
+    ///   frame was read.
+    /// * EXPTIME, GAIN, OFFSET and, if known, CCD-TEMP, from the frame's
+    ///   metadata.
+    /// * XBINNING, YBINNING, and XORGSUBF, YORGSUBF giving the ROI's start
+    ///   position in binned pixels.
+    /// * XPIXSZ, YPIXSZ: binned pixel size in microns.
+    /// * INSTRUME: the camera's name.
+    /// * BAYERPAT, for RAW8 and RAW16 frames from color cameras: the four
+    ///   letter CFA code, e.g. RGGB.
+    /// * ROWORDER: TOP-DOWN, as the rows are written in the order the camera
+    ///   delivers them.
+    pub fn from_frame(frame: &Frame, camera_info: &CameraInfo) -> Self {
+        let metadata = &frame.metadata;
+        let exposure_start = metadata.system_time.checked_sub(metadata.exposure)
+            .unwrap_or(metadata.system_time);
+        let pixel_size = camera_info.pixel_size * frame.bin as f64;
+        let mut header = FitsHeader::new();
+        header.push("DATE-OBS", format_date(exposure_start).into(),
+                    "UTC start of exposure");
+        header.push("EXPTIME", metadata.exposure.as_secs_f64().into(),
+                    "Exposure time in seconds");
+        header.push("GAIN", metadata.gain.into(), "Camera gain");
+        header.push("OFFSET", metadata.offset.into(), "Camera offset");
+        if let Some(temperature) = metadata.temperature {
+            header.push("CCD-TEMP", temperature.into(), "Sensor temperature in C");
+        }
+        header.push("XBINNING", frame.bin.into(), "Binning factor in width");
+        header.push("YBINNING", frame.bin.into(), "Binning factor in height");
+        header.push("XORGSUBF", metadata.start_x.into(), "Subframe X position");
+        header.push("YORGSUBF", metadata.start_y.into(), "Subframe Y position");
+        header.push("XPIXSZ", pixel_size.into(), "Pixel width in microns");
+        header.push("YPIXSZ", pixel_size.into(), "Pixel height in microns");
+        header.push("INSTRUME", camera_info.name.as_str().into(), "Camera name");
+        if let Some(bayer_pattern) = camera_info.bayer_pattern {
+            if matches!(frame.img_type, ImgType::Raw8 | ImgType::Raw16) {
+                header.push("BAYERPAT", cfa_pattern(bayer_pattern).into(),
+                            "Bayer color pattern");
+            }
+        }
+        header.push("ROWORDER", "TOP-DOWN".into(), "Order of the rows");
+        header
+    }
+
+    /// Sets `keyword` (e.g. "OBJECT" or "TELESCOP") to `value`, replacing any
+    /// existing card for it. Keywords are at most 8 characters of upper case
+    /// letters, digits, '-' and '_'; structural keywords such as BITPIX cannot
+    /// be set. Float values must be finite, as FITS has no NaN or infinity.
+    pub fn set(&mut self, keyword: &str, value: impl Into<FitsValue>, comment: &str)
+               -> Result<(), ASIError> {
+        let value = value.into();
+        let valid = !keyword.is_empty() && keyword.len() <= 8 &&
+            keyword.bytes().all(|c| matches!(c, b'A'..=b'Z' | b'0'..=b'9' | b'-' | b'_')) &&
+            !STRUCTURAL_KEYWORDS.contains(&keyword) &&
+            !matches!(value, FitsValue::Float(value) if !value.is_finite());
+        if !valid {
+            return Err(ASIError::new(ASI_ERROR_CODE_ASI_ERROR_GENERAL_ERROR,
+                                     &format!("FitsHeader::set({:?})", keyword)));
+        }
+        self.push(keyword, value, comment);
+        Ok(())
+    }
+
+    /// Returns the value of `keyword`, if set.
+    pub fn get(&self, keyword: &str) -> Option<&FitsValue> {
+        self.cards.iter().find(|card| card.keyword == keyword).map(|card| &card.value)
+    }
+
+    // Returns the keyword, value (formatted as in a FITS header) and comment of
+    // each card.
+    pub(crate) fn cards(&self) -> impl Iterator<Item = (&str, String, &str)> {
+        self.cards.iter().map(|card| {
+            (card.keyword.as_str(), format_value(&card.value, /*pad=*/false),
+             card.comment.as_str())
+        })
+    }
+
+    // Non-finite floats, e.g. a temperature the camera failed to read, cannot
+    // be written, so are left out.
+    fn push(&mut self, keyword: &str, value: FitsValue, comment: &str) {
+        if matches!(value, FitsValue::Float(value) if !value.is_finite()) {
+            return;
+        }
+        let card = Card{keyword: keyword.to_string(), value, comment: comment.to_string()};
+        match self.cards.iter_mut().find(|card| card.keyword == keyword) {
+            Some(existing) => *existing = card,
+            None => self.cards.push(card),
+        }
+    }
+}
+
+/// Returns `frame` as a FITS file with the keywords of `header`.
+///
+/// RAW8 and Y8 frames are written as 8-bit images. RAW16 frames are written as
+/// 16-bit images with BZERO 32768, the FITS convention for unsigned data. RGB24
+/// frames are written as 8-bit images with three planes, in R, G, B order.
+pub fn encode_fits(frame: &Frame, header: &FitsHeader) -> Vec<u8> {
+    let mut fits = FitsHeader::new();
+    fits.push("SIMPLE", true.into(), "Conforms to the FITS standard");
+    let bitpix = if frame.img_type == ImgType::Raw16 { 16 } else { 8 };
+    fits.push("BITPIX", FitsValue::Integer(bitpix), "Bits per pixel value");
+    let naxis = if frame.img_type == ImgType::Rgb24 { 3 } else { 2 };
+    fits.push("NAXIS", FitsValue::Integer(naxis), "Number of axes");
+    fits.push("NAXIS1", frame.width.into(), "Width");
+    fits.push("NAXIS2", frame.height.into(), "Height");
+    if frame.img_type == ImgType::Rgb24 {
+        fits.push("NAXIS3", 3.into(), "Color planes: R, G, B");
+    }
+    if frame.img_type == ImgType::Raw16 {
+        fits.push("BZERO", 32768.into(), "Offset for unsigned 16-bit values");
+        fits.push("BSCALE", 1.into(), "Default scaling factor");
+    }
+    fits.cards.extend(header.cards.iter().cloned());
+
+    let mut bytes = Vec::with_capacity(
+        (fits.cards.len() + 1) * CARD_SIZE + BLOCK_SIZE + frame.data.len());
+    for card in &fits.cards {
+        bytes.extend_from_slice(format_card(card).as_bytes());
+    }
+    bytes.extend_from_slice(format!("{:<80}", "END").as_bytes());
+    pad_block(&mut bytes, b' ');
+
+    match frame.img_type {
+        ImgType::Raw16 => {
+            // Big-endian, less 32768, which flips the top bit.
+            for pixel in frame.data.chunks_exact(2) {
+                bytes.extend_from_slice(&[pixel[1] ^ 0x80, pixel[0]]);
+            }
+        },
+        ImgType::Rgb24 => {
+            // The SDK delivers B, G, R bytes for each pixel.
+            for channel in [2, 1, 0] {
+                bytes.extend(frame.data.iter().skip(channel).step_by(3));
+            }
+        },
+        _ => bytes.extend_from_slice(&frame.data),
+    }
+    pad_block(&mut bytes, 0);
+    bytes
+}
+
+/// Writes `frame` with the keywords of `header` to the FITS file `path`; see
+/// encode_fits().
+pub fn write_fits(frame: &Frame, header: &FitsHeader, path: &Path) -> Result<(), ASIError> {
+    fs::write(path, encode_fits(frame, header)).map_err(|e| {
+        warn!("Error writing {:?}: {}", path, e);
+        ASIError::new(ASI_ERROR_CODE_ASI_ERROR_INVALID_PATH, "write_fits")
+    })
+}
+
+/// Writes `frame`, just captured by `camera`, to the FITS file `path`, with the
+/// header keywords of FitsHeader::from_frame().
+pub fn save_fits<B>(camera: &B, frame: &Frame, path: &Path) -> Result<(), ASIError>
+where B: CameraBackend + ?Sized
+{
+    let header = FitsHeader::from_frame(frame, &camera.camera_info()?);
+    write_fits(frame, &header, path)
+}
+
+fn pad_block(bytes: &mut Vec<u8>, fill: u8) {
+    bytes.resize(bytes.len().div_ceil(BLOCK_SIZE) * BLOCK_SIZE, fill);
+}
+
+/// Returns the CFA code for `pattern` used by BAYERPAT (and by XISF's
+/// ColorFilterArray), giving the colors of the top-left 2x2 pixels row by row.
+pub fn cfa_pattern(pattern: BayerPattern) -> &'static str {
+    match pattern {
+        BayerPattern::Rg => "RGGB",
+        BayerPattern::Bg => "BGGR",
+        BayerPattern::Gr => "GRBG",
+        BayerPattern::Gb => "GBRG",
+    }
+}
+
+// Formats `card` in the FITS fixed format. Values always fit; the comment is
+// cut short, or left out, to fit the card in 80 characters.
+fn format_card(card: &Card) -> String {
+    let value = match &card.value {
+        FitsValue::String(_) => format_value(&card.value, /*pad=*/true),
+        _ => format!("{:>20}", format_value(&card.value, /*pad=*/false)),
+    };
+    let mut text = format!("{:<8}= {}", card.keyword, value);
+    let comment = printable_ascii(&card.comment);
+    if !comment.is_empty() && text.len() + 3 < CARD_SIZE {
+        text.push_str(" / ");
+        text.push_str(&comment);
+        // All ASCII, so this cannot split a character.
+        text.truncate(CARD_SIZE);
+    }
+    format!("{:<80}", text)
+}
+
+// Replaces the characters of `text` that are not allowed in FITS headers.
+fn printable_ascii(text: &str) -> String {
+    text.chars()
+        .map(|c| if c.is_ascii() && !c.is_ascii_control() { c } else { '?' })
+        .collect()
+}
+
+// Formats `value` as in a FITS header card. With `pad`, strings are padded to
+// the minimum of 8 characters required by the fixed format.
+fn format_value(value: &FitsValue, pad: bool) -> String {
+    match value {
+        FitsValue::Logical(value) => if *value { "T" } else { "F" }.to_string(),
+        FitsValue::Integer(value) => value.to_string(),
+        FitsValue::Float(value) if *value != 0.0 &&
+            !(1e-6..1e15).contains(&value.abs()) => format!("{:E}", value),
+        FitsValue::Float(value) => {
+            // Display never uses an exponent, but omits the point for whole
+            // numbers.
+            let mut text = value.to_string();
+            if !text.contains('.') {
+                text.push_str(".0");
+            }
+            text
+        },
+        FitsValue::String(value) => {
+            // Only printable ASCII is allowed; quotes are doubled. Long values
+            // are cut short to fit in a card, without splitting a doubled
+            // quote.
+            let mut escaped = String::new();
+            for c in printable_ascii(value).chars() {
+                let size = if c == '\'' { 2 } else { 1 };
+                if escaped.len() + size > MAX_STRING_SIZE {
+                    break;
+                }
+                escaped.push(c);
+                if c == '\'' {
+                    escaped.push(c);
+                }
+            }
+            let value = escaped;
+            if pad {
+                format!("'{:<8}'", value)
+            } else {
+                format!("'{}'", value)
+            }
+        },
+    }
+}
+
+// Formats `time` as a FITS date: yyyy-mm-ddThh:mm:ss.sss, UTC. This is also
+// ISO 8601.
+pub(crate) fn format_date(time: SystemTime) -> String {
+    let since_epoch = time.duration_since(UNIX_EPOCH).unwrap_or_default();
+    let secs = since_epoch.as_secs();
+    let (year, month, day) = civil_from_days((secs / 86400) as i64);
+    let secs_of_day = secs % 86400;
+    format!("{:04}-{:02}-{:02}T{:02}:{:02}:{:02}.{:03}",
+            year, month, day, secs_of_day / 3600, secs_of_day / 60 % 60,
+            secs_of_day % 60, since_epoch.subsec_millis())
+}
+
+// Returns the (year, month, day) of the given number of days since 1970-01-01,
+// in the proleptic Gregorian calendar. From Howard Hinnant's date algorithms.
+fn civil_from_days(days: i64) -> (i64, u32, u32) {
+    let z = days + 719468;
+    let era = z.div_euclid(146097);
+    let day_of_era = z.rem_euclid(146097);
+    let year_of_era =
+        (day_of_era - day_of_era / 1460 + day_of_era / 36524 - day_of_era / 146096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let mp = (5 * day_of_year + 2) / 153;
+    let day = (day_of_year - (153 * mp + 2) / 5 + 1) as u32;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 } as u32;
+    let year = year_of_era + era * 400 + if month <= 2 { 1 } else { 0 };
+    (year, month, day)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+    use crate::simulated_camera::test_support::simulated_frame;
+
+    // Returns the header cards of `fits`, up to END.
+    fn header_cards(fits: &[u8]) -> Vec<&str> {
+        let cards: Vec<&str> = fits.chunks_exact(CARD_SIZE)
+            .map(|card| std::str::from_utf8(card).unwrap())
+            .take_while(|card| !card.starts_with("END "))
+            .collect();
+        assert!(cards.len() * CARD_SIZE < fits.len());
+        cards
+    }
+
+    #[test]
+    fn raw16_layout() {
+        let (frame, camera_info) = simulated_frame(ImgType::Raw16);
+        let fits = encode_fits(&frame, &FitsHeader::from_frame(&frame, &camera_info));
+        let cards = header_cards(&fits);
+        assert!(cards[0].starts_with(&format!("SIMPLE  = {:>20} / ", "T")));
+        assert_eq!(cards[1].trim_end(),
+                   "BITPIX  =                   16 / Bits per pixel value");
+        assert!(cards.contains(&format!("{:<80}", "NAXIS1  =                   64 / Width")
+                               .as_str()));
+        assert!(cards.iter().any(|card| card.starts_with("BZERO   =                32768")));
+        assert!(cards.iter().any(|card| card.starts_with("BAYERPAT= 'RGGB    '")));
+
+        // The data starts at the block after END, and is padded to a block.
+        let data_start = (cards.len() * CARD_SIZE).div_ceil(BLOCK_SIZE) * BLOCK_SIZE;
+        assert_eq!(fits.len(), (data_start + frame.data.len()).div_ceil(BLOCK_SIZE) *
+                   BLOCK_SIZE);
+        assert!(fits[(cards.len() + 1) * CARD_SIZE..data_start].iter().all(|c| *c == b' '));
+        assert!(fits[data_start + frame.data.len()..].iter().all(|c| *c == 0));
+        // Big-endian signed values, plus BZERO.
+        let values: Vec<u16> = fits[data_start..data_start + frame.data.len()]
+            .chunks_exact(2)
+            .map(|pixel| (i16::from_be_bytes([pixel[0], pixel[1]]) as i32 + 32768) as u16)
+            .collect();
+        assert_eq!(values, frame.pixels_u16().unwrap());
+    }
+
+    #[test]
+    fn rgb24_planes() {
+        let (frame, camera_info) = simulated_frame(ImgType::Rgb24);
+        let fits = encode_fits(&frame, &FitsHeader::from_frame(&frame, &camera_info));
+        let cards = header_cards(&fits);
+        assert!(cards.iter().any(|card| card.starts_with("NAXIS3  =                    3")));
+        // No CFA for debayered frames.
+        assert!(!cards.iter().any(|card| card.starts_with("BAYERPAT")));
+        let data_start = (cards.len() * CARD_SIZE).div_ceil(BLOCK_SIZE) * BLOCK_SIZE;
+        let plane_size = frame.data.len() / 3;
+        // The first pixel's B, G, R bytes go to the R, G and B planes.
+        assert_eq!(fits[data_start], frame.data[2]);
+        assert_eq!(fits[data_start + plane_size], frame.data[1]);
+        assert_eq!(fits[data_start + 2 * plane_size], frame.data[0]);
+    }
+
+    #[test]
+    fn long_cards_truncated() {
+        let mut header = FitsHeader::new();
+        header.set("OBJECT", "x".repeat(67) + "'s", &"comment ".repeat(20)).unwrap();
+        header.set("TELESCOP", "Scope", &"comment ".repeat(20)).unwrap();
+        header.set("OBSERVER", "Anders Ångström", "Név").unwrap();
+        assert!(header.set("BITPIX", 8, "").is_err());
+        assert!(header.set("lower", 8, "").is_err());
+        let (frame, _camera_info) = simulated_frame(ImgType::Raw8);
+        let fits = encode_fits(&frame, &header);
+        assert!(fits.is_ascii());
+        let cards = header_cards(&fits);
+        let object = cards.iter().find(|card| card.starts_with("OBJECT")).unwrap();
+        // The doubled quote does not fit, and there is no room for the
+        // comment.
+        assert_eq!(*object, format!("{:<80}", format!("OBJECT  = '{}'", "x".repeat(67))));
+        let telescope = cards.iter().find(|card| card.starts_with("TELESCOP")).unwrap();
+        assert!(telescope.starts_with("TELESCOP= 'Scope   ' / comment comment"));
+        assert_eq!(telescope.trim_end().len(), CARD_SIZE);
+        let observer = cards.iter().find(|card| card.starts_with("OBSERVER")).unwrap();
+        assert_eq!(observer.trim_end(), "OBSERVER= 'Anders ?ngstr?m' / N?v");
+    }
+
+    #[test]
+    fn float_values() {
+        assert_eq!(format_value(&FitsValue::Float(2.0), false), "2.0");
+        assert_eq!(format_value(&FitsValue::Float(0.0), false), "0.0");
+        assert_eq!(format_value(&FitsValue::Float(1.5e-9), false), "1.5E-9");
+
+        let mut header = FitsHeader::new();
+        assert!(header.set("FOCUS", f64::NAN, "").is_err());
+        assert!(header.set("FOCUS", f64::INFINITY, "").is_err());
+        let (mut frame, camera_info) = simulated_frame(ImgType::Raw8);
+        frame.metadata.temperature = Some(-5.5);
+        let header = FitsHeader::from_frame(&frame, &camera_info);
+        assert_eq!(header.get("CCD-TEMP"), Some(&FitsValue::Float(-5.5)));
+        frame.metadata.temperature = Some(f64::NAN);
+        let header = FitsHeader::from_frame(&frame, &camera_info);
+        assert_eq!(header.get("CCD-TEMP"), None);
+    }
+
+    #[test]
+    fn dates() {
+        assert_eq!(format_date(UNIX_EPOCH), "1970-01-01T00:00:00.000");
+        // 2024-02-29T12:34:56.789
+        let time = UNIX_EPOCH + Duration::from_millis(1_709_210_096_789);
+        assert_eq!(format_date(time), "2024-02-29T12:34:56.789");
+    }
+}
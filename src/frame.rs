@@ -0,0 +1,98 @@
+// Copyright (c) 2023 Steven Rosenthal smr@dt3.org
+// See LICENSE file in root directory for license terms.
+
+use std::time::{Duration, Instant, SystemTime};
+
+use crate::types::ImgType;
+
+/// Returns the number of bytes needed to hold a `width` x `height` frame of the
+/// given image type.
+pub fn frame_size(width: i32, height: i32, img_type: ImgType) -> usize {
+    width as usize * height as usize * img_type.bytes_per_pixel()
+}
+
+/// The conditions a Frame was captured under, as recorded when it was read
+/// from the camera.
+///
+/// The camera settings are read back just after the frame, so in video mode a
+/// setting changed while the frame was being exposed is reported with its new
+/// value.
+#[derive(Clone, Debug)]
+pub struct FrameMetadata {
+    /// When the frame was read from the camera, i.e. shortly after the end of
+    /// its exposure. For frames not read from a camera, when the Frame was
+    /// created.
+    pub timestamp: Instant,
+    /// Wall clock time corresponding to `timestamp`.
+    pub system_time: SystemTime,
+
+    /// Counts the frames read from the camera, starting from 0 for the first
+    /// frame read after the camera instance was created. Frames skipped by the
+    /// SDK are not counted; see `dropped_frames`.
+    pub sequence_number: u64,
+
+    /// Position of the ROI on the sensor, in binned pixels.
+    pub start_x: i32,
+    pub start_y: i32,
+
+    pub exposure: Duration,
+    pub gain: i64,
+    pub offset: i64,
+    /// Sensor temperature in degrees Celsius, or None if the camera does not
+    /// report it.
+    pub temperature: Option<f64>,
+
+    /// The camera's count of dropped video frames (see
+    /// CameraBackend::get_dropped_frames()) when the frame was read.
+    pub dropped_frames: i32,
+}
+
+impl Default for FrameMetadata {
+    fn default() -> Self {
+        FrameMetadata{timestamp: Instant::now(),
+                      system_time: SystemTime::now(),
+                      sequence_number: 0,
+                      start_x: 0,
+                      start_y: 0,
+                      exposure: Duration::ZERO,
+                      gain: 0,
+                      offset: 0,
+                      temperature: None,
+                      dropped_frames: 0}
+    }
+}
+
+/// An image read from the camera, along with the ROI format and other
+/// conditions it was captured with.
+#[derive(Clone, Debug)]
+pub struct Frame {
+    pub width: i32,
+    pub height: i32,
+    pub bin: i32,
+    pub img_type: ImgType,
+    pub metadata: FrameMetadata,
+
+    /// The pixels as delivered by the SDK, row by row. Each pixel is one byte
+    /// for RAW8 and Y8, two little-endian bytes for RAW16, and three bytes in
+    /// B, G, R order for RGB24.
+    pub data: Vec<u8>,
+}
+
+impl Frame {
+    /// Returns a zero-filled frame of the given format, with default metadata.
+    pub fn new(width: i32, height: i32, bin: i32, img_type: ImgType) -> Self {
+        Frame{width, height, bin, img_type, metadata: FrameMetadata::default(),
+              data: vec![0; frame_size(width, height, img_type)]}
+    }
+
+    /// Returns the pixel values of a RAW16 frame, or None for other image
+    /// types.
+    pub fn pixels_u16(&self) -> Option<Vec<u16>> {
+        if self.img_type != ImgType::Raw16 {
+            return None;
+        }
+        Some(self.data.chunks_exact(2)
+             .map(|pixel| u16::from_le_bytes([pixel[0], pixel[1]]))
+             .collect())
+    }
+}
@@ -0,0 +1,944 @@
+// Copyright (c) 2023 Steven Rosenthal smr@dt3.org
+// See LICENSE file in root directory for license terms.
+
+use std::collections::HashMap;
+use std::fmt;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::mpsc::{self, SyncSender};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use log::{info, warn};
+use serde_json::{json, Map, Value};
+
+use crate::asi_camera2_sdk::{ASIError,
+                             ASI_ERROR_CODE_ASI_ERROR_INVALID_FILEFORMAT,
+                             ASI_ERROR_CODE_ASI_ERROR_INVALID_PATH};
+use crate::camera_backend::CameraBackend;
+use crate::frame::{self, FrameMetadata};
+use crate::types::{CameraInfo, CameraMode, ControlCaps, ControlType,
+                   ExposureStatus, GainOffsets, GuideDirection, ImgType, LmhGainOffsets,
+                   TriggerOutputConfig, TriggerOutputPin};
+use crate::lock_ignoring_poison;
+
+/// Name of the file describing the camera in a dump directory.
+pub const CAMERA_FILE: &str = "camera.json";
+
+/// The SDK call that returned a dumped frame.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum DumpCall {
+    GetVideoData,
+    GetDataAfterExp,
+}
+
+impl fmt::Display for DumpCall {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DumpCall::GetVideoData => write!(f, "get_video_data"),
+            DumpCall::GetDataAfterExp => write!(f, "get_data_after_exp"),
+        }
+    }
+}
+
+impl FromStr for DumpCall {
+    type Err = ASIError;
+
+    fn from_str(s: &str) -> Result<Self, ASIError> {
+        match s {
+            "get_video_data" => Ok(DumpCall::GetVideoData),
+            "get_data_after_exp" => Ok(DumpCall::GetDataAfterExp),
+            _ => Err(ASIError::new(ASI_ERROR_CODE_ASI_ERROR_INVALID_FILEFORMAT,
+                                   &format!("DumpCall::from_str({:?})", s))),
+        }
+    }
+}
+
+/// The camera that a dump directory was recorded from, as described by its
+/// camera.json file. Values the camera did not provide are None or empty.
+#[derive(Clone, Debug, PartialEq)]
+pub struct DumpedCamera {
+    pub camera_info: CameraInfo,
+    /// The caps of all of the camera's controls; see CameraBackend::controls().
+    pub controls: Vec<ControlCaps>,
+    pub serial_number: Option<String>,
+    pub alias: Option<String>,
+    pub camera_modes: Vec<CameraMode>,
+    pub gain_offsets: Option<GainOffsets>,
+    pub lmh_gain_offsets: Option<LmhGainOffsets>,
+}
+
+impl DumpedCamera {
+    /// Describes `camera`, which must be opened.
+    pub fn from_camera<B>(camera: &B) -> Result<Self, ASIError>
+    where B: CameraBackend + ?Sized
+    {
+        let mut controls: Vec<ControlCaps> = camera.controls()?.into_values().collect();
+        controls.sort_by_key(|caps| caps.name.clone());
+        Ok(DumpedCamera{
+            camera_info: camera.camera_info()?,
+            controls,
+            serial_number: camera.get_serial_number().ok(),
+            alias: camera.get_id().ok(),
+            camera_modes: camera.get_camera_support_mode().unwrap_or_default(),
+            gain_offsets: camera.get_gain_offset().ok(),
+            lmh_gain_offsets: camera.get_lmh_gain_offset().ok(),
+        })
+    }
+
+    /// Reads the camera.json file of the dump directory `dir`.
+    pub fn read(dir: &Path) -> Result<Self, ASIError> {
+        let path = dir.join(CAMERA_FILE);
+        let json = read_json(&path)?;
+        let fields = Field{json: &json, path: &path}.object()?;
+        let info = fields.object("camera_info")?;
+        let camera_info = CameraInfo{
+            name: info.string("name")?,
+            camera_id: info.i32("camera_id")?,
+            max_height: info.i32("max_height")?,
+            max_width: info.i32("max_width")?,
+            is_color_cam: info.bool("is_color_cam")?,
+            bayer_pattern: info.optional("bayer_pattern", |value| value.parse())?,
+            supported_bins: info.array("supported_bins")?.iter()
+                .map(|bin| bin.i64().map(|bin| bin as u32))
+                .collect::<Result<_, _>>()?,
+            supported_video_formats: info.array("supported_video_formats")?.iter()
+                .map(|img_type| img_type.parse())
+                .collect::<Result<_, _>>()?,
+            pixel_size: info.f64("pixel_size")?,
+            mechanical_shutter: info.bool("mechanical_shutter")?,
+            st4_port: info.bool("st4_port")?,
+            is_cooler_cam: info.bool("is_cooler_cam")?,
+            is_usb3_host: info.bool("is_usb3_host")?,
+            is_usb3_camera: info.bool("is_usb3_camera")?,
+            elec_per_adu: info.f64("elec_per_adu")? as f32,
+            bit_depth: info.i32("bit_depth")?,
+            is_trigger_cam: info.bool("is_trigger_cam")?,
+        };
+        let controls = fields.array("controls")?.iter().map(|caps| {
+            let caps = caps.object()?;
+            Ok(ControlCaps{
+                name: caps.string("name")?,
+                description: caps.string("description")?,
+                max_value: caps.i64("max_value")?,
+                min_value: caps.i64("min_value")?,
+                default_value: caps.i64("default_value")?,
+                is_auto_supported: caps.bool("is_auto_supported")?,
+                is_writable: caps.bool("is_writable")?,
+                control_type: caps.parse("control_type")?,
+            })
+        }).collect::<Result<_, ASIError>>()?;
+        Ok(DumpedCamera{
+            camera_info,
+            controls,
+            serial_number: fields.optional("serial_number", |value| value.string())?,
+            alias: fields.optional("alias", |value| value.string())?,
+            camera_modes: fields.array("camera_modes")?.iter()
+                .map(|mode| mode.parse())
+                .collect::<Result<_, _>>()?,
+            gain_offsets: fields.optional("gain_offsets", |value| {
+                let offsets = value.object()?;
+                Ok(GainOffsets{
+                    offset_highest_dr: offsets.i32("offset_highest_dr")?,
+                    offset_unity_gain: offsets.i32("offset_unity_gain")?,
+                    gain_lowest_read_noise: offsets.i32("gain_lowest_read_noise")?,
+                    offset_lowest_read_noise: offsets.i32("offset_lowest_read_noise")?,
+                })
+            })?,
+            lmh_gain_offsets: fields.optional("lmh_gain_offsets", |value| {
+                let lmh = value.object()?;
+                Ok(LmhGainOffsets{
+                    low_gain: lmh.i32("low_gain")?,
+                    medium_gain: lmh.i32("medium_gain")?,
+                    high_gain: lmh.i32("high_gain")?,
+                    high_offset: lmh.i32("high_offset")?,
+                })
+            })?,
+        })
+    }
+
+    /// Writes the camera.json file of the dump directory `dir`.
+    pub fn write(&self, dir: &Path) -> Result<(), ASIError> {
+        let info = &self.camera_info;
+        let json = json!({
+            "camera_info": {
+                "name": info.name,
+                "camera_id": info.camera_id,
+                "max_height": info.max_height,
+                "max_width": info.max_width,
+                "is_color_cam": info.is_color_cam,
+                "bayer_pattern": info.bayer_pattern.map(|pattern| pattern.to_string()),
+                "supported_bins": info.supported_bins,
+                "supported_video_formats": info.supported_video_formats.iter()
+                    .map(|img_type| img_type.to_string()).collect::<Vec<_>>(),
+                "pixel_size": info.pixel_size,
+                "mechanical_shutter": info.mechanical_shutter,
+                "st4_port": info.st4_port,
+                "is_cooler_cam": info.is_cooler_cam,
+                "is_usb3_host": info.is_usb3_host,
+                "is_usb3_camera": info.is_usb3_camera,
+                "elec_per_adu": info.elec_per_adu,
+                "bit_depth": info.bit_depth,
+                "is_trigger_cam": info.is_trigger_cam,
+            },
+            "controls": self.controls.iter().map(|caps| json!({
+                "name": caps.name,
+                "description": caps.description,
+                "max_value": caps.max_value,
+                "min_value": caps.min_value,
+                "default_value": caps.default_value,
+                "is_auto_supported": caps.is_auto_supported,
+                "is_writable": caps.is_writable,
+                "control_type": caps.control_type.to_string(),
+            })).collect::<Vec<_>>(),
+            "serial_number": self.serial_number,
+            "alias": self.alias,
+            "camera_modes": self.camera_modes.iter()
+                .map(|mode| mode.to_string()).collect::<Vec<_>>(),
+            "gain_offsets": self.gain_offsets.map(|offsets| json!({
+                "offset_highest_dr": offsets.offset_highest_dr,
+                "offset_unity_gain": offsets.offset_unity_gain,
+                "gain_lowest_read_noise": offsets.gain_lowest_read_noise,
+                "offset_lowest_read_noise": offsets.offset_lowest_read_noise,
+            })),
+            "lmh_gain_offsets": self.lmh_gain_offsets.map(|lmh| json!({
+                "low_gain": lmh.low_gain,
+                "medium_gain": lmh.medium_gain,
+                "high_gain": lmh.high_gain,
+                "high_offset": lmh.high_offset,
+            })),
+        });
+        write_json(&dir.join(CAMERA_FILE), &json)
+    }
+}
+
+/// One dumped frame, as described by its JSON sidecar file. The frame's bytes,
+/// exactly as the SDK call returned them, are in `data_file`.
+#[derive(Clone, Debug, PartialEq)]
+pub struct DumpedFrame {
+    /// Counts up from 0 for each frame recorded by a DumpRecorder.
+    pub index: u64,
+    pub call: DumpCall,
+    /// Relative to the dump directory; see data_file_name().
+    pub data_file: String,
+    pub width: i32,
+    pub height: i32,
+    pub bin: i32,
+    pub img_type: ImgType,
+    pub start_x: i32,
+    pub start_y: i32,
+    /// Value and whether automatic, for each control that could be read.
+    pub controls: HashMap<ControlType, (i64, bool)>,
+    pub dropped_frames: i32,
+    /// When the SDK call returned.
+    pub system_time: SystemTime,
+    /// When the SDK call returned, relative to when recording started.
+    pub elapsed: Duration,
+    /// How long the SDK call took.
+    pub call_duration: Duration,
+    /// The wait_ms argument of get_video_data().
+    pub wait_ms: Option<i32>,
+}
+
+impl DumpedFrame {
+    /// Returns the path of the sidecar file for frame `index` in the dump
+    /// directory `dir`.
+    pub fn sidecar_path(dir: &Path, index: u64) -> PathBuf {
+        dir.join(format!("frame_{:06}.json", index))
+    }
+
+    /// Returns the name of the data file for frame `index`, relative to the
+    /// dump directory.
+    pub fn data_file_name(index: u64) -> String {
+        format!("frame_{:06}.raw", index)
+    }
+
+    /// Returns the number of bytes of the frame's data.
+    pub fn frame_size(&self) -> usize {
+        frame::frame_size(self.width, self.height, self.img_type)
+    }
+
+    /// Returns the FrameMetadata recorded for the frame, with the given
+    /// timestamp and sequence number. Controls that were not recorded are
+    /// taken to be 0.
+    pub fn metadata(&self, timestamp: Instant, sequence_number: u64) -> FrameMetadata {
+        let control = |control_type| {
+            self.controls.get(&control_type).map_or(0, |(value, _auto)| *value)
+        };
+        FrameMetadata{timestamp, system_time: self.system_time, sequence_number,
+                      start_x: self.start_x, start_y: self.start_y,
+                      exposure: Duration::from_micros(
+                          control(ControlType::Exposure).max(0) as u64),
+                      gain: control(ControlType::Gain),
+                      offset: control(ControlType::Offset),
+                      temperature: self.controls.get(&ControlType::Temperature)
+                          .map(|(value, _auto)| *value as f64 / 10.0),
+                      dropped_frames: self.dropped_frames}
+    }
+
+    /// Reads the sidecar file `path`.
+    pub fn read(path: &Path) -> Result<Self, ASIError> {
+        let json = read_json(path)?;
+        let fields = Field{json: &json, path}.object()?;
+        let controls = fields.object("controls")?;
+        let controls = controls.map.iter().map(|(name, setting)| {
+            let setting = Field{json: setting, path}.object()?;
+            let control_type = name.parse()
+                .map_err(|_| invalid(path, &format!("control {:?}", name)))?;
+            Ok((control_type, (setting.i64("value")?, setting.bool("auto")?)))
+        }).collect::<Result<_, ASIError>>()?;
+        let index = fields.u64("index")?;
+        // Anything else could name a file outside the dump directory.
+        let data_file = fields.string("data_file")?;
+        if data_file != DumpedFrame::data_file_name(index) {
+            return Err(invalid(path, "data_file"));
+        }
+        Ok(DumpedFrame{
+            index,
+            call: fields.parse("call")?,
+            data_file,
+            width: fields.i32("width")?,
+            height: fields.i32("height")?,
+            bin: fields.i32("bin")?,
+            img_type: fields.parse("img_type")?,
+            start_x: fields.i32("start_x")?,
+            start_y: fields.i32("start_y")?,
+            controls,
+            dropped_frames: fields.i32("dropped_frames")?,
+            system_time: UNIX_EPOCH + Duration::from_micros(fields.u64("system_time_us")?),
+            elapsed: Duration::from_micros(fields.u64("elapsed_us")?),
+            call_duration: Duration::from_micros(fields.u64("call_duration_us")?),
+            wait_ms: fields.optional("wait_ms", |value| value.i32())?,
+        })
+    }
+
+    /// Writes the frame's sidecar file to the dump directory `dir`.
+    pub fn write(&self, dir: &Path) -> Result<(), ASIError> {
+        // Sorted, so that sidecars are easy to compare.
+        let mut controls: Vec<_> = self.controls.iter().collect();
+        controls.sort_by_key(|(control_type, _)| control_type.to_string());
+        let controls: Map<String, Value> = controls.into_iter()
+            .map(|(control_type, (value, auto))| {
+                (control_type.to_string(), json!({"value": value, "auto": auto}))
+            }).collect();
+        let system_time = self.system_time.duration_since(UNIX_EPOCH)
+            .unwrap_or_default();
+        let json = json!({
+            "index": self.index,
+            "call": self.call.to_string(),
+            "data_file": self.data_file,
+            "data_size": self.frame_size(),
+            "width": self.width,
+            "height": self.height,
+            "bin": self.bin,
+            "img_type": self.img_type.to_string(),
+            "start_x": self.start_x,
+            "start_y": self.start_y,
+            "controls": controls,
+            "dropped_frames": self.dropped_frames,
+            "system_time_us": system_time.as_micros() as u64,
+            "elapsed_us": self.elapsed.as_micros() as u64,
+            "call_duration_us": self.call_duration.as_micros() as u64,
+            "wait_ms": self.wait_ms,
+        });
+        write_json(&Self::sidecar_path(dir, self.index), &json)
+    }
+}
+
+/// Returns the sidecar paths of all of the frames in the dump directory `dir`,
+/// in the order they were recorded.
+pub fn dumped_frame_paths(dir: &Path) -> Result<Vec<PathBuf>, ASIError> {
+    let entries = fs::read_dir(dir).map_err(|e| {
+        warn!("Error reading {:?}: {}", dir, e);
+        ASIError::new(ASI_ERROR_CODE_ASI_ERROR_INVALID_PATH, "dumped_frame_paths")
+    })?;
+    let mut paths: Vec<PathBuf> = entries.filter_map(|entry| entry.ok())
+        .map(|entry| entry.path())
+        .filter(|path| is_frame_file(path, ".json"))
+        .collect();
+    // The zero padded indices sort in recording order.
+    paths.sort();
+    Ok(paths)
+}
+
+// Whether `path` is a frame_NNNNNN file with the given extension.
+fn is_frame_file(path: &Path, extension: &str) -> bool {
+    path.file_name().and_then(|name| name.to_str()).is_some_and(|name| {
+        name.starts_with("frame_") && name.ends_with(extension)
+    })
+}
+
+// Deletes the frames of a previous recording in `dir`, so that they do not get
+// replayed with the new one.
+fn remove_frame_files(dir: &Path) -> Result<(), ASIError> {
+    let io_error = |path: &Path, e: std::io::Error| {
+        warn!("Error removing old frames from {:?}: {}", path, e);
+        ASIError::new(ASI_ERROR_CODE_ASI_ERROR_INVALID_PATH, "DumpRecorder::new")
+    };
+    for entry in fs::read_dir(dir).map_err(|e| io_error(dir, e))? {
+        let path = entry.map_err(|e| io_error(dir, e))?.path();
+        if is_frame_file(&path, ".json") || is_frame_file(&path, ".raw") {
+            fs::remove_file(&path).map_err(|e| io_error(&path, e))?;
+        }
+    }
+    Ok(())
+}
+
+// How often DumpRecorder re-reads all control values, to pick up changes made
+// by the camera itself (auto controls, the sensor temperature).
+const CONTROL_REFRESH_INTERVAL: Duration = Duration::from_secs(1);
+
+// Frames queued for DumpRecorder's writer thread before reads wait for it.
+const WRITE_QUEUE_SIZE: usize = 16;
+
+/// Wraps a camera, dumping every frame read with get_video_data() or
+/// get_data_after_exp() (and so with get_video_frame(), capture_exposure(),
+/// etc.) to a directory, for debugging and for replay with
+/// replay_camera::ReplayCamera.
+///
+/// Each frame's bytes are written, exactly as the SDK returned them, to
+/// frame_NNNNNN.raw, and a JSON sidecar describing its ROI format, start
+/// position, control values and timing (see DumpedFrame) to
+/// frame_NNNNNN.json. The camera itself is described by camera.json (see
+/// DumpedCamera). All other calls are passed through to the camera.
+///
+/// To keep the cost per frame low, the files are written by a background
+/// thread, and the ROI format, start position and control values are those
+/// last set through the DumpRecorder, with all controls re-read at most once a
+/// second; only the dropped frame count is read from the camera for each
+/// frame. The read still copies the frame, and waits for the writer thread if
+/// the disk falls 16 frames behind. frame_metadata() reports the values in the
+/// frame's sidecar.
+///
+/// If a frame cannot be written, the next frame read fails with
+/// ASIErrorKind::InvalidPath (its data is still in the caller's buffer).
+pub struct DumpRecorder<B: CameraBackend> {
+    camera: B,
+    dir: PathBuf,
+    // The controls whose values are recorded for each frame.
+    control_types: Vec<ControlType>,
+    settings: Mutex<Settings>,
+    writer: DumpWriter,
+    start: Instant,
+    frames_dumped: AtomicU64,
+}
+
+// The camera settings recorded for each frame.
+struct Settings {
+    roi_format: (i32, i32, i32, ImgType),
+    start_pos: (i32, i32),
+    controls: HashMap<ControlType, (i64, bool)>,
+    controls_read: Instant,
+    // The sidecar of the last frame dumped, for frame_metadata().
+    last_frame: Option<DumpedFrame>,
+}
+
+impl<B: CameraBackend> DumpRecorder<B> {
+    /// Starts dumping the frames read from `camera`, which must be opened and
+    /// initialized, to the directory `dir`, creating it if needed and writing
+    /// its camera.json file. The frames of any previous recording in `dir` are
+    /// deleted.
+    pub fn new(camera: B, dir: &Path) -> Result<Self, ASIError> {
+        fs::create_dir_all(dir).map_err(|e| {
+            warn!("Error creating {:?}: {}", dir, e);
+            ASIError::new(ASI_ERROR_CODE_ASI_ERROR_INVALID_PATH, "DumpRecorder::new")
+        })?;
+        remove_frame_files(dir)?;
+        let dumped_camera = DumpedCamera::from_camera(&camera)?;
+        dumped_camera.write(dir)?;
+        info!("Dumping frames of camera id {} to {:?}", camera.camera_id(), dir);
+        let control_types: Vec<ControlType> =
+            dumped_camera.controls.iter().map(|caps| caps.control_type).collect();
+        let settings = Settings{roi_format: camera.get_roi_format()?,
+                                start_pos: camera.get_start_pos()?,
+                                controls: read_controls(&camera, &control_types),
+                                controls_read: Instant::now(),
+                                last_frame: None};
+        Ok(DumpRecorder{camera, dir: dir.to_path_buf(), control_types,
+                        settings: Mutex::new(settings), writer: DumpWriter::new(dir),
+                        start: Instant::now(), frames_dumped: AtomicU64::new(0)})
+    }
+
+    pub fn dir(&self) -> &Path { &self.dir }
+
+    /// The number of frames dumped so far, including any still being written.
+    pub fn frames_dumped(&self) -> u64 {
+        self.frames_dumped.load(Ordering::Relaxed)
+    }
+
+    /// Waits for the frames being written, then returns the camera, which keeps
+    /// its current state.
+    pub fn into_inner(self) -> B {
+        drop(self.writer);
+        self.camera
+    }
+
+    fn lock_settings(&self) -> MutexGuard<'_, Settings> {
+        lock_ignoring_poison(&self.settings)
+    }
+
+    // Re-reads all of the settings, e.g. after init() has reset them.
+    fn read_settings(&self) -> Result<(), ASIError> {
+        let roi_format = self.camera.get_roi_format()?;
+        let start_pos = self.camera.get_start_pos()?;
+        let controls = read_controls(&self.camera, &self.control_types);
+        let mut settings = self.lock_settings();
+        settings.roi_format = roi_format;
+        settings.start_pos = start_pos;
+        settings.controls = controls;
+        settings.controls_read = Instant::now();
+        Ok(())
+    }
+
+    // Dumps the frame that `call` has just read into `buffer`, of `buff_size`
+    // bytes. The call started at `call_start`.
+    fn dump(&self, call: DumpCall, wait_ms: Option<i32>, buffer: *const u8, buff_size: i64,
+            call_start: Instant) -> Result<(), ASIError> {
+        let now = Instant::now();
+        let system_time = SystemTime::now();
+        let dropped_frames = self.camera.get_dropped_frames().unwrap_or_else(|e| {
+            warn!("Error reading dropped frames: {}", e);
+            0
+        });
+        let mut settings = self.lock_settings();
+        if now - settings.controls_read >= CONTROL_REFRESH_INTERVAL {
+            settings.controls = read_controls(&self.camera, &self.control_types);
+            settings.controls_read = now;
+        }
+        let (width, height, bin, img_type) = settings.roi_format;
+        let (start_x, start_y) = settings.start_pos;
+        let index = self.frames_dumped.fetch_add(1, Ordering::Relaxed);
+        let frame = DumpedFrame{
+            index, call, data_file: DumpedFrame::data_file_name(index),
+            width, height, bin, img_type, start_x, start_y,
+            controls: settings.controls.clone(), dropped_frames,
+            system_time, elapsed: now - self.start, call_duration: now - call_start,
+            wait_ms,
+        };
+        settings.last_frame = Some(frame.clone());
+        drop(settings);
+        // The call succeeded, so the buffer holds a whole frame.
+        let size = frame.frame_size().min(buff_size.max(0) as usize);
+        let data = unsafe { std::slice::from_raw_parts(buffer, size) }.to_vec();
+        self.writer.write(frame, data)
+    }
+}
+
+// Writes dumped frames on a background thread.
+struct DumpWriter {
+    frames: Option<SyncSender<(DumpedFrame, Vec<u8>)>>,
+    thread: Option<JoinHandle<()>>,
+    // The first error writing a frame not yet reported by write().
+    error: Arc<Mutex<Option<ASIError>>>,
+}
+
+impl DumpWriter {
+    fn new(dir: &Path) -> Self {
+        let (frames, receiver) = mpsc::sync_channel::<(DumpedFrame, Vec<u8>)>(WRITE_QUEUE_SIZE);
+        let error = Arc::new(Mutex::new(None));
+        let thread_error = error.clone();
+        let dir = dir.to_path_buf();
+        let thread = thread::spawn(move || {
+            for (frame, data) in receiver {
+                if let Err(e) = write_frame(&dir, &frame, &data) {
+                    lock_ignoring_poison(&thread_error)
+                        .get_or_insert(e);
+                }
+            }
+        });
+        DumpWriter{frames: Some(frames), thread: Some(thread), error}
+    }
+
+    // Queues `frame` to be written, first returning any error writing earlier
+    // frames.
+    fn write(&self, frame: DumpedFrame, data: Vec<u8>) -> Result<(), ASIError> {
+        if let Some(e) = self.take_error() {
+            return Err(e);
+        }
+        // The thread only ends once `frames` is dropped.
+        let _ = self.frames.as_ref().unwrap().send((frame, data));
+        Ok(())
+    }
+
+    fn take_error(&self) -> Option<ASIError> {
+        lock_ignoring_poison(&self.error).take()
+    }
+}
+
+impl Drop for DumpWriter {
+    // Waits for the queued frames to be written.
+    fn drop(&mut self) {
+        self.frames.take();
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+        if let Some(e) = self.take_error() {
+            warn!("Frames were not dumped: {}", e);
+        }
+    }
+}
+
+fn write_frame(dir: &Path, frame: &DumpedFrame, data: &[u8]) -> Result<(), ASIError> {
+    let path = dir.join(&frame.data_file);
+    fs::write(&path, data).map_err(|e| {
+        warn!("Error writing {:?}: {}", path, e);
+        ASIError::new(ASI_ERROR_CODE_ASI_ERROR_INVALID_PATH, "DumpRecorder")
+    })?;
+    frame.write(dir)
+}
+
+// Returns the values of those of `control_types` that can be read.
+fn read_controls<B>(camera: &B, control_types: &[ControlType])
+                    -> HashMap<ControlType, (i64, bool)>
+where B: CameraBackend + ?Sized
+{
+    control_types.iter()
+        .filter_map(|control_type| {
+            camera.get_control_value(*control_type).ok()
+                .map(|setting| (*control_type, setting))
+        }).collect()
+}
+
+impl<B: CameraBackend> CameraBackend for DumpRecorder<B> {
+    fn camera_id(&self) -> i32 { self.camera.camera_id() }
+    fn camera_info(&self) -> Result<CameraInfo, ASIError> { self.camera.camera_info() }
+
+    fn open(&mut self) -> Result<(), ASIError> { self.camera.open() }
+    fn init(&self) -> Result<(), ASIError> {
+        self.camera.init()?;
+        self.read_settings()
+    }
+    fn close(&mut self) -> Result<(), ASIError> { self.camera.close() }
+
+    fn get_num_controls(&self) -> Result<i32, ASIError> { self.camera.get_num_controls() }
+    fn get_control_caps(&self, control_index: i32)
+                        -> Result<ControlCaps, ASIError> {
+        self.camera.get_control_caps(control_index)
+    }
+    fn get_control_value(&self, control_type: ControlType)
+                         -> Result<(i64, bool), ASIError> {
+        self.camera.get_control_value(control_type)
+    }
+    fn set_control_value(&mut self, control_type: ControlType,
+                         value: i64, auto: bool) -> Result<(), ASIError> {
+        self.camera.set_control_value(control_type, value, auto)?;
+        // The camera may have clamped the value.
+        if let Ok(setting) = self.camera.get_control_value(control_type) {
+            self.lock_settings().controls.insert(control_type, setting);
+        }
+        Ok(())
+    }
+
+    fn get_roi_format(&self) -> Result<(i32, i32, i32, ImgType), ASIError> {
+        self.camera.get_roi_format()
+    }
+    fn set_roi_format(&mut self, width: i32, height: i32,
+                      bin: i32, img_type: ImgType) -> Result<(), ASIError> {
+        self.camera.set_roi_format(width, height, bin, img_type)?;
+        // Setting the ROI format re-centers the ROI.
+        let start_pos = self.camera.get_start_pos()?;
+        let mut settings = self.lock_settings();
+        settings.roi_format = (width, height, bin, img_type);
+        settings.start_pos = start_pos;
+        Ok(())
+    }
+
+    fn get_start_pos(&self) -> Result<(i32, i32), ASIError> { self.camera.get_start_pos() }
+    fn set_start_pos(&mut self, start_x: i32, start_y: i32)
+                     -> Result<(), ASIError> {
+        self.camera.set_start_pos(start_x, start_y)?;
+        self.lock_settings().start_pos = (start_x, start_y);
+        Ok(())
+    }
+
+    fn get_dropped_frames(&self) -> Result<i32, ASIError> {
+        self.camera.get_dropped_frames()
+    }
+    fn next_sequence_number(&self) -> u64 { self.camera.next_sequence_number() }
+
+    fn enable_dark_subtract(&mut self, bmp_path: &Path) -> Result<(), ASIError> {
+        self.camera.enable_dark_subtract(bmp_path)
+    }
+    fn disable_dark_subtract(&mut self) -> Result<(), ASIError> {
+        self.camera.disable_dark_subtract()
+    }
+
+    fn start_video_capture(&mut self) -> Result<(), ASIError> {
+        self.camera.start_video_capture()
+    }
+    fn stop_video_capture(&mut self) -> Result<(), ASIError> {
+        self.camera.stop_video_capture()
+    }
+    unsafe fn get_video_data(&self, buffer: *mut u8, buff_size: i64, wait_ms: i32)
+                             -> Result<(), ASIError> {
+        let call_start = Instant::now();
+        self.camera.get_video_data(buffer, buff_size, wait_ms)?;
+        self.dump(DumpCall::GetVideoData, Some(wait_ms), buffer, buff_size, call_start)
+    }
+
+    fn pulse_guide_on(&self, direction: GuideDirection) -> Result<(), ASIError> {
+        self.camera.pulse_guide_on(direction)
+    }
+    fn pulse_guide_off(&self, direction: GuideDirection) -> Result<(), ASIError> {
+        self.camera.pulse_guide_off(direction)
+    }
+    fn pulse_guide(&self, direction: GuideDirection, duration: Duration)
+                   -> Result<(), ASIError> {
+        self.camera.pulse_guide(direction, duration)
+    }
+
+    fn start_exposure(&mut self, is_dark: bool) -> Result<(), ASIError> {
+        self.camera.start_exposure(is_dark)
+    }
+    fn stop_exposure(&mut self) -> Result<(), ASIError> { self.camera.stop_exposure() }
+    fn get_exp_status(&self) -> Result<ExposureStatus, ASIError> {
+        self.camera.get_exp_status()
+    }
+    unsafe fn get_data_after_exp(&self, buffer: *mut u8, buff_size: i64)
+                                 -> Result<(), ASIError> {
+        let call_start = Instant::now();
+        self.camera.get_data_after_exp(buffer, buff_size)?;
+        self.dump(DumpCall::GetDataAfterExp, None, buffer, buff_size, call_start)
+    }
+
+    fn get_gain_offset(&self) -> Result<GainOffsets, ASIError> {
+        self.camera.get_gain_offset()
+    }
+    fn get_lmh_gain_offset(&self) -> Result<LmhGainOffsets, ASIError> {
+        self.camera.get_lmh_gain_offset()
+    }
+
+    fn get_id(&self) -> Result<String, ASIError> { self.camera.get_id() }
+    fn set_id(&mut self, alias: &str) -> Result<(), ASIError> { self.camera.set_id(alias) }
+    fn get_serial_number(&self) -> Result<String, ASIError> {
+        self.camera.get_serial_number()
+    }
+
+    fn get_camera_support_mode(&self) -> Result<Vec<CameraMode>, ASIError> {
+        self.camera.get_camera_support_mode()
+    }
+    fn get_camera_mode(&self) -> Result<CameraMode, ASIError> {
+        self.camera.get_camera_mode()
+    }
+    fn set_camera_mode(&mut self, mode: CameraMode) -> Result<(), ASIError> {
+        self.camera.set_camera_mode(mode)
+    }
+    fn send_soft_trigger(&self, start: bool) -> Result<(), ASIError> {
+        self.camera.send_soft_trigger(start)
+    }
+    fn get_trigger_output_config(&self, pin: TriggerOutputPin)
+                                 -> Result<TriggerOutputConfig, ASIError> {
+        self.camera.get_trigger_output_config(pin)
+    }
+    fn set_trigger_output_config(&mut self, pin: TriggerOutputPin,
+                                 config: &TriggerOutputConfig) -> Result<(), ASIError> {
+        self.camera.set_trigger_output_config(pin, config)
+    }
+
+    /// Returns the metadata recorded in the sidecar of the frame just read;
+    /// see DumpedFrame::metadata().
+    fn frame_metadata(&self) -> Result<FrameMetadata, ASIError> {
+        let settings = self.lock_settings();
+        match &settings.last_frame {
+            Some(frame) => Ok(frame.metadata(self.start + frame.elapsed,
+                                             self.next_sequence_number())),
+            None => self.camera.frame_metadata(),
+        }
+    }
+}
+
+fn read_json(path: &Path) -> Result<Value, ASIError> {
+    let text = fs::read_to_string(path).map_err(|e| {
+        warn!("Error reading {:?}: {}", path, e);
+        ASIError::new(ASI_ERROR_CODE_ASI_ERROR_INVALID_PATH, "frame_dump")
+    })?;
+    serde_json::from_str(&text).map_err(|e| {
+        warn!("Error parsing {:?}: {}", path, e);
+        ASIError::new(ASI_ERROR_CODE_ASI_ERROR_INVALID_FILEFORMAT, "frame_dump")
+    })
+}
+
+fn write_json(path: &Path, json: &Value) -> Result<(), ASIError> {
+    // Serializing a Value cannot fail.
+    let text = serde_json::to_string_pretty(json).unwrap();
+    fs::write(path, text + "\n").map_err(|e| {
+        warn!("Error writing {:?}: {}", path, e);
+        ASIError::new(ASI_ERROR_CODE_ASI_ERROR_INVALID_PATH, "frame_dump")
+    })
+}
+
+// A JSON value read from `path`, with accessors that report a missing or
+// mistyped value as an InvalidFileFormat error.
+#[derive(Clone, Copy)]
+struct Field<'a> {
+    json: &'a Value,
+    path: &'a Path,
+}
+
+impl<'a> Field<'a> {
+    fn invalid(&self, what: &str) -> ASIError {
+        invalid(self.path, &format!("{} {}", what, self.json))
+    }
+
+    fn object(&self) -> Result<Object<'a>, ASIError> {
+        let map = self.json.as_object().ok_or_else(|| self.invalid("object"))?;
+        Ok(Object{map, path: self.path})
+    }
+
+    fn i64(&self) -> Result<i64, ASIError> {
+        self.json.as_i64().ok_or_else(|| self.invalid("integer"))
+    }
+
+    fn i32(&self) -> Result<i32, ASIError> {
+        i32::try_from(self.i64()?).map_err(|_| self.invalid("integer"))
+    }
+
+    fn string(&self) -> Result<String, ASIError> {
+        self.json.as_str().map(str::to_string).ok_or_else(|| self.invalid("string"))
+    }
+
+    fn parse<T: FromStr>(&self) -> Result<T, ASIError> {
+        self.string()?.parse().map_err(|_| self.invalid("value"))
+    }
+}
+
+// A JSON object read from `path`, with accessors for its fields.
+struct Object<'a> {
+    map: &'a Map<String, Value>,
+    path: &'a Path,
+}
+
+impl<'a> Object<'a> {
+    fn get(&self, key: &str) -> Result<Field<'a>, ASIError> {
+        self.map.get(key).map(|json| Field{json, path: self.path})
+            .ok_or_else(|| invalid(self.path, &format!("missing {:?}", key)))
+    }
+
+    // Missing and null fields are None.
+    fn optional<T, F>(&self, key: &str, f: F) -> Result<Option<T>, ASIError>
+    where F: FnOnce(Field<'a>) -> Result<T, ASIError>
+    {
+        match self.map.get(key) {
+            None | Some(Value::Null) => Ok(None),
+            Some(json) => f(Field{json, path: self.path}).map(Some),
+        }
+    }
+
+    fn array(&self, key: &str) -> Result<Vec<Field<'a>>, ASIError> {
+        let field = self.get(key)?;
+        let array = field.json.as_array().ok_or_else(|| field.invalid("array"))?;
+        Ok(array.iter().map(|json| Field{json, path: self.path}).collect())
+    }
+
+    fn object(&self, key: &str) -> Result<Object<'a>, ASIError> { self.get(key)?.object() }
+    fn string(&self, key: &str) -> Result<String, ASIError> { self.get(key)?.string() }
+    fn i64(&self, key: &str) -> Result<i64, ASIError> { self.get(key)?.i64() }
+    fn i32(&self, key: &str) -> Result<i32, ASIError> { self.get(key)?.i32() }
+    fn parse<T: FromStr>(&self, key: &str) -> Result<T, ASIError> { self.get(key)?.parse() }
+
+    fn u64(&self, key: &str) -> Result<u64, ASIError> {
+        let field = self.get(key)?;
+        field.json.as_u64().ok_or_else(|| field.invalid("integer"))
+    }
+
+    fn f64(&self, key: &str) -> Result<f64, ASIError> {
+        let field = self.get(key)?;
+        field.json.as_f64().ok_or_else(|| field.invalid("number"))
+    }
+
+    fn bool(&self, key: &str) -> Result<bool, ASIError> {
+        let field = self.get(key)?;
+        field.json.as_bool().ok_or_else(|| field.invalid("boolean"))
+    }
+}
+
+fn invalid(path: &Path, what: &str) -> ASIError {
+    warn!("Error parsing {:?}: invalid {}", path, what);
+    ASIError::new(ASI_ERROR_CODE_ASI_ERROR_INVALID_FILEFORMAT, "frame_dump")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::asi_camera2_sdk::ASIErrorKind;
+    use crate::frame::Frame;
+    use crate::replay_camera::ReplayCamera;
+    use crate::simulated_camera::{SimulatedCamera, SimulatorConfig};
+    use crate::simulated_camera::test_support::temp_path;
+
+    fn micros(time: SystemTime) -> u128 {
+        time.duration_since(UNIX_EPOCH).unwrap().as_micros()
+    }
+
+    #[test]
+    fn replay_round_trip() {
+        let dir = temp_path("replay_round_trip");
+        let mut camera = SimulatedCamera::new(SimulatorConfig::default());
+        camera.open().unwrap();
+        camera.init().unwrap();
+        let mut recorder = DumpRecorder::new(camera, &dir).unwrap();
+        recorder.set_roi_format(64, 32, 2, ImgType::Raw16).unwrap();
+        recorder.set_start_pos(8, 4).unwrap();
+        recorder.set_control_value(ControlType::Exposure, 1000, false).unwrap();
+        // Clamped to the control's range.
+        recorder.set_control_value(ControlType::Gain, 1000, false).unwrap();
+        recorder.start_video_capture().unwrap();
+        let mut recorded: Vec<Frame> =
+            (0..3).map(|_| recorder.get_video_frame(1000).unwrap()).collect();
+        recorder.stop_video_capture().unwrap();
+        recorder.set_roi_format(64, 32, 1, ImgType::Raw8).unwrap();
+        recorded.push(recorder.capture_exposure(
+            Duration::from_millis(1), false, None).unwrap());
+        assert_eq!(recorder.frames_dumped(), 4);
+        let camera = recorder.into_inner();
+        assert_eq!(recorded[0].metadata.gain, 510);
+        assert_eq!(recorded[0].metadata.start_x, 8);
+        assert_eq!(recorded[3].metadata.start_x, (1280 - 64) / 2);
+
+        let mut replay = ReplayCamera::new(&dir).unwrap();
+        assert_eq!(replay.dumped_camera().camera_info.name, camera.camera_info().name);
+        assert_eq!(replay.frames().len(), 4);
+        assert_eq!(replay.frames()[3].call, DumpCall::GetDataAfterExp);
+        replay.open().unwrap();
+        replay.set_roi_format(64, 32, 2, ImgType::Raw16).unwrap();
+        replay.start_video_capture().unwrap();
+        let mut replayed: Vec<Frame> =
+            (0..3).map(|_| replay.get_video_frame(1000).unwrap()).collect();
+        replay.stop_video_capture().unwrap();
+        replay.set_roi_format(64, 32, 1, ImgType::Raw8).unwrap();
+        replayed.push(replay.capture_exposure(
+            Duration::from_millis(1), false, None).unwrap());
+        assert_eq!(replay.frames_remaining(), 0);
+
+        for (recorded, replayed) in recorded.iter().zip(&replayed) {
+            assert_eq!((replayed.width, replayed.height, replayed.bin, replayed.img_type),
+                       (recorded.width, recorded.height, recorded.bin, recorded.img_type));
+            assert_eq!(replayed.data, recorded.data);
+            let (recorded, replayed) = (&recorded.metadata, &replayed.metadata);
+            assert_eq!(micros(replayed.system_time), micros(recorded.system_time));
+            assert_eq!((replayed.start_x, replayed.start_y),
+                       (recorded.start_x, recorded.start_y));
+            assert_eq!((replayed.exposure, replayed.gain, replayed.offset),
+                       (recorded.exposure, recorded.gain, recorded.offset));
+            assert_eq!(replayed.temperature, recorded.temperature);
+            assert_eq!(replayed.dropped_frames, recorded.dropped_frames);
+        }
+
+        // Sidecars cannot point outside the dump.
+        let sidecar = DumpedFrame::sidecar_path(&dir, 0);
+        let json = fs::read_to_string(&sidecar).unwrap();
+        fs::write(&sidecar, json.replace("frame_000000.raw", "../../x")).unwrap();
+        assert_eq!(DumpedFrame::read(&sidecar).unwrap_err().kind(),
+                   ASIErrorKind::InvalidFileFormat);
+        assert_eq!(ReplayCamera::new(&dir).err().map(|e| e.kind()),
+                   Some(ASIErrorKind::InvalidFileFormat));
+
+        // A new recording replaces the frames of the old one.
+        drop(DumpRecorder::new(camera, &dir).unwrap());
+        assert!(dumped_frame_paths(&dir).unwrap().is_empty());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -7,26 +7,157 @@
 
 mod usb_reset;
 
+#[cfg(not(feature = "sdk"))]
+mod sdk_types;
+
+#[cfg(feature = "dynamic")]
+mod dynamic_sdk;
+
+/// The async_camera module provides AsyncCamera, which runs a camera on a
+/// worker thread for use from async code.
+#[cfg(feature = "async")]
+pub mod async_camera;
+
+/// The CameraBackend trait abstracts over the operations of an ASI camera, so
+/// that code can run against either a real camera or a simulated one.
+pub mod camera_backend;
+
+/// The dark_frame module captures median-combined dark frames and writes them
+/// as BMP files for the SDK's dark subtraction.
+pub mod dark_frame;
+
+/// The exposure module provides capture_exposure(), which takes a single
+/// exposure with timeout, retry and cancellation.
+pub mod exposure;
+
+/// The export module converts frames to images and writes them losslessly as
+/// PNG or TIFF files, with capture metadata embedded.
+pub mod export;
+
+/// The fits module writes frames as FITS files, with headers describing how
+/// they were acquired.
+pub mod fits;
+
+/// The frame module provides Frame, an owned image read from a camera, and
+/// helpers for sizing frame buffers.
+pub mod frame;
+
+/// The frame_dump module provides DumpRecorder, which dumps the raw frames read
+/// from a camera, with JSON sidecars describing how they were captured.
+pub mod frame_dump;
+
+/// The types module provides Rust enums for the SDK's image type, control
+/// type, bayer pattern, flip, exposure status, camera mode and trigger output
+/// values, owned forms of its camera info and control caps structs, and gain
+/// presets.
+pub mod types;
+
+/// The pulse_guide module provides PulseGuider, which times ST4 guide pulses on
+/// a background thread.
+pub mod pulse_guide;
+
+/// The replay_camera module provides ReplayCamera, a CameraBackend that replays
+/// frames dumped by frame_dump::DumpRecorder.
+pub mod replay_camera;
+
+/// The ser module records video frames to SER files, as used by planetary
+/// imaging software, and reads them back.
+pub mod ser;
+
+/// The session module provides Camera, an opened and initialized camera, and
+/// VideoSession, which stops video capture when dropped. These make it
+/// impossible to mix up the order of SDK calls, e.g. starting an exposure
+/// while video capture is running.
+pub mod session;
+
+/// The supervisor module provides SupervisedCamera, which recovers a camera
+/// from USB failures by resetting and re-opening it.
+pub mod supervisor;
+
+/// The trigger module provides TriggeredCapture, for capturing frames from
+/// trigger cameras in their soft or hardware trigger modes.
+pub mod trigger;
+
+/// The video_stream module provides VideoStream, which runs video capture on a
+/// worker thread and delivers frames through a bounded queue.
+pub mod video_stream;
+
+/// The xisf module writes frames as XISF files, PixInsight's native format,
+/// with their acquisition metadata.
+pub mod xisf;
+
+/// The simulated_camera module provides SimulatedCamera, a CameraBackend that
+/// renders synthetic star field frames without any camera hardware.
+pub mod simulated_camera;
+
+// Locks `mutex`, even if a thread panicked while holding it. The crate's shared
+// state is only changed in steps that each leave it consistent, so a panic
+// elsewhere is no reason to fail here too.
+pub(crate) fn lock_ignoring_poison<T>(mutex: &std::sync::Mutex<T>)
+                                      -> std::sync::MutexGuard<'_, T> {
+    mutex.lock().unwrap_or_else(|e| e.into_inner())
+}
+
 /// The asi_camera2_sdk module provides a thin wrapper of the ASI Camera2 SDK.
 /// Aside from making the ASI camera SDK callable from Rust, the only value adds
 /// are:
 /// * ASIError type (instead of a raw integer code)
-/// * Drop trait s.t. when an ASICamera instance goes out of scope it closes
-///   the camera.
+/// * Rust enums (see the types module) instead of the SDK's raw integer enum
+///   values.
+/// * Drop trait s.t. when an ASICamera instance goes out of scope it stops any
+///   video capture or exposure in progress and closes the camera.
 /// * Logic to Reset USB device on error.
+///
+/// ASICamera is only available with the `sdk` feature. Without it, this module
+/// still provides the SDK's type definitions and ASIError.
 pub mod asi_camera2_sdk {
     use std::fmt;
     use std::error::Error;
+    #[cfg(feature = "sdk")]
+    use std::ffi::{CStr, CString};
+    #[cfg(feature = "sdk")]
     use std::mem::MaybeUninit;
+    #[cfg(feature = "sdk")]
+    use std::os::unix::ffi::OsStrExt;
+    #[cfg(feature = "sdk")]
+    use std::path::Path;
+    #[cfg(feature = "sdk")]
+    use std::time::Duration;
+    #[cfg(feature = "sdk")]
+    use std::os::raw::c_long;
+    #[cfg(feature = "sdk")]
+    use std::sync::atomic::{AtomicU64, Ordering};
 
-    use log::{info, warn};
+    use log::warn;
+    #[cfg(feature = "sdk")]
+    use log::info;
+    #[cfg(feature = "sdk")]
+    use std::os::raw::{c_char, c_int};
+    #[cfg(feature = "sdk")]
+    use crate::camera_backend::{self, CameraBackend};
+    #[cfg(feature = "sdk")]
+    use crate::pulse_guide::PulseGuider;
+    #[cfg(feature = "sdk")]
+    use crate::types::{CameraInfo, CameraMode, ControlCaps, ControlType, ExposureStatus,
+                       GainOffsets, GuideDirection, ImgType, LmhGainOffsets, SdkVersion,
+                       TriggerOutputConfig, TriggerOutputPin,
+                       asi_id_from_str, asi_id_to_string, serial_number_to_hex};
     use crate::usb_reset;
 
+    #[cfg(feature = "sdk")]
     include!(concat!(env!("OUT_DIR"), "/asi_sdk_bindings.rs"));
+    #[cfg(not(feature = "sdk"))]
+    pub use crate::sdk_types::*;
+    // With the `dynamic` feature, the SDK functions called by ASICamera are
+    // resolved from libASICamera2 at runtime rather than linked.
+    #[cfg(feature = "dynamic")]
+    pub use crate::dynamic_sdk::*;
+
+    /// USB vendor ID of ZWO devices.
+    pub const ZWO_VENDOR_ID: u16 = 0x03c3;
 
     // Resets all ASI devices connected to USB.
     pub fn reset_asi_cameras() {
-        let ZWO_VENDOR_ID = 0x03c3;
         if let Err(e) = usb_reset::reset_usb_device(
             ZWO_VENDOR_ID, /*product_id=*/None)
         {
@@ -34,20 +165,60 @@ pub mod asi_camera2_sdk {
         }
     }
 
+    #[cfg(feature = "sdk")]
     #[derive(Debug)]
     pub struct ASICamera {
         camera_id: i32,
         opened: bool,
+        // Whether start_video_capture() has been called without a matching
+        // stop_video_capture().
+        video_active: bool,
+        pulse_guider: PulseGuider,
+        // Frames read so far; see CameraBackend::next_sequence_number().
+        frames_read: AtomicU64,
     }
 
+    #[cfg(feature = "sdk")]
     impl ASICamera {
         /// Returns the number of connected ASI cameras.
         pub fn num_connected_asi_cameras() -> i32 {
             unsafe { ASIGetNumOfConnectedCameras() }
         }
 
+        /// Returns the version of the SDK library, or an error if it cannot be
+        /// parsed.
+        pub fn get_sdk_version() -> Result<SdkVersion, ASIError> {
+            let version = unsafe { ASIGetSDKVersion() };
+            if version.is_null() {
+                // Only with the `dynamic` feature, when the library is missing.
+                return Err(ASIError{error_code: ASI_ERROR_LIBRARY_UNAVAILABLE,
+                                    source: "get_sdk_version".to_string()});
+            }
+            let version = unsafe { CStr::from_ptr(version) }.to_string_lossy();
+            version.parse().map_err(|e: ASIError| {
+                ASIError{error_code: e.error_code,
+                         source: format!("get_sdk_version: {}", e.source)}
+            })
+        }
+
+        /// Returns the USB product IDs of the camera models the SDK supports.
+        /// The SDK has deprecated this in favor of is_supported_usb_device().
+        pub fn get_product_ids() -> Vec<u16> {
+            let num_product_ids = unsafe { ASIGetProductIDs(std::ptr::null_mut()) };
+            let mut product_ids: Vec<c_int> = vec![0; num_product_ids.max(0) as usize];
+            let num_product_ids = unsafe { ASIGetProductIDs(product_ids.as_mut_ptr()) };
+            product_ids.truncate(num_product_ids.max(0) as usize);
+            product_ids.into_iter().map(|product_id| product_id as u16).collect()
+        }
+
+        /// Returns whether the USB device with the given IDs is an ASI camera
+        /// supported by the SDK.
+        pub fn is_supported_usb_device(vendor_id: u16, product_id: u16) -> bool {
+            unsafe { ASICameraCheck(vendor_id as c_int, product_id as c_int) != 0 }
+        }
+
         /// Get description for given camera index.
-        pub fn get_property(camera_index: i32) -> Result<ASI_CAMERA_INFO, ASIError> {
+        pub fn get_property(camera_index: i32) -> Result<CameraInfo, ASIError> {
             let mut uninit_camera_info: MaybeUninit<ASI_CAMERA_INFO> =
                 MaybeUninit::zeroed();
             let error_code = unsafe { ASIGetCameraProperty(
@@ -56,20 +227,80 @@ pub mod asi_camera2_sdk {
             if error_code != 0 {
                 Err(ASIError{error_code, source: "get_property".to_string()})
             } else {
-                Ok(unsafe{ uninit_camera_info.assume_init() })
+                CameraInfo::try_from(unsafe{ &uninit_camera_info.assume_init() })
             }
         }
 
+        /// Like get_property(), but for the camera with the given `camera_id`
+        /// rather than index. Camera ids stay the same as other cameras are
+        /// connected and disconnected, whereas indices can change.
+        pub fn get_property_by_id(camera_id: i32) -> Result<CameraInfo, ASIError> {
+            let mut uninit_camera_info: MaybeUninit<ASI_CAMERA_INFO> =
+                MaybeUninit::zeroed();
+            let error_code = unsafe { ASIGetCameraPropertyByID(
+                camera_id, &mut *uninit_camera_info.as_mut_ptr())
+            };
+            if error_code != 0 {
+                Err(ASIError{error_code, source: "get_property_by_id".to_string()})
+            } else {
+                CameraInfo::try_from(unsafe{ &uninit_camera_info.assume_init() })
+            }
+        }
+
+        /// Returns the description of the connected camera whose serial number
+        /// (see get_serial_number(); compared case-insensitively) is
+        /// `serial_number`, or None if there is no such camera.
+        ///
+        /// Each camera is opened to be queried and then closed. The SDK reads
+        /// serial numbers only from open cameras, so cameras this process
+        /// already has open are skipped rather than disturbed, as are cameras
+        /// that cannot be opened (e.g. because another process is using them).
+        pub fn find_camera_by_serial(serial_number: &str)
+                                     -> Result<Option<CameraInfo>, ASIError> {
+            let serial_number = serial_number.trim();
+            Self::find_camera(|camera| {
+                Ok(camera.get_serial_number()?.eq_ignore_ascii_case(serial_number))
+            })
+        }
+
+        /// Like find_camera_by_serial(), but finds the camera whose alias (see
+        /// get_id()) is `alias`.
+        pub fn find_camera_by_alias(alias: &str)
+                                    -> Result<Option<CameraInfo>, ASIError> {
+            Self::find_camera(|camera| Ok(camera.get_id()? == alias))
+        }
+
+        fn find_camera<F>(matches: F) -> Result<Option<CameraInfo>, ASIError>
+        where F: Fn(&ASICamera) -> Result<bool, ASIError>
+        {
+            let camera_ids = (0..Self::num_connected_asi_cameras())
+                .map(|camera_index| Ok(Self::get_property(camera_index)?.camera_id))
+                .collect::<Result<Vec<_>, ASIError>>()?;
+            let cameras = camera_ids.into_iter().map(ASICamera::new);
+            camera_backend::find_camera(cameras, matches)
+                .map(|camera| camera.camera_info())
+                .transpose()
+        }
+
         /// Creates an ASICamera instance corresponding to the given `camera_id`.
         /// The returned instance is *not* opened by this function, you need to call
         /// open() explicitly.
         pub fn new(camera_id: i32) -> Self {
             info!("Created ASICamera id {}", camera_id);
-            ASICamera{camera_id, opened: false}
+            let pulse_guider = PulseGuider::new(move |direction, on| {
+                pulse_guide_switch(camera_id, direction, on)
+            });
+            ASICamera{camera_id, opened: false, video_active: false, pulse_guider,
+                      frames_read: AtomicU64::new(0)}
         }
 
         pub fn camera_id(&self) -> i32 { self.camera_id }
 
+        /// Like get_property_by_id(), for this camera.
+        pub fn camera_info(&self) -> Result<CameraInfo, ASIError> {
+            Self::get_property_by_id(self.camera_id)
+        }
+
         pub fn open(&mut self) -> Result<(), ASIError> {
             if self.opened {
                 return Ok(())
@@ -79,6 +310,7 @@ pub mod asi_camera2_sdk {
                 return Err(ASIError{error_code, source: "open".to_string()})
             }
             self.opened = true;
+            camera_backend::set_camera_open::<ASICamera>(self.camera_id, true);
             Ok(())
         }
 
@@ -91,15 +323,36 @@ pub mod asi_camera2_sdk {
             }
         }
 
+        /// Stops any guide pulses, video capture or exposure in progress, then
+        /// closes the camera. Errors stopping capture are logged and otherwise ignored;
+        /// the returned error, if any, is from closing the camera.
+        ///
+        /// Dropping an ASICamera also closes it, but only logs the error.
         pub fn close(&mut self) -> Result<(), ASIError> {
             if !self.opened {
                 return Ok(())
             }
+            self.pulse_guider.stop();
+            if self.video_active {
+                if let Err(e) = self.stop_video_capture() {
+                    warn!("Error stopping video capture on camera id {}: {}",
+                          self.camera_id, e);
+                }
+            }
+            if let Ok(ExposureStatus::Working) = self.get_exp_status() {
+                if let Err(e) = self.stop_exposure() {
+                    warn!("Error stopping exposure on camera id {}: {}",
+                          self.camera_id, e);
+                }
+            }
             let error_code = unsafe{ ASICloseCamera(self.camera_id) };
+            // Even if closing failed (e.g. the camera was unplugged), there is no
+            // point trying again.
+            self.opened = false;
+            camera_backend::set_camera_open::<ASICamera>(self.camera_id, false);
             if error_code != 0 {
                 return Err(ASIError{error_code, source: "close".to_string()})
             }
-            self.opened = false;
             Ok(())
         }
 
@@ -116,7 +369,7 @@ pub mod asi_camera2_sdk {
         }
 
         pub fn get_control_caps(&self, control_index: i32)
-                                -> Result<ASI_CONTROL_CAPS, ASIError> {
+                                -> Result<ControlCaps, ASIError> {
             let mut uninit_control_caps: MaybeUninit<ASI_CONTROL_CAPS> =
                 MaybeUninit::zeroed();
             let error_code = unsafe { ASIGetControlCaps(
@@ -125,29 +378,30 @@ pub mod asi_camera2_sdk {
             if error_code != 0 {
                 Err(ASIError{error_code, source: "get_control_caps".to_string()})
             } else {
-                Ok(unsafe{ uninit_control_caps.assume_init() })
+                ControlCaps::try_from(unsafe{ &uninit_control_caps.assume_init() })
             }
         }
 
         /// The return value is the control's value and whether it is automatic.
-        pub fn get_control_value(&self, control_type: ASI_CONTROL_TYPE)
+        pub fn get_control_value(&self, control_type: ControlType)
                                  -> Result<(i64, bool), ASIError> {
-            let mut value: i64 = 0;
+            let mut value: c_long = 0;
             let mut auto: i32 = 0;
             let error_code = unsafe { ASIGetControlValue(
-                self.camera_id, control_type.try_into().unwrap(), &mut value, &mut auto)
+                self.camera_id, raw_control_type(control_type), &mut value, &mut auto)
             };
             if error_code != 0 {
                 Err(ASIError{error_code, source: "get_control_value".to_string()})
             } else {
-                Ok((value, auto != 0))
+                Ok((value as i64, auto != 0))
             }
         }
 
-        pub fn set_control_value(&mut self, control_type: ASI_CONTROL_TYPE,
+        pub fn set_control_value(&mut self, control_type: ControlType,
                                  value: i64, auto: bool) -> Result<(), ASIError> {
             let error_code = unsafe { ASISetControlValue(
-                self.camera_id, control_type.try_into().unwrap(), value, auto as i32)
+                self.camera_id, raw_control_type(control_type), value as c_long,
+                auto as i32)
             };
             if error_code != 0 {
                 Err(ASIError{error_code, source: "set_control_value".to_string()})
@@ -158,7 +412,7 @@ pub mod asi_camera2_sdk {
 
         /// The return value is (width, height, bin, img_type).
         pub fn get_roi_format(&self)
-                              -> Result<(i32, i32, i32, ASI_IMG_TYPE), ASIError> {
+                              -> Result<(i32, i32, i32, ImgType), ASIError> {
             let mut width = 0;
             let mut height = 0;
             let mut bin = 0;
@@ -169,15 +423,15 @@ pub mod asi_camera2_sdk {
             if error_code != 0 {
                 Err(ASIError{error_code, source: "get_roi_format".to_string()})
             } else {
-                Ok((width, height, bin, img_type))
+                Ok((width, height, bin, ImgType::try_from(img_type)?))
             }
         }
 
         pub fn set_roi_format(&mut self, width: i32, height: i32,
-                              bin: i32, img_type:ASI_IMG_TYPE)
+                              bin: i32, img_type: ImgType)
                               -> Result<(), ASIError> {
             let error_code = unsafe { ASISetROIFormat(
-                self.camera_id, width, height, bin, img_type)
+                self.camera_id, width, height, bin, img_type.into())
             };
             if error_code != 0 {
                 Err(ASIError{error_code, source: "set_roi_format".to_string()})
@@ -224,20 +478,53 @@ pub mod asi_camera2_sdk {
             }
         }
 
-        // TODO: enable_dark_subtract()
-        // TODO: disable_dark_subtract()
+        /// See CameraBackend::next_sequence_number().
+        pub fn next_sequence_number(&self) -> u64 {
+            self.frames_read.fetch_add(1, Ordering::Relaxed)
+        }
+
+        /// `bmp_path` is an 8-bit BMP file of the camera's max width and
+        /// height; see dark_frame::create_dark_file(). Dark subtraction applies
+        /// whatever the ROI, but only to 8-bit image types.
+        pub fn enable_dark_subtract(&mut self, bmp_path: &Path) -> Result<(), ASIError> {
+            let Ok(c_path) = CString::new(bmp_path.as_os_str().as_bytes()) else {
+                return Err(ASIError::new(ASI_ERROR_CODE_ASI_ERROR_INVALID_PATH,
+                                         "enable_dark_subtract"));
+            };
+            // The SDK does not modify the path, despite taking it as char*.
+            let error_code = unsafe { ASIEnableDarkSubtract(
+                self.camera_id, c_path.as_ptr() as *mut c_char)
+            };
+            if error_code != 0 {
+                Err(ASIError{error_code, source: "enable_dark_subtract".to_string()})
+            } else {
+                Ok(())
+            }
+        }
+
+        pub fn disable_dark_subtract(&mut self) -> Result<(), ASIError> {
+            let error_code = unsafe { ASIDisableDarkSubtract(self.camera_id) };
+            if error_code != 0 {
+                Err(ASIError{error_code, source: "disable_dark_subtract".to_string()})
+            } else {
+                Ok(())
+            }
+        }
 
         pub fn start_video_capture(&mut self) -> Result<(), ASIError> {
             let error_code = unsafe { ASIStartVideoCapture(self.camera_id) };
             if error_code != 0 {
                 Err(ASIError{error_code, source: "start_video_capture".to_string()})
             } else {
+                self.video_active = true;
                 Ok(())
             }
         }
 
         pub fn stop_video_capture(&mut self) -> Result<(), ASIError> {
             let error_code = unsafe { ASIStopVideoCapture(self.camera_id) };
+            // Even if stopping failed, there is no point trying again.
+            self.video_active = false;
             if error_code != 0 {
                 Err(ASIError{error_code, source: "stop_video_capture".to_string()})
             } else {
@@ -245,9 +532,17 @@ pub mod asi_camera2_sdk {
             }
         }
 
+        /// See CameraBackend::read_video_data() and get_video_frame() for safe
+        /// alternatives.
+        ///
+        /// # Safety
+        /// `buffer` must be valid for writes of `buff_size` bytes, and
+        /// `buff_size` must be large enough to hold a frame of the current ROI
+        /// format; the SDK crashes otherwise.
         pub unsafe fn get_video_data(&self, buffer: *mut u8, buff_size: i64, wait_ms: i32)
                                   -> Result<(), ASIError> {
-            let error_code = ASIGetVideoData(self.camera_id, buffer, buff_size, wait_ms);
+            let error_code = ASIGetVideoData(
+                self.camera_id, buffer, buff_size as c_long, wait_ms);
             if error_code != 0 {
                 Err(ASIError{error_code, source: "get_video_data".to_string()})
             } else {
@@ -255,8 +550,24 @@ pub mod asi_camera2_sdk {
             }
         }
 
-        // TODO: pulse_guide_on()
-        // TODO: pulse_guide_off()
+        /// Turns on the ST4 guide output for `direction`, until
+        /// pulse_guide_off() is called. Only for cameras with an ST4 port (see
+        /// CameraInfo::st4_port).
+        pub fn pulse_guide_on(&self, direction: GuideDirection) -> Result<(), ASIError> {
+            pulse_guide_switch(self.camera_id, direction, /*on=*/true)
+        }
+
+        pub fn pulse_guide_off(&self, direction: GuideDirection) -> Result<(), ASIError> {
+            pulse_guide_switch(self.camera_id, direction, /*on=*/false)
+        }
+
+        /// Turns on the ST4 guide output for `direction`, and returns. The
+        /// output is turned off after `duration` by a background thread, or
+        /// when the camera is closed. See PulseGuider.
+        pub fn pulse_guide(&self, direction: GuideDirection, duration: Duration)
+                           -> Result<(), ASIError> {
+            self.pulse_guider.pulse(direction, duration)
+        }
 
         /// `is_dark` is relevant only if the camera has a mechanical shutter.
         pub fn start_exposure(&mut self, is_dark: bool) -> Result<(), ASIError> {
@@ -277,20 +588,27 @@ pub mod asi_camera2_sdk {
             }
         }
 
-        pub fn get_exp_status(&self) -> Result<ASI_EXPOSURE_STATUS, ASIError> {
+        pub fn get_exp_status(&self) -> Result<ExposureStatus, ASIError> {
             let mut exp_status: ASI_EXPOSURE_STATUS = ASI_EXPOSURE_STATUS_ASI_EXP_IDLE;
             let error_code = unsafe { ASIGetExpStatus(self.camera_id, &mut exp_status) };
             if error_code != 0 {
                 Err(ASIError{error_code, source: "get_exp_status".to_string()})
             } else {
-                Ok(exp_status)
+                ExposureStatus::try_from(exp_status)
             }
         }
 
+        /// See CameraBackend::read_data_after_exp() and get_frame_after_exp()
+        /// for safe alternatives.
+        ///
+        /// # Safety
+        /// `buffer` must be valid for writes of `buff_size` bytes, and
+        /// `buff_size` must be large enough to hold a frame of the current ROI
+        /// format; the SDK crashes otherwise.
         pub unsafe fn get_data_after_exp(&self, buffer: *mut u8, buff_size: i64)
                                   -> Result<(), ASIError> {
             let error_code = ASIGetDataAfterExp(
-                self.camera_id, buffer, buff_size);
+                self.camera_id, buffer, buff_size as c_long);
             if error_code != 0 {
                 Err(ASIError{error_code, source: "get_data_after_exp".to_string()})
             } else {
@@ -298,60 +616,506 @@ pub mod asi_camera2_sdk {
             }
         }
 
-        // TODO: get_id()
-        // TODO: set_id()
-        // TODO: camera_check()
-        // TODO: get_sdk_version()
-        // TODO: get_camera_support_mode()
-        // TODO: get_camera_mode()
-        // TODO: set_camera_mode()
-        // TODO: send_soft_trigger()
+        pub fn get_gain_offset(&self) -> Result<GainOffsets, ASIError> {
+            let mut offset_highest_dr: c_int = 0;
+            let mut offset_unity_gain: c_int = 0;
+            let mut gain_lowest_read_noise: c_int = 0;
+            let mut offset_lowest_read_noise: c_int = 0;
+            let error_code = unsafe { ASIGetGainOffset(
+                self.camera_id, &mut offset_highest_dr, &mut offset_unity_gain,
+                &mut gain_lowest_read_noise, &mut offset_lowest_read_noise)
+            };
+            if error_code != 0 {
+                Err(ASIError{error_code, source: "get_gain_offset".to_string()})
+            } else {
+                Ok(GainOffsets{offset_highest_dr, offset_unity_gain,
+                               gain_lowest_read_noise, offset_lowest_read_noise})
+            }
+        }
+
+        pub fn get_lmh_gain_offset(&self) -> Result<LmhGainOffsets, ASIError> {
+            let mut low_gain: c_int = 0;
+            let mut medium_gain: c_int = 0;
+            let mut high_gain: c_int = 0;
+            let mut high_offset: c_int = 0;
+            let error_code = unsafe { ASIGetLMHGainOffset(
+                self.camera_id, &mut low_gain, &mut medium_gain, &mut high_gain,
+                &mut high_offset)
+            };
+            if error_code != 0 {
+                Err(ASIError{error_code, source: "get_lmh_gain_offset".to_string()})
+            } else {
+                Ok(LmhGainOffsets{low_gain, medium_gain, high_gain, high_offset})
+            }
+        }
+
+        /// Returns the alias stored in the camera's flash; empty if none has
+        /// been set. Only USB3 cameras support aliases.
+        pub fn get_id(&self) -> Result<String, ASIError> {
+            let mut asi_id = ASI_ID{id: [0; 8]};
+            let error_code = unsafe { ASIGetID(self.camera_id, &mut asi_id) };
+            if error_code != 0 {
+                Err(ASIError{error_code, source: "get_id".to_string()})
+            } else {
+                Ok(asi_id_to_string(&asi_id))
+            }
+        }
+
+        /// Writes `alias`, which must be at most types::MAX_ALIAS_LEN
+        /// characters of printable ASCII, to the camera's flash, where it
+        /// persists across power cycles. Only USB3 cameras support aliases.
+        pub fn set_id(&mut self, alias: &str) -> Result<(), ASIError> {
+            let asi_id = asi_id_from_str(alias, "set_id")?;
+            let error_code = unsafe { ASISetID(self.camera_id, asi_id) };
+            if error_code != 0 {
+                Err(ASIError{error_code, source: "set_id".to_string()})
+            } else {
+                Ok(())
+            }
+        }
+
+        /// Returns the camera's serial number as 16 lowercase hex digits. Fails
+        /// with ASIErrorKind::GeneralError if the camera has no serial number.
+        pub fn get_serial_number(&self) -> Result<String, ASIError> {
+            let mut serial_number = ASI_SN{id: [0; 8]};
+            let error_code = unsafe {
+                ASIGetSerialNumber(self.camera_id, &mut serial_number)
+            };
+            if error_code != 0 {
+                Err(ASIError{error_code, source: "get_serial_number".to_string()})
+            } else {
+                Ok(serial_number_to_hex(&serial_number))
+            }
+        }
+
+        /// Modes of unknown type (e.g. added by a newer SDK) are omitted.
+        pub fn get_camera_support_mode(&self) -> Result<Vec<CameraMode>, ASIError> {
+            let mut uninit_supported_mode: MaybeUninit<ASI_SUPPORTED_MODE> =
+                MaybeUninit::zeroed();
+            let error_code = unsafe { ASIGetCameraSupportMode(
+                self.camera_id, &mut *uninit_supported_mode.as_mut_ptr())
+            };
+            if error_code != 0 {
+                return Err(ASIError{error_code,
+                                    source: "get_camera_support_mode".to_string()});
+            }
+            let supported_mode = unsafe { uninit_supported_mode.assume_init() };
+            let mut modes = Vec::new();
+            for mode in supported_mode.SupportedCameraMode {
+                if mode == ASI_CAMERA_MODE_ASI_MODE_END {
+                    break;
+                }
+                match CameraMode::try_from(mode) {
+                    Ok(mode) => modes.push(mode),
+                    Err(e) => warn!("Skipping camera mode: {}", e),
+                }
+            }
+            Ok(modes)
+        }
+
+        pub fn get_camera_mode(&self) -> Result<CameraMode, ASIError> {
+            let mut mode: ASI_CAMERA_MODE = ASI_CAMERA_MODE_ASI_MODE_NORMAL;
+            let error_code = unsafe { ASIGetCameraMode(self.camera_id, &mut mode) };
+            if error_code != 0 {
+                Err(ASIError{error_code, source: "get_camera_mode".to_string()})
+            } else {
+                CameraMode::try_from(mode)
+            }
+        }
+
+        pub fn set_camera_mode(&mut self, mode: CameraMode) -> Result<(), ASIError> {
+            let error_code = unsafe { ASISetCameraMode(self.camera_id, mode.into()) };
+            if error_code != 0 {
+                Err(ASIError{error_code, source: "set_camera_mode".to_string()})
+            } else {
+                Ok(())
+            }
+        }
+
+        pub fn send_soft_trigger(&self, start: bool) -> Result<(), ASIError> {
+            let error_code = unsafe {
+                ASISendSoftTrigger(self.camera_id, start as c_int)
+            };
+            if error_code != 0 {
+                Err(ASIError{error_code, source: "send_soft_trigger".to_string()})
+            } else {
+                Ok(())
+            }
+        }
+
+        pub fn get_trigger_output_config(&self, pin: TriggerOutputPin)
+                                         -> Result<TriggerOutputConfig, ASIError> {
+            let mut pin_high: c_int = 0;
+            let mut delay: c_long = 0;
+            let mut duration: c_long = 0;
+            let error_code = unsafe { ASIGetTriggerOutputIOConf(
+                self.camera_id, pin.into(), &mut pin_high, &mut delay, &mut duration)
+            };
+            if error_code != 0 {
+                Err(ASIError{error_code,
+                             source: "get_trigger_output_config".to_string()})
+            } else {
+                Ok(TriggerOutputConfig{
+                    pin_high: pin_high != 0,
+                    delay: Duration::from_micros(delay.max(0) as u64),
+                    duration: Duration::from_micros(duration.max(0) as u64),
+                })
+            }
+        }
+
+        pub fn set_trigger_output_config(&mut self, pin: TriggerOutputPin,
+                                         config: &TriggerOutputConfig)
+                                         -> Result<(), ASIError> {
+            let error_code = unsafe { ASISetTriggerOutputIOConf(
+                self.camera_id, pin.into(), config.pin_high as c_int,
+                config.delay.as_micros() as c_long,
+                config.duration.as_micros() as c_long)
+            };
+            if error_code != 0 {
+                Err(ASIError{error_code,
+                             source: "set_trigger_output_config".to_string()})
+            } else {
+                Ok(())
+            }
+        }
     }  // impl ASICamera
 
-    /// We arrange to call close() when ASICamera object goes out of scope.
+    #[cfg(feature = "sdk")]
+    fn pulse_guide_switch(camera_id: i32, direction: GuideDirection, on: bool)
+                          -> Result<(), ASIError> {
+        let direction = ASI_GUIDE_DIRECTION::from(direction) as c_int;
+        let (error_code, source) = if on {
+            (unsafe { ASIPulseGuideOn(camera_id, direction) }, "pulse_guide_on")
+        } else {
+            (unsafe { ASIPulseGuideOff(camera_id, direction) }, "pulse_guide_off")
+        };
+        if error_code != 0 {
+            Err(ASIError{error_code, source: source.to_string()})
+        } else {
+            Ok(())
+        }
+    }
+
+    // The SDK functions take the control type as an int.
+    #[cfg(feature = "sdk")]
+    fn raw_control_type(control_type: ControlType) -> c_int {
+        ASI_CONTROL_TYPE::from(control_type) as c_int
+    }
+
+    #[cfg(feature = "sdk")]
+    impl CameraBackend for ASICamera {
+        fn camera_id(&self) -> i32 { ASICamera::camera_id(self) }
+        fn camera_info(&self) -> Result<CameraInfo, ASIError> {
+            ASICamera::camera_info(self)
+        }
+
+        fn open(&mut self) -> Result<(), ASIError> { ASICamera::open(self) }
+        fn init(&self) -> Result<(), ASIError> { ASICamera::init(self) }
+        fn close(&mut self) -> Result<(), ASIError> { ASICamera::close(self) }
+
+        fn get_num_controls(&self) -> Result<i32, ASIError> {
+            ASICamera::get_num_controls(self)
+        }
+        fn get_control_caps(&self, control_index: i32)
+                            -> Result<ControlCaps, ASIError> {
+            ASICamera::get_control_caps(self, control_index)
+        }
+        fn get_control_value(&self, control_type: ControlType)
+                             -> Result<(i64, bool), ASIError> {
+            ASICamera::get_control_value(self, control_type)
+        }
+        fn set_control_value(&mut self, control_type: ControlType,
+                             value: i64, auto: bool) -> Result<(), ASIError> {
+            ASICamera::set_control_value(self, control_type, value, auto)
+        }
+
+        fn get_roi_format(&self)
+                          -> Result<(i32, i32, i32, ImgType), ASIError> {
+            ASICamera::get_roi_format(self)
+        }
+        fn set_roi_format(&mut self, width: i32, height: i32,
+                          bin: i32, img_type: ImgType)
+                          -> Result<(), ASIError> {
+            ASICamera::set_roi_format(self, width, height, bin, img_type)
+        }
+
+        fn get_start_pos(&self) -> Result<(i32, i32), ASIError> {
+            ASICamera::get_start_pos(self)
+        }
+        fn set_start_pos(&mut self, start_x: i32, start_y: i32)
+                         -> Result<(), ASIError> {
+            ASICamera::set_start_pos(self, start_x, start_y)
+        }
+
+        fn get_dropped_frames(&self) -> Result<i32, ASIError> {
+            ASICamera::get_dropped_frames(self)
+        }
+        fn next_sequence_number(&self) -> u64 {
+            ASICamera::next_sequence_number(self)
+        }
+
+        fn enable_dark_subtract(&mut self, bmp_path: &Path) -> Result<(), ASIError> {
+            ASICamera::enable_dark_subtract(self, bmp_path)
+        }
+        fn disable_dark_subtract(&mut self) -> Result<(), ASIError> {
+            ASICamera::disable_dark_subtract(self)
+        }
+
+        fn start_video_capture(&mut self) -> Result<(), ASIError> {
+            ASICamera::start_video_capture(self)
+        }
+        fn stop_video_capture(&mut self) -> Result<(), ASIError> {
+            ASICamera::stop_video_capture(self)
+        }
+        unsafe fn get_video_data(&self, buffer: *mut u8, buff_size: i64,
+                                 wait_ms: i32) -> Result<(), ASIError> {
+            ASICamera::get_video_data(self, buffer, buff_size, wait_ms)
+        }
+
+        fn pulse_guide_on(&self, direction: GuideDirection) -> Result<(), ASIError> {
+            ASICamera::pulse_guide_on(self, direction)
+        }
+        fn pulse_guide_off(&self, direction: GuideDirection) -> Result<(), ASIError> {
+            ASICamera::pulse_guide_off(self, direction)
+        }
+        fn pulse_guide(&self, direction: GuideDirection, duration: Duration)
+                       -> Result<(), ASIError> {
+            ASICamera::pulse_guide(self, direction, duration)
+        }
+
+        fn start_exposure(&mut self, is_dark: bool) -> Result<(), ASIError> {
+            ASICamera::start_exposure(self, is_dark)
+        }
+        fn stop_exposure(&mut self) -> Result<(), ASIError> {
+            ASICamera::stop_exposure(self)
+        }
+        fn get_exp_status(&self) -> Result<ExposureStatus, ASIError> {
+            ASICamera::get_exp_status(self)
+        }
+        unsafe fn get_data_after_exp(&self, buffer: *mut u8, buff_size: i64)
+                                     -> Result<(), ASIError> {
+            ASICamera::get_data_after_exp(self, buffer, buff_size)
+        }
+
+        fn get_gain_offset(&self) -> Result<GainOffsets, ASIError> {
+            ASICamera::get_gain_offset(self)
+        }
+        fn get_lmh_gain_offset(&self) -> Result<LmhGainOffsets, ASIError> {
+            ASICamera::get_lmh_gain_offset(self)
+        }
+
+        fn get_id(&self) -> Result<String, ASIError> { ASICamera::get_id(self) }
+        fn set_id(&mut self, alias: &str) -> Result<(), ASIError> {
+            ASICamera::set_id(self, alias)
+        }
+        fn get_serial_number(&self) -> Result<String, ASIError> {
+            ASICamera::get_serial_number(self)
+        }
+
+        fn get_camera_support_mode(&self) -> Result<Vec<CameraMode>, ASIError> {
+            ASICamera::get_camera_support_mode(self)
+        }
+        fn get_camera_mode(&self) -> Result<CameraMode, ASIError> {
+            ASICamera::get_camera_mode(self)
+        }
+        fn set_camera_mode(&mut self, mode: CameraMode) -> Result<(), ASIError> {
+            ASICamera::set_camera_mode(self, mode)
+        }
+        fn send_soft_trigger(&self, start: bool) -> Result<(), ASIError> {
+            ASICamera::send_soft_trigger(self, start)
+        }
+        fn get_trigger_output_config(&self, pin: TriggerOutputPin)
+                                     -> Result<TriggerOutputConfig, ASIError> {
+            ASICamera::get_trigger_output_config(self, pin)
+        }
+        fn set_trigger_output_config(&mut self, pin: TriggerOutputPin,
+                                     config: &TriggerOutputConfig)
+                                     -> Result<(), ASIError> {
+            ASICamera::set_trigger_output_config(self, pin, config)
+        }
+    }
+
+    /// We arrange to call close() when ASICamera object goes out of scope. This
+    /// must not panic: the camera may have been unplugged, or we may be
+    /// dropping during an unwind.
+    #[cfg(feature = "sdk")]
     impl Drop for ASICamera {
         fn drop(&mut self) {
-            self.close().unwrap_or_else(|err| {
-                panic!("Error closing camera id {}: {}", self.camera_id, err);
-            });
+            if let Err(e) = self.close() {
+                warn!("Error closing camera id {}: {}", self.camera_id, e);
+            }
         }
     }
 
-    /// Wraps the integer error code returned by the SDK functions.
-    pub struct ASIError {
-        error_code: i32,
-        source: String,
+    /// ASIError code used when libASICamera2 could not be loaded at runtime, or
+    /// lacks the function called (`dynamic` feature only). This is outside the
+    /// range of the SDK's own error codes.
+    pub const ASI_ERROR_LIBRARY_UNAVAILABLE: i32 = -1;
+
+    /// ASIError code used when an operation was cancelled through an
+    /// exposure::CancelToken. Also outside the range of the SDK's error codes.
+    pub const ASI_ERROR_CANCELLED: i32 = -2;
+
+    /// ASIError code used when exposures kept ending in ExposureStatus::Failed
+    /// until capture_exposure() ran out of retries. Also outside the range of
+    /// the SDK's error codes.
+    pub const ASI_ERROR_EXPOSURE_FAILED: i32 = -3;
+
+    /// Classifies an ASIError. There is one kind per SDK error code, plus
+    /// LibraryUnavailable, Cancelled, ExposureFailed and Unknown.
+    #[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+    #[non_exhaustive]
+    pub enum ASIErrorKind {
+        /// No camera connected or index value out of boundary.
+        InvalidIndex,
+        InvalidId,
+        InvalidControlType,
+        /// Camera didn't open.
+        CameraClosed,
+        /// Failed to find the camera, maybe the camera has been removed.
+        CameraRemoved,
+        InvalidPath,
+        InvalidFileFormat,
+        /// Wrong video format size.
+        InvalidSize,
+        InvalidImgType,
+        /// The start position is outside the image boundary.
+        OutOfBoundary,
+        Timeout,
+        /// Stop capture first.
+        InvalidSequence,
+        BufferTooSmall,
+        VideoModeActive,
+        /// Wait for the exposure to finish (see get_exp_status()), or stop it
+        /// with stop_exposure(), before trying again.
+        ExposureInProgress,
+        /// E.g. value is out of valid range.
+        GeneralError,
+        /// The camera is in the wrong mode (see set_camera_mode()).
+        InvalidMode,
+        /// libASICamera2 could not be loaded at runtime, or lacks the function
+        /// called (`dynamic` feature only).
+        LibraryUnavailable,
+        /// Cancelled through an exposure::CancelToken.
+        Cancelled,
+        /// Exposures failed on every attempt; see
+        /// exposure::ExposureOptions::max_retries.
+        ExposureFailed,
+        /// An error code not known to this crate, e.g. from a newer SDK.
+        Unknown,
     }
 
-    impl fmt::Display for ASIError {
+    impl ASIErrorKind {
+        pub fn from_error_code(error_code: i32) -> Self {
+            if error_code == ASI_ERROR_LIBRARY_UNAVAILABLE {
+                return ASIErrorKind::LibraryUnavailable;
+            }
+            if error_code == ASI_ERROR_CANCELLED {
+                return ASIErrorKind::Cancelled;
+            }
+            if error_code == ASI_ERROR_EXPOSURE_FAILED {
+                return ASIErrorKind::ExposureFailed;
+            }
+            match error_code as u32 {
+                ASI_ERROR_CODE_ASI_ERROR_INVALID_INDEX => ASIErrorKind::InvalidIndex,
+                ASI_ERROR_CODE_ASI_ERROR_INVALID_ID => ASIErrorKind::InvalidId,
+                ASI_ERROR_CODE_ASI_ERROR_INVALID_CONTROL_TYPE =>
+                    ASIErrorKind::InvalidControlType,
+                ASI_ERROR_CODE_ASI_ERROR_CAMERA_CLOSED => ASIErrorKind::CameraClosed,
+                ASI_ERROR_CODE_ASI_ERROR_CAMERA_REMOVED => ASIErrorKind::CameraRemoved,
+                ASI_ERROR_CODE_ASI_ERROR_INVALID_PATH => ASIErrorKind::InvalidPath,
+                ASI_ERROR_CODE_ASI_ERROR_INVALID_FILEFORMAT =>
+                    ASIErrorKind::InvalidFileFormat,
+                ASI_ERROR_CODE_ASI_ERROR_INVALID_SIZE => ASIErrorKind::InvalidSize,
+                ASI_ERROR_CODE_ASI_ERROR_INVALID_IMGTYPE => ASIErrorKind::InvalidImgType,
+                ASI_ERROR_CODE_ASI_ERROR_OUTOF_BOUNDARY => ASIErrorKind::OutOfBoundary,
+                ASI_ERROR_CODE_ASI_ERROR_TIMEOUT => ASIErrorKind::Timeout,
+                ASI_ERROR_CODE_ASI_ERROR_INVALID_SEQUENCE => ASIErrorKind::InvalidSequence,
+                ASI_ERROR_CODE_ASI_ERROR_BUFFER_TOO_SMALL => ASIErrorKind::BufferTooSmall,
+                ASI_ERROR_CODE_ASI_ERROR_VIDEO_MODE_ACTIVE => ASIErrorKind::VideoModeActive,
+                ASI_ERROR_CODE_ASI_ERROR_EXPOSURE_IN_PROGRESS =>
+                    ASIErrorKind::ExposureInProgress,
+                ASI_ERROR_CODE_ASI_ERROR_GENERAL_ERROR => ASIErrorKind::GeneralError,
+                ASI_ERROR_CODE_ASI_ERROR_INVALID_MODE => ASIErrorKind::InvalidMode,
+                _ => ASIErrorKind::Unknown,
+            }
+        }
+
+        /// Whether the failed operation may succeed if simply retried, as
+        /// opposed to errors needing corrective action (e.g. reconnecting a
+        /// removed camera, or stopping video capture or the exposure in
+        /// progress first).
+        pub fn is_transient(self) -> bool {
+            self == ASIErrorKind::Timeout
+        }
+    }
+
+    impl fmt::Display for ASIErrorKind {
         fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-            let msg = match self.error_code as u32 {
-                ASI_ERROR_CODE_ASI_SUCCESS => "OK",
-                ASI_ERROR_CODE_ASI_ERROR_INVALID_INDEX =>
+            let msg = match self {
+                ASIErrorKind::InvalidIndex =>
                     "No camera connected or index value out of boundary",
-                ASI_ERROR_CODE_ASI_ERROR_INVALID_ID => "Invalid ID",
-                ASI_ERROR_CODE_ASI_ERROR_INVALID_CONTROL_TYPE => "Invalid control type",
-                ASI_ERROR_CODE_ASI_ERROR_CAMERA_CLOSED => "Camera didn't open",
-                ASI_ERROR_CODE_ASI_ERROR_CAMERA_REMOVED =>
+                ASIErrorKind::InvalidId => "Invalid ID",
+                ASIErrorKind::InvalidControlType => "Invalid control type",
+                ASIErrorKind::CameraClosed => "Camera didn't open",
+                ASIErrorKind::CameraRemoved =>
                     "Failed to find the camera, maybe the camera has been removed",
-                ASI_ERROR_CODE_ASI_ERROR_INVALID_PATH =>
-                    "Cannot find the path of the file",
-                ASI_ERROR_CODE_ASI_ERROR_INVALID_FILEFORMAT => "Invalid file format",
-                ASI_ERROR_CODE_ASI_ERROR_INVALID_SIZE => "Wrong video format size",
-                ASI_ERROR_CODE_ASI_ERROR_INVALID_IMGTYPE => "Unsupported image format",
-                ASI_ERROR_CODE_ASI_ERROR_OUTOF_BOUNDARY =>
+                ASIErrorKind::InvalidPath => "Cannot find the path of the file",
+                ASIErrorKind::InvalidFileFormat => "Invalid file format",
+                ASIErrorKind::InvalidSize => "Wrong video format size",
+                ASIErrorKind::InvalidImgType => "Unsupported image format",
+                ASIErrorKind::OutOfBoundary =>
                     "The startpos is outside the image boundary",
-                ASI_ERROR_CODE_ASI_ERROR_TIMEOUT => "Timeout",
-                ASI_ERROR_CODE_ASI_ERROR_INVALID_SEQUENCE => "Stop capture first",
-                ASI_ERROR_CODE_ASI_ERROR_BUFFER_TOO_SMALL =>
-                    "Buffer size is not big enough",
-                ASI_ERROR_CODE_ASI_ERROR_VIDEO_MODE_ACTIVE => "Video mode active",
-                ASI_ERROR_CODE_ASI_ERROR_EXPOSURE_IN_PROGRESS => "Exposure in progress",
-                ASI_ERROR_CODE_ASI_ERROR_GENERAL_ERROR =>
+                ASIErrorKind::Timeout => "Timeout",
+                ASIErrorKind::InvalidSequence => "Stop capture first",
+                ASIErrorKind::BufferTooSmall => "Buffer size is not big enough",
+                ASIErrorKind::VideoModeActive => "Video mode active",
+                ASIErrorKind::ExposureInProgress => "Exposure in progress",
+                ASIErrorKind::GeneralError =>
                     "General error, e.g. value is out of valid range",
-                _ => "Unknown error",
+                ASIErrorKind::InvalidMode => "The current mode is wrong",
+                ASIErrorKind::LibraryUnavailable =>
+                    "ASI Camera2 SDK library is unavailable",
+                ASIErrorKind::Cancelled => "Cancelled",
+                ASIErrorKind::ExposureFailed => "Exposure failed",
+                ASIErrorKind::Unknown => "Unknown error",
             };
-            write!(f, "{} (source={}, code={})", msg, self.source, self.error_code)
+            write!(f, "{}", msg)
+        }
+    }
+
+    /// Wraps the integer error code returned by the SDK functions.
+    #[derive(Clone)]
+    pub struct ASIError {
+        pub(crate) error_code: i32,
+        pub(crate) source: String,
+    }
+
+    impl ASIError {
+        pub(crate) fn new(error_code: ASI_ERROR_CODE, source: &str) -> Self {
+            ASIError{error_code: error_code as i32, source: source.to_string()}
+        }
+
+        pub fn kind(&self) -> ASIErrorKind {
+            ASIErrorKind::from_error_code(self.error_code)
+        }
+
+        /// The ASI_ERROR_CODE returned by the SDK, or
+        /// ASI_ERROR_LIBRARY_UNAVAILABLE, ASI_ERROR_CANCELLED or
+        /// ASI_ERROR_EXPOSURE_FAILED.
+        pub fn error_code(&self) -> i32 { self.error_code }
+
+        /// The operation that failed, e.g. "set_roi_format".
+        pub fn operation(&self) -> &str { &self.source }
+
+        /// See ASIErrorKind::is_transient().
+        pub fn is_transient(&self) -> bool { self.kind().is_transient() }
+    }
+
+    impl fmt::Display for ASIError {
+        fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+            write!(f, "{} (source={}, code={})", self.kind(), self.source,
+                   self.error_code)
         }
     }
 
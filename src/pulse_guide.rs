@@ -0,0 +1,259 @@
+// Copyright (c) 2023 Steven Rosenthal smr@dt3.org
+// See LICENSE file in root directory for license terms.
+
+use std::fmt;
+use std::sync::{Arc, Condvar, Mutex, MutexGuard};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+use log::warn;
+
+use crate::asi_camera2_sdk::ASIError;
+use crate::types::GuideDirection;
+use crate::lock_ignoring_poison;
+
+/// Turns the ST4 guide output for a direction on (`true`) or off (`false`).
+pub type GuideSwitch =
+    dyn Fn(GuideDirection, bool) -> Result<(), ASIError> + Send + Sync;
+
+/// Times ST4 guide pulses. Each pulse is turned on immediately and turned off
+/// by a background thread once its duration has elapsed, so the caller need not
+/// block. A declination (North/South) pulse and a right ascension (East/West)
+/// pulse can be active at the same time; a new pulse on an axis replaces any
+/// pulse still active on that axis.
+///
+/// Any active pulses are turned off when the PulseGuider is dropped.
+pub struct PulseGuider {
+    shared: Arc<Shared>,
+    // Started on the first pulse.
+    worker: Mutex<Option<JoinHandle<()>>>,
+}
+
+struct Shared {
+    switch: Box<GuideSwitch>,
+    state: Mutex<GuideState>,
+    // Signalled when a pulse is started or shutdown is requested.
+    wakeup: Condvar,
+}
+
+struct GuideState {
+    // Indexed by axis: declination, then right ascension.
+    pulses: [Option<Pulse>; 2],
+    shutdown: bool,
+}
+
+#[derive(Clone, Copy)]
+struct Pulse {
+    direction: GuideDirection,
+    end: Instant,
+}
+
+impl PulseGuider {
+    /// `switch` is called to turn guide outputs on and off; it is called from
+    /// both the caller's thread and the PulseGuider's timer thread, but never
+    /// concurrently.
+    pub fn new<F>(switch: F) -> Self
+    where F: Fn(GuideDirection, bool) -> Result<(), ASIError> + Send + Sync + 'static
+    {
+        let shared = Shared{
+            switch: Box::new(switch),
+            state: Mutex::new(GuideState{pulses: [None, None], shutdown: false}),
+            wakeup: Condvar::new(),
+        };
+        PulseGuider{shared: Arc::new(shared), worker: Mutex::new(None)}
+    }
+
+    /// Turns on the guide output for `direction`, and arranges for it to be
+    /// turned off after `duration`. Returns once the output is on.
+    pub fn pulse(&self, direction: GuideDirection, duration: Duration)
+                 -> Result<(), ASIError> {
+        self.start_worker();
+        let mut state = self.shared.lock_state();
+        let axis = axis(direction);
+        match state.pulses[axis] {
+            // Already on; just re-time it.
+            Some(pulse) if pulse.direction == direction => (),
+            Some(pulse) => {
+                state.pulses[axis] = None;
+                self.shared.turn_off(pulse.direction);
+                (self.shared.switch)(direction, true)?;
+            },
+            None => (self.shared.switch)(direction, true)?,
+        }
+        state.pulses[axis] = Some(Pulse{direction, end: Instant::now() + duration});
+        self.shared.wakeup.notify_one();
+        Ok(())
+    }
+
+    /// Returns whether a pulse is active in `direction`.
+    pub fn is_pulsing(&self, direction: GuideDirection) -> bool {
+        let state = self.shared.lock_state();
+        matches!(state.pulses[axis(direction)],
+                 Some(pulse) if pulse.direction == direction)
+    }
+
+    /// Turns off any active pulses now.
+    pub fn stop(&self) {
+        let mut state = self.shared.lock_state();
+        for slot in &mut state.pulses {
+            if let Some(pulse) = slot.take() {
+                self.shared.turn_off(pulse.direction);
+            }
+        }
+    }
+
+    fn start_worker(&self) {
+        let mut worker = lock_ignoring_poison(&self.worker);
+        if worker.is_none() {
+            let shared = self.shared.clone();
+            *worker = Some(thread::spawn(move || shared.run()));
+        }
+    }
+}
+
+impl Drop for PulseGuider {
+    fn drop(&mut self) {
+        self.shared.lock_state().shutdown = true;
+        self.shared.wakeup.notify_one();
+        let worker = self.worker.get_mut().unwrap_or_else(|e| e.into_inner());
+        match worker.take() {
+            Some(worker) => {
+                if worker.join().is_err() {
+                    warn!("Pulse guide thread panicked");
+                }
+            },
+            None => self.stop(),
+        }
+    }
+}
+
+impl fmt::Debug for PulseGuider {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("PulseGuider").finish_non_exhaustive()
+    }
+}
+
+impl Shared {
+    fn lock_state(&self) -> MutexGuard<'_, GuideState> {
+        lock_ignoring_poison(&self.state)
+    }
+
+    // There is nobody to report an error to, so log it.
+    fn turn_off(&self, direction: GuideDirection) {
+        if let Err(e) = (self.switch)(direction, false) {
+            warn!("Error ending {} guide pulse: {}", direction, e);
+        }
+    }
+
+    // Body of the timer thread: ends each pulse when its time is up.
+    fn run(&self) {
+        let mut state = self.lock_state();
+        loop {
+            let now = Instant::now();
+            let shutdown = state.shutdown;
+            for slot in &mut state.pulses {
+                if let Some(pulse) = *slot {
+                    if pulse.end <= now || shutdown {
+                        self.turn_off(pulse.direction);
+                        *slot = None;
+                    }
+                }
+            }
+            if shutdown {
+                return;
+            }
+            let next_end = state.pulses.iter().flatten().map(|pulse| pulse.end).min();
+            state = match next_end {
+                Some(end) => self.wakeup.wait_timeout(state, end - now)
+                    .unwrap_or_else(|e| e.into_inner()).0,
+                None => self.wakeup.wait(state).unwrap_or_else(|e| e.into_inner()),
+            };
+        }
+    }
+}
+
+fn axis(direction: GuideDirection) -> usize {
+    if direction.is_declination() { 0 } else { 1 }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::thread::sleep;
+
+    use super::*;
+    use GuideDirection::{East, North, South};
+
+    // (direction, on, time since the guider was created) for each switch call.
+    type Events = Arc<Mutex<Vec<(GuideDirection, bool, Duration)>>>;
+
+    fn recording_guider() -> (PulseGuider, Events) {
+        let events = Events::default();
+        let recorded = events.clone();
+        let start = Instant::now();
+        let guider = PulseGuider::new(move |direction, on| {
+            recorded.lock().unwrap().push((direction, on, start.elapsed()));
+            Ok(())
+        });
+        (guider, events)
+    }
+
+    // Returns when the output for `direction` was last turned off.
+    fn off_time(events: &Events, direction: GuideDirection) -> Option<Duration> {
+        events.lock().unwrap().iter().rev()
+            .find(|(d, on, _)| *d == direction && !on)
+            .map(|(_, _, time)| *time)
+    }
+
+    #[test]
+    fn concurrent_axes() {
+        let (guider, events) = recording_guider();
+        guider.pulse(North, Duration::from_millis(50)).unwrap();
+        guider.pulse(East, Duration::from_millis(100)).unwrap();
+        assert!(guider.is_pulsing(North) && guider.is_pulsing(East));
+        sleep(Duration::from_millis(250));
+        assert!(!guider.is_pulsing(North) && !guider.is_pulsing(East));
+        let north_off = off_time(&events, North).unwrap();
+        let east_off = off_time(&events, East).unwrap();
+        assert!(north_off >= Duration::from_millis(50) &&
+                north_off < Duration::from_millis(100), "{:?}", north_off);
+        assert!(east_off >= Duration::from_millis(100) &&
+                east_off < Duration::from_millis(150), "{:?}", east_off);
+        assert_eq!(events.lock().unwrap().len(), 4);
+    }
+
+    #[test]
+    fn same_axis_replaces() {
+        let (guider, events) = recording_guider();
+        guider.pulse(North, Duration::from_secs(10)).unwrap();
+        guider.pulse(South, Duration::from_millis(50)).unwrap();
+        assert!(!guider.is_pulsing(North) && guider.is_pulsing(South));
+        let switched: Vec<_> =
+            events.lock().unwrap().iter().map(|(d, on, _)| (*d, *on)).collect();
+        assert_eq!(switched, [(North, true), (North, false), (South, true)]);
+
+        // Re-timed rather than switched off and on.
+        guider.pulse(South, Duration::from_millis(200)).unwrap();
+        sleep(Duration::from_millis(100));
+        assert!(guider.is_pulsing(South));
+        sleep(Duration::from_millis(200));
+        assert!(!guider.is_pulsing(South));
+        let switched: Vec<_> =
+            events.lock().unwrap().iter().map(|(d, on, _)| (*d, *on)).collect();
+        assert_eq!(switched[3..], [(South, false)]);
+    }
+
+    #[test]
+    fn stop_and_drop() {
+        let (guider, events) = recording_guider();
+        guider.pulse(North, Duration::from_secs(10)).unwrap();
+        guider.pulse(East, Duration::from_secs(10)).unwrap();
+        guider.stop();
+        assert!(!guider.is_pulsing(North) && !guider.is_pulsing(East));
+        assert!(off_time(&events, North).is_some() && off_time(&events, East).is_some());
+
+        events.lock().unwrap().clear();
+        guider.pulse(South, Duration::from_secs(10)).unwrap();
+        drop(guider);
+        assert!(off_time(&events, South).is_some());
+    }
+}
@@ -0,0 +1,540 @@
+// Copyright (c) 2023 Steven Rosenthal smr@dt3.org
+// See LICENSE file in root directory for license terms.
+
+use std::collections::HashMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::{Mutex, MutexGuard};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::thread::sleep;
+use std::time::{Duration, Instant};
+
+use log::{info, warn};
+
+use crate::asi_camera2_sdk::{
+    ASIError,
+    ASI_ERROR_CODE_ASI_ERROR_BUFFER_TOO_SMALL,
+    ASI_ERROR_CODE_ASI_ERROR_CAMERA_CLOSED,
+    ASI_ERROR_CODE_ASI_ERROR_EXPOSURE_IN_PROGRESS,
+    ASI_ERROR_CODE_ASI_ERROR_GENERAL_ERROR,
+    ASI_ERROR_CODE_ASI_ERROR_INVALID_CONTROL_TYPE,
+    ASI_ERROR_CODE_ASI_ERROR_INVALID_FILEFORMAT,
+    ASI_ERROR_CODE_ASI_ERROR_INVALID_IMGTYPE,
+    ASI_ERROR_CODE_ASI_ERROR_INVALID_INDEX,
+    ASI_ERROR_CODE_ASI_ERROR_INVALID_MODE,
+    ASI_ERROR_CODE_ASI_ERROR_INVALID_PATH,
+    ASI_ERROR_CODE_ASI_ERROR_INVALID_SEQUENCE,
+    ASI_ERROR_CODE_ASI_ERROR_INVALID_SIZE,
+    ASI_ERROR_CODE_ASI_ERROR_TIMEOUT,
+    ASI_ERROR_CODE_ASI_ERROR_VIDEO_MODE_ACTIVE,
+    ASI_TRIG_OUTPUT_PIN,
+};
+use crate::camera_backend::CameraBackend;
+use crate::frame::FrameMetadata;
+use crate::frame_dump::{self, DumpedCamera, DumpedFrame};
+use crate::pulse_guide::PulseGuider;
+use crate::types::{CameraInfo, CameraMode, ControlCaps, ControlType, ExposureStatus,
+                   GainOffsets, GuideDirection, ImgType, LmhGainOffsets,
+                   TriggerOutputConfig, TriggerOutputPin};
+use crate::lock_ignoring_poison;
+
+/// A CameraBackend that replays a directory of frames dumped by
+/// frame_dump::DumpRecorder, so that a recorded session can be run through
+/// the same code as a live camera, on any host.
+///
+/// The recorded frames are returned in order by get_video_data() and
+/// get_data_after_exp(), whichever call recorded them, as fast as they are
+/// asked for: replay does not depend on timing, so it is deterministic. Once
+/// all frames have been returned, video reads time out and exposures fail.
+///
+/// Each frame's recorded start position, control values and dropped frame
+/// count become the camera's state once it is read, so frame_metadata()
+/// reports what was recorded; its timestamp and system time are those of the
+/// recording too. Settings the caller makes in between are kept until then,
+/// except that the ROI format must match that of the next frame, else reading
+/// fails with ASIErrorKind::InvalidSize. Dark subtraction is accepted but has
+/// no effect, and guide pulses go nowhere.
+pub struct ReplayCamera {
+    dir: PathBuf,
+    camera: DumpedCamera,
+    frames: Vec<DumpedFrame>,
+    state: Mutex<ReplayState>,
+    pulse_guider: PulseGuider,
+    frames_read: AtomicU64,
+    // The timestamps of replayed frames are relative to this.
+    start: Instant,
+}
+
+impl ReplayCamera {
+    /// Reads the camera.json file and frame sidecars of the dump directory
+    /// `dir`. The returned instance is *not* opened by this function, you need
+    /// to call open() explicitly.
+    pub fn new(dir: &Path) -> Result<Self, ASIError> {
+        let camera = DumpedCamera::read(dir)?;
+        let frames = frame_dump::dumped_frame_paths(dir)?.iter()
+            .map(|path| DumpedFrame::read(path))
+            .collect::<Result<Vec<_>, _>>()?;
+        info!("Replaying {} frames of camera {:?} from {:?}",
+              frames.len(), camera.camera_info.name, dir);
+        let state = ReplayState::new(&camera, frames.first());
+        Ok(ReplayCamera{dir: dir.to_path_buf(), camera, frames,
+                        state: Mutex::new(state),
+                        pulse_guider: PulseGuider::new(|_direction, _on| Ok(())),
+                        frames_read: AtomicU64::new(0), start: Instant::now()})
+    }
+
+    pub fn dumped_camera(&self) -> &DumpedCamera { &self.camera }
+    pub fn frames(&self) -> &[DumpedFrame] { &self.frames }
+
+    /// The number of recorded frames not yet read.
+    pub fn frames_remaining(&self) -> usize {
+        self.frames.len() - self.lock_state().next_frame
+    }
+
+    /// Starts the replay over from the first frame, resetting the camera's
+    /// state.
+    pub fn rewind(&self) {
+        let opened = self.lock_state().opened;
+        let mut state = ReplayState::new(&self.camera, self.frames.first());
+        state.opened = opened;
+        *self.lock_state() = state;
+    }
+
+    fn lock_state(&self) -> MutexGuard<'_, ReplayState> {
+        lock_ignoring_poison(&self.state)
+    }
+
+    fn find_caps(&self, control_type: ControlType, source: &str)
+                 -> Result<&ControlCaps, ASIError> {
+        self.camera.controls.iter().find(|caps| caps.control_type == control_type)
+            .ok_or_else(|| ASIError::new(ASI_ERROR_CODE_ASI_ERROR_INVALID_CONTROL_TYPE,
+                                         source))
+    }
+
+    // Copies the next recorded frame into `buffer` and makes its recorded
+    // settings the camera's state.
+    unsafe fn read_next_frame(&self, state: &mut ReplayState, buffer: *mut u8,
+                              buff_size: i64, source: &str) -> Result<(), ASIError> {
+        let frame = &self.frames[state.next_frame];
+        let roi_format = (frame.width, frame.height, frame.bin, frame.img_type);
+        if roi_format != state.roi_format {
+            warn!("Frame {} was recorded with ROI format {:?}, not {:?}",
+                  frame.index, roi_format, state.roi_format);
+            return Err(ASIError::new(ASI_ERROR_CODE_ASI_ERROR_INVALID_SIZE, source));
+        }
+        let frame_size = frame.frame_size();
+        if buff_size < frame_size as i64 {
+            return Err(ASIError::new(ASI_ERROR_CODE_ASI_ERROR_BUFFER_TOO_SMALL, source));
+        }
+        let path = self.dir.join(&frame.data_file);
+        let data = fs::read(&path).map_err(|e| {
+            warn!("Error reading {:?}: {}", path, e);
+            ASIError::new(ASI_ERROR_CODE_ASI_ERROR_INVALID_PATH, source)
+        })?;
+        if data.len() != frame_size {
+            warn!("{:?} has {} bytes, expected {}", path, data.len(), frame_size);
+            return Err(ASIError::new(ASI_ERROR_CODE_ASI_ERROR_INVALID_FILEFORMAT, source));
+        }
+        std::slice::from_raw_parts_mut(buffer, frame_size).copy_from_slice(&data);
+        state.start_pos = (frame.start_x, frame.start_y);
+        state.controls.extend(frame.controls.iter().map(|(k, v)| (*k, *v)));
+        state.dropped_frames = frame.dropped_frames;
+        state.last_frame = Some(state.next_frame);
+        state.next_frame += 1;
+        Ok(())
+    }
+}
+
+impl CameraBackend for ReplayCamera {
+    fn camera_id(&self) -> i32 { self.camera.camera_info.camera_id }
+    fn camera_info(&self) -> Result<CameraInfo, ASIError> {
+        Ok(self.camera.camera_info.clone())
+    }
+
+    fn open(&mut self) -> Result<(), ASIError> {
+        self.lock_state().opened = true;
+        Ok(())
+    }
+
+    fn init(&self) -> Result<(), ASIError> {
+        self.lock_state().check_open("init")
+    }
+
+    fn close(&mut self) -> Result<(), ASIError> {
+        self.pulse_guider.stop();
+        let mut state = self.lock_state();
+        state.opened = false;
+        state.video_active = false;
+        state.exp_status = ExposureStatus::Idle;
+        Ok(())
+    }
+
+    fn get_num_controls(&self) -> Result<i32, ASIError> {
+        self.lock_state().check_open("get_num_controls")?;
+        Ok(self.camera.controls.len() as i32)
+    }
+
+    fn get_control_caps(&self, control_index: i32)
+                        -> Result<ControlCaps, ASIError> {
+        self.lock_state().check_open("get_control_caps")?;
+        usize::try_from(control_index).ok()
+            .and_then(|index| self.camera.controls.get(index))
+            .cloned()
+            .ok_or_else(|| ASIError::new(ASI_ERROR_CODE_ASI_ERROR_INVALID_INDEX,
+                                         "get_control_caps"))
+    }
+
+    fn get_control_value(&self, control_type: ControlType)
+                         -> Result<(i64, bool), ASIError> {
+        let state = self.lock_state();
+        state.check_open("get_control_value")?;
+        let caps = self.find_caps(control_type, "get_control_value")?;
+        Ok(state.controls.get(&control_type).copied()
+           .unwrap_or((caps.default_value, false)))
+    }
+
+    fn set_control_value(&mut self, control_type: ControlType,
+                         value: i64, auto: bool) -> Result<(), ASIError> {
+        let mut state = self.lock_state();
+        state.check_open("set_control_value")?;
+        let caps = self.find_caps(control_type, "set_control_value")?;
+        if !caps.is_writable {
+            return Err(ASIError::new(ASI_ERROR_CODE_ASI_ERROR_GENERAL_ERROR,
+                                     "set_control_value"));
+        }
+        state.controls.insert(control_type,
+                              (value.clamp(caps.min_value, caps.max_value),
+                               auto && caps.is_auto_supported));
+        Ok(())
+    }
+
+    fn get_roi_format(&self) -> Result<(i32, i32, i32, ImgType), ASIError> {
+        let state = self.lock_state();
+        state.check_open("get_roi_format")?;
+        Ok(state.roi_format)
+    }
+
+    fn set_roi_format(&mut self, width: i32, height: i32,
+                      bin: i32, img_type: ImgType) -> Result<(), ASIError> {
+        let mut state = self.lock_state();
+        state.check_open("set_roi_format")?;
+        let info = &self.camera.camera_info;
+        if !info.supported_bins.contains(&(bin as u32)) ||
+            width <= 0 || height <= 0 ||
+            width * bin > info.max_width || height * bin > info.max_height
+        {
+            return Err(ASIError::new(ASI_ERROR_CODE_ASI_ERROR_INVALID_SIZE,
+                                     "set_roi_format"));
+        }
+        if !info.supported_video_formats.contains(&img_type) {
+            return Err(ASIError::new(ASI_ERROR_CODE_ASI_ERROR_INVALID_IMGTYPE,
+                                     "set_roi_format"));
+        }
+        state.roi_format = (width, height, bin, img_type);
+        // Like the SDK, center the new ROI.
+        state.start_pos = ((info.max_width / bin - width) / 2,
+                           (info.max_height / bin - height) / 2);
+        Ok(())
+    }
+
+    fn get_start_pos(&self) -> Result<(i32, i32), ASIError> {
+        let state = self.lock_state();
+        state.check_open("get_start_pos")?;
+        Ok(state.start_pos)
+    }
+
+    fn set_start_pos(&mut self, start_x: i32, start_y: i32)
+                     -> Result<(), ASIError> {
+        let mut state = self.lock_state();
+        state.check_open("set_start_pos")?;
+        state.start_pos = (start_x, start_y);
+        Ok(())
+    }
+
+    fn get_dropped_frames(&self) -> Result<i32, ASIError> {
+        let state = self.lock_state();
+        state.check_open("get_dropped_frames")?;
+        Ok(state.dropped_frames)
+    }
+
+    fn next_sequence_number(&self) -> u64 {
+        self.frames_read.fetch_add(1, Ordering::Relaxed)
+    }
+
+    fn enable_dark_subtract(&mut self, bmp_path: &Path) -> Result<(), ASIError> {
+        self.lock_state().check_open("enable_dark_subtract")?;
+        info!("Ignoring dark frame {:?}: replayed frames are as recorded", bmp_path);
+        Ok(())
+    }
+
+    fn disable_dark_subtract(&mut self) -> Result<(), ASIError> {
+        self.lock_state().check_open("disable_dark_subtract")
+    }
+
+    fn start_video_capture(&mut self) -> Result<(), ASIError> {
+        let mut state = self.lock_state();
+        state.check_open("start_video_capture")?;
+        if state.exp_status == ExposureStatus::Working {
+            return Err(ASIError::new(ASI_ERROR_CODE_ASI_ERROR_EXPOSURE_IN_PROGRESS,
+                                     "start_video_capture"));
+        }
+        state.video_active = true;
+        Ok(())
+    }
+
+    fn stop_video_capture(&mut self) -> Result<(), ASIError> {
+        let mut state = self.lock_state();
+        state.check_open("stop_video_capture")?;
+        state.video_active = false;
+        Ok(())
+    }
+
+    unsafe fn get_video_data(&self, buffer: *mut u8, buff_size: i64, wait_ms: i32)
+                             -> Result<(), ASIError> {
+        let mut state = self.lock_state();
+        state.check_open("get_video_data")?;
+        if !state.video_active {
+            return Err(ASIError::new(ASI_ERROR_CODE_ASI_ERROR_INVALID_SEQUENCE,
+                                     "get_video_data"));
+        }
+        if state.next_frame == self.frames.len() {
+            // Like a camera that has stopped delivering frames. Don't wait
+            // forever, though.
+            drop(state);
+            sleep(Duration::from_millis(wait_ms.max(0) as u64));
+            return Err(ASIError::new(ASI_ERROR_CODE_ASI_ERROR_TIMEOUT, "get_video_data"));
+        }
+        self.read_next_frame(&mut state, buffer, buff_size, "get_video_data")
+    }
+
+    fn pulse_guide_on(&self, _direction: GuideDirection) -> Result<(), ASIError> {
+        self.lock_state().check_open("pulse_guide_on")
+    }
+
+    fn pulse_guide_off(&self, _direction: GuideDirection) -> Result<(), ASIError> {
+        self.lock_state().check_open("pulse_guide_off")
+    }
+
+    fn pulse_guide(&self, direction: GuideDirection, duration: Duration)
+                   -> Result<(), ASIError> {
+        self.lock_state().check_open("pulse_guide")?;
+        self.pulse_guider.pulse(direction, duration)
+    }
+
+    fn start_exposure(&mut self, _is_dark: bool) -> Result<(), ASIError> {
+        let mut state = self.lock_state();
+        state.check_open("start_exposure")?;
+        if state.video_active {
+            return Err(ASIError::new(ASI_ERROR_CODE_ASI_ERROR_VIDEO_MODE_ACTIVE,
+                                     "start_exposure"));
+        }
+        if state.exp_status == ExposureStatus::Working {
+            return Err(ASIError::new(ASI_ERROR_CODE_ASI_ERROR_EXPOSURE_IN_PROGRESS,
+                                     "start_exposure"));
+        }
+        state.exp_status = ExposureStatus::Working;
+        Ok(())
+    }
+
+    fn stop_exposure(&mut self) -> Result<(), ASIError> {
+        let mut state = self.lock_state();
+        state.check_open("stop_exposure")?;
+        if state.exp_status == ExposureStatus::Working {
+            state.exp_status = ExposureStatus::Failed;
+        }
+        Ok(())
+    }
+
+    fn get_exp_status(&self) -> Result<ExposureStatus, ASIError> {
+        let mut state = self.lock_state();
+        state.check_open("get_exp_status")?;
+        // Exposures complete as soon as they are polled, unless the recording
+        // has run out.
+        if state.exp_status == ExposureStatus::Working {
+            state.exp_status = if state.next_frame < self.frames.len() {
+                ExposureStatus::Success
+            } else {
+                ExposureStatus::Failed
+            };
+        }
+        Ok(state.exp_status)
+    }
+
+    unsafe fn get_data_after_exp(&self, buffer: *mut u8, buff_size: i64)
+                                 -> Result<(), ASIError> {
+        let mut state = self.lock_state();
+        state.check_open("get_data_after_exp")?;
+        if state.exp_status != ExposureStatus::Success {
+            return Err(ASIError::new(ASI_ERROR_CODE_ASI_ERROR_TIMEOUT,
+                                     "get_data_after_exp"));
+        }
+        self.read_next_frame(&mut state, buffer, buff_size, "get_data_after_exp")?;
+        state.exp_status = ExposureStatus::Idle;
+        Ok(())
+    }
+
+    fn get_gain_offset(&self) -> Result<GainOffsets, ASIError> {
+        self.lock_state().check_open("get_gain_offset")?;
+        self.camera.gain_offsets.ok_or_else(|| {
+            ASIError::new(ASI_ERROR_CODE_ASI_ERROR_GENERAL_ERROR, "get_gain_offset")
+        })
+    }
+
+    fn get_lmh_gain_offset(&self) -> Result<LmhGainOffsets, ASIError> {
+        self.lock_state().check_open("get_lmh_gain_offset")?;
+        self.camera.lmh_gain_offsets.ok_or_else(|| {
+            ASIError::new(ASI_ERROR_CODE_ASI_ERROR_GENERAL_ERROR, "get_lmh_gain_offset")
+        })
+    }
+
+    fn get_id(&self) -> Result<String, ASIError> {
+        let state = self.lock_state();
+        state.check_open("get_id")?;
+        state.alias.clone().ok_or_else(|| {
+            ASIError::new(ASI_ERROR_CODE_ASI_ERROR_GENERAL_ERROR, "get_id")
+        })
+    }
+
+    fn set_id(&mut self, alias: &str) -> Result<(), ASIError> {
+        let mut state = self.lock_state();
+        state.check_open("set_id")?;
+        state.alias = Some(alias.to_string());
+        Ok(())
+    }
+
+    fn get_serial_number(&self) -> Result<String, ASIError> {
+        self.lock_state().check_open("get_serial_number")?;
+        self.camera.serial_number.clone().ok_or_else(|| {
+            ASIError::new(ASI_ERROR_CODE_ASI_ERROR_GENERAL_ERROR, "get_serial_number")
+        })
+    }
+
+    fn get_camera_support_mode(&self) -> Result<Vec<CameraMode>, ASIError> {
+        self.lock_state().check_open("get_camera_support_mode")?;
+        Ok(self.camera.camera_modes.clone())
+    }
+
+    fn get_camera_mode(&self) -> Result<CameraMode, ASIError> {
+        let state = self.lock_state();
+        state.check_open("get_camera_mode")?;
+        Ok(state.camera_mode)
+    }
+
+    fn set_camera_mode(&mut self, mode: CameraMode) -> Result<(), ASIError> {
+        let mut state = self.lock_state();
+        state.check_open("set_camera_mode")?;
+        if state.video_active {
+            return Err(ASIError::new(ASI_ERROR_CODE_ASI_ERROR_INVALID_SEQUENCE,
+                                     "set_camera_mode"));
+        }
+        if mode != CameraMode::Normal && !self.camera.camera_modes.contains(&mode) {
+            return Err(ASIError::new(ASI_ERROR_CODE_ASI_ERROR_INVALID_MODE,
+                                     "set_camera_mode"));
+        }
+        state.camera_mode = mode;
+        Ok(())
+    }
+
+    fn send_soft_trigger(&self, _start: bool) -> Result<(), ASIError> {
+        // The recorded frames were already triggered.
+        self.lock_state().check_open("send_soft_trigger")
+    }
+
+    fn get_trigger_output_config(&self, pin: TriggerOutputPin)
+                                 -> Result<TriggerOutputConfig, ASIError> {
+        let state = self.lock_state();
+        state.check_open("get_trigger_output_config")?;
+        Ok(state.trigger_outputs[ASI_TRIG_OUTPUT_PIN::from(pin) as usize])
+    }
+
+    fn set_trigger_output_config(&mut self, pin: TriggerOutputPin,
+                                 config: &TriggerOutputConfig) -> Result<(), ASIError> {
+        let mut state = self.lock_state();
+        state.check_open("set_trigger_output_config")?;
+        state.trigger_outputs[ASI_TRIG_OUTPUT_PIN::from(pin) as usize] = *config;
+        Ok(())
+    }
+
+    /// Like the provided method, except that the timestamp and system time
+    /// are those recorded for the frame.
+    fn frame_metadata(&self) -> Result<FrameMetadata, ASIError> {
+        let frame = {
+            let state = self.lock_state();
+            state.check_open("frame_metadata")?;
+            match state.last_frame {
+                Some(index) => &self.frames[index],
+                None => return Err(ASIError::new(
+                    ASI_ERROR_CODE_ASI_ERROR_INVALID_SEQUENCE, "frame_metadata")),
+            }
+        };
+        let (exposure_us, _auto) = self.get_control_value(ControlType::Exposure)?;
+        let (gain, _auto) = self.get_control_value(ControlType::Gain)?;
+        let (offset, _auto) = self.get_control_value(ControlType::Offset)?;
+        let temperature = frame.controls.get(&ControlType::Temperature)
+            .map(|(value, _auto)| *value as f64 / 10.0);
+        Ok(FrameMetadata{timestamp: self.start + frame.elapsed,
+                         system_time: frame.system_time,
+                         sequence_number: self.next_sequence_number(),
+                         start_x: frame.start_x, start_y: frame.start_y,
+                         exposure: Duration::from_micros(exposure_us.max(0) as u64),
+                         gain, offset, temperature,
+                         dropped_frames: frame.dropped_frames})
+    }
+}
+
+// Mutable state of a ReplayCamera.
+struct ReplayState {
+    opened: bool,
+    roi_format: (i32, i32, i32, ImgType),
+    start_pos: (i32, i32),
+    // Value and whether automatic; controls not in here have their default
+    // value.
+    controls: HashMap<ControlType, (i64, bool)>,
+    dropped_frames: i32,
+    video_active: bool,
+    exp_status: ExposureStatus,
+    camera_mode: CameraMode,
+    trigger_outputs: [TriggerOutputConfig; 2],
+    alias: Option<String>,
+    // Indices into ReplayCamera::frames.
+    next_frame: usize,
+    last_frame: Option<usize>,
+}
+
+impl ReplayState {
+    // The initial state is that of the first frame, if any, so that code that
+    // does not set the ROI format reads frames in the recorded one.
+    fn new(camera: &DumpedCamera, first_frame: Option<&DumpedFrame>) -> Self {
+        let info = &camera.camera_info;
+        let (roi_format, start_pos, controls) = match first_frame {
+            Some(frame) => ((frame.width, frame.height, frame.bin, frame.img_type),
+                            (frame.start_x, frame.start_y),
+                            frame.controls.clone()),
+            None => ((info.max_width, info.max_height, 1, ImgType::Raw8),
+                     (0, 0),
+                     HashMap::new()),
+        };
+        ReplayState{
+            opened: false,
+            roi_format,
+            start_pos,
+            controls,
+            dropped_frames: 0,
+            video_active: false,
+            exp_status: ExposureStatus::Idle,
+            camera_mode: CameraMode::Normal,
+            trigger_outputs: [TriggerOutputConfig::default(); 2],
+            alias: camera.alias.clone(),
+            next_frame: 0,
+            last_frame: None,
+        }
+    }
+
+    fn check_open(&self, source: &str) -> Result<(), ASIError> {
+        if self.opened {
+            Ok(())
+        } else {
+            Err(ASIError::new(ASI_ERROR_CODE_ASI_ERROR_CAMERA_CLOSED, source))
+        }
+    }
+}
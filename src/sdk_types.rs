@@ -0,0 +1,161 @@
+// Copyright (c) 2023 Steven Rosenthal smr@dt3.org
+// See LICENSE file in root directory for license terms.
+
+// Pure-Rust copies of the type definitions in ASICamera2.h, named as bindgen
+// names them. When the `sdk` feature is disabled these stand in for the
+// generated bindings, so that the SDK-independent parts of the crate (e.g.
+// SimulatedCamera) are written against the same types either way.
+
+use std::os::raw::{c_char, c_int, c_long, c_uchar, c_uint};
+
+pub const ASICAMERA_ID_MAX: u32 = 128;
+
+pub const ASI_BAYER_PATTERN_ASI_BAYER_RG: ASI_BAYER_PATTERN = 0;
+pub const ASI_BAYER_PATTERN_ASI_BAYER_BG: ASI_BAYER_PATTERN = 1;
+pub const ASI_BAYER_PATTERN_ASI_BAYER_GR: ASI_BAYER_PATTERN = 2;
+pub const ASI_BAYER_PATTERN_ASI_BAYER_GB: ASI_BAYER_PATTERN = 3;
+pub type ASI_BAYER_PATTERN = c_uint;
+
+pub const ASI_IMG_TYPE_ASI_IMG_RAW8: ASI_IMG_TYPE = 0;
+pub const ASI_IMG_TYPE_ASI_IMG_RGB24: ASI_IMG_TYPE = 1;
+pub const ASI_IMG_TYPE_ASI_IMG_RAW16: ASI_IMG_TYPE = 2;
+pub const ASI_IMG_TYPE_ASI_IMG_Y8: ASI_IMG_TYPE = 3;
+pub const ASI_IMG_TYPE_ASI_IMG_END: ASI_IMG_TYPE = -1;
+pub type ASI_IMG_TYPE = c_int;
+
+pub const ASI_GUIDE_DIRECTION_ASI_GUIDE_NORTH: ASI_GUIDE_DIRECTION = 0;
+pub const ASI_GUIDE_DIRECTION_ASI_GUIDE_SOUTH: ASI_GUIDE_DIRECTION = 1;
+pub const ASI_GUIDE_DIRECTION_ASI_GUIDE_EAST: ASI_GUIDE_DIRECTION = 2;
+pub const ASI_GUIDE_DIRECTION_ASI_GUIDE_WEST: ASI_GUIDE_DIRECTION = 3;
+pub type ASI_GUIDE_DIRECTION = c_uint;
+
+pub const ASI_FLIP_STATUS_ASI_FLIP_NONE: ASI_FLIP_STATUS = 0;
+pub const ASI_FLIP_STATUS_ASI_FLIP_HORIZ: ASI_FLIP_STATUS = 1;
+pub const ASI_FLIP_STATUS_ASI_FLIP_VERT: ASI_FLIP_STATUS = 2;
+pub const ASI_FLIP_STATUS_ASI_FLIP_BOTH: ASI_FLIP_STATUS = 3;
+pub type ASI_FLIP_STATUS = c_uint;
+
+pub const ASI_CAMERA_MODE_ASI_MODE_NORMAL: ASI_CAMERA_MODE = 0;
+pub const ASI_CAMERA_MODE_ASI_MODE_TRIG_SOFT_EDGE: ASI_CAMERA_MODE = 1;
+pub const ASI_CAMERA_MODE_ASI_MODE_TRIG_RISE_EDGE: ASI_CAMERA_MODE = 2;
+pub const ASI_CAMERA_MODE_ASI_MODE_TRIG_FALL_EDGE: ASI_CAMERA_MODE = 3;
+pub const ASI_CAMERA_MODE_ASI_MODE_TRIG_SOFT_LEVEL: ASI_CAMERA_MODE = 4;
+pub const ASI_CAMERA_MODE_ASI_MODE_TRIG_HIGH_LEVEL: ASI_CAMERA_MODE = 5;
+pub const ASI_CAMERA_MODE_ASI_MODE_TRIG_LOW_LEVEL: ASI_CAMERA_MODE = 6;
+pub const ASI_CAMERA_MODE_ASI_MODE_END: ASI_CAMERA_MODE = -1;
+pub type ASI_CAMERA_MODE = c_int;
+
+pub const ASI_TRIG_OUTPUT_ASI_TRIG_OUTPUT_PINA: ASI_TRIG_OUTPUT = 0;
+pub const ASI_TRIG_OUTPUT_ASI_TRIG_OUTPUT_PINB: ASI_TRIG_OUTPUT = 1;
+pub const ASI_TRIG_OUTPUT_ASI_TRIG_OUTPUT_NONE: ASI_TRIG_OUTPUT = -1;
+pub type ASI_TRIG_OUTPUT = c_int;
+pub use self::ASI_TRIG_OUTPUT as ASI_TRIG_OUTPUT_PIN;
+
+pub const ASI_ERROR_CODE_ASI_SUCCESS: ASI_ERROR_CODE = 0;
+pub const ASI_ERROR_CODE_ASI_ERROR_INVALID_INDEX: ASI_ERROR_CODE = 1;
+pub const ASI_ERROR_CODE_ASI_ERROR_INVALID_ID: ASI_ERROR_CODE = 2;
+pub const ASI_ERROR_CODE_ASI_ERROR_INVALID_CONTROL_TYPE: ASI_ERROR_CODE = 3;
+pub const ASI_ERROR_CODE_ASI_ERROR_CAMERA_CLOSED: ASI_ERROR_CODE = 4;
+pub const ASI_ERROR_CODE_ASI_ERROR_CAMERA_REMOVED: ASI_ERROR_CODE = 5;
+pub const ASI_ERROR_CODE_ASI_ERROR_INVALID_PATH: ASI_ERROR_CODE = 6;
+pub const ASI_ERROR_CODE_ASI_ERROR_INVALID_FILEFORMAT: ASI_ERROR_CODE = 7;
+pub const ASI_ERROR_CODE_ASI_ERROR_INVALID_SIZE: ASI_ERROR_CODE = 8;
+pub const ASI_ERROR_CODE_ASI_ERROR_INVALID_IMGTYPE: ASI_ERROR_CODE = 9;
+pub const ASI_ERROR_CODE_ASI_ERROR_OUTOF_BOUNDARY: ASI_ERROR_CODE = 10;
+pub const ASI_ERROR_CODE_ASI_ERROR_TIMEOUT: ASI_ERROR_CODE = 11;
+pub const ASI_ERROR_CODE_ASI_ERROR_INVALID_SEQUENCE: ASI_ERROR_CODE = 12;
+pub const ASI_ERROR_CODE_ASI_ERROR_BUFFER_TOO_SMALL: ASI_ERROR_CODE = 13;
+pub const ASI_ERROR_CODE_ASI_ERROR_VIDEO_MODE_ACTIVE: ASI_ERROR_CODE = 14;
+pub const ASI_ERROR_CODE_ASI_ERROR_EXPOSURE_IN_PROGRESS: ASI_ERROR_CODE = 15;
+pub const ASI_ERROR_CODE_ASI_ERROR_GENERAL_ERROR: ASI_ERROR_CODE = 16;
+pub const ASI_ERROR_CODE_ASI_ERROR_INVALID_MODE: ASI_ERROR_CODE = 17;
+pub const ASI_ERROR_CODE_ASI_ERROR_END: ASI_ERROR_CODE = 18;
+pub type ASI_ERROR_CODE = c_uint;
+
+pub const ASI_BOOL_ASI_FALSE: ASI_BOOL = 0;
+pub const ASI_BOOL_ASI_TRUE: ASI_BOOL = 1;
+pub type ASI_BOOL = c_uint;
+
+#[repr(C)]
+#[derive(Debug, Copy, Clone)]
+pub struct _ASI_CAMERA_INFO {
+    pub Name: [c_char; 64],
+    pub CameraID: c_int,
+    pub MaxHeight: c_long,
+    pub MaxWidth: c_long,
+    pub IsColorCam: ASI_BOOL,
+    pub BayerPattern: ASI_BAYER_PATTERN,
+    pub SupportedBins: [c_int; 16],
+    pub SupportedVideoFormat: [ASI_IMG_TYPE; 8],
+    pub PixelSize: f64,
+    pub MechanicalShutter: ASI_BOOL,
+    pub ST4Port: ASI_BOOL,
+    pub IsCoolerCam: ASI_BOOL,
+    pub IsUSB3Host: ASI_BOOL,
+    pub IsUSB3Camera: ASI_BOOL,
+    pub ElecPerADU: f32,
+    pub BitDepth: c_int,
+    pub IsTriggerCam: ASI_BOOL,
+    pub Unused: [c_char; 16],
+}
+pub type ASI_CAMERA_INFO = _ASI_CAMERA_INFO;
+
+pub const ASI_CONTROL_TYPE_ASI_GAIN: ASI_CONTROL_TYPE = 0;
+pub const ASI_CONTROL_TYPE_ASI_EXPOSURE: ASI_CONTROL_TYPE = 1;
+pub const ASI_CONTROL_TYPE_ASI_GAMMA: ASI_CONTROL_TYPE = 2;
+pub const ASI_CONTROL_TYPE_ASI_WB_R: ASI_CONTROL_TYPE = 3;
+pub const ASI_CONTROL_TYPE_ASI_WB_B: ASI_CONTROL_TYPE = 4;
+pub const ASI_CONTROL_TYPE_ASI_OFFSET: ASI_CONTROL_TYPE = 5;
+pub const ASI_CONTROL_TYPE_ASI_BANDWIDTHOVERLOAD: ASI_CONTROL_TYPE = 6;
+pub const ASI_CONTROL_TYPE_ASI_OVERCLOCK: ASI_CONTROL_TYPE = 7;
+pub const ASI_CONTROL_TYPE_ASI_TEMPERATURE: ASI_CONTROL_TYPE = 8;
+pub const ASI_CONTROL_TYPE_ASI_FLIP: ASI_CONTROL_TYPE = 9;
+pub const ASI_CONTROL_TYPE_ASI_AUTO_MAX_GAIN: ASI_CONTROL_TYPE = 10;
+pub const ASI_CONTROL_TYPE_ASI_AUTO_MAX_EXP: ASI_CONTROL_TYPE = 11;
+pub const ASI_CONTROL_TYPE_ASI_AUTO_TARGET_BRIGHTNESS: ASI_CONTROL_TYPE = 12;
+pub const ASI_CONTROL_TYPE_ASI_HARDWARE_BIN: ASI_CONTROL_TYPE = 13;
+pub const ASI_CONTROL_TYPE_ASI_HIGH_SPEED_MODE: ASI_CONTROL_TYPE = 14;
+pub const ASI_CONTROL_TYPE_ASI_COOLER_POWER_PERC: ASI_CONTROL_TYPE = 15;
+pub const ASI_CONTROL_TYPE_ASI_TARGET_TEMP: ASI_CONTROL_TYPE = 16;
+pub const ASI_CONTROL_TYPE_ASI_COOLER_ON: ASI_CONTROL_TYPE = 17;
+pub const ASI_CONTROL_TYPE_ASI_MONO_BIN: ASI_CONTROL_TYPE = 18;
+pub const ASI_CONTROL_TYPE_ASI_FAN_ON: ASI_CONTROL_TYPE = 19;
+pub const ASI_CONTROL_TYPE_ASI_PATTERN_ADJUST: ASI_CONTROL_TYPE = 20;
+pub const ASI_CONTROL_TYPE_ASI_ANTI_DEW_HEATER: ASI_CONTROL_TYPE = 21;
+pub type ASI_CONTROL_TYPE = c_uint;
+
+#[repr(C)]
+#[derive(Debug, Copy, Clone)]
+pub struct _ASI_CONTROL_CAPS {
+    pub Name: [c_char; 64],
+    pub Description: [c_char; 128],
+    pub MaxValue: c_long,
+    pub MinValue: c_long,
+    pub DefaultValue: c_long,
+    pub IsAutoSupported: ASI_BOOL,
+    pub IsWritable: ASI_BOOL,
+    pub ControlType: ASI_CONTROL_TYPE,
+    pub Unused: [c_char; 32],
+}
+pub type ASI_CONTROL_CAPS = _ASI_CONTROL_CAPS;
+
+pub const ASI_EXPOSURE_STATUS_ASI_EXP_IDLE: ASI_EXPOSURE_STATUS = 0;
+pub const ASI_EXPOSURE_STATUS_ASI_EXP_WORKING: ASI_EXPOSURE_STATUS = 1;
+pub const ASI_EXPOSURE_STATUS_ASI_EXP_SUCCESS: ASI_EXPOSURE_STATUS = 2;
+pub const ASI_EXPOSURE_STATUS_ASI_EXP_FAILED: ASI_EXPOSURE_STATUS = 3;
+pub type ASI_EXPOSURE_STATUS = c_uint;
+
+#[repr(C)]
+#[derive(Debug, Copy, Clone)]
+pub struct _ASI_ID {
+    pub id: [c_uchar; 8],
+}
+pub type ASI_ID = _ASI_ID;
+pub type ASI_SN = ASI_ID;
+
+#[repr(C)]
+#[derive(Debug, Copy, Clone)]
+pub struct _ASI_SUPPORTED_MODE {
+    pub SupportedCameraMode: [ASI_CAMERA_MODE; 16],
+}
+pub type ASI_SUPPORTED_MODE = _ASI_SUPPORTED_MODE;
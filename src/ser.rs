@@ -0,0 +1,566 @@
+// Copyright (c) 2023 Steven Rosenthal smr@dt3.org
+// See LICENSE file in root directory for license terms.
+
+use std::fs::File;
+use std::io::{self, BufWriter, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use log::{info, warn};
+
+use crate::asi_camera2_sdk::{ASIError, ASIErrorKind,
+                             ASI_ERROR_CODE_ASI_ERROR_INVALID_FILEFORMAT,
+                             ASI_ERROR_CODE_ASI_ERROR_INVALID_IMGTYPE,
+                             ASI_ERROR_CODE_ASI_ERROR_INVALID_INDEX,
+                             ASI_ERROR_CODE_ASI_ERROR_INVALID_PATH,
+                             ASI_ERROR_CODE_ASI_ERROR_INVALID_SIZE};
+use crate::camera_backend::CameraBackend;
+use crate::frame::{self, Frame, FrameMetadata};
+use crate::types::{BayerPattern, CameraInfo, ImgType};
+
+const FILE_ID: &[u8; 14] = b"LUCAM-RECORDER";
+const HEADER_SIZE: u64 = 178;
+// Size of the Observer, Instrument and Telescope fields.
+const TEXT_FIELD_SIZE: usize = 40;
+// .NET ticks (100ns units since 0001-01-01) at the Unix epoch.
+const TICKS_AT_UNIX_EPOCH: u64 = 621_355_968_000_000_000;
+
+/// The ColorID of a SER file, giving the layout of its pixels.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SerColorId {
+    Mono,
+    BayerRggb,
+    BayerGrbg,
+    BayerGbrg,
+    BayerBggr,
+    /// Three planes per pixel, in R, G, B order.
+    Rgb,
+    /// Three planes per pixel, in B, G, R order, as the SDK delivers RGB24.
+    Bgr,
+}
+
+impl SerColorId {
+    /// Returns the ColorID for frames of `img_type` from the camera described
+    /// by `camera_info`: Bgr for RGB24, Mono for Y8 and for raw frames from
+    /// monochrome cameras, and the camera's Bayer pattern for other raw frames.
+    pub fn for_frames(camera_info: &CameraInfo, img_type: ImgType) -> Self {
+        match (img_type, camera_info.bayer_pattern) {
+            (ImgType::Rgb24, _) => SerColorId::Bgr,
+            (ImgType::Raw8 | ImgType::Raw16, Some(BayerPattern::Rg)) =>
+                SerColorId::BayerRggb,
+            (ImgType::Raw8 | ImgType::Raw16, Some(BayerPattern::Gr)) =>
+                SerColorId::BayerGrbg,
+            (ImgType::Raw8 | ImgType::Raw16, Some(BayerPattern::Gb)) =>
+                SerColorId::BayerGbrg,
+            (ImgType::Raw8 | ImgType::Raw16, Some(BayerPattern::Bg)) =>
+                SerColorId::BayerBggr,
+            _ => SerColorId::Mono,
+        }
+    }
+
+    /// Number of planes (bytes or 16-bit values) per pixel.
+    pub fn planes(self) -> usize {
+        match self {
+            SerColorId::Rgb | SerColorId::Bgr => 3,
+            _ => 1,
+        }
+    }
+
+    fn to_raw(self) -> i32 {
+        match self {
+            SerColorId::Mono => 0,
+            SerColorId::BayerRggb => 8,
+            SerColorId::BayerGrbg => 9,
+            SerColorId::BayerGbrg => 10,
+            SerColorId::BayerBggr => 11,
+            SerColorId::Rgb => 100,
+            SerColorId::Bgr => 101,
+        }
+    }
+
+    fn from_raw(raw: i32) -> Option<Self> {
+        match raw {
+            0 => Some(SerColorId::Mono),
+            8 => Some(SerColorId::BayerRggb),
+            9 => Some(SerColorId::BayerGrbg),
+            10 => Some(SerColorId::BayerGbrg),
+            11 => Some(SerColorId::BayerBggr),
+            100 => Some(SerColorId::Rgb),
+            101 => Some(SerColorId::Bgr),
+            _ => None,
+        }
+    }
+}
+
+/// The header of a SER file.
+#[derive(Clone, Debug)]
+pub struct SerHeader {
+    pub color_id: SerColorId,
+    /// Whether 16-bit values are little-endian. Always true for files written
+    /// by SerWriter.
+    pub little_endian: bool,
+    pub width: i32,
+    pub height: i32,
+    /// Bits per plane: 8, or 16 for RAW16 frames.
+    pub pixel_depth: i32,
+    pub frame_count: i32,
+    pub observer: String,
+    pub instrument: String,
+    pub telescope: String,
+    /// Start of the recording. SerWriter writes the UTC time for the local
+    /// time field too, as the time zone is not known.
+    pub date_time_utc: SystemTime,
+}
+
+impl SerHeader {
+    /// Returns the header for recording frames of `width` x `height` and
+    /// `img_type` from the camera described by `camera_info`, with the
+    /// instrument set to the camera's name.
+    pub fn new(camera_info: &CameraInfo, width: i32, height: i32, img_type: ImgType)
+               -> Self {
+        SerHeader{color_id: SerColorId::for_frames(camera_info, img_type),
+                  little_endian: true,
+                  width, height,
+                  pixel_depth: if img_type == ImgType::Raw16 { 16 } else { 8 },
+                  frame_count: 0,
+                  observer: String::new(),
+                  instrument: camera_info.name.clone(),
+                  telescope: String::new(),
+                  date_time_utc: SystemTime::now()}
+    }
+
+    /// Bytes of image data per frame, or None if that overflows.
+    pub fn frame_size(&self) -> Option<usize> {
+        let bytes_per_plane = if self.pixel_depth > 8 { 2 } else { 1 };
+        (self.width.max(0) as usize)
+            .checked_mul(self.height.max(0) as usize)?
+            .checked_mul(self.color_id.planes() * bytes_per_plane)
+    }
+
+    /// The type of the frames returned by SerReader::read_frame(), or None if
+    /// it has no ImgType (16-bit color).
+    pub fn img_type(&self) -> Option<ImgType> {
+        match (self.color_id.planes(), self.pixel_depth > 8) {
+            (1, false) => Some(ImgType::Raw8),
+            (1, true) => Some(ImgType::Raw16),
+            (_, false) => Some(ImgType::Rgb24),
+            (_, true) => None,
+        }
+    }
+
+    fn to_bytes(&self) -> Vec<u8> {
+        let ticks = to_ticks(self.date_time_utc);
+        let mut bytes = Vec::with_capacity(HEADER_SIZE as usize);
+        bytes.extend_from_slice(FILE_ID);
+        bytes.extend_from_slice(&0i32.to_le_bytes());  // LuID.
+        bytes.extend_from_slice(&self.color_id.to_raw().to_le_bytes());
+        bytes.extend_from_slice(&(self.little_endian as i32).to_le_bytes());
+        bytes.extend_from_slice(&self.width.to_le_bytes());
+        bytes.extend_from_slice(&self.height.to_le_bytes());
+        bytes.extend_from_slice(&self.pixel_depth.to_le_bytes());
+        bytes.extend_from_slice(&self.frame_count.to_le_bytes());
+        for text in [&self.observer, &self.instrument, &self.telescope] {
+            let mut field = [0u8; TEXT_FIELD_SIZE];
+            let text: Vec<u8> = text.bytes().filter(u8::is_ascii).collect();
+            let len = text.len().min(TEXT_FIELD_SIZE);
+            field[..len].copy_from_slice(&text[..len]);
+            bytes.extend_from_slice(&field);
+        }
+        bytes.extend_from_slice(&ticks.to_le_bytes());  // Local time.
+        bytes.extend_from_slice(&ticks.to_le_bytes());
+        bytes
+    }
+
+    fn from_bytes(bytes: &[u8; HEADER_SIZE as usize]) -> Result<Self, ASIError> {
+        let invalid = || ASIError::new(ASI_ERROR_CODE_ASI_ERROR_INVALID_FILEFORMAT,
+                                       "SerHeader");
+        if &bytes[..14] != FILE_ID {
+            return Err(invalid());
+        }
+        let i32_at = |offset: usize| {
+            i32::from_le_bytes(bytes[offset..offset + 4].try_into().unwrap())
+        };
+        let text_at = |offset: usize| {
+            let field = &bytes[offset..offset + TEXT_FIELD_SIZE];
+            let len = field.iter().position(|c| *c == 0).unwrap_or(TEXT_FIELD_SIZE);
+            String::from_utf8_lossy(&field[..len]).trim_end().to_string()
+        };
+        let header = SerHeader{
+            color_id: SerColorId::from_raw(i32_at(18)).ok_or_else(invalid)?,
+            little_endian: i32_at(22) != 0,
+            width: i32_at(26),
+            height: i32_at(30),
+            pixel_depth: i32_at(34),
+            frame_count: i32_at(38),
+            observer: text_at(42),
+            instrument: text_at(82),
+            telescope: text_at(122),
+            date_time_utc: from_ticks(u64::from_le_bytes(bytes[170..178].try_into().unwrap())),
+        };
+        if header.width <= 0 || header.height <= 0 || header.frame_count < 0 ||
+            !(1..=16).contains(&header.pixel_depth)
+        {
+            return Err(invalid());
+        }
+        Ok(header)
+    }
+}
+
+/// Writes frames to a SER video file, the format used by planetary imaging
+/// software. Each frame's timestamp (the start of its exposure, UTC) is
+/// recorded in the file's trailer.
+///
+/// The frame count in the header and the trailer are written by close();
+/// dropping a SerWriter closes it, but only logs errors.
+pub struct SerWriter {
+    path: PathBuf,
+    file: Option<BufWriter<File>>,
+    header: SerHeader,
+    timestamps: Vec<u64>,
+}
+
+impl SerWriter {
+    /// Creates the SER file `path`, replacing any existing file.
+    pub fn create(path: &Path, header: SerHeader) -> Result<Self, ASIError> {
+        let mut writer = SerWriter{path: path.to_path_buf(), file: None,
+                                   header, timestamps: Vec::new()};
+        writer.header.frame_count = 0;
+        let mut file = BufWriter::new(File::create(path).map_err(|e| writer.error(e))?);
+        file.write_all(&writer.header.to_bytes()).map_err(|e| writer.error(e))?;
+        writer.file = Some(file);
+        Ok(writer)
+    }
+
+    /// Creates the SER file `path` for frames of `camera`'s current ROI format.
+    pub fn for_camera<B>(camera: &B, path: &Path) -> Result<Self, ASIError>
+    where B: CameraBackend + ?Sized
+    {
+        let (width, height, _bin, img_type) = camera.get_roi_format()?;
+        Self::create(path, SerHeader::new(&camera.camera_info()?, width, height, img_type))
+    }
+
+    pub fn header(&self) -> &SerHeader { &self.header }
+
+    /// Frames written so far.
+    pub fn frame_count(&self) -> usize { self.timestamps.len() }
+
+    /// Appends `frame`, which must have the width and height of the header
+    /// and matching pixels: RAW8 or Y8 for 8-bit mono and Bayer, RAW16 for
+    /// 16-bit, RGB24 for Bgr.
+    pub fn write_frame(&mut self, frame: &Frame) -> Result<(), ASIError> {
+        if frame.width != self.header.width || frame.height != self.header.height {
+            return Err(ASIError::new(ASI_ERROR_CODE_ASI_ERROR_INVALID_SIZE,
+                                     "SerWriter::write_frame"));
+        }
+        let matches = match frame.img_type {
+            ImgType::Raw8 | ImgType::Y8 =>
+                self.header.pixel_depth <= 8 && self.header.color_id.planes() == 1,
+            ImgType::Raw16 =>
+                self.header.pixel_depth > 8 && self.header.color_id.planes() == 1,
+            ImgType::Rgb24 =>
+                self.header.pixel_depth <= 8 && self.header.color_id == SerColorId::Bgr,
+        };
+        if !matches || self.frame_count() >= i32::MAX as usize {
+            return Err(ASIError::new(ASI_ERROR_CODE_ASI_ERROR_INVALID_IMGTYPE,
+                                     "SerWriter::write_frame"));
+        }
+        let Some(file) = self.file.as_mut() else {
+            return Err(ASIError::new(ASI_ERROR_CODE_ASI_ERROR_INVALID_PATH,
+                                     "SerWriter::write_frame"));
+        };
+        let result = file.write_all(&frame.data);
+        result.map_err(|e| self.error(e))?;
+        let metadata = &frame.metadata;
+        let exposure_start = metadata.system_time.checked_sub(metadata.exposure)
+            .unwrap_or(metadata.system_time);
+        if self.timestamps.is_empty() {
+            self.header.date_time_utc = exposure_start;
+        }
+        self.timestamps.push(to_ticks(exposure_start));
+        Ok(())
+    }
+
+    /// Writes the trailer and the final header, and closes the file. Returns
+    /// the number of frames written.
+    pub fn close(mut self) -> Result<usize, ASIError> {
+        self.finish()
+    }
+
+    fn finish(&mut self) -> Result<usize, ASIError> {
+        let Some(mut file) = self.file.take() else {
+            return Ok(self.frame_count());
+        };
+        self.header.frame_count = self.frame_count() as i32;
+        let result = (|| -> io::Result<()> {
+            for timestamp in &self.timestamps {
+                file.write_all(&timestamp.to_le_bytes())?;
+            }
+            file.seek(SeekFrom::Start(0))?;
+            file.write_all(&self.header.to_bytes())?;
+            file.flush()
+        })();
+        result.map_err(|e| self.error(e))?;
+        info!("Wrote {} frames to {:?}", self.frame_count(), self.path);
+        Ok(self.frame_count())
+    }
+
+    fn error(&self, e: io::Error) -> ASIError {
+        warn!("Error writing {:?}: {}", self.path, e);
+        ASIError::new(ASI_ERROR_CODE_ASI_ERROR_INVALID_PATH, "SerWriter")
+    }
+}
+
+impl Drop for SerWriter {
+    fn drop(&mut self) {
+        if let Err(e) = self.finish() {
+            warn!("Error closing SER file {:?}: {}", self.path, e);
+        }
+    }
+}
+
+/// Reads the frames of a SER file, e.g. one written by SerWriter.
+pub struct SerReader {
+    path: PathBuf,
+    file: File,
+    header: SerHeader,
+    // Empty if the file has no trailer.
+    timestamps: Vec<SystemTime>,
+}
+
+impl SerReader {
+    pub fn open(path: &Path) -> Result<Self, ASIError> {
+        let io_error = |e: io::Error| {
+            warn!("Error reading {:?}: {}", path, e);
+            ASIError::new(ASI_ERROR_CODE_ASI_ERROR_INVALID_PATH, "SerReader::open")
+        };
+        let invalid = || {
+            ASIError::new(ASI_ERROR_CODE_ASI_ERROR_INVALID_FILEFORMAT, "SerReader::open")
+        };
+        let mut file = File::open(path).map_err(io_error)?;
+        let mut header_bytes = [0u8; HEADER_SIZE as usize];
+        file.read_exact(&mut header_bytes).map_err(|_| invalid())?;
+        let header = SerHeader::from_bytes(&header_bytes)?;
+        let file_size = file.metadata().map_err(io_error)?.len();
+        let frame_count = header.frame_count as u64;
+        let trailer_offset = header.frame_size()
+            .and_then(|frame_size| frame_count.checked_mul(frame_size as u64))
+            .and_then(|data_size| data_size.checked_add(HEADER_SIZE))
+            .ok_or_else(invalid)?;
+        if file_size < trailer_offset {
+            return Err(invalid());
+        }
+        let mut timestamps = Vec::new();
+        if file_size >= trailer_offset + frame_count * 8 {
+            file.seek(SeekFrom::Start(trailer_offset)).map_err(io_error)?;
+            let mut trailer = vec![0u8; frame_count as usize * 8];
+            file.read_exact(&mut trailer).map_err(io_error)?;
+            timestamps = trailer.chunks_exact(8)
+                .map(|ticks| from_ticks(u64::from_le_bytes(ticks.try_into().unwrap())))
+                .collect();
+        }
+        Ok(SerReader{path: path.to_path_buf(), file, header, timestamps})
+    }
+
+    pub fn header(&self) -> &SerHeader { &self.header }
+
+    pub fn frame_count(&self) -> usize { self.header.frame_count as usize }
+
+    /// The UTC timestamp of frame `index` from the trailer, if the file has
+    /// one.
+    pub fn timestamp(&self, index: usize) -> Option<SystemTime> {
+        self.timestamps.get(index).copied()
+    }
+
+    /// Reads frame `index` (counting from 0) as a Frame in the SDK's layout:
+    /// RAW8 for 8-bit mono and Bayer files, RAW16 (little-endian) for 16-bit
+    /// ones, and RGB24 (B, G, R) for 8-bit color files. 16-bit color files are
+    /// not supported.
+    ///
+    /// The metadata's sequence number is `index` and its system time the
+    /// frame's timestamp, if any; other fields have their default values.
+    pub fn read_frame(&mut self, index: usize) -> Result<Frame, ASIError> {
+        if index >= self.frame_count() {
+            return Err(ASIError::new(ASI_ERROR_CODE_ASI_ERROR_INVALID_INDEX,
+                                     "SerReader::read_frame"));
+        }
+        let Some(img_type) = self.header.img_type() else {
+            return Err(ASIError::new(ASI_ERROR_CODE_ASI_ERROR_INVALID_IMGTYPE,
+                                     "SerReader::read_frame"));
+        };
+        // Checked by open().
+        let frame_size = self.header.frame_size().unwrap();
+        let mut data = vec![0u8; frame_size];
+        let offset = HEADER_SIZE + (index * frame_size) as u64;
+        let result = self.file.seek(SeekFrom::Start(offset))
+            .and_then(|_| self.file.read_exact(&mut data));
+        result.map_err(|e| {
+            warn!("Error reading {:?}: {}", self.path, e);
+            ASIError::new(ASI_ERROR_CODE_ASI_ERROR_INVALID_PATH, "SerReader::read_frame")
+        })?;
+        if img_type == ImgType::Raw16 && !self.header.little_endian {
+            for pixel in data.chunks_exact_mut(2) {
+                pixel.swap(0, 1);
+            }
+        }
+        if self.header.color_id == SerColorId::Rgb {
+            for pixel in data.chunks_exact_mut(3) {
+                pixel.swap(0, 2);
+            }
+        }
+        debug_assert_eq!(data.len(),
+                         frame::frame_size(self.header.width, self.header.height, img_type));
+        let mut metadata = FrameMetadata{sequence_number: index as u64,
+                                         ..Default::default()};
+        if let Some(timestamp) = self.timestamp(index) {
+            metadata.system_time = timestamp;
+        }
+        Ok(Frame{width: self.header.width, height: self.header.height, bin: 1,
+                 img_type, metadata, data})
+    }
+}
+
+/// Records `num_frames` video frames from `camera`, which must be opened and
+/// initialized, to the SER file `path`. Video capture is started and then
+/// stopped again. Each wait for a frame is bounded by `wait_ms`, but timeouts
+/// are retried. Returns the number of frames written.
+///
+/// If capture fails, the frames recorded so far are kept in a valid file and
+/// the error is returned.
+pub fn record_video<B>(camera: &mut B, path: &Path, num_frames: usize, wait_ms: i32)
+                       -> Result<usize, ASIError>
+where B: CameraBackend + ?Sized
+{
+    let mut writer = SerWriter::for_camera(camera, path)?;
+    camera.start_video_capture()?;
+    let mut result = Ok(());
+    while writer.frame_count() < num_frames {
+        match camera.get_video_frame(wait_ms) {
+            Ok(frame) => {
+                if let Err(e) = writer.write_frame(&frame) {
+                    result = Err(e);
+                    break;
+                }
+            },
+            Err(e) if e.kind() == ASIErrorKind::Timeout => (),
+            Err(e) => {
+                warn!("Recording to {:?} stopped: {}", path, e);
+                result = Err(e);
+                break;
+            },
+        }
+    }
+    if let Err(e) = camera.stop_video_capture() {
+        warn!("Error stopping video capture on camera id {}: {}", camera.camera_id(), e);
+    }
+    let frames_written = writer.close()?;
+    result.map(|()| frames_written)
+}
+
+fn to_ticks(time: SystemTime) -> u64 {
+    let since_epoch = time.duration_since(UNIX_EPOCH).unwrap_or_default();
+    TICKS_AT_UNIX_EPOCH + (since_epoch.as_nanos() / 100) as u64
+}
+
+fn from_ticks(ticks: u64) -> SystemTime {
+    let ticks = ticks.saturating_sub(TICKS_AT_UNIX_EPOCH);
+    UNIX_EPOCH + Duration::from_nanos(ticks.saturating_mul(100))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use super::*;
+    use crate::simulated_camera::test_support::{simulated_camera, temp_path};
+
+    #[test]
+    fn round_trip() {
+        let path = temp_path("round_trip.ser");
+        let mut camera = simulated_camera(ImgType::Raw16);
+        let mut writer = SerWriter::for_camera(&camera, &path).unwrap();
+        camera.start_video_capture().unwrap();
+        let frames: Vec<Frame> =
+            (0..3).map(|_| camera.get_video_frame(1000).unwrap()).collect();
+        for frame in &frames {
+            writer.write_frame(frame).unwrap();
+        }
+        assert_eq!(writer.close().unwrap(), 3);
+
+        let mut reader = SerReader::open(&path).unwrap();
+        let header = reader.header().clone();
+        assert_eq!(header.color_id, SerColorId::BayerRggb);
+        assert_eq!((header.width, header.height, header.pixel_depth), (64, 32, 16));
+        assert_eq!(header.instrument, "ZWO ASI Simulator");
+        assert_eq!(reader.frame_count(), 3);
+        for (index, frame) in frames.iter().enumerate() {
+            let read = reader.read_frame(index).unwrap();
+            assert_eq!(read.img_type, ImgType::Raw16);
+            assert_eq!(read.data, frame.data);
+            // Exposure starts, to the 100ns resolution of the trailer.
+            let exposure_start = frame.metadata.system_time - frame.metadata.exposure;
+            let timestamp = from_ticks(to_ticks(exposure_start));
+            assert_eq!(reader.timestamp(index), Some(timestamp));
+            assert_eq!(read.metadata.system_time, timestamp);
+            if index == 0 {
+                assert_eq!(header.date_time_utc, timestamp);
+            }
+        }
+        assert_eq!(reader.read_frame(3).unwrap_err().kind(), ASIErrorKind::InvalidIndex);
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn record_rgb_video() {
+        let path = temp_path("record_rgb_video.ser");
+        let mut camera = simulated_camera(ImgType::Rgb24);
+        assert_eq!(record_video(&mut camera, &path, 2, 1000).unwrap(), 2);
+        let mut reader = SerReader::open(&path).unwrap();
+        assert_eq!(reader.header().color_id, SerColorId::Bgr);
+        assert_eq!(reader.frame_count(), 2);
+        assert_eq!(reader.read_frame(1).unwrap().data.len(), 64 * 32 * 3);
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn dropped_writer_is_closed() {
+        let path = temp_path("dropped_writer.ser");
+        let camera = simulated_camera(ImgType::Raw8);
+        let mut writer = SerWriter::for_camera(&camera, &path).unwrap();
+        let mut frame = Frame::new(64, 32, 1, ImgType::Raw8);
+        writer.write_frame(&frame).unwrap();
+        frame.width = 32;
+        assert_eq!(writer.write_frame(&frame).unwrap_err().kind(),
+                   ASIErrorKind::InvalidSize);
+        drop(writer);
+        let reader = SerReader::open(&path).unwrap();
+        assert_eq!(reader.frame_count(), 1);
+        assert!(reader.timestamp(0).is_some());
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn invalid_files() {
+        let path = temp_path("invalid.ser");
+        let camera_info = simulated_camera(ImgType::Raw8).camera_info();
+        let mut header = SerHeader::new(&camera_info, 64, 32, ImgType::Raw8);
+        // More frames than the file holds.
+        header.frame_count = 2;
+        fs::write(&path, header.to_bytes()).unwrap();
+        assert_eq!(SerReader::open(&path).err().unwrap().kind(),
+                   ASIErrorKind::InvalidFileFormat);
+        // Frames too large to address.
+        header.width = i32::MAX;
+        header.height = i32::MAX;
+        header.pixel_depth = 16;
+        header.color_id = SerColorId::Rgb;
+        header.frame_count = i32::MAX;
+        assert_eq!(header.frame_size(), None);
+        fs::write(&path, header.to_bytes()).unwrap();
+        assert_eq!(SerReader::open(&path).err().unwrap().kind(),
+                   ASIErrorKind::InvalidFileFormat);
+        fs::write(&path, b"LUCAM-RECORDER").unwrap();
+        assert_eq!(SerReader::open(&path).err().unwrap().kind(),
+                   ASIErrorKind::InvalidFileFormat);
+        fs::remove_file(&path).unwrap();
+    }
+}
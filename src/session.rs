@@ -0,0 +1,239 @@
+// Copyright (c) 2023 Steven Rosenthal smr@dt3.org
+// See LICENSE file in root directory for license terms.
+
+use std::collections::HashMap;
+use std::time::Duration;
+
+use log::warn;
+
+use crate::asi_camera2_sdk::ASIError;
+#[cfg(feature = "sdk")]
+use crate::asi_camera2_sdk::ASICamera;
+use crate::camera_backend::CameraBackend;
+use crate::exposure::ExposureOptions;
+use crate::frame::Frame;
+use crate::trigger::TriggeredCapture;
+use crate::types::{CameraMode, ControlCaps, ControlType, ExposureStatus, GainPreset,
+                   GuideDirection, ImgType, TriggerOutputConfig, TriggerOutputPin};
+
+/// An opened and initialized camera, ready to be configured and to capture.
+///
+/// Camera and VideoSession encode the SDK's sequencing rules in the type
+/// system:
+/// * A Camera can only be obtained by opening and initializing the camera, so
+///   it cannot be configured before init.
+/// * start_video_capture() returns a VideoSession that mutably borrows the
+///   Camera, so single-frame exposures (and ROI changes) are unavailable until
+///   the session is stopped or dropped.
+///
+/// The underlying backend remains available via backend() and backend_mut()
+/// for operations not covered here.
+pub struct Camera<B: CameraBackend> {
+    backend: B,
+}
+
+#[cfg(feature = "sdk")]
+impl Camera<ASICamera> {
+    /// Opens and initializes the camera with the given `camera_id` (see
+    /// ASICamera::get_property()).
+    pub fn open(camera_id: i32) -> Result<Self, ASIError> {
+        Camera::new(ASICamera::new(camera_id))
+    }
+}
+
+impl<B: CameraBackend> Camera<B> {
+    /// Opens and initializes `backend`.
+    pub fn new(mut backend: B) -> Result<Self, ASIError> {
+        backend.open()?;
+        backend.init()?;
+        Ok(Camera{backend})
+    }
+
+    pub fn backend(&self) -> &B { &self.backend }
+    pub fn backend_mut(&mut self) -> &mut B { &mut self.backend }
+
+    /// Closes the camera, returning the backend.
+    pub fn close(mut self) -> Result<B, ASIError> {
+        self.backend.close()?;
+        Ok(self.backend)
+    }
+
+    pub fn camera_id(&self) -> i32 { self.backend.camera_id() }
+
+    /// See ASICamera::get_id() and set_id().
+    pub fn get_id(&self) -> Result<String, ASIError> { self.backend.get_id() }
+    pub fn set_id(&mut self, alias: &str) -> Result<(), ASIError> {
+        self.backend.set_id(alias)
+    }
+    pub fn get_serial_number(&self) -> Result<String, ASIError> {
+        self.backend.get_serial_number()
+    }
+
+    pub fn controls(&self) -> Result<HashMap<ControlType, ControlCaps>, ASIError> {
+        self.backend.controls()
+    }
+
+    /// The return value is the control's value and whether it is automatic.
+    pub fn get_control_value(&self, control_type: ControlType)
+                             -> Result<(i64, bool), ASIError> {
+        self.backend.get_control_value(control_type)
+    }
+    pub fn set_control_value(&mut self, control_type: ControlType,
+                             value: i64, auto: bool) -> Result<(), ASIError> {
+        self.backend.set_control_value(control_type, value, auto)
+    }
+
+    /// Sets the Gain and Offset controls; see CameraBackend::gain_preset().
+    pub fn apply_gain_preset(&mut self, preset: GainPreset)
+                             -> Result<(i64, i64), ASIError> {
+        self.backend.apply_gain_preset(preset)
+    }
+
+    /// The return value is (width, height, bin, img_type).
+    pub fn get_roi_format(&self) -> Result<(i32, i32, i32, ImgType), ASIError> {
+        self.backend.get_roi_format()
+    }
+    pub fn set_roi_format(&mut self, width: i32, height: i32,
+                          bin: i32, img_type: ImgType) -> Result<(), ASIError> {
+        self.backend.set_roi_format(width, height, bin, img_type)
+    }
+
+    /// The return value is (x, y).
+    pub fn get_start_pos(&self) -> Result<(i32, i32), ASIError> {
+        self.backend.get_start_pos()
+    }
+    pub fn set_start_pos(&mut self, start_x: i32, start_y: i32)
+                         -> Result<(), ASIError> {
+        self.backend.set_start_pos(start_x, start_y)
+    }
+
+    /// See CameraBackend::pulse_guide().
+    pub fn pulse_guide(&self, direction: GuideDirection, duration: Duration)
+                       -> Result<(), ASIError> {
+        self.backend.pulse_guide(direction, duration)
+    }
+
+    /// `is_dark` is relevant only if the camera has a mechanical shutter.
+    pub fn start_exposure(&mut self, is_dark: bool) -> Result<(), ASIError> {
+        self.backend.start_exposure(is_dark)
+    }
+    pub fn stop_exposure(&mut self) -> Result<(), ASIError> {
+        self.backend.stop_exposure()
+    }
+    pub fn get_exp_status(&self) -> Result<ExposureStatus, ASIError> {
+        self.backend.get_exp_status()
+    }
+    pub fn read_data_after_exp(&self, buffer: &mut [u8]) -> Result<(), ASIError> {
+        self.backend.read_data_after_exp(buffer)
+    }
+    pub fn read_data_after_exp_u16(&self, buffer: &mut [u16]) -> Result<(), ASIError> {
+        self.backend.read_data_after_exp_u16(buffer)
+    }
+    pub fn get_frame_after_exp(&self) -> Result<Frame, ASIError> {
+        self.backend.get_frame_after_exp()
+    }
+    pub fn capture_exposure(&mut self, duration: Duration, is_dark: bool,
+                            timeout: Option<Duration>) -> Result<Frame, ASIError> {
+        self.backend.capture_exposure(duration, is_dark, timeout)
+    }
+    pub fn capture_exposure_with(&mut self, duration: Duration, is_dark: bool,
+                                 timeout: Option<Duration>, options: &ExposureOptions)
+                                 -> Result<Frame, ASIError> {
+        self.backend.capture_exposure_with(duration, is_dark, timeout, options)
+    }
+
+    /// Starts video capture. Capture stops when the returned VideoSession is
+    /// stopped or dropped.
+    pub fn start_video_capture(&mut self) -> Result<VideoSession<'_, B>, ASIError> {
+        self.backend.start_video_capture()?;
+        Ok(VideoSession{camera: self})
+    }
+
+    /// Only for trigger cameras.
+    pub fn get_camera_support_mode(&self) -> Result<Vec<CameraMode>, ASIError> {
+        self.backend.get_camera_support_mode()
+    }
+    pub fn get_trigger_output_config(&self, pin: TriggerOutputPin)
+                                     -> Result<TriggerOutputConfig, ASIError> {
+        self.backend.get_trigger_output_config(pin)
+    }
+    pub fn set_trigger_output_config(&mut self, pin: TriggerOutputPin,
+                                     config: &TriggerOutputConfig)
+                                     -> Result<(), ASIError> {
+        self.backend.set_trigger_output_config(pin, config)
+    }
+
+    /// Starts video capture in the trigger mode `mode`. Capture stops, and the
+    /// camera returns to CameraMode::Normal, when the returned TriggeredCapture
+    /// is stopped or dropped.
+    pub fn start_triggered_capture(&mut self, mode: CameraMode)
+                                   -> Result<TriggeredCapture<'_, B>, ASIError> {
+        TriggeredCapture::start(&mut self.backend, mode)
+    }
+}
+
+/// Video capture in progress on a Camera. Dropping the session stops capture;
+/// call stop() instead to find out whether that succeeded.
+pub struct VideoSession<'a, B: CameraBackend> {
+    camera: &'a mut Camera<B>,
+}
+
+impl<B: CameraBackend> VideoSession<'_, B> {
+    pub fn camera(&self) -> &Camera<B> { self.camera }
+
+    /// Stops video capture.
+    pub fn stop(self) -> Result<(), ASIError> {
+        let result = self.camera.backend.stop_video_capture();
+        std::mem::forget(self);
+        result
+    }
+
+    /// Returns the next frame, waiting up to `wait_ms` (-1 means forever).
+    pub fn get_video_frame(&self, wait_ms: i32) -> Result<Frame, ASIError> {
+        self.camera.backend.get_video_frame(wait_ms)
+    }
+    pub fn read_video_data(&self, buffer: &mut [u8], wait_ms: i32)
+                           -> Result<(), ASIError> {
+        self.camera.backend.read_video_data(buffer, wait_ms)
+    }
+    pub fn read_video_data_u16(&self, buffer: &mut [u16], wait_ms: i32)
+                               -> Result<(), ASIError> {
+        self.camera.backend.read_video_data_u16(buffer, wait_ms)
+    }
+
+    pub fn get_dropped_frames(&self) -> Result<i32, ASIError> {
+        self.camera.backend.get_dropped_frames()
+    }
+
+    /// Controls (e.g. exposure time and gain) can be changed while video
+    /// capture is running.
+    pub fn get_control_value(&self, control_type: ControlType)
+                             -> Result<(i64, bool), ASIError> {
+        self.camera.get_control_value(control_type)
+    }
+    pub fn set_control_value(&mut self, control_type: ControlType,
+                             value: i64, auto: bool) -> Result<(), ASIError> {
+        self.camera.set_control_value(control_type, value, auto)
+    }
+
+    /// Guiding usually runs alongside video capture.
+    pub fn pulse_guide(&self, direction: GuideDirection, duration: Duration)
+                       -> Result<(), ASIError> {
+        self.camera.pulse_guide(direction, duration)
+    }
+
+    /// Moving the ROI while video capture is running does not interrupt it.
+    pub fn set_start_pos(&mut self, start_x: i32, start_y: i32)
+                         -> Result<(), ASIError> {
+        self.camera.set_start_pos(start_x, start_y)
+    }
+}
+
+impl<B: CameraBackend> Drop for VideoSession<'_, B> {
+    fn drop(&mut self) {
+        if let Err(e) = self.camera.backend.stop_video_capture() {
+            warn!("Error stopping video capture on camera id {}: {}",
+                  self.camera.camera_id(), e);
+        }
+    }
+}
@@ -0,0 +1,1238 @@
+// Copyright (c) 2023 Steven Rosenthal smr@dt3.org
+// See LICENSE file in root directory for license terms.
+
+use std::collections::VecDeque;
+use std::f64::consts::PI;
+use std::path::Path;
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::thread::sleep;
+use std::time::{Duration, Instant};
+
+use log::{info, warn};
+
+use crate::asi_camera2_sdk::{
+    ASIError,
+    ASI_ERROR_CODE_ASI_ERROR_BUFFER_TOO_SMALL,
+    ASI_ERROR_CODE_ASI_ERROR_CAMERA_CLOSED,
+    ASI_ERROR_CODE_ASI_ERROR_EXPOSURE_IN_PROGRESS,
+    ASI_ERROR_CODE_ASI_ERROR_GENERAL_ERROR,
+    ASI_ERROR_CODE_ASI_ERROR_INVALID_CONTROL_TYPE,
+    ASI_ERROR_CODE_ASI_ERROR_INVALID_FILEFORMAT,
+    ASI_ERROR_CODE_ASI_ERROR_INVALID_IMGTYPE,
+    ASI_ERROR_CODE_ASI_ERROR_INVALID_INDEX,
+    ASI_ERROR_CODE_ASI_ERROR_INVALID_MODE,
+    ASI_ERROR_CODE_ASI_ERROR_INVALID_SEQUENCE,
+    ASI_ERROR_CODE_ASI_ERROR_INVALID_SIZE,
+    ASI_ERROR_CODE_ASI_ERROR_OUTOF_BOUNDARY,
+    ASI_ERROR_CODE_ASI_ERROR_TIMEOUT,
+    ASI_ERROR_CODE_ASI_ERROR_VIDEO_MODE_ACTIVE,
+    ASI_FLIP_STATUS,
+    ASI_ID,
+    ASI_SN,
+    ASI_TRIG_OUTPUT_PIN,
+};
+use crate::camera_backend::{self, CameraBackend};
+use crate::dark_frame;
+use crate::frame;
+use crate::pulse_guide::PulseGuider;
+use crate::types::{BayerPattern, CameraInfo, CameraMode, ControlCaps, ControlType,
+                   ExposureStatus, FlipStatus, GainOffsets, GuideDirection, ImgType,
+                   LmhGainOffsets,
+                   TriggerOutputConfig, TriggerOutputPin, asi_id_from_str,
+                   asi_id_to_string, serial_number_to_hex};
+use crate::lock_ignoring_poison;
+
+/// A point source in the simulated star field. `x` and `y` are in unbinned
+/// sensor pixels; `flux` is in electrons per second.
+#[derive(Clone, Debug)]
+pub struct SimulatedStar {
+    pub x: f64,
+    pub y: f64,
+    pub flux: f64,
+}
+
+impl SimulatedStar {
+    /// Returns `num_stars` stars scattered uniformly over a `width` x `height`
+    /// sensor, with fluxes spread log-uniformly between 100 and 100000
+    /// electrons per second. The same `seed` always yields the same field.
+    pub fn random_field(num_stars: usize, width: i32, height: i32, seed: u64)
+                        -> Vec<SimulatedStar> {
+        let mut rng = Rng::new(seed);
+        (0..num_stars).map(|_| SimulatedStar{
+            x: rng.next_f64() * width as f64,
+            y: rng.next_f64() * height as f64,
+            flux: 10f64.powf(2.0 + 3.0 * rng.next_f64()),
+        }).collect()
+    }
+}
+
+/// Describes the sensor and the scene rendered by a SimulatedCamera.
+#[derive(Clone, Debug)]
+pub struct SimulatorConfig {
+    pub camera_id: i32,
+    pub name: String,
+    pub max_width: i32,
+    pub max_height: i32,
+    /// Color cameras additionally support ASI_IMG_RGB24.
+    pub is_color: bool,
+    pub supported_bins: Vec<i32>,
+    /// Microns.
+    pub pixel_size: f64,
+    /// Native ADC bit depth, between 8 and 16. RAW16 frames are scaled up to
+    /// 16 bits and RAW8 frames are scaled down to 8 bits, as the SDK does.
+    pub bit_depth: i32,
+    /// Electrons per ADU at gain 0.
+    pub elec_per_adu: f64,
+    /// Electrons RMS.
+    pub read_noise: f64,
+    /// Electrons per second per pixel.
+    pub dark_current: f64,
+    /// Electrons per second per pixel.
+    pub sky_background: f64,
+    /// Degrees C.
+    pub temperature: f64,
+    /// Initial alias; see CameraBackend::set_id().
+    pub alias: String,
+    /// None if the camera has no serial number.
+    pub serial_number: Option<[u8; 8]>,
+    /// Trigger cameras support all of the CameraModes; see
+    /// SimulatedCamera::set_trigger_input().
+    pub is_trigger_cam: bool,
+    /// Gaussian sigma of the star images, in unbinned pixels.
+    pub star_sigma: f64,
+    pub stars: Vec<SimulatedStar>,
+    /// Seeds the noise generator, so that runs are repeatable.
+    pub seed: u64,
+    /// How long after the exposure time an exposure reports
+    /// ExposureStatus::Success; real cameras take a while to read out.
+    pub readout_time: Duration,
+    /// This many exposures, counting from creation or init(), end in
+    /// ExposureStatus::Failed instead of Success.
+    pub failed_exposures: u32,
+}
+
+impl Default for SimulatorConfig {
+    fn default() -> Self {
+        SimulatorConfig{
+            camera_id: 0,
+            name: "ZWO ASI Simulator".to_string(),
+            max_width: 1280,
+            max_height: 960,
+            is_color: false,
+            supported_bins: vec![1, 2, 3, 4],
+            pixel_size: 3.75,
+            bit_depth: 12,
+            elec_per_adu: 4.0,
+            read_noise: 3.0,
+            dark_current: 0.01,
+            sky_background: 20.0,
+            temperature: 20.0,
+            alias: String::new(),
+            serial_number: Some(*b"ASISIM00"),
+            is_trigger_cam: false,
+            star_sigma: 1.2,
+            stars: SimulatedStar::random_field(50, 1280, 960, /*seed=*/1),
+            seed: 1,
+            readout_time: Duration::ZERO,
+            failed_exposures: 0,
+        }
+    }
+}
+
+/// A pure-Rust stand-in for ASICamera. Frames are rendered from the
+/// SimulatorConfig's star field plus sky background, dark current, shot noise
+/// and read noise, and honor the exposure time, gain, offset, flip, ROI,
+/// binning and image type settings. Exposures and video frames take as long
+/// as the exposure time, as they would on a real camera.
+///
+/// The simulated camera has an ST4 port; guide pulses do not move the star
+/// field, but their total duration in each direction is recorded (see
+/// guide_pulse_time()).
+pub struct SimulatedCamera {
+    config: SimulatorConfig,
+    // Like the camera's flash, survives init().
+    alias: ASI_ID,
+    state: Mutex<SimState>,
+    // Shared with pulse_guider's timer thread.
+    guide_outputs: Arc<Mutex<GuideOutputs>>,
+    pulse_guider: PulseGuider,
+    frames_read: AtomicU64,
+}
+
+impl SimulatedCamera {
+    /// The returned instance is *not* opened by this function, you need to call
+    /// open() explicitly.
+    ///
+    /// Panics if `config.bit_depth` is not between 8 and 16.
+    pub fn new(config: SimulatorConfig) -> Self {
+        assert!((8..=16).contains(&config.bit_depth),
+                "SimulatorConfig::bit_depth {} is not between 8 and 16", config.bit_depth);
+        info!("Created SimulatedCamera id {}", config.camera_id);
+        let state = SimState::new(&config);
+        let guide_outputs = Arc::new(Mutex::new(GuideOutputs::default()));
+        let outputs = guide_outputs.clone();
+        let pulse_guider = PulseGuider::new(move |direction, on| {
+            lock_ignoring_poison(&outputs).switch(direction, on);
+            Ok(())
+        });
+        let alias = asi_id_from_str(&config.alias, "SimulatedCamera::new")
+            .unwrap_or_else(|e| {
+                warn!("Ignoring invalid alias: {}", e);
+                ASI_ID{id: [0; 8]}
+            });
+        SimulatedCamera{config, alias, state: Mutex::new(state), guide_outputs,
+                        pulse_guider, frames_read: AtomicU64::new(0)}
+    }
+
+    pub fn config(&self) -> &SimulatorConfig { &self.config }
+
+    /// Describes the simulated camera in the same form as
+    /// ASICamera::get_property().
+    pub fn camera_info(&self) -> CameraInfo {
+        let config = &self.config;
+        CameraInfo{
+            name: config.name.clone(),
+            camera_id: config.camera_id,
+            max_height: config.max_height,
+            max_width: config.max_width,
+            is_color_cam: config.is_color,
+            bayer_pattern: if config.is_color { Some(BayerPattern::Rg) } else { None },
+            supported_bins: config.supported_bins.iter().map(|bin| *bin as u32).collect(),
+            supported_video_formats: supported_img_types(config),
+            pixel_size: config.pixel_size,
+            mechanical_shutter: false,
+            st4_port: true,
+            is_cooler_cam: false,
+            is_usb3_host: true,
+            is_usb3_camera: true,
+            elec_per_adu: config.elec_per_adu as f32,
+            bit_depth: config.bit_depth,
+            is_trigger_cam: config.is_trigger_cam,
+        }
+    }
+
+    /// Sets the level of the simulated camera's hardware trigger input, for
+    /// the TrigRiseEdge, TrigFallEdge, TrigHighLevel and TrigLowLevel modes.
+    pub fn set_trigger_input(&self, high: bool) {
+        let mut state = self.lock_state();
+        if state.trigger_input == high {
+            return;
+        }
+        state.trigger_input = high;
+        let active = match state.camera_mode {
+            CameraMode::TrigRiseEdge | CameraMode::TrigHighLevel => high,
+            CameraMode::TrigFallEdge | CameraMode::TrigLowLevel => !high,
+            _ => return,
+        };
+        state.trigger(active);
+    }
+
+    /// Returns how long the guide output for `direction` has been on, in
+    /// total, since the camera was created.
+    pub fn guide_pulse_time(&self, direction: GuideDirection) -> Duration {
+        let outputs = lock_ignoring_poison(&self.guide_outputs);
+        let index = u32::from(direction) as usize;
+        outputs.total[index] +
+            outputs.on_since[index].map_or(Duration::ZERO, |since| since.elapsed())
+    }
+
+    fn lock_state(&self) -> MutexGuard<'_, SimState> {
+        lock_ignoring_poison(&self.state)
+    }
+
+    fn check_buffer(state: &SimState, buff_size: i64, source: &str)
+                    -> Result<usize, ASIError> {
+        let frame_size = state.frame_size();
+        if buff_size < frame_size as i64 {
+            return Err(ASIError::new(ASI_ERROR_CODE_ASI_ERROR_BUFFER_TOO_SMALL, source));
+        }
+        Ok(frame_size)
+    }
+
+    // Fills `buffer` with a frame exposed for `exposure`, using the current ROI
+    // format and control settings.
+    fn render(&self, state: &mut SimState, exposure: Duration, buffer: &mut [u8]) {
+        let config = &self.config;
+        let width = state.width as usize;
+        let height = state.height as usize;
+        let bin = state.bin as i64;
+        let exposure_secs = exposure.as_secs_f64();
+
+        // Expected signal, in electrons, of each binned pixel.
+        let background = (config.sky_background + config.dark_current) *
+            exposure_secs * (bin * bin) as f64;
+        let mut electrons = vec![background; width * height];
+        let sigma = config.star_sigma;
+        let radius = (4.0 * sigma).ceil() as i64;
+        let roi_x = state.start_x as i64 * bin;
+        let roi_y = state.start_y as i64 * bin;
+        for star in &config.stars {
+            let norm = star.flux * exposure_secs / (2.0 * PI * sigma * sigma);
+            let (cx, cy) = (star.x as i64, star.y as i64);
+            for sy in cy - radius..=cy + radius {
+                for sx in cx - radius..=cx + radius {
+                    if sx < roi_x || sy < roi_y {
+                        continue;
+                    }
+                    let ox = ((sx - roi_x) / bin) as usize;
+                    let oy = ((sy - roi_y) / bin) as usize;
+                    if ox >= width || oy >= height {
+                        continue;
+                    }
+                    let dx = sx as f64 + 0.5 - star.x;
+                    let dy = sy as f64 + 0.5 - star.y;
+                    electrons[oy * width + ox] +=
+                        norm * (-(dx * dx + dy * dy) / (2.0 * sigma * sigma)).exp();
+                }
+            }
+        }
+
+        // Convert to ADU. Gain is in units of 0.1dB.
+        let gain = state.control_value(ControlType::Gain) as f64;
+        let elec_per_adu = config.elec_per_adu / 10f64.powf(gain / 200.0);
+        let offset = state.control_value(ControlType::Offset) as f64;
+        let read_noise = config.read_noise * bin as f64;
+        let bit_depth = config.bit_depth as u32;
+        let max_adu = ((1u32 << bit_depth) - 1) as f64;
+        // The Flip control's range is that of FlipStatus.
+        let flip = FlipStatus::try_from(
+            state.control_value(ControlType::Flip) as ASI_FLIP_STATUS).unwrap();
+        let flip_horiz = flip.is_horizontal();
+        let flip_vert = flip.is_vertical();
+        for oy in 0..height {
+            for ox in 0..width {
+                let signal = electrons[oy * width + ox];
+                let noise = (signal + read_noise * read_noise).sqrt();
+                let gaussian =
+                    state.gaussians[(state.rng.next_u64() >> 48) as usize];
+                let value = signal + noise * gaussian;
+                let adu = (value / elec_per_adu + offset).round().clamp(0.0, max_adu)
+                    as u32;
+                let adu = match &state.dark {
+                    // Like the SDK, dark subtraction applies only to 8-bit
+                    // outputs. The dark frame is in unbinned sensor pixels.
+                    Some(dark) if state.img_type != ImgType::Raw16 => {
+                        let sx = (roi_x + ox as i64 * bin) as usize;
+                        let sy = (roi_y + oy as i64 * bin) as usize;
+                        let dark_adu = dark[sy * config.max_width as usize + sx] as u32;
+                        adu.saturating_sub(dark_adu << (bit_depth - 8))
+                    },
+                    _ => adu,
+                };
+                let x = if flip_horiz { width - 1 - ox } else { ox };
+                let y = if flip_vert { height - 1 - oy } else { oy };
+                let index = y * width + x;
+                match state.img_type {
+                    ImgType::Raw16 => {
+                        let pixel = ((adu << (16 - bit_depth)) as u16).to_le_bytes();
+                        buffer[2 * index..2 * index + 2].copy_from_slice(&pixel);
+                    },
+                    ImgType::Rgb24 => {
+                        let pixel = (adu >> (bit_depth - 8)) as u8;
+                        buffer[3 * index..3 * index + 3].fill(pixel);
+                    },
+                    _ => {
+                        buffer[index] = (adu >> (bit_depth - 8)) as u8;
+                    },
+                }
+            }
+        }
+    }
+}
+
+impl CameraBackend for SimulatedCamera {
+    fn camera_id(&self) -> i32 { self.config.camera_id }
+    fn camera_info(&self) -> Result<CameraInfo, ASIError> {
+        Ok(SimulatedCamera::camera_info(self))
+    }
+
+    fn open(&mut self) -> Result<(), ASIError> {
+        let mut state = self.lock_state();
+        if !state.opened {
+            state.opened = true;
+            camera_backend::set_camera_open::<SimulatedCamera>(self.config.camera_id, true);
+        }
+        Ok(())
+    }
+
+    fn init(&self) -> Result<(), ASIError> {
+        let mut state = self.lock_state();
+        state.check_open("init")?;
+        *state = SimState::new(&self.config);
+        state.opened = true;
+        Ok(())
+    }
+
+    fn close(&mut self) -> Result<(), ASIError> {
+        self.pulse_guider.stop();
+        let mut state = self.lock_state();
+        if state.opened {
+            state.opened = false;
+            camera_backend::set_camera_open::<SimulatedCamera>(self.config.camera_id, false);
+        }
+        state.video_active = false;
+        state.exp_status = ExposureStatus::Idle;
+        Ok(())
+    }
+
+    fn get_num_controls(&self) -> Result<i32, ASIError> {
+        let state = self.lock_state();
+        state.check_open("get_num_controls")?;
+        Ok(state.controls.len() as i32)
+    }
+
+    fn get_control_caps(&self, control_index: i32)
+                        -> Result<ControlCaps, ASIError> {
+        let state = self.lock_state();
+        state.check_open("get_control_caps")?;
+        match usize::try_from(control_index).ok()
+            .and_then(|index| state.controls.get(index))
+        {
+            Some(control) => Ok(control.caps.clone()),
+            None => Err(ASIError::new(ASI_ERROR_CODE_ASI_ERROR_INVALID_INDEX,
+                                   "get_control_caps")),
+        }
+    }
+
+    fn get_control_value(&self, control_type: ControlType)
+                         -> Result<(i64, bool), ASIError> {
+        let state = self.lock_state();
+        state.check_open("get_control_value")?;
+        let control = state.find_control(control_type, "get_control_value")?;
+        Ok((control.value, control.auto))
+    }
+
+    fn set_control_value(&mut self, control_type: ControlType,
+                         value: i64, auto: bool) -> Result<(), ASIError> {
+        let mut state = self.lock_state();
+        state.check_open("set_control_value")?;
+        let control = state.find_control_mut(control_type, "set_control_value")?;
+        if !control.caps.is_writable {
+            return Err(ASIError::new(ASI_ERROR_CODE_ASI_ERROR_GENERAL_ERROR,
+                                     "set_control_value"));
+        }
+        // Like the SDK, clamp out of range values rather than failing.
+        control.value = value.clamp(control.caps.min_value, control.caps.max_value);
+        control.auto = auto && control.caps.is_auto_supported;
+        Ok(())
+    }
+
+    fn get_roi_format(&self) -> Result<(i32, i32, i32, ImgType), ASIError> {
+        let state = self.lock_state();
+        state.check_open("get_roi_format")?;
+        Ok((state.width, state.height, state.bin, state.img_type))
+    }
+
+    fn set_roi_format(&mut self, width: i32, height: i32,
+                      bin: i32, img_type: ImgType) -> Result<(), ASIError> {
+        let mut state = self.lock_state();
+        state.check_open("set_roi_format")?;
+        if !self.config.supported_bins.contains(&bin) ||
+            width <= 0 || height <= 0 || width % 8 != 0 || height % 2 != 0 ||
+            width * bin > self.config.max_width ||
+            height * bin > self.config.max_height
+        {
+            return Err(ASIError::new(ASI_ERROR_CODE_ASI_ERROR_INVALID_SIZE,
+                                     "set_roi_format"));
+        }
+        if !supported_img_types(&self.config).contains(&img_type) {
+            return Err(ASIError::new(ASI_ERROR_CODE_ASI_ERROR_INVALID_IMGTYPE,
+                                     "set_roi_format"));
+        }
+        state.width = width;
+        state.height = height;
+        state.bin = bin;
+        state.img_type = img_type;
+        // Like the SDK, center the new ROI.
+        state.start_x = (self.config.max_width / bin - width) / 2;
+        state.start_y = (self.config.max_height / bin - height) / 2;
+        Ok(())
+    }
+
+    fn get_start_pos(&self) -> Result<(i32, i32), ASIError> {
+        let state = self.lock_state();
+        state.check_open("get_start_pos")?;
+        Ok((state.start_x, state.start_y))
+    }
+
+    fn set_start_pos(&mut self, start_x: i32, start_y: i32)
+                     -> Result<(), ASIError> {
+        let mut state = self.lock_state();
+        state.check_open("set_start_pos")?;
+        // The start position is relative to the binned image.
+        if start_x < 0 || start_y < 0 ||
+            (start_x + state.width) * state.bin > self.config.max_width ||
+            (start_y + state.height) * state.bin > self.config.max_height
+        {
+            return Err(ASIError::new(ASI_ERROR_CODE_ASI_ERROR_OUTOF_BOUNDARY,
+                                     "set_start_pos"));
+        }
+        state.start_x = start_x;
+        state.start_y = start_y;
+        Ok(())
+    }
+
+    fn get_dropped_frames(&self) -> Result<i32, ASIError> {
+        let state = self.lock_state();
+        state.check_open("get_dropped_frames")?;
+        Ok(state.dropped_frames)
+    }
+
+    fn next_sequence_number(&self) -> u64 {
+        self.frames_read.fetch_add(1, Ordering::Relaxed)
+    }
+
+    fn enable_dark_subtract(&mut self, bmp_path: &Path) -> Result<(), ASIError> {
+        self.lock_state().check_open("enable_dark_subtract")?;
+        let (width, height, pixels) = dark_frame::read_bmp(bmp_path)?;
+        if width != self.config.max_width || height != self.config.max_height {
+            return Err(ASIError::new(ASI_ERROR_CODE_ASI_ERROR_INVALID_FILEFORMAT,
+                                     "enable_dark_subtract"));
+        }
+        self.lock_state().dark = Some(pixels);
+        Ok(())
+    }
+
+    fn disable_dark_subtract(&mut self) -> Result<(), ASIError> {
+        let mut state = self.lock_state();
+        state.check_open("disable_dark_subtract")?;
+        state.dark = None;
+        Ok(())
+    }
+
+    fn start_video_capture(&mut self) -> Result<(), ASIError> {
+        let mut state = self.lock_state();
+        state.check_open("start_video_capture")?;
+        state.update_exp_status();
+        if state.exp_status == ExposureStatus::Working {
+            return Err(ASIError::new(ASI_ERROR_CODE_ASI_ERROR_EXPOSURE_IN_PROGRESS,
+                                     "start_video_capture"));
+        }
+        if !state.video_active {
+            state.video_active = true;
+            state.dropped_frames = 0;
+            state.next_frame_time = Instant::now() + state.exposure();
+        }
+        Ok(())
+    }
+
+    fn stop_video_capture(&mut self) -> Result<(), ASIError> {
+        let mut state = self.lock_state();
+        state.check_open("stop_video_capture")?;
+        state.video_active = false;
+        state.level_start = None;
+        state.triggered_frames.clear();
+        Ok(())
+    }
+
+    unsafe fn get_video_data(&self, buffer: *mut u8, buff_size: i64, wait_ms: i32)
+                             -> Result<(), ASIError> {
+        let deadline = if wait_ms < 0 {
+            None
+        } else {
+            Some(Instant::now() + Duration::from_millis(wait_ms as u64))
+        };
+        loop {
+            let mut state = self.lock_state();
+            state.check_open("get_video_data")?;
+            if !state.video_active {
+                return Err(ASIError::new(ASI_ERROR_CODE_ASI_ERROR_INVALID_SEQUENCE,
+                                         "get_video_data"));
+            }
+            let frame_size = Self::check_buffer(&state, buff_size, "get_video_data")?;
+            let now = Instant::now();
+            let wake_time = if state.camera_mode.is_triggered() {
+                match state.triggered_frames.front() {
+                    Some(&(ready, exposure)) if ready <= now => {
+                        state.triggered_frames.pop_front();
+                        let pixels = std::slice::from_raw_parts_mut(buffer, frame_size);
+                        self.render(&mut state, exposure, pixels);
+                        return Ok(());
+                    },
+                    Some(&(ready, _)) => ready,
+                    // The trigger could come from another thread at any time.
+                    None => now + TRIGGER_POLL_INTERVAL,
+                }
+            } else {
+                let exposure = state.exposure();
+                // Frames that completed while nobody was waiting for them are
+                // dropped, like the SDK's small video buffer does.
+                if now > state.next_frame_time + exposure {
+                    let missed = ((now - state.next_frame_time).as_secs_f64() /
+                                  exposure.as_secs_f64()) as u32;
+                    state.dropped_frames = state.dropped_frames
+                        .saturating_add(i32::try_from(missed).unwrap_or(i32::MAX));
+                    state.next_frame_time += exposure * missed;
+                }
+                if now >= state.next_frame_time {
+                    state.next_frame_time += exposure;
+                    let pixels = std::slice::from_raw_parts_mut(buffer, frame_size);
+                    self.render(&mut state, exposure, pixels);
+                    return Ok(());
+                }
+                state.next_frame_time
+            };
+            let mut wake_time = wake_time;
+            if let Some(deadline) = deadline {
+                if deadline <= now {
+                    return Err(ASIError::new(ASI_ERROR_CODE_ASI_ERROR_TIMEOUT,
+                                             "get_video_data"));
+                }
+                wake_time = wake_time.min(deadline);
+            }
+            // Don't hold the lock while waiting for the frame.
+            drop(state);
+            sleep(wake_time - now);
+        }
+    }
+
+    fn pulse_guide_on(&self, direction: GuideDirection) -> Result<(), ASIError> {
+        self.lock_state().check_open("pulse_guide_on")?;
+        lock_ignoring_poison(&self.guide_outputs).switch(direction, true);
+        Ok(())
+    }
+
+    fn pulse_guide_off(&self, direction: GuideDirection) -> Result<(), ASIError> {
+        self.lock_state().check_open("pulse_guide_off")?;
+        lock_ignoring_poison(&self.guide_outputs).switch(direction, false);
+        Ok(())
+    }
+
+    fn pulse_guide(&self, direction: GuideDirection, duration: Duration)
+                   -> Result<(), ASIError> {
+        self.lock_state().check_open("pulse_guide")?;
+        self.pulse_guider.pulse(direction, duration)
+    }
+
+    fn start_exposure(&mut self, _is_dark: bool) -> Result<(), ASIError> {
+        let mut state = self.lock_state();
+        state.check_open("start_exposure")?;
+        if state.video_active {
+            return Err(ASIError::new(ASI_ERROR_CODE_ASI_ERROR_VIDEO_MODE_ACTIVE,
+                                     "start_exposure"));
+        }
+        state.update_exp_status();
+        if state.exp_status == ExposureStatus::Working {
+            return Err(ASIError::new(ASI_ERROR_CODE_ASI_ERROR_EXPOSURE_IN_PROGRESS,
+                                     "start_exposure"));
+        }
+        state.exp_status = ExposureStatus::Working;
+        state.exp_start = Instant::now();
+        state.exp_duration = state.exposure();
+        state.exp_readout = self.config.readout_time;
+        state.exp_fails = state.failures_left > 0;
+        state.failures_left = state.failures_left.saturating_sub(1);
+        Ok(())
+    }
+
+    fn stop_exposure(&mut self) -> Result<(), ASIError> {
+        let mut state = self.lock_state();
+        state.check_open("stop_exposure")?;
+        state.update_exp_status();
+        if state.exp_status == ExposureStatus::Working {
+            state.exp_status = ExposureStatus::Failed;
+        }
+        Ok(())
+    }
+
+    fn get_exp_status(&self) -> Result<ExposureStatus, ASIError> {
+        let mut state = self.lock_state();
+        state.check_open("get_exp_status")?;
+        state.update_exp_status();
+        Ok(state.exp_status)
+    }
+
+    fn get_gain_offset(&self) -> Result<GainOffsets, ASIError> {
+        self.lock_state().check_open("get_gain_offset")?;
+        Ok(GainOffsets{
+            offset_highest_dr: 8,
+            offset_unity_gain: 15,
+            gain_lowest_read_noise: SIM_GAIN_LOWEST_READ_NOISE,
+            offset_lowest_read_noise: SIM_OFFSET_LOWEST_READ_NOISE,
+        })
+    }
+
+    fn get_lmh_gain_offset(&self) -> Result<LmhGainOffsets, ASIError> {
+        self.lock_state().check_open("get_lmh_gain_offset")?;
+        Ok(LmhGainOffsets{
+            low_gain: 0,
+            medium_gain: SIM_GAIN_LOWEST_READ_NOISE / 2,
+            high_gain: SIM_GAIN_LOWEST_READ_NOISE,
+            high_offset: SIM_OFFSET_LOWEST_READ_NOISE,
+        })
+    }
+
+    fn get_id(&self) -> Result<String, ASIError> {
+        self.lock_state().check_open("get_id")?;
+        Ok(asi_id_to_string(&self.alias))
+    }
+
+    fn set_id(&mut self, alias: &str) -> Result<(), ASIError> {
+        self.lock_state().check_open("set_id")?;
+        self.alias = asi_id_from_str(alias, "set_id")?;
+        Ok(())
+    }
+
+    fn get_serial_number(&self) -> Result<String, ASIError> {
+        self.lock_state().check_open("get_serial_number")?;
+        match self.config.serial_number {
+            Some(serial_number) =>
+                Ok(serial_number_to_hex(&ASI_SN{id: serial_number})),
+            None => Err(ASIError::new(ASI_ERROR_CODE_ASI_ERROR_GENERAL_ERROR,
+                                      "get_serial_number")),
+        }
+    }
+
+    fn get_camera_support_mode(&self) -> Result<Vec<CameraMode>, ASIError> {
+        self.lock_state().check_open("get_camera_support_mode")?;
+        if self.config.is_trigger_cam {
+            Ok(CameraMode::ALL.to_vec())
+        } else {
+            Ok(vec![CameraMode::Normal])
+        }
+    }
+
+    fn get_camera_mode(&self) -> Result<CameraMode, ASIError> {
+        let state = self.lock_state();
+        state.check_open("get_camera_mode")?;
+        Ok(state.camera_mode)
+    }
+
+    fn set_camera_mode(&mut self, mode: CameraMode) -> Result<(), ASIError> {
+        let mut state = self.lock_state();
+        state.check_open("set_camera_mode")?;
+        if state.video_active {
+            return Err(ASIError::new(ASI_ERROR_CODE_ASI_ERROR_INVALID_SEQUENCE,
+                                     "set_camera_mode"));
+        }
+        if mode.is_triggered() && !self.config.is_trigger_cam {
+            return Err(ASIError::new(ASI_ERROR_CODE_ASI_ERROR_INVALID_MODE,
+                                     "set_camera_mode"));
+        }
+        state.camera_mode = mode;
+        Ok(())
+    }
+
+    fn send_soft_trigger(&self, start: bool) -> Result<(), ASIError> {
+        let mut state = self.lock_state();
+        state.check_open("send_soft_trigger")?;
+        #[cfg(test)]
+        if state.soft_trigger_broken {
+            return Err(ASIError::new(ASI_ERROR_CODE_ASI_ERROR_GENERAL_ERROR,
+                                     "send_soft_trigger"));
+        }
+        if state.camera_mode.is_soft_triggered() {
+            state.trigger(start);
+        }
+        Ok(())
+    }
+
+    fn get_trigger_output_config(&self, pin: TriggerOutputPin)
+                                 -> Result<TriggerOutputConfig, ASIError> {
+        let state = self.lock_state();
+        state.check_open("get_trigger_output_config")?;
+        Ok(state.trigger_outputs[ASI_TRIG_OUTPUT_PIN::from(pin) as usize])
+    }
+
+    fn set_trigger_output_config(&mut self, pin: TriggerOutputPin,
+                                 config: &TriggerOutputConfig) -> Result<(), ASIError> {
+        let mut state = self.lock_state();
+        state.check_open("set_trigger_output_config")?;
+        let max_time = Duration::from_secs(2000);
+        if config.delay > max_time || config.duration > max_time {
+            return Err(ASIError::new(ASI_ERROR_CODE_ASI_ERROR_GENERAL_ERROR,
+                                     "set_trigger_output_config"));
+        }
+        state.trigger_outputs[ASI_TRIG_OUTPUT_PIN::from(pin) as usize] = *config;
+        Ok(())
+    }
+
+    unsafe fn get_data_after_exp(&self, buffer: *mut u8, buff_size: i64)
+                                 -> Result<(), ASIError> {
+        let mut state = self.lock_state();
+        state.check_open("get_data_after_exp")?;
+        let frame_size = Self::check_buffer(&state, buff_size, "get_data_after_exp")?;
+        state.update_exp_status();
+        if state.exp_status != ExposureStatus::Success {
+            return Err(ASIError::new(ASI_ERROR_CODE_ASI_ERROR_TIMEOUT,
+                                     "get_data_after_exp"));
+        }
+        let exposure = state.exp_duration;
+        let pixels = std::slice::from_raw_parts_mut(buffer, frame_size);
+        self.render(&mut state, exposure, pixels);
+        state.exp_status = ExposureStatus::Idle;
+        Ok(())
+    }
+}
+
+impl Drop for SimulatedCamera {
+    fn drop(&mut self) {
+        // Closing cannot fail.
+        let _ = self.close();
+    }
+}
+
+// State of the simulated ST4 port, indexed by ASI_GUIDE_DIRECTION.
+#[derive(Default)]
+struct GuideOutputs {
+    on_since: [Option<Instant>; 4],
+    total: [Duration; 4],
+}
+
+impl GuideOutputs {
+    fn switch(&mut self, direction: GuideDirection, on: bool) {
+        let index = u32::from(direction) as usize;
+        match (on, self.on_since[index]) {
+            (true, None) => self.on_since[index] = Some(Instant::now()),
+            (false, Some(since)) => {
+                self.total[index] += since.elapsed();
+                self.on_since[index] = None;
+            },
+            _ => (),
+        }
+    }
+}
+
+struct SimControl {
+    caps: ControlCaps,
+    value: i64,
+    auto: bool,
+}
+
+// Recommended settings reported by get_gain_offset() and
+// get_lmh_gain_offset().
+const SIM_GAIN_LOWEST_READ_NOISE: i32 = 300;
+const SIM_OFFSET_LOWEST_READ_NOISE: i32 = 30;
+
+// How often get_video_data() checks for a trigger in the trigger modes.
+const TRIGGER_POLL_INTERVAL: Duration = Duration::from_millis(5);
+
+// Mutable state of a SimulatedCamera.
+struct SimState {
+    opened: bool,
+    controls: Vec<SimControl>,
+
+    width: i32,
+    height: i32,
+    bin: i32,
+    img_type: ImgType,
+    start_x: i32,
+    start_y: i32,
+
+    // Full sensor, 8-bit dark frame to subtract; see enable_dark_subtract().
+    dark: Option<Vec<u8>>,
+
+    video_active: bool,
+    // When the video frame currently being exposed will be complete.
+    next_frame_time: Instant,
+    dropped_frames: i32,
+
+    exp_status: ExposureStatus,
+    exp_start: Instant,
+    exp_duration: Duration,
+    exp_readout: Duration,
+    // Whether the current exposure will end in ExposureStatus::Failed.
+    exp_fails: bool,
+    // Remaining SimulatorConfig::failed_exposures.
+    failures_left: u32,
+
+    camera_mode: CameraMode,
+    trigger_outputs: [TriggerOutputConfig; 2],
+    // Level of the hardware trigger input.
+    trigger_input: bool,
+    // When the current level triggered exposure started.
+    level_start: Option<Instant>,
+    // Triggered video frames: when each is complete, and its exposure time.
+    triggered_frames: VecDeque<(Instant, Duration)>,
+    // See test_support::break_soft_trigger().
+    #[cfg(test)]
+    soft_trigger_broken: bool,
+
+    rng: Rng,
+    // Drawing a fresh normal deviate for every pixel is too slow for video
+    // rates, so we sample from a table of 64K of them instead.
+    gaussians: Vec<f64>,
+}
+
+impl SimState {
+    fn new(config: &SimulatorConfig) -> Self {
+        let mut rng = Rng::new(config.seed);
+        let gaussians = (0..1 << 16).map(|_| rng.next_gaussian()).collect();
+        SimState{
+            opened: false,
+            controls: sim_controls(config),
+            width: config.max_width,
+            height: config.max_height,
+            bin: 1,
+            img_type: ImgType::Raw8,
+            start_x: 0,
+            start_y: 0,
+            dark: None,
+            video_active: false,
+            next_frame_time: Instant::now(),
+            dropped_frames: 0,
+            exp_status: ExposureStatus::Idle,
+            exp_start: Instant::now(),
+            exp_duration: Duration::ZERO,
+            exp_readout: Duration::ZERO,
+            exp_fails: false,
+            failures_left: config.failed_exposures,
+            camera_mode: CameraMode::Normal,
+            trigger_outputs: [TriggerOutputConfig::default(); 2],
+            trigger_input: false,
+            level_start: None,
+            triggered_frames: VecDeque::new(),
+            #[cfg(test)]
+            soft_trigger_broken: false,
+            rng,
+            gaussians,
+        }
+    }
+
+    fn check_open(&self, source: &str) -> Result<(), ASIError> {
+        if self.opened {
+            Ok(())
+        } else {
+            Err(ASIError::new(ASI_ERROR_CODE_ASI_ERROR_CAMERA_CLOSED, source))
+        }
+    }
+
+    fn find_control(&self, control_type: ControlType, source: &str)
+                    -> Result<&SimControl, ASIError> {
+        self.controls.iter().find(|c| c.caps.control_type == control_type)
+            .ok_or_else(|| invalid_control_type(source))
+    }
+
+    fn find_control_mut(&mut self, control_type: ControlType, source: &str)
+                        -> Result<&mut SimControl, ASIError> {
+        self.controls.iter_mut().find(|c| c.caps.control_type == control_type)
+            .ok_or_else(|| invalid_control_type(source))
+    }
+
+    // Only for controls that every SimulatedCamera has.
+    fn control_value(&self, control_type: ControlType) -> i64 {
+        self.find_control(control_type, "").unwrap().value
+    }
+
+    fn exposure(&self) -> Duration {
+        Duration::from_micros(self.control_value(ControlType::Exposure) as u64)
+    }
+
+    fn frame_size(&self) -> usize {
+        frame::frame_size(self.width, self.height, self.img_type)
+    }
+
+    // Applies a soft or hardware trigger in the current mode: `active` starts
+    // an edge triggered exposure, or starts or (when false) ends a level
+    // triggered one. Triggers are ignored unless video capture is running.
+    fn trigger(&mut self, active: bool) {
+        if !self.video_active {
+            return;
+        }
+        let now = Instant::now();
+        if !self.camera_mode.is_level_triggered() {
+            if active {
+                let exposure = self.exposure();
+                self.triggered_frames.push_back((now + exposure, exposure));
+            }
+        } else if active {
+            self.level_start.get_or_insert(now);
+        } else if let Some(start) = self.level_start.take() {
+            self.triggered_frames.push_back((now, now - start));
+        }
+    }
+
+    // An exposure in progress becomes successful once its time has elapsed.
+    fn update_exp_status(&mut self) {
+        if self.exp_status == ExposureStatus::Working &&
+            self.exp_start.elapsed() >= self.exp_duration + self.exp_readout
+        {
+            self.exp_status = if self.exp_fails {
+                ExposureStatus::Failed
+            } else {
+                ExposureStatus::Success
+            };
+        }
+    }
+}
+
+fn invalid_control_type(source: &str) -> ASIError {
+    ASIError::new(ASI_ERROR_CODE_ASI_ERROR_INVALID_CONTROL_TYPE, source)
+}
+
+fn supported_img_types(config: &SimulatorConfig) -> Vec<ImgType> {
+    if config.is_color {
+        vec![ImgType::Raw8, ImgType::Rgb24, ImgType::Raw16, ImgType::Y8]
+    } else {
+        vec![ImgType::Raw8, ImgType::Raw16, ImgType::Y8]
+    }
+}
+
+fn sim_controls(config: &SimulatorConfig) -> Vec<SimControl> {
+    let control = |name: &str, description: &str, control_type: ControlType,
+                   min: i64, max: i64, default: i64,
+                   auto_supported: bool, writable: bool| {
+        SimControl{
+            caps: ControlCaps{
+                name: name.to_string(),
+                description: description.to_string(),
+                max_value: max,
+                min_value: min,
+                default_value: default,
+                is_auto_supported: auto_supported,
+                is_writable: writable,
+                control_type,
+            },
+            value: default,
+            auto: false,
+        }
+    };
+    vec![
+        control("Gain", "Gain", ControlType::Gain,
+                0, 510, 0, /*auto_supported=*/true, /*writable=*/true),
+        control("Exposure", "Exposure Time(us)", ControlType::Exposure,
+                32, 2_000_000_000, 10_000, true, true),
+        control("Offset", "offset", ControlType::Offset,
+                0, 80, 8, false, true),
+        control("BandWidth", "The total data transfer rate percentage",
+                ControlType::BandwidthOverload, 40, 100, 50, true, true),
+        control("Flip", "Flip: 0->None 1->Horiz 2->Vert 3->Both",
+                ControlType::Flip, u32::from(FlipStatus::None) as i64,
+                u32::from(FlipStatus::Both) as i64, 0, false, true),
+        control("HighSpeedMode", "Is high speed mode:0->No 1->Yes",
+                ControlType::HighSpeedMode, 0, 1, 0, false, true),
+        control("Temperature", "Sensor temperature(degrees Celsius)",
+                ControlType::Temperature, -500, 1000,
+                (config.temperature * 10.0).round() as i64, false, false),
+    ]
+}
+
+// Small deterministic pseudo-random generator (SplitMix64). We only need
+// repeatable noise, not statistical perfection.
+struct Rng {
+    state: u64,
+}
+
+impl Rng {
+    fn new(seed: u64) -> Self {
+        Rng{state: seed}
+    }
+
+    fn next_u64(&mut self) -> u64 {
+        self.state = self.state.wrapping_add(0x9e3779b97f4a7c15);
+        let mut z = self.state;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58476d1ce4e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d049bb133111eb);
+        z ^ (z >> 31)
+    }
+
+    // Uniform in [0, 1).
+    fn next_f64(&mut self) -> f64 {
+        (self.next_u64() >> 11) as f64 / (1u64 << 53) as f64
+    }
+
+    // Standard normal, via the Box-Muller transform.
+    fn next_gaussian(&mut self) -> f64 {
+        let u1 = 1.0 - self.next_f64();  // Avoid ln(0).
+        let u2 = self.next_f64();
+        (-2.0 * u1.ln()).sqrt() * (2.0 * PI * u2).cos()
+    }
+}
+
+// Helpers for the tests of modules that need a camera, a frame or a scratch
+// file.
+#[cfg(test)]
+pub(crate) mod test_support {
+    use std::path::PathBuf;
+    use std::time::Duration;
+
+    use super::*;
+    use crate::frame::Frame;
+
+    // Returns an opened color camera capturing 64x32 `img_type` frames with
+    // 1 ms exposures.
+    pub(crate) fn simulated_camera(img_type: ImgType) -> SimulatedCamera {
+        let mut camera = SimulatedCamera::new(
+            SimulatorConfig{is_color: true, ..Default::default()});
+        camera.open().unwrap();
+        camera.set_roi_format(64, 32, 1, img_type).unwrap();
+        camera.set_control_value(ControlType::Exposure, 1000, false).unwrap();
+        camera
+    }
+
+    // Returns a frame exposed by simulated_camera(img_type), and the camera's
+    // info.
+    pub(crate) fn simulated_frame(img_type: ImgType) -> (Frame, CameraInfo) {
+        let mut camera = simulated_camera(img_type);
+        let frame = camera.capture_exposure(
+            Duration::from_millis(1), false, None).unwrap();
+        (frame, camera.camera_info())
+    }
+
+    // Makes send_soft_trigger() on `camera` fail with ASIErrorKind::GeneralError.
+    pub(crate) fn break_soft_trigger(camera: &SimulatedCamera) {
+        camera.lock_state().soft_trigger_broken = true;
+    }
+
+    // Returns a path named `name` in the temporary directory, unique to this
+    // process.
+    pub(crate) fn temp_path(name: &str) -> PathBuf {
+        std::env::temp_dir().join(format!("asi_camera2_{}_{}", std::process::id(), name))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::asi_camera2_sdk::ASIErrorKind;
+
+    fn opened_camera(config: SimulatorConfig) -> SimulatedCamera {
+        let mut camera = SimulatedCamera::new(config);
+        camera.open().unwrap();
+        camera.init().unwrap();
+        camera.set_control_value(ControlType::Exposure, 1000, false).unwrap();
+        camera
+    }
+
+    #[test]
+    fn closed_camera() {
+        let camera = SimulatedCamera::new(SimulatorConfig::default());
+        assert_eq!(camera.get_roi_format().unwrap_err().kind(),
+                   ASIErrorKind::CameraClosed);
+    }
+
+    #[test]
+    #[should_panic(expected = "bit_depth")]
+    fn invalid_bit_depth() {
+        SimulatedCamera::new(SimulatorConfig{bit_depth: 17, ..Default::default()});
+    }
+
+    #[test]
+    fn roi_format_sizes_frames() {
+        let config = SimulatorConfig{is_color: true, ..Default::default()};
+        let mut camera = opened_camera(config);
+        assert_eq!(camera.get_roi_format().unwrap(), (1280, 960, 1, ImgType::Raw8));
+        assert_eq!(camera.frame_size().unwrap(), 1280 * 960);
+
+        for (img_type, bytes_per_pixel) in [(ImgType::Raw8, 1), (ImgType::Y8, 1),
+                                            (ImgType::Raw16, 2), (ImgType::Rgb24, 3)] {
+            camera.set_roi_format(320, 240, 2, img_type).unwrap();
+            // The new ROI is centered on the binned sensor.
+            assert_eq!(camera.get_start_pos().unwrap(), (160, 120));
+            assert_eq!(camera.frame_size().unwrap(), 320 * 240 * bytes_per_pixel);
+            camera.start_exposure(false).unwrap();
+            sleep(Duration::from_millis(2));
+            let frame = camera.get_frame_after_exp().unwrap();
+            assert_eq!((frame.width, frame.height, frame.bin, frame.img_type),
+                       (320, 240, 2, img_type));
+            assert_eq!(frame.data.len(), 320 * 240 * bytes_per_pixel);
+            assert_eq!(frame.metadata.start_x, 160);
+        }
+    }
+
+    #[test]
+    fn invalid_roi_format() {
+        let mut camera = opened_camera(SimulatorConfig::default());
+        let kind = |result: Result<(), ASIError>| result.unwrap_err().kind();
+        // Width not a multiple of 8.
+        assert_eq!(kind(camera.set_roi_format(100, 100, 1, ImgType::Raw8)),
+                   ASIErrorKind::InvalidSize);
+        // Larger than the binned sensor.
+        assert_eq!(kind(camera.set_roi_format(1280, 960, 2, ImgType::Raw8)),
+                   ASIErrorKind::InvalidSize);
+        // Unsupported bin.
+        assert_eq!(kind(camera.set_roi_format(320, 240, 5, ImgType::Raw8)),
+                   ASIErrorKind::InvalidSize);
+        // Mono cameras have no RGB24.
+        assert_eq!(kind(camera.set_roi_format(320, 240, 1, ImgType::Rgb24)),
+                   ASIErrorKind::InvalidImgType);
+        camera.set_roi_format(320, 240, 2, ImgType::Raw8).unwrap();
+        assert_eq!(kind(camera.set_start_pos(400, 0)), ASIErrorKind::OutOfBoundary);
+        camera.set_start_pos(320, 0).unwrap();
+        assert_eq!(camera.get_start_pos().unwrap(), (320, 0));
+        // Unchanged by the failed calls.
+        assert_eq!(camera.get_roi_format().unwrap(), (320, 240, 2, ImgType::Raw8));
+    }
+
+    #[test]
+    fn raw16_scaled_to_16_bits() {
+        let mut camera = opened_camera(SimulatorConfig::default());
+        camera.set_roi_format(64, 64, 1, ImgType::Raw16).unwrap();
+        camera.start_exposure(false).unwrap();
+        sleep(Duration::from_millis(2));
+        let frame = camera.get_frame_after_exp().unwrap();
+        // 12-bit samples occupy the top 12 bits.
+        assert!(frame.pixels_u16().unwrap().iter().all(|pixel| pixel & 0xf == 0));
+    }
+
+    #[test]
+    fn exposure_status() {
+        let mut camera = opened_camera(SimulatorConfig::default());
+        camera.set_control_value(ControlType::Exposure, 50_000, false).unwrap();
+        assert_eq!(camera.get_exp_status().unwrap(), ExposureStatus::Idle);
+        camera.start_exposure(false).unwrap();
+        assert_eq!(camera.get_exp_status().unwrap(), ExposureStatus::Working);
+        // Data is not available until the exposure completes.
+        assert_eq!(camera.get_frame_after_exp().unwrap_err().kind(),
+                   ASIErrorKind::Timeout);
+        sleep(Duration::from_millis(60));
+        assert_eq!(camera.get_exp_status().unwrap(), ExposureStatus::Success);
+        camera.get_frame_after_exp().unwrap();
+        assert_eq!(camera.get_exp_status().unwrap(), ExposureStatus::Idle);
+
+        camera.start_exposure(false).unwrap();
+        camera.stop_exposure().unwrap();
+        assert_eq!(camera.get_exp_status().unwrap(), ExposureStatus::Failed);
+    }
+
+    #[test]
+    fn video_and_exposure_sequencing() {
+        let mut camera = opened_camera(SimulatorConfig::default());
+        assert_eq!(camera.get_video_frame(100).unwrap_err().kind(),
+                   ASIErrorKind::InvalidSequence);
+
+        camera.start_exposure(false).unwrap();
+        assert_eq!(camera.start_exposure(false).unwrap_err().kind(),
+                   ASIErrorKind::ExposureInProgress);
+        camera.set_control_value(ControlType::Exposure, 50_000, false).unwrap();
+        camera.stop_exposure().unwrap();
+        camera.start_exposure(false).unwrap();
+        assert_eq!(camera.start_video_capture().unwrap_err().kind(),
+                   ASIErrorKind::ExposureInProgress);
+        camera.stop_exposure().unwrap();
+
+        camera.set_control_value(ControlType::Exposure, 1000, false).unwrap();
+        camera.start_video_capture().unwrap();
+        assert_eq!(camera.start_exposure(false).unwrap_err().kind(),
+                   ASIErrorKind::VideoModeActive);
+        assert_eq!(camera.set_camera_mode(CameraMode::Normal).unwrap_err().kind(),
+                   ASIErrorKind::InvalidSequence);
+        let first = camera.get_video_frame(1000).unwrap();
+        let second = camera.get_video_frame(1000).unwrap();
+        assert_eq!(second.metadata.sequence_number, first.metadata.sequence_number + 1);
+        camera.stop_video_capture().unwrap();
+        assert_eq!(camera.get_video_frame(100).unwrap_err().kind(),
+                   ASIErrorKind::InvalidSequence);
+    }
+
+    #[test]
+    fn video_timeout() {
+        let mut camera = opened_camera(SimulatorConfig::default());
+        camera.set_control_value(ControlType::Exposure, 1_000_000, false).unwrap();
+        camera.start_video_capture().unwrap();
+        assert_eq!(camera.get_video_frame(10).unwrap_err().kind(),
+                   ASIErrorKind::Timeout);
+    }
+
+    #[test]
+    fn dropped_frames_saturate() {
+        let mut camera = opened_camera(SimulatorConfig::default());
+        camera.start_video_capture().unwrap();
+        camera.lock_state().dropped_frames = i32::MAX - 1;
+        // Several 1 ms frames complete unread.
+        sleep(Duration::from_millis(20));
+        camera.get_video_frame(1000).unwrap();
+        assert_eq!(camera.get_dropped_frames().unwrap(), i32::MAX);
+    }
+}
@@ -0,0 +1,720 @@
+// Copyright (c) 2023 Steven Rosenthal smr@dt3.org
+// See LICENSE file in root directory for license terms.
+
+use std::collections::HashMap;
+use std::fmt;
+use std::path::Path;
+use std::sync::{Mutex, MutexGuard};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::thread::sleep;
+use std::time::{Duration, Instant};
+
+use log::{info, warn};
+
+#[cfg(feature = "sdk")]
+use crate::asi_camera2_sdk::{ASICamera, reset_asi_cameras};
+use crate::asi_camera2_sdk::{ASIError, ASIErrorKind,
+                             ASI_ERROR_CODE_ASI_ERROR_CAMERA_REMOVED};
+use crate::camera_backend::CameraBackend;
+use crate::types::{CameraInfo, CameraMode, ControlCaps, ControlType, ExposureStatus,
+                   GainOffsets, GuideDirection, ImgType, LmhGainOffsets,
+                   TriggerOutputConfig, TriggerOutputPin};
+use crate::lock_ignoring_poison;
+
+/// How a SupervisedCamera gets hold of its camera again after a USB failure.
+pub trait Reconnector<B>: Send {
+    /// Resets the camera's USB connection, making it re-enumerate.
+    fn reset(&mut self);
+
+    /// Returns a new, unopened camera for the device with `serial_number`, or
+    /// None if it has not (yet) been enumerated.
+    fn find(&mut self, serial_number: &str) -> Result<Option<B>, ASIError>;
+}
+
+/// Reconnects an ASICamera by resetting all ZWO USB devices (see
+/// reset_asi_cameras()) and then looking the camera up by serial number.
+/// Other cameras in use are reset too, so they need their own supervision.
+#[cfg(feature = "sdk")]
+#[derive(Clone, Copy, Debug, Default)]
+pub struct ASIReconnector;
+
+#[cfg(feature = "sdk")]
+impl Reconnector<ASICamera> for ASIReconnector {
+    fn reset(&mut self) { reset_asi_cameras(); }
+
+    fn find(&mut self, serial_number: &str) -> Result<Option<ASICamera>, ASIError> {
+        let camera_info = ASICamera::find_camera_by_serial(serial_number)?;
+        Ok(camera_info.map(|camera_info| ASICamera::new(camera_info.camera_id)))
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct RecoveryConfig {
+    /// Consecutive ASIErrorKind::Timeout errors reading frames before recovery
+    /// is attempted.
+    pub max_consecutive_timeouts: u32,
+    /// The timeouts must also span at least the exposure duration plus this
+    /// long, so that short waits polling for frames during long exposures do
+    /// not trigger recovery.
+    pub timeout_grace: Duration,
+    /// Consecutive exposures ending in ExposureStatus::Failed before recovery
+    /// is attempted.
+    pub max_consecutive_exposure_failures: u32,
+    /// Recovery attempts (each resetting the USB device) before giving up.
+    pub max_attempts: u32,
+    /// How long each attempt waits for the camera to re-enumerate after the
+    /// reset.
+    pub reenumeration_timeout: Duration,
+    /// How often to look for the camera while waiting for it to re-enumerate.
+    pub poll_interval: Duration,
+    /// After recovery gives up, the next call at least this long afterwards
+    /// tries again.
+    pub retry_interval: Duration,
+}
+
+impl Default for RecoveryConfig {
+    fn default() -> Self {
+        RecoveryConfig{
+            max_consecutive_timeouts: 5,
+            timeout_grace: Duration::from_secs(5),
+            max_consecutive_exposure_failures: 3,
+            max_attempts: 3,
+            reenumeration_timeout: Duration::from_secs(10),
+            poll_interval: Duration::from_millis(500),
+            retry_interval: Duration::from_secs(30),
+        }
+    }
+}
+
+/// Why a SupervisedCamera started recovery.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum RecoveryReason {
+    /// An SDK call failed with ASIErrorKind::CameraRemoved.
+    CameraRemoved,
+    /// This many consecutive timeouts reading frames.
+    Timeouts(u32),
+    /// This many consecutive exposures ended in ExposureStatus::Failed.
+    ExposureFailures(u32),
+    /// SupervisedCamera::recover() was called.
+    Requested,
+    /// The previous recovery gave up; see RecoveryConfig::retry_interval.
+    Retry,
+}
+
+impl fmt::Display for RecoveryReason {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            RecoveryReason::CameraRemoved => write!(f, "camera removed"),
+            RecoveryReason::Timeouts(n) => write!(f, "{} consecutive timeouts", n),
+            RecoveryReason::ExposureFailures(n) =>
+                write!(f, "{} consecutive failed exposures", n),
+            RecoveryReason::Requested => write!(f, "requested"),
+            RecoveryReason::Retry => write!(f, "retrying failed recovery"),
+        }
+    }
+}
+
+/// Reported to SupervisedCamera::on_recovery() callbacks.
+#[derive(Clone, Debug)]
+pub enum RecoveryEvent {
+    Started{reason: RecoveryReason},
+    /// Attempt number `attempt` (counting from 1) failed. Another attempt
+    /// follows unless it was the last.
+    AttemptFailed{attempt: u32, error: ASIError},
+    /// The camera is back, with the given camera id, after `attempts`
+    /// attempts.
+    Recovered{attempts: u32, camera_id: i32},
+    /// All attempts failed; `error` is from the last of them. The camera stays
+    /// closed, and calls fail with ASIErrorKind::CameraClosed, until a later
+    /// recovery succeeds.
+    Failed{error: ASIError},
+}
+
+type RecoveryCallback = dyn FnMut(&RecoveryEvent) + Send;
+
+/// Wraps a camera, recovering from USB failures: when an SDK call fails with
+/// ASIErrorKind::CameraRemoved, when reading frames keeps timing out, or when
+/// exposures keep failing (see RecoveryConfig), the camera is closed, its USB
+/// device is reset, and once it has re-enumerated it is re-opened by serial
+/// number. The last ROI format, start position and control values, and video
+/// capture if it was running, are then re-applied.
+///
+/// Recovery happens within the call that detected the failure, which still
+/// returns its error; callers retry as they would after a transient error.
+/// If recovery gives up, it is tried again by the first call after
+/// RecoveryConfig::retry_interval, and can also be started with recover().
+/// Attempts and outcomes are logged and reported to the on_recovery()
+/// callbacks.
+///
+/// SupervisedCamera is itself a CameraBackend, so it can be used with
+/// session::Camera, VideoStream and the like. The camera id can change on
+/// recovery; sequence numbers continue across it. Calls are serialized, so
+/// e.g. get_control_value() waits while get_video_data() waits for a frame.
+pub struct SupervisedCamera<B: CameraBackend> {
+    inner: Mutex<Inner<B>>,
+    // Kept here rather than by the camera, so that it continues across
+    // recovery.
+    frames_read: AtomicU64,
+}
+
+struct Inner<B> {
+    camera: B,
+    serial_number: String,
+    reconnector: Box<dyn Reconnector<B>>,
+    config: RecoveryConfig,
+    callbacks: Vec<Box<RecoveryCallback>>,
+    settings: Settings,
+    video_active: bool,
+    consecutive_timeouts: u32,
+    // When the current run of timeouts started.
+    timeouts_start: Option<Instant>,
+    consecutive_exposure_failures: u32,
+    // Whether an exposure has been started but not yet seen to end, so that
+    // each exposure is counted once however often its status is polled.
+    exposure_pending: bool,
+    // When the last recovery gave up, if the camera has not been recovered
+    // since.
+    recovery_failed_at: Option<Instant>,
+}
+
+// What is re-applied to the camera after recovery.
+struct Settings {
+    roi_format: (i32, i32, i32, ImgType),
+    start_pos: (i32, i32),
+    // Value and whether automatic, for each writable control.
+    controls: HashMap<ControlType, (i64, bool)>,
+}
+
+#[cfg(feature = "sdk")]
+impl SupervisedCamera<ASICamera> {
+    /// Opens and initializes the camera with the given `camera_id`, and
+    /// supervises it with an ASIReconnector.
+    pub fn open(camera_id: i32, config: RecoveryConfig) -> Result<Self, ASIError> {
+        let mut camera = ASICamera::new(camera_id);
+        camera.open()?;
+        camera.init()?;
+        SupervisedCamera::new(camera, ASIReconnector, config)
+    }
+}
+
+impl<B: CameraBackend> SupervisedCamera<B> {
+    /// Supervises `camera`, which must be opened and initialized and have a
+    /// serial number. Its current settings are recorded to be re-applied after
+    /// recovery, and are updated as they are changed through the
+    /// SupervisedCamera.
+    pub fn new<R>(camera: B, reconnector: R, config: RecoveryConfig)
+                  -> Result<Self, ASIError>
+    where R: Reconnector<B> + 'static
+    {
+        let serial_number = camera.get_serial_number()?;
+        let mut controls = HashMap::new();
+        for (control_type, caps) in camera.controls()? {
+            if caps.is_writable {
+                controls.insert(control_type, camera.get_control_value(control_type)?);
+            }
+        }
+        let settings = Settings{roi_format: camera.get_roi_format()?,
+                                start_pos: camera.get_start_pos()?,
+                                controls};
+        let inner = Inner{camera, serial_number, reconnector: Box::new(reconnector),
+                          config, callbacks: Vec::new(), settings,
+                          video_active: false, consecutive_timeouts: 0,
+                          timeouts_start: None, consecutive_exposure_failures: 0,
+                          exposure_pending: false, recovery_failed_at: None};
+        Ok(SupervisedCamera{inner: Mutex::new(inner), frames_read: AtomicU64::new(0)})
+    }
+
+    /// Adds a callback to be told of recovery attempts and their outcomes.
+    /// Callbacks are called on the thread doing the recovery, with the
+    /// SupervisedCamera locked: calls from other threads wait for them, and
+    /// calling back into the SupervisedCamera deadlocks.
+    pub fn on_recovery<F>(&mut self, callback: F)
+    where F: FnMut(&RecoveryEvent) + Send + 'static
+    {
+        self.lock().callbacks.push(Box::new(callback));
+    }
+
+    /// The serial number the camera is re-opened by.
+    pub fn serial_number(&self) -> String {
+        self.lock().serial_number.clone()
+    }
+
+    /// Recovers the camera now, e.g. after a previous recovery gave up,
+    /// without waiting for RecoveryConfig::retry_interval.
+    pub fn recover(&self) -> Result<(), ASIError> {
+        self.lock().recover(RecoveryReason::Requested)
+    }
+
+    /// Returns the camera, which is closed if the last recovery failed.
+    pub fn into_inner(self) -> B {
+        self.inner.into_inner().unwrap_or_else(|e| e.into_inner()).camera
+    }
+
+    fn lock(&self) -> MutexGuard<'_, Inner<B>> {
+        lock_ignoring_poison(&self.inner)
+    }
+
+    // Locks the camera, first retrying recovery if it is due.
+    fn lock_recovered(&self) -> MutexGuard<'_, Inner<B>> {
+        let mut inner = self.lock();
+        if inner.recovery_failed_at.is_some_and(
+            |failed_at| failed_at.elapsed() >= inner.config.retry_interval)
+        {
+            // The call goes ahead either way, failing if the camera is still
+            // closed.
+            let _ = inner.recover(RecoveryReason::Retry);
+        }
+        inner
+    }
+
+    // Runs `f` on the camera, recovering if it fails with CameraRemoved or
+    // completes a run of timeouts.
+    fn call<T, F>(&self, f: F) -> Result<T, ASIError>
+    where F: FnOnce(&mut B) -> Result<T, ASIError>
+    {
+        let mut inner = self.lock_recovered();
+        let result = f(&mut inner.camera);
+        if let Err(e) = &result {
+            inner.check_error(e);
+        }
+        result
+    }
+
+    // Like call(), for reading a frame, which resets the run of timeouts.
+    fn read_frame<F>(&self, f: F) -> Result<(), ASIError>
+    where F: FnOnce(&mut B) -> Result<(), ASIError>
+    {
+        let mut inner = self.lock_recovered();
+        let result = f(&mut inner.camera);
+        match &result {
+            Ok(()) => {
+                inner.consecutive_timeouts = 0;
+                inner.timeouts_start = None;
+            },
+            Err(e) => inner.check_error(e),
+        }
+        result
+    }
+}
+
+impl<B: CameraBackend> Inner<B> {
+    fn check_error(&mut self, e: &ASIError) {
+        let reason = match e.kind() {
+            ASIErrorKind::CameraRemoved => RecoveryReason::CameraRemoved,
+            ASIErrorKind::Timeout => {
+                let now = Instant::now();
+                let start = *self.timeouts_start.get_or_insert(now);
+                self.consecutive_timeouts += 1;
+                let exposure_us = self.settings.controls.get(&ControlType::Exposure)
+                    .map_or(0, |(value, _auto)| (*value).max(0) as u64);
+                let min_duration =
+                    Duration::from_micros(exposure_us) + self.config.timeout_grace;
+                if self.consecutive_timeouts < self.config.max_consecutive_timeouts ||
+                    now - start < min_duration
+                {
+                    return;
+                }
+                RecoveryReason::Timeouts(self.consecutive_timeouts)
+            },
+            _ => return,
+        };
+        // The failed call returns its own error; the outcome is reported
+        // through logs and callbacks.
+        let _ = self.recover(reason);
+    }
+
+    fn check_exp_status(&mut self, status: ExposureStatus) {
+        if !self.exposure_pending || status == ExposureStatus::Working {
+            return;
+        }
+        self.exposure_pending = false;
+        match status {
+            ExposureStatus::Success => self.consecutive_exposure_failures = 0,
+            ExposureStatus::Failed => {
+                self.consecutive_exposure_failures += 1;
+                let failures = self.consecutive_exposure_failures;
+                if failures >= self.config.max_consecutive_exposure_failures {
+                    let _ = self.recover(RecoveryReason::ExposureFailures(failures));
+                }
+            },
+            _ => (),
+        }
+    }
+
+    fn recover(&mut self, reason: RecoveryReason) -> Result<(), ASIError> {
+        warn!("Recovering camera {} (id {}): {}",
+              self.serial_number, self.camera.camera_id(), reason);
+        self.notify(&RecoveryEvent::Started{reason});
+        self.consecutive_timeouts = 0;
+        self.timeouts_start = None;
+        self.consecutive_exposure_failures = 0;
+        self.exposure_pending = false;
+        if let Err(e) = self.camera.close() {
+            warn!("Error closing camera {}: {}", self.serial_number, e);
+        }
+        let mut error = ASIError::new(ASI_ERROR_CODE_ASI_ERROR_CAMERA_REMOVED, "recover");
+        for attempt in 1..=self.config.max_attempts {
+            match self.reconnect() {
+                Ok(()) => {
+                    let camera_id = self.camera.camera_id();
+                    info!("Recovered camera {} as id {} after {} attempt(s)",
+                          self.serial_number, camera_id, attempt);
+                    self.recovery_failed_at = None;
+                    self.notify(&RecoveryEvent::Recovered{attempts: attempt, camera_id});
+                    return Ok(());
+                },
+                Err(e) => {
+                    warn!("Recovery attempt {} for camera {} failed: {}",
+                          attempt, self.serial_number, e);
+                    self.notify(&RecoveryEvent::AttemptFailed{attempt,
+                                                              error: e.clone()});
+                    error = e;
+                },
+            }
+        }
+        warn!("Giving up recovering camera {}", self.serial_number);
+        self.recovery_failed_at = Some(Instant::now());
+        self.notify(&RecoveryEvent::Failed{error: error.clone()});
+        Err(error)
+    }
+
+    // Resets the USB device, waits for the camera to re-enumerate, then opens
+    // it and re-applies the settings.
+    fn reconnect(&mut self) -> Result<(), ASIError> {
+        self.reconnector.reset();
+        let deadline = Instant::now() + self.config.reenumeration_timeout;
+        let mut error = ASIError::new(ASI_ERROR_CODE_ASI_ERROR_CAMERA_REMOVED, "recover");
+        let mut camera = loop {
+            match self.reconnector.find(&self.serial_number) {
+                Ok(Some(camera)) => break camera,
+                Ok(None) => (),
+                // Enumeration can fail while devices are still coming back.
+                Err(e) => error = e,
+            }
+            if Instant::now() >= deadline {
+                return Err(error);
+            }
+            sleep(self.config.poll_interval);
+        };
+        camera.open()?;
+        camera.init()?;
+        let (width, height, bin, img_type) = self.settings.roi_format;
+        camera.set_roi_format(width, height, bin, img_type)?;
+        let (start_x, start_y) = self.settings.start_pos;
+        camera.set_start_pos(start_x, start_y)?;
+        for (control_type, (value, auto)) in &self.settings.controls {
+            camera.set_control_value(*control_type, *value, *auto)?;
+        }
+        if self.video_active {
+            camera.start_video_capture()?;
+        }
+        // The old camera has already been closed.
+        self.camera = camera;
+        Ok(())
+    }
+
+    fn notify(&mut self, event: &RecoveryEvent) {
+        for callback in &mut self.callbacks {
+            callback(event);
+        }
+    }
+}
+
+impl<B: CameraBackend> CameraBackend for SupervisedCamera<B> {
+    fn camera_id(&self) -> i32 { self.lock().camera.camera_id() }
+    fn camera_info(&self) -> Result<CameraInfo, ASIError> {
+        self.call(|camera| camera.camera_info())
+    }
+
+    fn open(&mut self) -> Result<(), ASIError> { self.call(|camera| camera.open()) }
+    fn init(&self) -> Result<(), ASIError> { self.call(|camera| camera.init()) }
+    fn close(&mut self) -> Result<(), ASIError> {
+        let mut inner = self.lock();
+        inner.video_active = false;
+        // Closed on purpose, so don't recover.
+        inner.recovery_failed_at = None;
+        inner.camera.close()
+    }
+
+    fn get_num_controls(&self) -> Result<i32, ASIError> {
+        self.call(|camera| camera.get_num_controls())
+    }
+    fn get_control_caps(&self, control_index: i32)
+                        -> Result<ControlCaps, ASIError> {
+        self.call(|camera| camera.get_control_caps(control_index))
+    }
+    fn get_control_value(&self, control_type: ControlType)
+                         -> Result<(i64, bool), ASIError> {
+        self.call(|camera| camera.get_control_value(control_type))
+    }
+    fn set_control_value(&mut self, control_type: ControlType,
+                         value: i64, auto: bool) -> Result<(), ASIError> {
+        self.call(|camera| camera.set_control_value(control_type, value, auto))?;
+        self.lock().settings.controls.insert(control_type, (value, auto));
+        Ok(())
+    }
+
+    fn get_roi_format(&self) -> Result<(i32, i32, i32, ImgType), ASIError> {
+        self.call(|camera| camera.get_roi_format())
+    }
+    fn set_roi_format(&mut self, width: i32, height: i32,
+                      bin: i32, img_type: ImgType) -> Result<(), ASIError> {
+        self.call(|camera| {
+            camera.set_roi_format(width, height, bin, img_type)?;
+            // Setting the ROI format re-centers the ROI.
+            camera.get_start_pos()
+        }).map(|start_pos| {
+            let mut inner = self.lock();
+            inner.settings.roi_format = (width, height, bin, img_type);
+            inner.settings.start_pos = start_pos;
+        })
+    }
+
+    fn get_start_pos(&self) -> Result<(i32, i32), ASIError> {
+        self.call(|camera| camera.get_start_pos())
+    }
+    fn set_start_pos(&mut self, start_x: i32, start_y: i32)
+                     -> Result<(), ASIError> {
+        self.call(|camera| camera.set_start_pos(start_x, start_y))?;
+        self.lock().settings.start_pos = (start_x, start_y);
+        Ok(())
+    }
+
+    fn get_dropped_frames(&self) -> Result<i32, ASIError> {
+        self.call(|camera| camera.get_dropped_frames())
+    }
+    fn next_sequence_number(&self) -> u64 {
+        self.frames_read.fetch_add(1, Ordering::Relaxed)
+    }
+
+    fn enable_dark_subtract(&mut self, bmp_path: &Path) -> Result<(), ASIError> {
+        self.call(|camera| camera.enable_dark_subtract(bmp_path))
+    }
+    fn disable_dark_subtract(&mut self) -> Result<(), ASIError> {
+        self.call(|camera| camera.disable_dark_subtract())
+    }
+
+    fn start_video_capture(&mut self) -> Result<(), ASIError> {
+        self.call(|camera| camera.start_video_capture())?;
+        self.lock().video_active = true;
+        Ok(())
+    }
+    fn stop_video_capture(&mut self) -> Result<(), ASIError> {
+        // Recovery must not restart capture, even if stopping failed.
+        self.lock().video_active = false;
+        self.call(|camera| camera.stop_video_capture())
+    }
+    unsafe fn get_video_data(&self, buffer: *mut u8, buff_size: i64, wait_ms: i32)
+                             -> Result<(), ASIError> {
+        self.read_frame(|camera| camera.get_video_data(buffer, buff_size, wait_ms))
+    }
+
+    fn pulse_guide_on(&self, direction: GuideDirection) -> Result<(), ASIError> {
+        self.call(|camera| camera.pulse_guide_on(direction))
+    }
+    fn pulse_guide_off(&self, direction: GuideDirection) -> Result<(), ASIError> {
+        self.call(|camera| camera.pulse_guide_off(direction))
+    }
+    fn pulse_guide(&self, direction: GuideDirection, duration: Duration)
+                   -> Result<(), ASIError> {
+        self.call(|camera| camera.pulse_guide(direction, duration))
+    }
+
+    fn start_exposure(&mut self, is_dark: bool) -> Result<(), ASIError> {
+        self.call(|camera| camera.start_exposure(is_dark))?;
+        self.lock().exposure_pending = true;
+        Ok(())
+    }
+    fn stop_exposure(&mut self) -> Result<(), ASIError> {
+        // A stopped exposure ends as Failed, but is not a camera failure.
+        self.lock().exposure_pending = false;
+        self.call(|camera| camera.stop_exposure())
+    }
+    fn get_exp_status(&self) -> Result<ExposureStatus, ASIError> {
+        let mut inner = self.lock_recovered();
+        match inner.camera.get_exp_status() {
+            Ok(status) => {
+                inner.check_exp_status(status);
+                Ok(status)
+            },
+            Err(e) => {
+                inner.check_error(&e);
+                Err(e)
+            },
+        }
+    }
+    unsafe fn get_data_after_exp(&self, buffer: *mut u8, buff_size: i64)
+                                 -> Result<(), ASIError> {
+        self.read_frame(|camera| camera.get_data_after_exp(buffer, buff_size))
+    }
+
+    fn get_gain_offset(&self) -> Result<GainOffsets, ASIError> {
+        self.call(|camera| camera.get_gain_offset())
+    }
+    fn get_lmh_gain_offset(&self) -> Result<LmhGainOffsets, ASIError> {
+        self.call(|camera| camera.get_lmh_gain_offset())
+    }
+
+    fn get_id(&self) -> Result<String, ASIError> { self.call(|camera| camera.get_id()) }
+    fn set_id(&mut self, alias: &str) -> Result<(), ASIError> {
+        self.call(|camera| camera.set_id(alias))
+    }
+    fn get_serial_number(&self) -> Result<String, ASIError> {
+        self.call(|camera| camera.get_serial_number())
+    }
+
+    fn get_camera_support_mode(&self) -> Result<Vec<CameraMode>, ASIError> {
+        self.call(|camera| camera.get_camera_support_mode())
+    }
+    fn get_camera_mode(&self) -> Result<CameraMode, ASIError> {
+        self.call(|camera| camera.get_camera_mode())
+    }
+    fn set_camera_mode(&mut self, mode: CameraMode) -> Result<(), ASIError> {
+        self.call(|camera| camera.set_camera_mode(mode))
+    }
+    fn send_soft_trigger(&self, start: bool) -> Result<(), ASIError> {
+        self.call(|camera| camera.send_soft_trigger(start))
+    }
+    fn get_trigger_output_config(&self, pin: TriggerOutputPin)
+                                 -> Result<TriggerOutputConfig, ASIError> {
+        self.call(|camera| camera.get_trigger_output_config(pin))
+    }
+    fn set_trigger_output_config(&mut self, pin: TriggerOutputPin,
+                                 config: &TriggerOutputConfig) -> Result<(), ASIError> {
+        self.call(|camera| camera.set_trigger_output_config(pin, config))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use super::*;
+    use crate::camera_backend::find_camera;
+    use crate::simulated_camera::{SimulatedCamera, SimulatorConfig};
+
+    // Finds the camera only once `failures` lookups have failed.
+    struct FlakyReconnector {
+        failures: u32,
+    }
+
+    impl Reconnector<SimulatedCamera> for FlakyReconnector {
+        fn reset(&mut self) {}
+
+        fn find(&mut self, _serial_number: &str)
+                -> Result<Option<SimulatedCamera>, ASIError> {
+            if self.failures > 0 {
+                self.failures -= 1;
+                return Ok(None);
+            }
+            Ok(Some(SimulatedCamera::new(SimulatorConfig::default())))
+        }
+    }
+
+    // Looks cameras up by serial number among `configs`, recording the ids of
+    // the cameras it queried.
+    struct SimReconnector {
+        configs: Vec<SimulatorConfig>,
+        queried: Arc<Mutex<Vec<i32>>>,
+    }
+
+    impl Reconnector<SimulatedCamera> for SimReconnector {
+        fn reset(&mut self) {}
+
+        fn find(&mut self, serial_number: &str)
+                -> Result<Option<SimulatedCamera>, ASIError> {
+            let cameras = self.configs.iter().cloned().map(SimulatedCamera::new);
+            Ok(find_camera(cameras, |camera| {
+                self.queried.lock().unwrap().push(camera.camera_id());
+                Ok(camera.get_serial_number()? == serial_number)
+            }))
+        }
+    }
+
+    #[test]
+    fn find_skips_open_cameras() {
+        // Ids not used by other tests, which may run concurrently.
+        let configs: Vec<_> = [(100, *b"ASISIM01"), (101, *b"ASISIM02")].into_iter()
+            .map(|(camera_id, serial_number)| SimulatorConfig{
+                camera_id, serial_number: Some(serial_number), ..Default::default()})
+            .collect();
+        let queried = Arc::new(Mutex::new(Vec::new()));
+        let mut supervised: Vec<_> = configs.iter().map(|config| {
+            let mut camera = SimulatedCamera::new(config.clone());
+            camera.open().unwrap();
+            let reconnector = SimReconnector{configs: configs.clone(),
+                                             queried: queried.clone()};
+            SupervisedCamera::new(camera, reconnector, RecoveryConfig::default()).unwrap()
+        }).collect();
+
+        supervised[1].recover().unwrap();
+        // The other camera is still open, so only the recovering one was queried.
+        assert_eq!(*queried.lock().unwrap(), vec![101]);
+        assert_eq!(supervised[1].camera_id(), 101);
+        for camera in &mut supervised {
+            camera.start_exposure(false).unwrap();
+            camera.stop_exposure().unwrap();
+        }
+    }
+
+    #[test]
+    fn counts_each_failed_exposure_once() {
+        let mut camera = SimulatedCamera::new(SimulatorConfig::default());
+        camera.open().unwrap();
+        let mut supervised = SupervisedCamera::new(
+            camera, FlakyReconnector{failures: 0}, RecoveryConfig::default()).unwrap();
+        let events = Arc::new(Mutex::new(Vec::new()));
+        let recorded = events.clone();
+        supervised.on_recovery(move |event| recorded.lock().unwrap().push(event.clone()));
+
+        let max_failures = RecoveryConfig::default().max_consecutive_exposure_failures;
+        for exposure in 1..=max_failures {
+            supervised.start_exposure(false).unwrap();
+            // The camera fails the exposure by itself.
+            supervised.lock().camera.stop_exposure().unwrap();
+            assert_eq!(supervised.get_exp_status().unwrap(), ExposureStatus::Failed);
+            for _ in 1..max_failures {
+                supervised.get_exp_status().unwrap();
+            }
+            if exposure < max_failures {
+                assert!(events.lock().unwrap().is_empty());
+            }
+        }
+        let events = events.lock().unwrap();
+        assert_eq!(events.len(), 2);
+        assert!(matches!(
+            events[0],
+            RecoveryEvent::Started{reason: RecoveryReason::ExposureFailures(n)}
+                if n == max_failures));
+    }
+
+    #[test]
+    fn retries_failed_recovery() {
+        let mut camera = SimulatedCamera::new(SimulatorConfig::default());
+        camera.open().unwrap();
+        camera.set_roi_format(320, 240, 2, ImgType::Raw16).unwrap();
+        let config = RecoveryConfig{max_attempts: 1,
+                                    reenumeration_timeout: Duration::ZERO,
+                                    retry_interval: Duration::from_millis(50),
+                                    ..Default::default()};
+        let mut supervised = SupervisedCamera::new(
+            camera, FlakyReconnector{failures: 1}, config).unwrap();
+        let events = Arc::new(Mutex::new(Vec::new()));
+        let recorded = events.clone();
+        supervised.on_recovery(move |event| recorded.lock().unwrap().push(event.clone()));
+
+        assert!(supervised.recover().is_err());
+        assert!(matches!(events.lock().unwrap().last(), Some(RecoveryEvent::Failed{..})));
+        // Too soon to retry.
+        assert_eq!(supervised.get_roi_format().unwrap_err().kind(),
+                   ASIErrorKind::CameraClosed);
+        sleep(Duration::from_millis(60));
+        // Retried, and the settings re-applied.
+        assert_eq!(supervised.get_roi_format().unwrap(), (320, 240, 2, ImgType::Raw16));
+        let events = events.lock().unwrap();
+        assert!(matches!(events[events.len() - 2],
+                         RecoveryEvent::Started{reason: RecoveryReason::Retry}));
+        assert!(matches!(events[events.len() - 1],
+                         RecoveryEvent::Recovered{attempts: 1, ..}));
+    }
+}
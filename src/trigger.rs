@@ -0,0 +1,279 @@
+// Copyright (c) 2023 Steven Rosenthal smr@dt3.org
+// See LICENSE file in root directory for license terms.
+
+use std::thread::sleep;
+use std::time::{Duration, Instant};
+
+use log::warn;
+
+use crate::asi_camera2_sdk::{ASIError, ASI_ERROR_CODE_ASI_ERROR_INVALID_MODE};
+use crate::camera_backend::CameraBackend;
+use crate::frame::Frame;
+use crate::types::{CameraMode, ControlType};
+
+/// Video capture in one of the trigger modes. Each trigger, sent with
+/// trigger() in the soft modes or arriving on the camera's trigger input in
+/// the hardware modes, yields one frame.
+///
+/// For synchronized captures across cameras, either wire their trigger inputs
+/// to a common signal (or to one camera's trigger output; see
+/// CameraBackend::set_trigger_output_config()) and use a hardware mode, or use
+/// a soft mode and trigger_all(). Hardware triggering is far more precise.
+///
+/// Dropping a TriggeredCapture stops video capture and returns the camera to
+/// CameraMode::Normal; call stop() instead to find out whether that
+/// succeeded.
+pub struct TriggeredCapture<'a, B: CameraBackend + ?Sized> {
+    camera: &'a mut B,
+    mode: CameraMode,
+}
+
+impl<'a, B: CameraBackend + ?Sized> TriggeredCapture<'a, B> {
+    /// Puts `camera` in `mode`, which must be a trigger mode the camera
+    /// supports, and starts video capture.
+    pub fn start(camera: &'a mut B, mode: CameraMode) -> Result<Self, ASIError> {
+        if !mode.is_triggered() {
+            return Err(ASIError::new(ASI_ERROR_CODE_ASI_ERROR_INVALID_MODE,
+                                     "TriggeredCapture::start"));
+        }
+        camera.set_camera_mode(mode)?;
+        if let Err(e) = camera.start_video_capture() {
+            if let Err(e) = camera.set_camera_mode(CameraMode::Normal) {
+                warn!("Error restoring normal mode on camera id {}: {}",
+                      camera.camera_id(), e);
+            }
+            return Err(e);
+        }
+        Ok(TriggeredCapture{camera, mode})
+    }
+
+    pub fn camera(&self) -> &B { self.camera }
+    pub fn mode(&self) -> CameraMode { self.mode }
+
+    /// Stops video capture and returns the camera to CameraMode::Normal.
+    pub fn stop(mut self) -> Result<(), ASIError> {
+        let result = self.finish();
+        std::mem::forget(self);
+        result
+    }
+
+    /// In the soft modes, triggers an exposure. For TrigSoftLevel, this holds
+    /// the trigger for the Exposure control's duration, and so blocks until
+    /// the exposure is over. Does nothing in the hardware modes.
+    pub fn trigger(&self) -> Result<(), ASIError> {
+        match self.mode {
+            CameraMode::TrigSoftEdge => self.camera.send_soft_trigger(true),
+            CameraMode::TrigSoftLevel => {
+                let exposure = self.exposure()?;
+                self.camera.send_soft_trigger(true)?;
+                sleep(exposure);
+                self.camera.send_soft_trigger(false)
+            },
+            _ => Ok(()),
+        }
+    }
+
+    /// Returns the next triggered frame, waiting up to `wait_ms` (-1 means
+    /// forever) for it.
+    pub fn wait_for_frame(&self, wait_ms: i32) -> Result<Frame, ASIError> {
+        self.camera.get_video_frame(wait_ms)
+    }
+
+    /// Triggers (in the soft modes) and returns the resulting frame; see
+    /// trigger() and wait_for_frame().
+    pub fn capture(&self, wait_ms: i32) -> Result<Frame, ASIError> {
+        self.trigger()?;
+        self.wait_for_frame(wait_ms)
+    }
+
+    /// The triggered-capture loop: captures frames as with capture(), passing
+    /// each to `on_frame` until it returns false. Returns the number of frames
+    /// captured, or the first error.
+    pub fn run<F>(&self, wait_ms: i32, mut on_frame: F) -> Result<usize, ASIError>
+    where F: FnMut(Frame) -> bool
+    {
+        let mut num_frames = 0;
+        loop {
+            let frame = self.capture(wait_ms)?;
+            num_frames += 1;
+            if !on_frame(frame) {
+                return Ok(num_frames);
+            }
+        }
+    }
+
+    fn exposure(&self) -> Result<Duration, ASIError> {
+        let (exposure_us, _auto) = self.camera.get_control_value(ControlType::Exposure)?;
+        Ok(Duration::from_micros(exposure_us.max(0) as u64))
+    }
+
+    fn finish(&mut self) -> Result<(), ASIError> {
+        let stop_result = self.camera.stop_video_capture();
+        let mode_result = self.camera.set_camera_mode(CameraMode::Normal);
+        stop_result.and(mode_result)
+    }
+}
+
+impl<B: CameraBackend + ?Sized> Drop for TriggeredCapture<'_, B> {
+    fn drop(&mut self) {
+        if let Err(e) = self.finish() {
+            warn!("Error ending triggered capture on camera id {}: {}",
+                  self.camera.camera_id(), e);
+        }
+    }
+}
+
+/// Triggers an exposure on each of `captures`, which must be in the same soft
+/// mode, with the triggers sent back to back to minimize the skew between
+/// cameras. For TrigSoftLevel, each camera's trigger is held for its own
+/// Exposure control's duration; this blocks until the longest is over.
+pub fn trigger_all<B>(captures: &[TriggeredCapture<'_, B>]) -> Result<(), ASIError>
+where B: CameraBackend + ?Sized
+{
+    let Some(first) = captures.first() else {
+        return Ok(());
+    };
+    let mode = first.mode;
+    if !mode.is_soft_triggered() || captures.iter().any(|c| c.mode != mode) {
+        return Err(ASIError::new(ASI_ERROR_CODE_ASI_ERROR_INVALID_MODE, "trigger_all"));
+    }
+    if mode == CameraMode::TrigSoftEdge {
+        for capture in captures {
+            capture.camera.send_soft_trigger(true)?;
+        }
+        return Ok(());
+    }
+
+    // Level triggered: end each camera's exposure in order of duration.
+    let mut ends = Vec::with_capacity(captures.len());
+    for capture in captures {
+        ends.push((capture.exposure()?, capture));
+    }
+    ends.sort_by_key(|(exposure, _)| *exposure);
+    let start = Instant::now();
+    for (index, capture) in captures.iter().enumerate() {
+        if let Err(e) = capture.camera.send_soft_trigger(true) {
+            // Don't leave the cameras already triggered exposing.
+            captures[..index].iter().for_each(release_trigger);
+            return Err(e);
+        }
+    }
+    let mut result = Ok(());
+    for (exposure, capture) in ends {
+        if let Some(remaining) = (start + exposure).checked_duration_since(Instant::now()) {
+            sleep(remaining);
+        }
+        // Release the remaining cameras even if one fails.
+        result = result.and(capture.camera.send_soft_trigger(false));
+    }
+    result
+}
+
+// Ends a level trigger while another error is being returned, so only logs
+// failure.
+fn release_trigger<B>(capture: &TriggeredCapture<'_, B>)
+where B: CameraBackend + ?Sized
+{
+    if let Err(e) = capture.camera.send_soft_trigger(false) {
+        warn!("Error releasing trigger on camera id {}: {}", capture.camera.camera_id(), e);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::asi_camera2_sdk::ASIErrorKind;
+    use crate::simulated_camera::{SimulatedCamera, SimulatorConfig};
+    use crate::simulated_camera::test_support::break_soft_trigger;
+    use crate::types::ImgType;
+
+    fn trigger_camera(exposure_us: i64) -> SimulatedCamera {
+        let mut camera = SimulatedCamera::new(
+            SimulatorConfig{is_trigger_cam: true, ..Default::default()});
+        camera.open().unwrap();
+        camera.set_roi_format(64, 32, 1, ImgType::Raw8).unwrap();
+        camera.set_control_value(ControlType::Exposure, exposure_us, false).unwrap();
+        camera
+    }
+
+    #[test]
+    fn invalid_modes() {
+        let mut camera = trigger_camera(1000);
+        let result = TriggeredCapture::start(&mut camera, CameraMode::Normal);
+        assert_eq!(result.err().map(|e| e.kind()), Some(ASIErrorKind::InvalidMode));
+        let mut camera = SimulatedCamera::new(SimulatorConfig::default());
+        camera.open().unwrap();
+        let result = TriggeredCapture::start(&mut camera, CameraMode::TrigSoftEdge);
+        assert_eq!(result.err().map(|e| e.kind()), Some(ASIErrorKind::InvalidMode));
+        assert_eq!(camera.get_camera_mode().unwrap(), CameraMode::Normal);
+    }
+
+    #[test]
+    fn capture_and_stop() {
+        let mut camera = trigger_camera(1000);
+        let capture = TriggeredCapture::start(&mut camera, CameraMode::TrigSoftEdge)
+            .unwrap();
+        assert_eq!(capture.mode(), CameraMode::TrigSoftEdge);
+        assert_eq!(capture.camera().get_camera_mode().unwrap(), CameraMode::TrigSoftEdge);
+        // Nothing is captured without a trigger.
+        assert_eq!(capture.wait_for_frame(20).unwrap_err().kind(), ASIErrorKind::Timeout);
+        let mut remaining = 3;
+        assert_eq!(capture.run(1000, |_frame| { remaining -= 1; remaining > 0 }).unwrap(),
+                   3);
+        capture.stop().unwrap();
+        assert_eq!(camera.get_camera_mode().unwrap(), CameraMode::Normal);
+    }
+
+    #[test]
+    fn drop_restores_normal_mode() {
+        let mut camera = trigger_camera(1000);
+        let capture = TriggeredCapture::start(&mut camera, CameraMode::TrigSoftLevel)
+            .unwrap();
+        capture.capture(1000).unwrap();
+        drop(capture);
+        assert_eq!(camera.get_camera_mode().unwrap(), CameraMode::Normal);
+        // Video capture was stopped.
+        assert_eq!(camera.get_video_frame(0).unwrap_err().kind(),
+                   ASIErrorKind::InvalidSequence);
+    }
+
+    #[test]
+    fn trigger_all_cameras() {
+        for mode in [CameraMode::TrigSoftEdge, CameraMode::TrigSoftLevel] {
+            let mut cameras = [trigger_camera(20_000), trigger_camera(40_000)];
+            let captures: Vec<_> = cameras.iter_mut()
+                .map(|camera| TriggeredCapture::start(camera, mode).unwrap())
+                .collect();
+            let start = Instant::now();
+            trigger_all(&captures).unwrap();
+            if mode == CameraMode::TrigSoftLevel {
+                // Held for the longest exposure.
+                assert!(start.elapsed() >= Duration::from_millis(40));
+            }
+            for capture in &captures {
+                capture.wait_for_frame(1000).unwrap();
+            }
+        }
+    }
+
+    #[test]
+    fn trigger_all_mixed_modes() {
+        let (mut camera1, mut camera2) = (trigger_camera(1000), trigger_camera(1000));
+        let captures = [
+            TriggeredCapture::start(&mut camera1, CameraMode::TrigSoftEdge).unwrap(),
+            TriggeredCapture::start(&mut camera2, CameraMode::TrigSoftLevel).unwrap()];
+        assert_eq!(trigger_all(&captures).unwrap_err().kind(), ASIErrorKind::InvalidMode);
+    }
+
+    #[test]
+    fn trigger_all_releases_on_error() {
+        let (mut camera1, mut camera2) = (trigger_camera(1000), trigger_camera(1000));
+        let captures = [
+            TriggeredCapture::start(&mut camera1, CameraMode::TrigSoftLevel).unwrap(),
+            TriggeredCapture::start(&mut camera2, CameraMode::TrigSoftLevel).unwrap()];
+        break_soft_trigger(captures[1].camera());
+        assert_eq!(trigger_all(&captures).unwrap_err().kind(), ASIErrorKind::GeneralError);
+        // The first camera's trigger was released, ending its exposure.
+        captures[0].wait_for_frame(0).unwrap();
+    }
+}
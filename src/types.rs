@@ -0,0 +1,588 @@
+// Copyright (c) 2023 Steven Rosenthal smr@dt3.org
+// See LICENSE file in root directory for license terms.
+
+use std::ffi::CStr;
+use std::fmt;
+use std::os::raw::{c_char, c_long};
+use std::str::FromStr;
+use std::time::Duration;
+
+use crate::asi_camera2_sdk::*;
+
+// Declares a Rust enum for one of the SDK's C enums. This generates:
+// * TryFrom<raw> for the enum; values this crate does not know about (e.g.
+//   returned by a newer SDK) yield an ASIError with `error_code`.
+// * From<enum> for raw.
+// * Display, giving `text`.
+// * FromStr, accepting `text` case-insensitively, with or without the SDK's
+//   `prefix` (e.g. "raw16", "RAW16" and "ASI_IMG_RAW16" all give Raw16).
+macro_rules! sdk_enum {
+    ($(#[$meta:meta])*
+     $name:ident($raw:ty), prefix = $prefix:literal, error_code = $error_code:expr,
+     {$($(#[$variant_meta:meta])* $variant:ident = $value:ident, $text:literal;)*}) => {
+        $(#[$meta])*
+        #[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+        #[non_exhaustive]
+        pub enum $name {
+            $($(#[$variant_meta])* $variant,)*
+        }
+
+        impl $name {
+            /// All of the values, in SDK order.
+            pub const ALL: &'static [$name] = &[$($name::$variant,)*];
+        }
+
+        impl TryFrom<$raw> for $name {
+            type Error = ASIError;
+
+            fn try_from(value: $raw) -> Result<Self, ASIError> {
+                match value {
+                    $($value => Ok($name::$variant),)*
+                    _ => Err(ASIError::new(
+                        $error_code,
+                        &format!("{}::try_from({})", stringify!($name), value))),
+                }
+            }
+        }
+
+        impl From<$name> for $raw {
+            fn from(value: $name) -> $raw {
+                match value {
+                    $($name::$variant => $value,)*
+                }
+            }
+        }
+
+        impl fmt::Display for $name {
+            fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+                let text = match self {
+                    $($name::$variant => $text,)*
+                };
+                write!(f, "{}", text)
+            }
+        }
+
+        impl FromStr for $name {
+            type Err = ASIError;
+
+            fn from_str(s: &str) -> Result<Self, ASIError> {
+                let trimmed = s.trim();
+                let text = match trimmed.get(..$prefix.len()) {
+                    Some(prefix) if prefix.eq_ignore_ascii_case($prefix) =>
+                        &trimmed[$prefix.len()..],
+                    _ => trimmed,
+                };
+                $(if text.eq_ignore_ascii_case($text) {
+                    return Ok($name::$variant);
+                })*
+                Err(ASIError::new(
+                    $error_code,
+                    &format!("{}::from_str({:?})", stringify!($name), s)))
+            }
+        }
+    };
+}
+
+sdk_enum! {
+    /// Pixel format of the frames delivered by the camera.
+    ImgType(ASI_IMG_TYPE), prefix = "ASI_IMG_",
+    error_code = ASI_ERROR_CODE_ASI_ERROR_INVALID_IMGTYPE,
+    {
+        Raw8 = ASI_IMG_TYPE_ASI_IMG_RAW8, "RAW8";
+        /// Three bytes per pixel, in B, G, R order.
+        Rgb24 = ASI_IMG_TYPE_ASI_IMG_RGB24, "RGB24";
+        /// Two bytes per pixel, little-endian.
+        Raw16 = ASI_IMG_TYPE_ASI_IMG_RAW16, "RAW16";
+        /// Luminance; one byte per pixel.
+        Y8 = ASI_IMG_TYPE_ASI_IMG_Y8, "Y8";
+    }
+}
+
+impl ImgType {
+    /// Returns the number of bytes the SDK uses for each pixel: 1 for RAW8 and
+    /// Y8, 2 for RAW16 and 3 for RGB24.
+    pub fn bytes_per_pixel(self) -> usize {
+        match self {
+            ImgType::Raw16 => 2,
+            ImgType::Rgb24 => 3,
+            _ => 1,
+        }
+    }
+}
+
+sdk_enum! {
+    /// Identifies a camera control, for get_control_value() and
+    /// set_control_value(). Not all cameras support all controls; see
+    /// get_control_caps().
+    ControlType(ASI_CONTROL_TYPE), prefix = "ASI_",
+    error_code = ASI_ERROR_CODE_ASI_ERROR_INVALID_CONTROL_TYPE,
+    {
+        Gain = ASI_CONTROL_TYPE_ASI_GAIN, "GAIN";
+        /// Microseconds.
+        Exposure = ASI_CONTROL_TYPE_ASI_EXPOSURE, "EXPOSURE";
+        Gamma = ASI_CONTROL_TYPE_ASI_GAMMA, "GAMMA";
+        WbR = ASI_CONTROL_TYPE_ASI_WB_R, "WB_R";
+        WbB = ASI_CONTROL_TYPE_ASI_WB_B, "WB_B";
+        Offset = ASI_CONTROL_TYPE_ASI_OFFSET, "OFFSET";
+        BandwidthOverload = ASI_CONTROL_TYPE_ASI_BANDWIDTHOVERLOAD,
+            "BANDWIDTHOVERLOAD";
+        Overclock = ASI_CONTROL_TYPE_ASI_OVERCLOCK, "OVERCLOCK";
+        /// Sensor temperature, in tenths of a degree Celsius. Read-only.
+        Temperature = ASI_CONTROL_TYPE_ASI_TEMPERATURE, "TEMPERATURE";
+        /// See FlipStatus.
+        Flip = ASI_CONTROL_TYPE_ASI_FLIP, "FLIP";
+        AutoMaxGain = ASI_CONTROL_TYPE_ASI_AUTO_MAX_GAIN, "AUTO_MAX_GAIN";
+        /// Milliseconds.
+        AutoMaxExp = ASI_CONTROL_TYPE_ASI_AUTO_MAX_EXP, "AUTO_MAX_EXP";
+        AutoTargetBrightness = ASI_CONTROL_TYPE_ASI_AUTO_TARGET_BRIGHTNESS,
+            "AUTO_TARGET_BRIGHTNESS";
+        HardwareBin = ASI_CONTROL_TYPE_ASI_HARDWARE_BIN, "HARDWARE_BIN";
+        HighSpeedMode = ASI_CONTROL_TYPE_ASI_HIGH_SPEED_MODE, "HIGH_SPEED_MODE";
+        CoolerPowerPerc = ASI_CONTROL_TYPE_ASI_COOLER_POWER_PERC,
+            "COOLER_POWER_PERC";
+        /// Degrees Celsius.
+        TargetTemp = ASI_CONTROL_TYPE_ASI_TARGET_TEMP, "TARGET_TEMP";
+        CoolerOn = ASI_CONTROL_TYPE_ASI_COOLER_ON, "COOLER_ON";
+        MonoBin = ASI_CONTROL_TYPE_ASI_MONO_BIN, "MONO_BIN";
+        FanOn = ASI_CONTROL_TYPE_ASI_FAN_ON, "FAN_ON";
+        PatternAdjust = ASI_CONTROL_TYPE_ASI_PATTERN_ADJUST, "PATTERN_ADJUST";
+        AntiDewHeater = ASI_CONTROL_TYPE_ASI_ANTI_DEW_HEATER, "ANTI_DEW_HEATER";
+    }
+}
+
+sdk_enum! {
+    /// Color filter arrangement of the top-left 2x2 pixels of a color sensor.
+    BayerPattern(ASI_BAYER_PATTERN), prefix = "ASI_BAYER_",
+    error_code = ASI_ERROR_CODE_ASI_ERROR_GENERAL_ERROR,
+    {
+        Rg = ASI_BAYER_PATTERN_ASI_BAYER_RG, "RG";
+        Bg = ASI_BAYER_PATTERN_ASI_BAYER_BG, "BG";
+        Gr = ASI_BAYER_PATTERN_ASI_BAYER_GR, "GR";
+        Gb = ASI_BAYER_PATTERN_ASI_BAYER_GB, "GB";
+    }
+}
+
+sdk_enum! {
+    /// Direction of an ST4 guide pulse.
+    GuideDirection(ASI_GUIDE_DIRECTION), prefix = "ASI_GUIDE_",
+    error_code = ASI_ERROR_CODE_ASI_ERROR_GENERAL_ERROR,
+    {
+        North = ASI_GUIDE_DIRECTION_ASI_GUIDE_NORTH, "NORTH";
+        South = ASI_GUIDE_DIRECTION_ASI_GUIDE_SOUTH, "SOUTH";
+        East = ASI_GUIDE_DIRECTION_ASI_GUIDE_EAST, "EAST";
+        West = ASI_GUIDE_DIRECTION_ASI_GUIDE_WEST, "WEST";
+    }
+}
+
+impl GuideDirection {
+    /// North and South pulses drive the declination axis; East and West
+    /// pulses drive the right ascension axis.
+    pub fn is_declination(self) -> bool {
+        matches!(self, GuideDirection::North | GuideDirection::South)
+    }
+}
+
+sdk_enum! {
+    /// Value of the Flip control.
+    FlipStatus(ASI_FLIP_STATUS), prefix = "ASI_FLIP_",
+    error_code = ASI_ERROR_CODE_ASI_ERROR_GENERAL_ERROR,
+    {
+        None = ASI_FLIP_STATUS_ASI_FLIP_NONE, "NONE";
+        Horiz = ASI_FLIP_STATUS_ASI_FLIP_HORIZ, "HORIZ";
+        Vert = ASI_FLIP_STATUS_ASI_FLIP_VERT, "VERT";
+        Both = ASI_FLIP_STATUS_ASI_FLIP_BOTH, "BOTH";
+    }
+}
+
+impl FlipStatus {
+    pub fn is_horizontal(self) -> bool {
+        matches!(self, FlipStatus::Horiz | FlipStatus::Both)
+    }
+    pub fn is_vertical(self) -> bool {
+        matches!(self, FlipStatus::Vert | FlipStatus::Both)
+    }
+}
+
+sdk_enum! {
+    /// State of a single-frame exposure.
+    ExposureStatus(ASI_EXPOSURE_STATUS), prefix = "ASI_EXP_",
+    error_code = ASI_ERROR_CODE_ASI_ERROR_GENERAL_ERROR,
+    {
+        /// Ready to start an exposure.
+        Idle = ASI_EXPOSURE_STATUS_ASI_EXP_IDLE, "IDLE";
+        Working = ASI_EXPOSURE_STATUS_ASI_EXP_WORKING, "WORKING";
+        /// Exposure finished; waiting for get_data_after_exp().
+        Success = ASI_EXPOSURE_STATUS_ASI_EXP_SUCCESS, "SUCCESS";
+        /// Exposure failed; start another.
+        Failed = ASI_EXPOSURE_STATUS_ASI_EXP_FAILED, "FAILED";
+    }
+}
+
+sdk_enum! {
+    /// How exposures are started. Only cameras whose CameraInfo::is_trigger_cam
+    /// is set support modes other than Normal.
+    CameraMode(ASI_CAMERA_MODE), prefix = "ASI_MODE_",
+    error_code = ASI_ERROR_CODE_ASI_ERROR_INVALID_MODE,
+    {
+        /// Free-running; exposures are started by start_exposure() or video
+        /// capture.
+        Normal = ASI_CAMERA_MODE_ASI_MODE_NORMAL, "NORMAL";
+        /// Each send_soft_trigger(true) starts an exposure of the Exposure
+        /// control's duration.
+        TrigSoftEdge = ASI_CAMERA_MODE_ASI_MODE_TRIG_SOFT_EDGE, "TRIG_SOFT_EDGE";
+        /// A rising edge on the trigger input starts an exposure.
+        TrigRiseEdge = ASI_CAMERA_MODE_ASI_MODE_TRIG_RISE_EDGE, "TRIG_RISE_EDGE";
+        /// A falling edge on the trigger input starts an exposure.
+        TrigFallEdge = ASI_CAMERA_MODE_ASI_MODE_TRIG_FALL_EDGE, "TRIG_FALL_EDGE";
+        /// The exposure lasts from send_soft_trigger(true) until
+        /// send_soft_trigger(false).
+        TrigSoftLevel = ASI_CAMERA_MODE_ASI_MODE_TRIG_SOFT_LEVEL, "TRIG_SOFT_LEVEL";
+        /// The exposure lasts while the trigger input is high.
+        TrigHighLevel = ASI_CAMERA_MODE_ASI_MODE_TRIG_HIGH_LEVEL, "TRIG_HIGH_LEVEL";
+        /// The exposure lasts while the trigger input is low.
+        TrigLowLevel = ASI_CAMERA_MODE_ASI_MODE_TRIG_LOW_LEVEL, "TRIG_LOW_LEVEL";
+    }
+}
+
+impl CameraMode {
+    pub fn is_triggered(self) -> bool {
+        self != CameraMode::Normal
+    }
+    /// Whether triggers are sent with send_soft_trigger() rather than on the
+    /// camera's trigger input.
+    pub fn is_soft_triggered(self) -> bool {
+        matches!(self, CameraMode::TrigSoftEdge | CameraMode::TrigSoftLevel)
+    }
+    /// Whether the trigger's duration, rather than the Exposure control, sets
+    /// the exposure time.
+    pub fn is_level_triggered(self) -> bool {
+        matches!(self, CameraMode::TrigSoftLevel | CameraMode::TrigHighLevel |
+                 CameraMode::TrigLowLevel)
+    }
+}
+
+sdk_enum! {
+    /// One of the output pins of a trigger camera's trigger port.
+    TriggerOutputPin(ASI_TRIG_OUTPUT_PIN), prefix = "ASI_TRIG_OUTPUT_",
+    error_code = ASI_ERROR_CODE_ASI_ERROR_GENERAL_ERROR,
+    {
+        PinA = ASI_TRIG_OUTPUT_ASI_TRIG_OUTPUT_PINA, "PINA";
+        PinB = ASI_TRIG_OUTPUT_ASI_TRIG_OUTPUT_PINB, "PINB";
+    }
+}
+
+/// Configuration of a trigger output pin. When the camera receives a trigger,
+/// the pin goes to its active level after `delay` and stays there for
+/// `duration`. A zero `duration` disables the pin.
+///
+/// Both times have microsecond resolution and range up to 2000 seconds.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct TriggerOutputConfig {
+    /// Whether the active level is high (true) or low (false).
+    pub pin_high: bool,
+    pub delay: Duration,
+    pub duration: Duration,
+}
+
+/// Describes a connected camera. This is an owned form of the SDK's
+/// ASI_CAMERA_INFO.
+#[derive(Clone, Debug, PartialEq)]
+pub struct CameraInfo {
+    pub name: String,
+    /// Pass to ASICamera::new().
+    pub camera_id: i32,
+    pub max_height: i32,
+    pub max_width: i32,
+    pub is_color_cam: bool,
+    /// None for monochrome cameras.
+    pub bayer_pattern: Option<BayerPattern>,
+    /// E.g. [1, 2, 4] if the camera supports 1x1, 2x2 and 4x4 binning.
+    pub supported_bins: Vec<u32>,
+    pub supported_video_formats: Vec<ImgType>,
+    /// Microns.
+    pub pixel_size: f64,
+    pub mechanical_shutter: bool,
+    pub st4_port: bool,
+    pub is_cooler_cam: bool,
+    pub is_usb3_host: bool,
+    pub is_usb3_camera: bool,
+    pub elec_per_adu: f32,
+    pub bit_depth: i32,
+    pub is_trigger_cam: bool,
+}
+
+impl TryFrom<&ASI_CAMERA_INFO> for CameraInfo {
+    type Error = ASIError;
+
+    fn try_from(info: &ASI_CAMERA_INFO) -> Result<Self, ASIError> {
+        let is_color_cam = info.IsColorCam != 0;
+        let bayer_pattern = if is_color_cam {
+            Some(BayerPattern::try_from(info.BayerPattern)?)
+        } else {
+            None
+        };
+        // Both lists are terminated by a 0 or ASI_IMG_END entry.
+        let supported_bins = info.SupportedBins.iter()
+            .take_while(|bin| **bin > 0)
+            .map(|bin| *bin as u32)
+            .collect();
+        let supported_video_formats = info.SupportedVideoFormat.iter()
+            .take_while(|img_type| **img_type != ASI_IMG_TYPE_ASI_IMG_END)
+            .map(|img_type| ImgType::try_from(*img_type))
+            .collect::<Result<_, _>>()?;
+        Ok(CameraInfo{
+            name: c_chars_to_string(&info.Name),
+            camera_id: info.CameraID,
+            max_height: info.MaxHeight as i32,
+            max_width: info.MaxWidth as i32,
+            is_color_cam,
+            bayer_pattern,
+            supported_bins,
+            supported_video_formats,
+            pixel_size: info.PixelSize,
+            mechanical_shutter: info.MechanicalShutter != 0,
+            st4_port: info.ST4Port != 0,
+            is_cooler_cam: info.IsCoolerCam != 0,
+            is_usb3_host: info.IsUSB3Host != 0,
+            is_usb3_camera: info.IsUSB3Camera != 0,
+            elec_per_adu: info.ElecPerADU,
+            bit_depth: info.BitDepth,
+            is_trigger_cam: info.IsTriggerCam != 0,
+        })
+    }
+}
+
+/// Describes one of a camera's controls. This is an owned form of the SDK's
+/// ASI_CONTROL_CAPS.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ControlCaps {
+    pub name: String,
+    pub description: String,
+    pub max_value: i64,
+    pub min_value: i64,
+    pub default_value: i64,
+    pub is_auto_supported: bool,
+    pub is_writable: bool,
+    pub control_type: ControlType,
+}
+
+impl TryFrom<&ASI_CONTROL_CAPS> for ControlCaps {
+    type Error = ASIError;
+
+    fn try_from(caps: &ASI_CONTROL_CAPS) -> Result<Self, ASIError> {
+        let max_value: c_long = caps.MaxValue;
+        let min_value: c_long = caps.MinValue;
+        let default_value: c_long = caps.DefaultValue;
+        Ok(ControlCaps{
+            name: c_chars_to_string(&caps.Name),
+            description: c_chars_to_string(&caps.Description),
+            max_value: max_value as i64,
+            min_value: min_value as i64,
+            default_value: default_value as i64,
+            is_auto_supported: caps.IsAutoSupported != 0,
+            is_writable: caps.IsWritable != 0,
+            control_type: ControlType::try_from(caps.ControlType)?,
+        })
+    }
+}
+
+// The SDK's strings are nul-terminated arrays of c_char, which is i8 on some
+// platforms (e.g. x86_64) and u8 on others (e.g. aarch64).
+pub(crate) fn c_chars_to_string(chars: &[c_char]) -> String {
+    let mut bytes: Vec<u8> = chars.iter().map(|c| c.to_ne_bytes()[0]).collect();
+    if !bytes.contains(&0) {
+        bytes.push(0);
+    }
+    CStr::from_bytes_until_nul(&bytes).unwrap().to_string_lossy().into_owned()
+}
+
+/// The manufacturer's recommended offsets, and gain for lowest read noise,
+/// from ASIGetGainOffset(). Gains are in the Gain control's units.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct GainOffsets {
+    /// Offset at the highest dynamic range, i.e. at minimum gain.
+    pub offset_highest_dr: i32,
+    pub offset_unity_gain: i32,
+    pub gain_lowest_read_noise: i32,
+    pub offset_lowest_read_noise: i32,
+}
+
+/// The manufacturer's frequently used gains, from ASIGetLMHGainOffset().
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct LmhGainOffsets {
+    pub low_gain: i32,
+    pub medium_gain: i32,
+    /// The gain at the lowest read noise.
+    pub high_gain: i32,
+    /// The offset at the lowest read noise.
+    pub high_offset: i32,
+}
+
+/// A gain and offset setting recommended for the camera; see
+/// CameraBackend::gain_preset().
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum GainPreset {
+    /// Minimum gain, for the largest full well relative to read noise.
+    HighestDR,
+    /// The gain at which one ADU is one electron.
+    Unity,
+    LowestReadNoise,
+    Low,
+    Medium,
+    High,
+}
+
+impl GainPreset {
+    pub const ALL: &'static [GainPreset] = &[
+        GainPreset::HighestDR, GainPreset::Unity, GainPreset::LowestReadNoise,
+        GainPreset::Low, GainPreset::Medium, GainPreset::High];
+
+    fn text(self) -> &'static str {
+        match self {
+            GainPreset::HighestDR => "HIGHEST_DR",
+            GainPreset::Unity => "UNITY",
+            GainPreset::LowestReadNoise => "LOWEST_READ_NOISE",
+            GainPreset::Low => "LOW",
+            GainPreset::Medium => "MEDIUM",
+            GainPreset::High => "HIGH",
+        }
+    }
+}
+
+impl fmt::Display for GainPreset {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.text())
+    }
+}
+
+impl FromStr for GainPreset {
+    type Err = ASIError;
+
+    /// Accepts the Display text case-insensitively, e.g. "unity" or
+    /// "Lowest_Read_Noise".
+    fn from_str(s: &str) -> Result<Self, ASIError> {
+        GainPreset::ALL.iter().copied()
+            .find(|preset| s.trim().eq_ignore_ascii_case(preset.text()))
+            .ok_or_else(|| ASIError::new(
+                ASI_ERROR_CODE_ASI_ERROR_GENERAL_ERROR,
+                &format!("GainPreset::from_str({:?})", s)))
+    }
+}
+
+/// Version of the ASI Camera2 SDK library; see ASICamera::get_sdk_version().
+/// Versions compare in (major, minor, patch) order.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct SdkVersion {
+    pub major: u32,
+    pub minor: u32,
+    pub patch: u32,
+}
+
+impl SdkVersion {
+    pub fn new(major: u32, minor: u32, patch: u32) -> Self {
+        SdkVersion{major, minor, patch}
+    }
+}
+
+impl fmt::Display for SdkVersion {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}.{}.{}", self.major, self.minor, self.patch)
+    }
+}
+
+impl FromStr for SdkVersion {
+    type Err = ASIError;
+
+    /// Accepts the SDK's form, e.g. "1, 29, 0413", as well as "1.29.413".
+    /// Omitted minor and patch components are taken as zero.
+    fn from_str(s: &str) -> Result<Self, ASIError> {
+        let invalid = || ASIError::new(ASI_ERROR_CODE_ASI_ERROR_GENERAL_ERROR,
+                                       &format!("SdkVersion::from_str({:?})", s));
+        let mut components = [0u32; 3];
+        for (index, part) in s.split([',', '.']).enumerate() {
+            let component = components.get_mut(index).ok_or_else(invalid)?;
+            *component = part.trim().parse().map_err(|_| invalid())?;
+        }
+        let [major, minor, patch] = components;
+        Ok(SdkVersion{major, minor, patch})
+    }
+}
+
+/// Longest alias that can be stored in a camera; see CameraBackend::set_id().
+pub const MAX_ALIAS_LEN: usize = 8;
+
+// Returns the alias stored in an ASI_ID: its bytes up to the first NUL.
+pub(crate) fn asi_id_to_string(asi_id: &ASI_ID) -> String {
+    let len = asi_id.id.iter().position(|b| *b == 0).unwrap_or(asi_id.id.len());
+    String::from_utf8_lossy(&asi_id.id[..len]).into_owned()
+}
+
+// Converts `alias` to a NUL padded ASI_ID. The alias must be at most
+// MAX_ALIAS_LEN characters of printable ASCII; spaces are allowed.
+pub(crate) fn asi_id_from_str(alias: &str, source: &str) -> Result<ASI_ID, ASIError> {
+    if alias.len() > MAX_ALIAS_LEN ||
+        !alias.bytes().all(|b| b.is_ascii_graphic() || b == b' ')
+    {
+        return Err(ASIError::new(ASI_ERROR_CODE_ASI_ERROR_GENERAL_ERROR,
+                                 &format!("{}({:?})", source, alias)));
+    }
+    let mut asi_id = ASI_ID{id: [0; 8]};
+    asi_id.id[..alias.len()].copy_from_slice(alias.as_bytes());
+    Ok(asi_id)
+}
+
+// Serial numbers are 8 bytes, conventionally shown as 16 hex digits.
+pub(crate) fn serial_number_to_hex(serial_number: &ASI_SN) -> String {
+    serial_number.id.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Checks the conversions generated by sdk_enum! for every value of each
+    // enum.
+    macro_rules! check_conversions {
+        ($($name:ident($raw:ty), $prefix:literal;)*) => {$(
+            for value in $name::ALL {
+                assert_eq!($name::try_from(<$raw>::from(*value)).unwrap(), *value);
+                let text = value.to_string();
+                assert_eq!(text.parse::<$name>().unwrap(), *value);
+                let prefixed = format!(" {}{} ", $prefix, text.to_lowercase());
+                assert_eq!(prefixed.parse::<$name>().unwrap(), *value, "{:?}", prefixed);
+            }
+            assert!($name::try_from(<$raw>::MAX).is_err());
+            assert!("".parse::<$name>().is_err());
+            assert!($prefix.parse::<$name>().is_err());
+        )*};
+    }
+
+    #[test]
+    fn enum_conversions() {
+        check_conversions! {
+            ImgType(ASI_IMG_TYPE), "ASI_IMG_";
+            ControlType(ASI_CONTROL_TYPE), "ASI_";
+            BayerPattern(ASI_BAYER_PATTERN), "ASI_BAYER_";
+            GuideDirection(ASI_GUIDE_DIRECTION), "ASI_GUIDE_";
+            FlipStatus(ASI_FLIP_STATUS), "ASI_FLIP_";
+            ExposureStatus(ASI_EXPOSURE_STATUS), "ASI_EXP_";
+            CameraMode(ASI_CAMERA_MODE), "ASI_MODE_";
+            TriggerOutputPin(ASI_TRIG_OUTPUT_PIN), "ASI_TRIG_OUTPUT_";
+        }
+        assert_eq!(ImgType::try_from(-2).unwrap_err().kind(), ASIErrorKind::InvalidImgType);
+        assert_eq!("asi_img_raw16".parse::<ImgType>().unwrap(), ImgType::Raw16);
+    }
+
+    #[test]
+    fn sdk_version() {
+        let version: SdkVersion = "1, 29, 0413".parse().unwrap();
+        assert_eq!(version, SdkVersion::new(1, 29, 413));
+        assert_eq!(version.to_string(), "1.29.413");
+        assert_eq!("1.29.413".parse::<SdkVersion>().unwrap(), version);
+        assert_eq!(" 1 , 2 ".parse::<SdkVersion>().unwrap(), SdkVersion::new(1, 2, 0));
+        assert!(version > SdkVersion::new(1, 3, 999));
+        for malformed in ["", "1, 29, 0413, 1", "1,,2", "1.x", "-1", "v1.2"] {
+            assert!(malformed.parse::<SdkVersion>().is_err(), "{:?}", malformed);
+        }
+    }
+}
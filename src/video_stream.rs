@@ -0,0 +1,506 @@
+// Copyright (c) 2023 Steven Rosenthal smr@dt3.org
+// See LICENSE file in root directory for license terms.
+
+use std::collections::VecDeque;
+use std::fmt;
+use std::ops::Deref;
+use std::sync::{Arc, Condvar, Mutex, MutexGuard, RwLock, RwLockReadGuard,
+                RwLockWriteGuard};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+use log::{info, warn};
+
+use crate::asi_camera2_sdk::{ASIError, ASIErrorKind,
+                             ASI_ERROR_CODE_ASI_ERROR_INVALID_SEQUENCE,
+                             ASI_ERROR_CODE_ASI_ERROR_TIMEOUT};
+use crate::camera_backend::CameraBackend;
+use crate::frame::{self, Frame, FrameMetadata};
+use crate::lock_ignoring_poison;
+
+/// What a VideoStream does with a new frame when its queue is full.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum DropPolicy {
+    /// Discard the oldest queued frame, so the consumer always gets the most
+    /// recent frames. Suits live display and guiding.
+    DropOldest,
+    /// Discard the new frame, so the queued frames stay contiguous. Suits
+    /// recording, where the consumer is expected to keep up.
+    DropNewest,
+}
+
+#[derive(Clone, Debug)]
+pub struct VideoStreamConfig {
+    /// Maximum number of frames waiting to be consumed.
+    pub queue_capacity: usize,
+    pub drop_policy: DropPolicy,
+    /// How long the worker waits for each frame before checking whether it
+    /// should stop. Frames are not lost when this elapses; it only bounds how
+    /// long stop() and camera_mut() wait for the worker.
+    pub poll_interval: Duration,
+}
+
+impl Default for VideoStreamConfig {
+    fn default() -> Self {
+        VideoStreamConfig{
+            queue_capacity: 4,
+            drop_policy: DropPolicy::DropOldest,
+            poll_interval: Duration::from_millis(100),
+        }
+    }
+}
+
+/// Runs video capture on a worker thread that owns the camera, keeping the
+/// SDK's small frame buffer drained. Frames are delivered through a bounded
+/// queue; when the consumer falls behind, frames are dropped according to the
+/// configured DropPolicy and counted (see queue_dropped_frames()), separately
+/// from frames dropped by the SDK itself (see sdk_dropped_frames()).
+///
+/// Frame buffers are recycled: a StreamFrame returns its buffer to the
+/// stream's pool when dropped, so steady-state streaming does not allocate.
+///
+/// Dropping a VideoStream stops capture; call stop() or into_camera() instead
+/// to find out whether that succeeded.
+pub struct VideoStream<B: CameraBackend + Send + Sync + 'static> {
+    shared: Arc<Shared<B>>,
+    worker: Option<JoinHandle<()>>,
+}
+
+struct Shared<B> {
+    config: VideoStreamConfig,
+    // The worker holds a read lock while waiting for each frame.
+    camera: RwLock<B>,
+    queue: Mutex<QueueState>,
+    // Signalled when a frame is queued or the worker exits.
+    frame_ready: Condvar,
+    stop: AtomicBool,
+    pool: Arc<BufferPool>,
+}
+
+struct QueueState {
+    frames: VecDeque<Frame>,
+    queue_dropped: u64,
+    worker_running: bool,
+    // Why the worker exited, if not because it was asked to stop.
+    error: Option<ASIError>,
+}
+
+/// A frame delivered by a VideoStream. Dereferences to the Frame; its buffer
+/// is returned to the stream's pool when it is dropped, unless it is taken
+/// with into_frame().
+pub struct StreamFrame {
+    frame: Frame,
+    pool: Arc<BufferPool>,
+}
+
+impl StreamFrame {
+    /// Takes ownership of the frame; its buffer will not be reused.
+    pub fn into_frame(mut self) -> Frame {
+        let frame = &mut self.frame;
+        Frame{width: frame.width, height: frame.height, bin: frame.bin,
+              img_type: frame.img_type, metadata: std::mem::take(&mut frame.metadata),
+              data: std::mem::take(&mut frame.data)}
+    }
+}
+
+impl Deref for StreamFrame {
+    type Target = Frame;
+    fn deref(&self) -> &Frame { &self.frame }
+}
+
+impl Drop for StreamFrame {
+    fn drop(&mut self) {
+        self.pool.put(std::mem::take(&mut self.frame.data));
+    }
+}
+
+impl fmt::Debug for StreamFrame {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.frame.fmt(f)
+    }
+}
+
+struct BufferPool {
+    buffers: Mutex<Vec<Vec<u8>>>,
+    max_buffers: usize,
+}
+
+impl BufferPool {
+    // Returns a buffer of `size` bytes, reusing a pooled one if possible.
+    fn take(&self, size: usize) -> Vec<u8> {
+        let buffer = lock_ignoring_poison(&self.buffers).pop();
+        let mut buffer = buffer.unwrap_or_default();
+        buffer.resize(size, 0);
+        buffer
+    }
+
+    fn put(&self, buffer: Vec<u8>) {
+        if buffer.capacity() == 0 {
+            return;
+        }
+        let mut buffers = lock_ignoring_poison(&self.buffers);
+        if buffers.len() < self.max_buffers {
+            buffers.push(buffer);
+        }
+    }
+}
+
+impl<B: CameraBackend + Send + Sync + 'static> VideoStream<B> {
+    /// Starts video capture on `camera`, which must be opened and initialized,
+    /// and starts the worker thread.
+    pub fn start(camera: B, config: VideoStreamConfig) -> Result<Self, ASIError> {
+        let pool = Arc::new(BufferPool{buffers: Mutex::new(Vec::new()),
+                                       // Queued frames plus one being filled
+                                       // and one being consumed.
+                                       max_buffers: config.queue_capacity + 2});
+        let shared = Arc::new(Shared{
+            config,
+            camera: RwLock::new(camera),
+            queue: Mutex::new(QueueState{frames: VecDeque::new(), queue_dropped: 0,
+                                         worker_running: false, error: None}),
+            frame_ready: Condvar::new(),
+            stop: AtomicBool::new(false),
+            pool,
+        });
+        let mut stream = VideoStream{shared, worker: None};
+        stream.restart()?;
+        Ok(stream)
+    }
+
+    /// Starts capture again after stop(), or after the worker exited with an
+    /// error. Frames still queued are kept. Does nothing if already running.
+    pub fn restart(&mut self) -> Result<(), ASIError> {
+        if self.worker.is_some() {
+            if self.is_running() {
+                return Ok(());
+            }
+            // The worker exited with an error; clean up after it.
+            self.stop()?;
+        }
+        self.shared.camera_mut().start_video_capture()?;
+        {
+            let mut queue = self.shared.lock_queue();
+            queue.worker_running = true;
+            queue.error = None;
+        }
+        self.shared.stop.store(false, Ordering::SeqCst);
+        let shared = self.shared.clone();
+        self.worker = Some(thread::spawn(move || shared.run()));
+        Ok(())
+    }
+
+    /// Stops the worker thread and video capture. Frames already queued can
+    /// still be read.
+    pub fn stop(&mut self) -> Result<(), ASIError> {
+        let Some(worker) = self.worker.take() else {
+            return Ok(());
+        };
+        self.shared.stop.store(true, Ordering::SeqCst);
+        if worker.join().is_err() {
+            warn!("Video stream worker panicked");
+        }
+        self.shared.camera_mut().stop_video_capture()
+    }
+
+    /// Stops capture and returns the camera, together with the result of
+    /// stopping video capture. The camera is returned even if that failed.
+    pub fn into_camera(mut self) -> (B, Result<(), ASIError>) {
+        let result = self.stop();
+        let shared = self.shared.clone();
+        // The worker has already been stopped, so dropping does nothing more.
+        drop(self);
+        // The worker has exited, so this is the only reference.
+        match Arc::try_unwrap(shared) {
+            Ok(shared) =>
+                (shared.camera.into_inner().unwrap_or_else(|e| e.into_inner()), result),
+            Err(_) => unreachable!("video stream worker still running"),
+        }
+    }
+
+    /// Whether the worker is capturing; false after stop() or once the worker
+    /// has exited due to an error.
+    pub fn is_running(&self) -> bool {
+        self.shared.lock_queue().worker_running
+    }
+
+    /// Returns the next frame, waiting up to `timeout` (None means forever).
+    /// Once the queue is empty, returns the error that stopped the worker, if
+    /// any, or an ASIErrorKind::InvalidSequence error if stopped.
+    pub fn next_frame(&self, timeout: Option<Duration>) -> Result<StreamFrame, ASIError> {
+        let deadline = timeout.map(|timeout| Instant::now() + timeout);
+        let mut queue = self.shared.lock_queue();
+        loop {
+            if let Some(frame) = queue.frames.pop_front() {
+                return Ok(StreamFrame{frame, pool: self.shared.pool.clone()});
+            }
+            if !queue.worker_running {
+                return Err(match &queue.error {
+                    Some(e) => e.clone(),
+                    None => ASIError::new(ASI_ERROR_CODE_ASI_ERROR_INVALID_SEQUENCE,
+                                          "next_frame"),
+                });
+            }
+            queue = match deadline {
+                Some(deadline) => {
+                    let now = Instant::now();
+                    if now >= deadline {
+                        return Err(ASIError::new(ASI_ERROR_CODE_ASI_ERROR_TIMEOUT,
+                                                 "next_frame"));
+                    }
+                    self.shared.frame_ready.wait_timeout(queue, deadline - now)
+                        .unwrap_or_else(|e| e.into_inner()).0
+                },
+                None => self.shared.frame_ready.wait(queue)
+                    .unwrap_or_else(|e| e.into_inner()),
+            };
+        }
+    }
+
+    /// Returns the next frame if one is queued.
+    pub fn try_next_frame(&self) -> Option<StreamFrame> {
+        let frame = self.shared.lock_queue().frames.pop_front()?;
+        Some(StreamFrame{frame, pool: self.shared.pool.clone()})
+    }
+
+    /// Number of frames waiting to be consumed.
+    pub fn queued_frames(&self) -> usize {
+        self.shared.lock_queue().frames.len()
+    }
+
+    /// Frames discarded because the queue was full, since the stream was
+    /// started.
+    pub fn queue_dropped_frames(&self) -> u64 {
+        self.shared.lock_queue().queue_dropped
+    }
+
+    /// Frames dropped by the SDK because the worker did not read them in time
+    /// (see CameraBackend::get_dropped_frames()). The SDK resets this count
+    /// when capture is restarted.
+    pub fn sdk_dropped_frames(&self) -> Result<i32, ASIError> {
+        self.shared.camera().get_dropped_frames()
+    }
+
+    /// Shared access to the camera, e.g. for get_control_value() or
+    /// pulse_guide(); does not interrupt capture.
+    pub fn camera(&self) -> RwLockReadGuard<'_, B> {
+        self.shared.camera()
+    }
+
+    /// Exclusive access to the camera, e.g. for set_control_value(). This
+    /// waits up to the configured poll interval for the worker to yield.
+    pub fn camera_mut(&self) -> RwLockWriteGuard<'_, B> {
+        self.shared.camera_mut()
+    }
+}
+
+impl<B: CameraBackend + Send + Sync + 'static> Drop for VideoStream<B> {
+    fn drop(&mut self) {
+        if let Err(e) = self.stop() {
+            warn!("Error stopping video stream: {}", e);
+        }
+    }
+}
+
+impl<B: CameraBackend> Shared<B> {
+    fn lock_queue(&self) -> MutexGuard<'_, QueueState> {
+        lock_ignoring_poison(&self.queue)
+    }
+
+    fn camera(&self) -> RwLockReadGuard<'_, B> {
+        self.camera.read().unwrap_or_else(|e| e.into_inner())
+    }
+
+    fn camera_mut(&self) -> RwLockWriteGuard<'_, B> {
+        self.camera.write().unwrap_or_else(|e| e.into_inner())
+    }
+
+    // Body of the worker thread.
+    fn run(&self) {
+        let wait_ms = self.config.poll_interval.as_millis().min(i32::MAX as u128) as i32;
+        while !self.stop.load(Ordering::SeqCst) {
+            match self.capture(wait_ms) {
+                Ok(frame) => self.enqueue(frame),
+                Err(e) if e.kind() == ASIErrorKind::Timeout => (),
+                Err(e) => {
+                    warn!("Video stream stopped: {}", e);
+                    let mut queue = self.lock_queue();
+                    queue.worker_running = false;
+                    queue.error = Some(e);
+                    self.frame_ready.notify_all();
+                    return;
+                },
+            }
+        }
+        info!("Video stream stopped");
+        self.lock_queue().worker_running = false;
+        self.frame_ready.notify_all();
+    }
+
+    fn capture(&self, wait_ms: i32) -> Result<Frame, ASIError> {
+        let camera = self.camera();
+        let (width, height, bin, img_type) = camera.get_roi_format()?;
+        let data = self.pool.take(frame::frame_size(width, height, img_type));
+        let mut frame = Frame{width, height, bin, img_type,
+                              metadata: FrameMetadata::default(), data};
+        match camera.read_video_data(&mut frame.data, wait_ms)
+            .and_then(|()| camera.frame_metadata())
+        {
+            Ok(metadata) => {
+                frame.metadata = metadata;
+                Ok(frame)
+            },
+            Err(e) => {
+                self.pool.put(frame.data);
+                Err(e)
+            },
+        }
+    }
+
+    fn enqueue(&self, frame: Frame) {
+        let mut queue = self.lock_queue();
+        if queue.frames.len() >= self.config.queue_capacity.max(1) {
+            queue.queue_dropped += 1;
+            let dropped = match self.config.drop_policy {
+                DropPolicy::DropOldest => {
+                    let oldest = queue.frames.pop_front();
+                    queue.frames.push_back(frame);
+                    oldest
+                },
+                DropPolicy::DropNewest => Some(frame),
+            };
+            if let Some(dropped) = dropped {
+                self.pool.put(dropped.data);
+            }
+        } else {
+            queue.frames.push_back(frame);
+        }
+        self.frame_ready.notify_all();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::thread::sleep;
+
+    use super::*;
+    use crate::simulated_camera::SimulatedCamera;
+    use crate::simulated_camera::test_support::simulated_camera;
+    use crate::types::ImgType;
+
+    // Streams 1 ms frames into a queue of 2 for 50 ms without consuming them.
+    fn overflowed_stream(drop_policy: DropPolicy) -> VideoStream<SimulatedCamera> {
+        let config = VideoStreamConfig{queue_capacity: 2, drop_policy,
+                                       ..Default::default()};
+        let mut stream = VideoStream::start(simulated_camera(ImgType::Raw8), config).unwrap();
+        sleep(Duration::from_millis(50));
+        stream.stop().unwrap();
+        stream
+    }
+
+    fn queued_sequence_numbers(stream: &VideoStream<SimulatedCamera>) -> Vec<u64> {
+        std::iter::from_fn(|| stream.try_next_frame())
+            .map(|frame| frame.metadata.sequence_number)
+            .collect()
+    }
+
+    #[test]
+    fn drop_oldest() {
+        let stream = overflowed_stream(DropPolicy::DropOldest);
+        let dropped = stream.queue_dropped_frames();
+        assert!(dropped > 0);
+        assert_eq!(queued_sequence_numbers(&stream), [dropped, dropped + 1]);
+    }
+
+    #[test]
+    fn drop_newest() {
+        let stream = overflowed_stream(DropPolicy::DropNewest);
+        assert!(stream.queue_dropped_frames() > 0);
+        assert_eq!(queued_sequence_numbers(&stream), [0, 1]);
+    }
+
+    #[test]
+    fn sdk_dropped_frames() {
+        let config = VideoStreamConfig{queue_capacity: 1000, ..Default::default()};
+        let stream = VideoStream::start(simulated_camera(ImgType::Raw8), config).unwrap();
+        stream.next_frame(Some(Duration::from_secs(1))).unwrap();
+        // Frames complete while the worker is kept from reading them.
+        {
+            let _camera = stream.camera_mut();
+            sleep(Duration::from_millis(50));
+        }
+        // Time for the worker to read again.
+        sleep(Duration::from_millis(20));
+        assert!(stream.sdk_dropped_frames().unwrap() > 0);
+        assert_eq!(stream.queue_dropped_frames(), 0);
+    }
+
+    #[test]
+    fn buffers_reused() {
+        let mut stream = VideoStream::start(simulated_camera(ImgType::Raw8),
+                                            VideoStreamConfig::default()).unwrap();
+        stream.next_frame(Some(Duration::from_secs(1))).unwrap();
+        stream.next_frame(Some(Duration::from_secs(1))).unwrap();
+        // Fill the queue.
+        sleep(Duration::from_millis(20));
+        stream.stop().unwrap();
+        let pool = stream.shared.pool.clone();
+        let pooled = lock_ignoring_poison(&pool.buffers).len();
+        assert!(pooled > 0);
+
+        let frame = stream.next_frame(Some(Duration::ZERO)).unwrap();
+        let data = frame.data.as_ptr();
+        drop(frame);
+        assert_eq!(lock_ignoring_poison(&pool.buffers).len(), pooled + 1);
+        // The next capture gets the returned buffer.
+        let buffer = pool.take(64 * 32);
+        assert_eq!(buffer.as_ptr(), data);
+
+        // A frame taken with into_frame() keeps its buffer.
+        let frame = stream.next_frame(Some(Duration::ZERO)).unwrap().into_frame();
+        assert_eq!(lock_ignoring_poison(&pool.buffers).len(), pooled);
+        assert_eq!(frame.data.len(), 64 * 32);
+    }
+
+    #[test]
+    fn stop_and_restart() {
+        let mut stream = VideoStream::start(simulated_camera(ImgType::Raw8),
+                                            VideoStreamConfig::default()).unwrap();
+        stream.next_frame(Some(Duration::from_secs(1))).unwrap();
+        stream.stop().unwrap();
+        assert!(!stream.is_running());
+        while stream.try_next_frame().is_some() {}
+        assert_eq!(stream.next_frame(None).unwrap_err().kind(),
+                   ASIErrorKind::InvalidSequence);
+
+        stream.restart().unwrap();
+        assert!(stream.is_running());
+        // Already running.
+        stream.restart().unwrap();
+        stream.next_frame(Some(Duration::from_secs(1))).unwrap();
+        let (camera, result) = stream.into_camera();
+        result.unwrap();
+        // Capture was stopped.
+        assert_eq!(camera.get_video_frame(0).unwrap_err().kind(),
+                   ASIErrorKind::InvalidSequence);
+    }
+
+    #[test]
+    fn camera_returned_on_error() {
+        let stream = VideoStream::start(simulated_camera(ImgType::Raw8),
+                                        VideoStreamConfig::default()).unwrap();
+        stream.camera_mut().close().unwrap();
+        // Queued frames, then the error that stopped the worker.
+        let error = loop {
+            match stream.next_frame(Some(Duration::from_secs(1))) {
+                Ok(_frame) => (),
+                Err(e) => break e,
+            }
+        };
+        assert_eq!(error.kind(), ASIErrorKind::CameraClosed);
+        assert!(!stream.is_running());
+        let (camera, result) = stream.into_camera();
+        assert_eq!(result.unwrap_err().kind(), ASIErrorKind::CameraClosed);
+        assert_eq!(camera.camera_id(), 0);
+    }
+}
@@ -0,0 +1,362 @@
+// Copyright (c) 2023 Steven Rosenthal smr@dt3.org
+// See LICENSE file in root directory for license terms.
+
+use std::fmt::Write as _;
+use std::fs;
+use std::io::Write;
+use std::path::Path;
+use std::time::SystemTime;
+
+use flate2::Compression;
+use flate2::write::ZlibEncoder;
+use log::warn;
+
+use crate::asi_camera2_sdk::{ASIError, ASI_ERROR_CODE_ASI_ERROR_INVALID_PATH};
+use crate::camera_backend::CameraBackend;
+use crate::fits::{format_date, FitsHeader, FitsValue};
+use crate::frame::Frame;
+use crate::types::{CameraInfo, ImgType};
+
+const SIGNATURE: &[u8; 8] = b"XISF0100";
+// Size of the signature, header length and reserved fields.
+const PREAMBLE_SIZE: usize = 16;
+// The image data block is aligned to this within the file.
+const BLOCK_ALIGNMENT: usize = 4096;
+
+/// How the image data block of an XISF file is compressed. 16-bit data is
+/// byte-shuffled before compression, which typically makes it compress
+/// better.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum XisfCompression {
+    #[default]
+    None,
+    Zlib,
+    Lz4,
+}
+
+/// The value of an XISF Property element.
+#[derive(Clone, Debug, PartialEq)]
+pub enum XisfValue {
+    Int32(i32),
+    Float64(f64),
+    String(String),
+    /// Written as an ISO 8601 UTC time.
+    TimePoint(SystemTime),
+}
+
+/// The FITS keywords and XISF properties of an XISF image.
+#[derive(Clone, Debug, Default)]
+pub struct XisfMetadata {
+    /// Written as FITSKeyword elements.
+    pub fits_keywords: FitsHeader,
+    /// Written as Property elements, in order.
+    pub properties: Vec<(String, XisfValue)>,
+}
+
+impl XisfMetadata {
+    /// Returns the metadata for `frame`, captured by the camera described by
+    /// `camera_info`: the FITS keywords of FitsHeader::from_frame(), and the
+    /// corresponding XISF properties. The exposure, temperature, binning, pixel
+    /// size, camera name and start time use the standard Instrument and
+    /// Observation properties; gain, offset and ROI start position, which have
+    /// no standard property, use ones in an ASI namespace.
+    pub fn from_frame(frame: &Frame, camera_info: &CameraInfo) -> Self {
+        let metadata = &frame.metadata;
+        let exposure_start = metadata.system_time.checked_sub(metadata.exposure)
+            .unwrap_or(metadata.system_time);
+        let pixel_size = camera_info.pixel_size * frame.bin as f64;
+        let mut properties = vec![
+            ("Observation:Time:Start".to_string(), XisfValue::TimePoint(exposure_start)),
+            ("Instrument:ExposureTime".to_string(),
+             XisfValue::Float64(metadata.exposure.as_secs_f64())),
+            ("Instrument:Camera:Name".to_string(),
+             XisfValue::String(camera_info.name.clone())),
+            ("Instrument:Camera:XBinning".to_string(), XisfValue::Int32(frame.bin)),
+            ("Instrument:Camera:YBinning".to_string(), XisfValue::Int32(frame.bin)),
+            ("Instrument:Sensor:XPixelSize".to_string(), XisfValue::Float64(pixel_size)),
+            ("Instrument:Sensor:YPixelSize".to_string(), XisfValue::Float64(pixel_size)),
+        ];
+        if let Some(temperature) = metadata.temperature {
+            properties.push(("Instrument:Sensor:Temperature".to_string(),
+                             XisfValue::Float64(temperature)));
+        }
+        properties.extend([
+            ("ASI:Gain".to_string(), XisfValue::Int32(metadata.gain as i32)),
+            ("ASI:Offset".to_string(), XisfValue::Int32(metadata.offset as i32)),
+            ("ASI:ROI:StartX".to_string(), XisfValue::Int32(metadata.start_x)),
+            ("ASI:ROI:StartY".to_string(), XisfValue::Int32(metadata.start_y)),
+        ]);
+        XisfMetadata{fits_keywords: FitsHeader::from_frame(frame, camera_info),
+                     properties}
+    }
+}
+
+/// Returns `frame` as a monolithic XISF file with the given metadata.
+///
+/// RAW8 and Y8 frames are written as 8-bit grayscale images and RAW16 frames as
+/// 16-bit ones; RGB24 frames are written as 8-bit RGB, in planar R, G, B order.
+/// For raw frames from color cameras, the Bayer pattern (the FITS keywords'
+/// BAYERPAT, a four letter CFA code such as RGGB) is also given by a 2x2
+/// ColorFilterArray element.
+pub fn encode_xisf(frame: &Frame, metadata: &XisfMetadata,
+                   compression: XisfCompression) -> Vec<u8> {
+    let (sample_format, item_size) = match frame.img_type {
+        ImgType::Raw16 => ("UInt16", 2),
+        _ => ("UInt8", 1),
+    };
+    let (channels, color_space) = match frame.img_type {
+        ImgType::Rgb24 => (3, "RGB"),
+        _ => (1, "Gray"),
+    };
+    let data = match frame.img_type {
+        // The SDK delivers B, G, R bytes for each pixel.
+        ImgType::Rgb24 => [2, 1, 0].iter()
+            .flat_map(|channel| frame.data.iter().skip(*channel).step_by(3).copied())
+            .collect(),
+        // Little-endian samples, as the SDK delivers them.
+        _ => frame.data.clone(),
+    };
+    let (block, compression_attribute) = compress(&data, item_size, compression);
+
+    let mut image_elements = String::new();
+    for (keyword, value, comment) in metadata.fits_keywords.cards() {
+        let _ = writeln!(image_elements,
+                         "  <FITSKeyword name=\"{}\" value=\"{}\" comment=\"{}\"/>",
+                         escape(keyword), escape(&value), escape(comment));
+    }
+    // The pattern must give the colors of all 2x2 pixels, e.g. RGGB.
+    let is_raw = matches!(frame.img_type, ImgType::Raw8 | ImgType::Raw16);
+    if let Some(FitsValue::String(pattern)) = metadata.fits_keywords.get("BAYERPAT")
+        .filter(|value| is_raw && matches!(value, FitsValue::String(p) if p.len() == 4))
+    {
+        let _ = writeln!(image_elements,
+                         "  <ColorFilterArray pattern=\"{}\" width=\"2\" height=\"2\"/>",
+                         escape(pattern));
+    }
+    for (id, value) in &metadata.properties {
+        let _ = writeln!(image_elements, "  {}", property_element(id, value));
+    }
+
+    let header = |position: usize| {
+        let mut xml = String::new();
+        xml.push_str("<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n");
+        xml.push_str("<xisf version=\"1.0\" xmlns=\"http://www.pixinsight.com/xisf\" \
+                      xmlns:xsi=\"http://www.w3.org/2001/XMLSchema-instance\" \
+                      xsi:schemaLocation=\"http://www.pixinsight.com/xisf \
+                      http://pixinsight.com/xisf/xisf-1.0.xsd\">\n");
+        let _ = writeln!(xml, " <Image geometry=\"{}:{}:{}\" sampleFormat=\"{}\" \
+                               colorSpace=\"{}\" location=\"attachment:{}:{}\"{}>",
+                         frame.width, frame.height, channels, sample_format,
+                         color_space, position, block.len(), compression_attribute);
+        xml.push_str(&image_elements);
+        xml.push_str(" </Image>\n <Metadata>\n");
+        let _ = writeln!(xml, "  {}", property_element(
+            "XISF:CreationTime", &XisfValue::TimePoint(SystemTime::now())));
+        let _ = writeln!(xml, "  {}", property_element(
+            "XISF:CreatorApplication", &XisfValue::String("asi_camera2".to_string())));
+        xml.push_str(" </Metadata>\n</xisf>\n");
+        xml
+    };
+    // The header gives the position of the data block, which follows it. Allow
+    // for the position's digits when working out where that is.
+    let position = (PREAMBLE_SIZE + header(0).len() + 20).div_ceil(BLOCK_ALIGNMENT) *
+        BLOCK_ALIGNMENT;
+    let xml = header(position);
+
+    let mut bytes = Vec::with_capacity(position + block.len());
+    bytes.extend_from_slice(SIGNATURE);
+    bytes.extend_from_slice(&(xml.len() as u32).to_le_bytes());
+    bytes.extend_from_slice(&[0; 4]);  // Reserved.
+    bytes.extend_from_slice(xml.as_bytes());
+    bytes.resize(position, 0);
+    bytes.extend_from_slice(&block);
+    bytes
+}
+
+/// Writes `frame` with the given metadata to the XISF file `path`; see
+/// encode_xisf().
+pub fn write_xisf(frame: &Frame, metadata: &XisfMetadata, compression: XisfCompression,
+                  path: &Path) -> Result<(), ASIError> {
+    fs::write(path, encode_xisf(frame, metadata, compression)).map_err(|e| {
+        warn!("Error writing {:?}: {}", path, e);
+        ASIError::new(ASI_ERROR_CODE_ASI_ERROR_INVALID_PATH, "write_xisf")
+    })
+}
+
+/// Writes `frame`, just captured by `camera`, to the XISF file `path`, with the
+/// metadata of XisfMetadata::from_frame().
+pub fn save_xisf<B>(camera: &B, frame: &Frame, compression: XisfCompression, path: &Path)
+                    -> Result<(), ASIError>
+where B: CameraBackend + ?Sized
+{
+    let metadata = XisfMetadata::from_frame(frame, &camera.camera_info()?);
+    write_xisf(frame, &metadata, compression, path)
+}
+
+// Returns the data block for `data`, whose samples are `item_size` bytes, and
+// the Image element's compression attribute (empty if uncompressed).
+fn compress(data: &[u8], item_size: usize, compression: XisfCompression)
+            -> (Vec<u8>, String) {
+    let codec = match compression {
+        XisfCompression::None => return (data.to_vec(), String::new()),
+        XisfCompression::Zlib => "zlib",
+        XisfCompression::Lz4 => "lz4",
+    };
+    let shuffled;
+    let input = if item_size > 1 {
+        shuffled = shuffle(data, item_size);
+        &shuffled
+    } else {
+        data
+    };
+    let block = match compression {
+        XisfCompression::Zlib => {
+            let mut encoder = ZlibEncoder::new(Vec::new(), Compression::default());
+            // Writing to a Vec cannot fail.
+            encoder.write_all(input).and_then(|()| encoder.finish())
+                .expect("zlib compression into memory failed")
+        },
+        _ => lz4_flex::block::compress(input),
+    };
+    let attribute = if item_size > 1 {
+        format!(" compression=\"{}+sh:{}:{}\"", codec, data.len(), item_size)
+    } else {
+        format!(" compression=\"{}:{}\"", codec, data.len())
+    };
+    (block, attribute)
+}
+
+// Byte shuffling: the first bytes of all items, then the second bytes, etc.
+fn shuffle(data: &[u8], item_size: usize) -> Vec<u8> {
+    (0..item_size)
+        .flat_map(|byte| data.iter().skip(byte).step_by(item_size).copied())
+        .collect()
+}
+
+fn property_element(id: &str, value: &XisfValue) -> String {
+    let (type_name, value) = match value {
+        XisfValue::Int32(value) => ("Int32", value.to_string()),
+        XisfValue::Float64(value) => ("Float64", value.to_string()),
+        XisfValue::String(value) => ("String", value.clone()),
+        XisfValue::TimePoint(time) => ("TimePoint", format!("{}Z", format_date(*time))),
+    };
+    format!("<Property id=\"{}\" type=\"{}\" value=\"{}\"/>",
+            escape(id), type_name, escape(&value))
+}
+
+// Escapes `text` for use in an XML attribute value.
+fn escape(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            '\'' => escaped.push_str("&apos;"),
+            _ => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Read;
+
+    use flate2::read::ZlibDecoder;
+
+    use super::*;
+    use crate::simulated_camera::test_support::simulated_frame;
+
+    // Returns the XML header of `xisf` and its data block, as given by the
+    // header's attachment location.
+    fn parse(xisf: &[u8]) -> (&str, &[u8]) {
+        assert_eq!(&xisf[..8], SIGNATURE);
+        let xml_size = u32::from_le_bytes(xisf[8..12].try_into().unwrap()) as usize;
+        let xml = std::str::from_utf8(&xisf[PREAMBLE_SIZE..PREAMBLE_SIZE + xml_size])
+            .unwrap();
+        let location = xml.split("location=\"attachment:").nth(1).unwrap();
+        let location: Vec<usize> = location[..location.find('"').unwrap()]
+            .split(':').map(|number| number.parse().unwrap()).collect();
+        let (position, size) = (location[0], location[1]);
+        assert_eq!(position % BLOCK_ALIGNMENT, 0);
+        assert!(position >= PREAMBLE_SIZE + xml_size);
+        assert_eq!(xisf.len(), position + size);
+        (xml, &xisf[position..])
+    }
+
+    // Undoes shuffle().
+    fn unshuffle(data: &[u8], item_size: usize) -> Vec<u8> {
+        let items = data.len() / item_size;
+        (0..data.len()).map(|i| data[(i % item_size) * items + i / item_size]).collect()
+    }
+
+    #[test]
+    fn raw16_compressions() {
+        let (frame, camera_info) = simulated_frame(ImgType::Raw16);
+        let metadata = XisfMetadata::from_frame(&frame, &camera_info);
+        let size = frame.data.len();
+        for compression in [XisfCompression::None, XisfCompression::Zlib,
+                            XisfCompression::Lz4] {
+            let xisf = encode_xisf(&frame, &metadata, compression);
+            let (xml, block) = parse(&xisf);
+            assert!(xml.contains(" <Image geometry=\"64:32:1\" sampleFormat=\"UInt16\" \
+                                  colorSpace=\"Gray\""));
+            assert!(xml.contains(
+                "<ColorFilterArray pattern=\"RGGB\" width=\"2\" height=\"2\"/>"));
+            assert!(xml.contains("<FITSKeyword name=\"BAYERPAT\" value=\"&apos;RGGB&apos;\""));
+            assert!(xml.contains("<Property id=\"ASI:ROI:StartX\" type=\"Int32\" \
+                                  value=\"608\"/>"));
+            let data = match compression {
+                XisfCompression::None => {
+                    assert!(!xml.contains("compression="));
+                    block.to_vec()
+                },
+                XisfCompression::Zlib => {
+                    assert!(xml.contains(&format!("compression=\"zlib+sh:{}:2\"", size)));
+                    let mut shuffled = Vec::new();
+                    ZlibDecoder::new(block).read_to_end(&mut shuffled).unwrap();
+                    unshuffle(&shuffled, 2)
+                },
+                XisfCompression::Lz4 => {
+                    assert!(xml.contains(&format!("compression=\"lz4+sh:{}:2\"", size)));
+                    unshuffle(&lz4_flex::block::decompress(block, size).unwrap(), 2)
+                },
+            };
+            assert_eq!(data, frame.data);
+        }
+    }
+
+    #[test]
+    fn rgb24_planes() {
+        let (frame, camera_info) = simulated_frame(ImgType::Rgb24);
+        let metadata = XisfMetadata::from_frame(&frame, &camera_info);
+        let xisf = encode_xisf(&frame, &metadata, XisfCompression::Lz4);
+        let (xml, block) = parse(&xisf);
+        assert!(xml.contains(" <Image geometry=\"64:32:3\" sampleFormat=\"UInt8\" \
+                              colorSpace=\"RGB\""));
+        assert!(xml.contains(&format!("compression=\"lz4:{}\"", frame.data.len())));
+        assert!(!xml.contains("ColorFilterArray"));
+        let data = lz4_flex::block::decompress(block, frame.data.len()).unwrap();
+        let plane_size = frame.data.len() / 3;
+        for (plane, channel) in [2, 1, 0].into_iter().enumerate() {
+            assert!(data[plane * plane_size..(plane + 1) * plane_size].iter()
+                    .eq(frame.data.iter().skip(channel).step_by(3)));
+        }
+    }
+
+    #[test]
+    fn escaped_metadata() {
+        let (frame, _camera_info) = simulated_frame(ImgType::Raw8);
+        let metadata = XisfMetadata{
+            properties: vec![("Observation:Object:Name".to_string(),
+                              XisfValue::String("M 42 <\"Orion\" & co>".to_string()))],
+            ..Default::default()
+        };
+        let xisf = encode_xisf(&frame, &metadata, XisfCompression::None);
+        let (xml, block) = parse(&xisf);
+        assert!(xml.contains("value=\"M 42 &lt;&quot;Orion&quot; &amp; co&gt;\""));
+        assert_eq!(block, frame.data);
+    }
+}